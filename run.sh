@@ -2,23 +2,29 @@
 
 set -euo pipefail
 
+cargo build --bin feather_pd
 cargo build --bin feather_kv
 cargo build --bin feather_db
 
+# The output is prefixed via process substitution, so that the jobs are the servers themselves.
+for ID in a; do
+    ./target/debug/feather_pd clusters/feather_pds/pd-$ID/feather_pd.yaml > >(sed -e "s/\\(.*\\)/|pd-$ID|  \\1/g") 2>&1 &
+done
+
 for ID in a b c; do
-    (./target/debug/feather_kv clusters/feather_kvs/kv-$ID/feather_kv.yaml 2>&1 | sed -e "s/\\(.*\\)/|kv-$ID|  \\1/g") &
+    ./target/debug/feather_kv clusters/feather_kvs/kv-$ID/feather_kv.yaml > >(sed -e "s/\\(.*\\)/|kv-$ID|  \\1/g") 2>&1 &
 done
 
 for ID in a; do
-    (./target/debug/feather_db clusters/feather_dbs/db-$ID/feather_db.yaml 2>&1 | sed -e "s/\\(.*\\)/|db-$ID|  \\1/g") &
+    ./target/debug/feather_db clusters/feather_dbs/db-$ID/feather_db.yaml > >(sed -e "s/\\(.*\\)/|db-$ID|  \\1/g") 2>&1 &
 done
 
-exit_port() {
-    for PORT in 9501 9701 9702 9703 9601 9602 9603; do
-        lsof -i :$PORT | awk 'NR!=1 {print $2}' | xargs kill
-    done
-    kill $(jobs -p)
+# Asks the servers to shut down gracefully, and waits for them to exit.
+shutdown() {
+    trap - EXIT INT TERM
+    kill -TERM $(jobs -p) 2>/dev/null || true
+    wait
 }
 
-trap exit_port EXIT
-wait < <(jobs -p)
\ No newline at end of file
+trap shutdown EXIT INT TERM
+wait
\ No newline at end of file
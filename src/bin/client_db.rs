@@ -10,7 +10,7 @@ use rustyline_derive::{Completer, Helper, Highlighter, Hinter};
 use tonic::transport::Channel;
 
 use featherdb::server::{ClientRequest, serialize, deserialize, ClientResponse};
-use featherdb::sql::parser::{Lexer, Token, Symbol};
+use featherdb::sql::parser::{format_timestamp, Lexer, Token, Symbol};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -112,6 +112,10 @@ impl FeatherClient {
                             "  Began read-only transaction {} in snapshot at version {}",
                             id, version
                         ),
+                        Mode::AsOf { time } => println!(
+                            "  Began read-only transaction {} in snapshot as of {}",
+                            id, format_timestamp(time)
+                        ),
                     },
                     ResultSet::Commit { id } => println!("  Committed transaction {}", id),
                     ResultSet::Rollback { id } => println!("  Rolled back transaction {}", id),
@@ -189,7 +193,22 @@ The following commands are also available:
             ),
 
             "!status" => {
-                todo!()
+                getargs(0)?;
+                let request = tonic::Request::new(ExecutionArgs {
+                    session_id: self.session_id,
+                    sequence_number: self.sequence_number,
+                    client_request: serialize(&ClientRequest::Status)?,
+                });
+                let reply = self.client.execute(request).await?.into_inner();
+
+                match deserialize::<Result<ClientResponse>>(&reply.result)?? {
+                    ClientResponse::Status(nodes) => {
+                        for (group_id, status) in nodes.values().flat_map(|groups| groups.iter()) {
+                            println!("Group {} on {}", group_id, status);
+                        }
+                    },
+                    _ => return Err(Error::Internal("Unexpected reply.".to_string())),
+                }
             },
 
             "!table" => {
@@ -14,7 +14,7 @@ use rustyline_derive::{Completer, Helper, Highlighter, Hinter};
 use tonic::transport::Channel;
 
 use featherdb::server::{ClientRequest, serialize, deserialize, ClientResponse};
-use featherdb::sql::parser::{Lexer, Token, Symbol};
+use featherdb::sql::parser::{format_timestamp, Lexer, Token, Symbol};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -118,6 +118,10 @@ impl FeatherClient {
                             "  Began read-only transaction {} in snapshot at version {}",
                             id, version
                         ),
+                        Mode::AsOf { time } => println!(
+                            "  Began read-only transaction {} in snapshot as of {}",
+                            id, format_timestamp(time)
+                        ),
                     },
                     ResultSet::Commit { id } => println!("  Committed transaction {}", id),
                     ResultSet::Rollback { id } => println!("  Rolled back transaction {}", id),
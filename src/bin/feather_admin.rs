@@ -0,0 +1,66 @@
+use std::collections::{BTreeMap, HashMap};
+
+use featherdb::error::{Error, Result};
+use featherdb::raft::Client;
+use serde::Deserialize;
+
+const USAGE: &str = "Usage: feather_admin <config_file_path> <command> [args]
+
+Commands:
+    add-node <id> <raft_addr>    Adds a node to the cluster, promoted to voter once caught up
+    add-witness <id> <raft_addr> Adds a witness, which votes but only stores entry headers
+    remove-node <id>             Removes a node from the cluster
+    transfer-leader <id>         Transfers leadership to the given voter";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 {
+        return Err(Error::Config(USAGE.to_string()));
+    }
+    let config = Config::new(&args[1])?;
+    let mut client = Client::new(config.kv_addrs).await?;
+
+    match (args[2].as_str(), &args[3..]) {
+        ("add-node", [id, addr]) => {
+            client.add_node(parse_id(id)?, addr.clone()).await?;
+            println!("Added node {} at {}", id, addr);
+        }
+        ("add-witness", [id, addr]) => {
+            client.add_witness(parse_id(id)?, addr.clone()).await?;
+            println!("Added witness {} at {}", id, addr);
+        }
+        ("remove-node", [id]) => {
+            client.remove_node(parse_id(id)?).await?;
+            println!("Removed node {}", id);
+        }
+        ("transfer-leader", [id]) => {
+            client.transfer_leader(parse_id(id)?).await?;
+            println!("Transferring leadership to node {}", id);
+        }
+        _ => return Err(Error::Config(USAGE.to_string())),
+    }
+    Ok(())
+}
+
+/// Parses a node ID argument.
+fn parse_id(id: &str) -> Result<u64> {
+    id.parse().map_err(|_| Error::Config(format!("Invalid node ID {}", id)))
+}
+
+#[derive(Debug, Deserialize)]
+struct Config {
+    kv_addrs: BTreeMap<u64, String>,
+}
+
+impl Config {
+    fn new(file: &str) -> Result<Self> {
+        let c = config::Config::builder()
+            .set_default("kv_addrs", HashMap::<String, String>::new())?
+
+            .add_source(config::File::with_name(file))
+            .add_source(config::Environment::with_prefix("FEATHERDB"));
+
+        Ok(c.build()?.try_deserialize()?)
+    }
+}
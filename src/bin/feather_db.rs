@@ -1,9 +1,20 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
 use featherdb::error::{Error, Result};
+use featherdb::concurrency::GcOptions;
 use featherdb::FeatherDB;
 use featherdb::proto::featherdb::FeatherDbServer;
+use featherdb::raft::Client;
+use featherdb::server::shutdown_signal;
+use featherdb::sql::engine::{Collector, Resolver};
 use tonic::transport::Server;
 use serde::Deserialize;
 
+/// How long to wait before retrying to connect a background task's client.
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let args: Vec<String> = std::env::args().collect();
@@ -11,30 +22,65 @@ async fn main() -> Result<()> {
         return Err(Error::Config("Usage: feather_db <config_file_path>".to_string()));
     }
     let config = Config::new(&args[1])?;
-    let server = FeatherDB::new(config.kv_addrs);
+    let server = Arc::new(FeatherDB::new(config.pd_addrs.clone()));
+
+    // Resolves the distributed transactions left prepared by sessions that died mid-commit.
+    let placement = config.pd_addrs.clone();
+    tokio::spawn(async move { Resolver::new(placed_client(placement).await).run().await });
+
+    // Garbage collects the ranges' stores through their Raft logs.
+    if config.gc_interval_ms > 0 {
+        let placement = config.pd_addrs.clone();
+        let opts = GcOptions {
+            interval: Duration::from_millis(config.gc_interval_ms),
+            batch_size: config.gc_batch_size,
+            retention: Duration::from_millis(config.gc_retention_ms),
+        };
+        tokio::spawn(async move { Collector::new(placed_client(placement).await, opts).run().await });
+    }
 
     println!("FeatherDB server listening on {}...", config.serve_addr.clone());
 
     Server::builder()
-        .add_service(FeatherDbServer::new(server))
-        .serve(config.serve_addr.parse()?)
+        .add_service(FeatherDbServer::from_arc(server.clone()))
+        .serve_with_shutdown(config.serve_addr.parse()?, shutdown_signal())
         .await
-        .or_else(|e| Err(Error::Internal(format!("FeatherDB server failed: {:?}", e))))
+        .or_else(|e| Err(Error::Internal(format!("FeatherDB server failed: {:?}", e))))?;
+    server.shutdown().await
+}
+
+/// Creates a client routed via the placement driver for a background task. Failures, e.g. while
+/// the placement driver is still starting, are logged and retried, so that the task does not end.
+async fn placed_client(placement: BTreeMap<u64, String>) -> Client {
+    loop {
+        match Client::placed(placement.clone()).await {
+            Ok(client) => return client,
+            Err(e) => println!("Failed to connect to the placement driver: {}", e),
+        }
+        tokio::time::sleep(CONNECT_RETRY_INTERVAL).await;
+    }
 }
 
 #[derive(Debug, Deserialize)]
 struct Config {
     // id: String,
-    kv_addrs: Vec<String>,
+    pd_addrs: BTreeMap<u64, String>,
     serve_addr: String,
+    gc_interval_ms: u64,
+    gc_batch_size: usize,
+    gc_retention_ms: u64,
 }
 
 impl Config {
     fn new(file: &str) -> Result<Self> {
+        let gc = GcOptions::default();
         let c = config::Config::builder()
             // .set_default("id", "toydb")?
-            .set_default("kv_addrs", Vec::<String>::new())?
+            .set_default("pd_addrs", HashMap::<String, String>::new())?
             .set_default("serve_addr", String::new())?
+            .set_default("gc_interval_ms", gc.interval.as_millis() as u64)?
+            .set_default("gc_batch_size", gc.batch_size as u64)?
+            .set_default("gc_retention_ms", gc.retention.as_millis() as u64)?
 
             .add_source(config::File::with_name(file))
             .add_source(config::Environment::with_prefix("FEATHERDB"));
@@ -1,6 +1,12 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
 use featherdb::error::{Error, Result};
 use featherdb::proto::featherkv::FeatherKvServer;
-use featherdb::{concurrency, FeatherKV, sql, storage};
+use featherdb::server::shutdown_signal;
+use featherdb::{concurrency, FeatherKV, raft, sql, storage};
 use tempfile::tempdir;
 use tonic::transport::Server;
 use serde::Deserialize;
@@ -12,42 +18,97 @@ async fn main() -> Result<()> {
         return Err(Error::Config("Usage: feather_kv <config_file_path>".to_string()));
     }
     let config = Config::new(&args[1])?;
+    raft::Node::check_id(Path::new(&config.data_dir), config.id)?;
+
+    let opts = raft::Options {
+        pre_vote: config.pre_vote,
+        check_quorum: config.check_quorum,
+        lease_reads: config.lease_reads,
+        tick_interval: Duration::from_millis(config.tick_interval_ms),
+        heartbeat_interval: config.heartbeat_interval,
+        election_timeout_min: config.election_timeout_min,
+        election_timeout_max: config.election_timeout_max,
+        session_clock_interval: Duration::from_millis(config.session_clock_interval_ms),
+    };
+    let ranges = raft::RangeTable::new(
+        config.split_keys.iter().map(|key| key.as_bytes().to_vec()).collect(),
+    )?;
+    let split = raft::SplitOptions {
+        max_size: config.split_max_size,
+        max_load: config.split_max_load,
+        merge_size: config.merge_size,
+    };
+    let group_config = config.clone();
+    let server = Arc::new(FeatherKV::new(
+        config.id,
+        config.peers.clone(),
+        config.join,
+        opts,
+        ranges,
+        split,
+        move |group_id| open_group(&group_config, group_id),
+    ).await?);
+    if !config.pd_addrs.is_empty() {
+        server.report_to(config.pd_addrs.clone(), config.serve_addr.clone(), config.capacity);
+    }
 
+    println!("FeatherKV server listening on {}...", config.serve_addr.clone());
+
+    Server::builder()
+        .add_service(FeatherKvServer::from_arc(server.clone()))
+        .serve_with_shutdown(config.serve_addr.parse()?, shutdown_signal())
+        .await
+        .or_else(|e| Err(Error::Internal(format!("FeatherKV server failed: {:?}", e))))?;
+    server.shutdown().await
+}
+
+/// Opens the state machine and Raft log storage of a range's Raft group. Each group keeps its
+/// on-disk data in its own subdirectory of the data directory.
+fn open_group(
+    config: &Config,
+    group_id: u64,
+) -> Result<(Box<dyn raft::State>, Box<dyn storage::log::LogStore>)> {
     let log_store: Box<dyn storage::log::LogStore> = match config.storage_log.as_str() {
         "memory" => Box::new(storage::log::Memory::new()),
         name => return Err(Error::Config(format!("Unknown log storage engine {}", name))),
     };
 
-    let kv_dir = tempdir().unwrap();
     let kv_store: Box<dyn storage::kv::KvStore> = match config.storage_kv.as_str() {
-        "LSM_tempdir" => Box::new(storage::kv::LsmStorage::open(kv_dir)?),
-        "LSM_local" => Box::new(storage::kv::LsmStorage::open(config.data_dir.clone())?),
+        "LSM_tempdir" => Box::new(storage::kv::LsmStorage::open(tempdir()?)?),
+        "LSM_local" => Box::new(storage::kv::LsmStorage::open(
+            Path::new(&config.data_dir).join(format!("group-{}", group_id)),
+        )?),
         "B+tree_memory" => Box::new(storage::kv::StdBPlusTree::new()),
         name => return Err(Error::Config(format!("Unknown key-value storage engine {}", name))),
     };
 
-    let state = Box::new(
-        sql::engine::StateMachine::new(
-            concurrency::MVCC::new(kv_store, true),
-        )?
-    );
-
-    let server = FeatherKV::new(config.id, config.peers.clone(), state, log_store).await?;
-
-    println!("FeatherKV server listening on {}...", config.serve_addr.clone());
-
-    Server::builder()
-        .add_service(FeatherKvServer::new(server))
-        .serve(config.serve_addr.parse()?)
-        .await
-        .or_else(|e| Err(Error::Internal(format!("FeatherKV server failed: {:?}", e))))
+    // Garbage is collected through the group's log by the SQL servers, not by a local collector,
+    // so that the replicas stay identical.
+    let mvcc = concurrency::MVCC::new(kv_store, true);
+    let state = Box::new(sql::engine::StateMachine::new(mvcc)?);
+    Ok((state, log_store))
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 struct Config {
     id: u64,
-    peers: Vec<String>,
+    peers: BTreeMap<u64, String>,
+    join: bool,
+    pre_vote: bool,
+    check_quorum: bool,
+    lease_reads: bool,
+    tick_interval_ms: u64,
+    heartbeat_interval: u64,
+    election_timeout_min: u64,
+    election_timeout_max: u64,
+    session_clock_interval_ms: u64,
+    split_keys: Vec<String>,
+    split_max_size: u64,
+    split_max_load: u64,
+    merge_size: u64,
     serve_addr: String,
+    pd_addrs: BTreeMap<u64, String>,
+    capacity: u64,
     // log_level: String,
     data_dir: String,
     // sync: bool,
@@ -57,10 +118,26 @@ struct Config {
 
 impl Config {
     fn new(file: &str) -> Result<Self> {
+        let opts = raft::Options::default();
         let c = config::Config::builder()
             .set_default("id", 0)?
-            .set_default("peers", Vec::<String>::new())?
+            .set_default("peers", HashMap::<String, String>::new())?
+            .set_default("join", false)?
+            .set_default("pre_vote", true)?
+            .set_default("check_quorum", true)?
+            .set_default("lease_reads", false)?
+            .set_default("tick_interval_ms", opts.tick_interval.as_millis() as u64)?
+            .set_default("heartbeat_interval", opts.heartbeat_interval)?
+            .set_default("election_timeout_min", opts.election_timeout_min)?
+            .set_default("election_timeout_max", opts.election_timeout_max)?
+            .set_default("session_clock_interval_ms", opts.session_clock_interval.as_millis() as u64)?
+            .set_default("split_keys", Vec::<String>::new())?
+            .set_default("split_max_size", 0)?
+            .set_default("split_max_load", 0)?
+            .set_default("merge_size", 0)?
             .set_default("serve_addr", String::new())?
+            .set_default("pd_addrs", HashMap::<String, String>::new())?
+            .set_default("capacity", 0)?
             // .set_default("log_level", "info")?
             .set_default("data_dir", "/var/lib/toydb")?
             // .set_default("sync", true)?
@@ -0,0 +1,69 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use featherdb::error::{Error, Result};
+use featherdb::placement::{PlacementState, Scheduler};
+use featherdb::proto::featherkv::FeatherKvServer;
+use featherdb::server::shutdown_signal;
+use featherdb::{FeatherKV, raft, storage};
+use tonic::transport::Server;
+use serde::Deserialize;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 2 {
+        return Err(Error::Config("Usage: feather_pd <config_file_path>".to_string()));
+    }
+    let config = Config::new(&args[1])?;
+
+    // The placement state is a single range, replicated by group 0 on the placement driver's nodes.
+    let server = Arc::new(FeatherKV::new(
+        config.id,
+        config.peers.clone(),
+        false,
+        raft::Options::default(),
+        raft::RangeTable::default(),
+        raft::SplitOptions::default(),
+        |_| {
+            let state: Box<dyn raft::State> = Box::new(PlacementState::new());
+            let log_store: Box<dyn storage::log::LogStore> = Box::new(storage::log::Memory::new());
+            Ok((state, log_store))
+        },
+    ).await?);
+    tokio::spawn(Scheduler::new(config.replicas).run(server.clone(), config.pd_addrs.clone()));
+
+    println!("Placement driver listening on {}...", config.serve_addr.clone());
+
+    Server::builder()
+        .add_service(FeatherKvServer::from_arc(server.clone()))
+        .serve_with_shutdown(config.serve_addr.parse()?, shutdown_signal())
+        .await
+        .map_err(|e| Error::Internal(format!("Placement driver failed: {:?}", e)))?;
+    server.shutdown().await
+}
+
+#[derive(Debug, Deserialize)]
+struct Config {
+    id: u64,
+    peers: BTreeMap<u64, String>,
+    pd_addrs: BTreeMap<u64, String>,
+    replicas: usize,
+    serve_addr: String,
+}
+
+impl Config {
+    fn new(file: &str) -> Result<Self> {
+        let c = config::Config::builder()
+            .set_default("id", 0)?
+            .set_default("peers", HashMap::<String, String>::new())?
+            .set_default("pd_addrs", HashMap::<String, String>::new())?
+            .set_default("replicas", 3)?
+            .set_default("serve_addr", String::new())?
+
+            .add_source(config::File::with_name(file))
+            .add_source(config::Environment::with_prefix("FEATHERDB"));
+
+        Ok(c.build()?.try_deserialize()?)
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::storage::kv::{KvStore, Range};
+use super::transaction::{now_millis, MvccKey};
+use super::Mode;
+
+/// Options for background garbage collection, whether by a local [`GarbageCollector`] or by
+/// batches proposed to a replicated store's log.
+#[derive(Clone, Copy, Debug)]
+pub struct GcOptions {
+    /// How long to wait between batches.
+    pub interval: Duration,
+    /// The number of keys examined per batch. A batch holds the store's write lock, blocking
+    /// transactions while it runs.
+    pub batch_size: usize,
+    /// How long versions stay readable by `Mode::AsOf` snapshots after they were superseded.
+    pub retention: Duration,
+}
+
+impl Default for GcOptions {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(100),
+            batch_size: 1000,
+            retention: Duration::from_secs(3600),
+        }
+    }
+}
+
+/// The outcome of a garbage collection batch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GcStats {
+    /// The low watermark. Every version below it is visible to all readers, so only the latest
+    /// of them is kept for each key.
+    pub watermark: u64,
+    /// The number of obsolete versions removed.
+    pub versions: u64,
+    /// The number of committed transactions' update markers removed.
+    pub updates: u64,
+    /// The number of snapshots removed that no transaction can use anymore.
+    pub snapshots: u64,
+    /// The number of commit times removed, of transactions below the watermark.
+    pub commits: u64,
+    /// Whether the batch finished a pass over the store.
+    pub complete: bool,
+}
+
+/// An incremental garbage collector for an MVCC store. Each batch advances the low watermark
+/// to the oldest version visible to an active transaction, or to a `Mode::AsOf` snapshot within
+/// the retention period, and then examines the next keys of the current pass over the store:
+///
+/// * Of the versions of a key below the watermark, all but the latest are removed, and so is the
+///   latest if it is a deletion.
+/// * The update markers of transactions below the watermark, which have all committed, are
+///   removed, since they are only needed for rollbacks.
+/// * The snapshots of versions below the watermark are removed. Snapshot transactions can no
+///   longer begin at those versions.
+/// * The commit times of transactions below the watermark are removed. `Mode::AsOf` snapshots
+///   can no longer begin before the latest of them.
+///
+/// The collector uses the local clock and keeps its pass in memory, so it must only run on stores
+/// that are not replicated, or the replicas would diverge. Replicated stores collect through their
+/// log instead, with `MVCC::collect_garbage()`.
+pub struct GarbageCollector {
+    /// The underlying KV store. The collector does not keep it alive.
+    store: Weak<RwLock<Box<dyn KvStore>>>,
+    /// The maximum number of keys examined per batch.
+    batch_size: usize,
+    /// How long superseded versions are kept for `Mode::AsOf` snapshots.
+    retention: Duration,
+    /// The key the current pass continues from, or None to start a new pass.
+    cursor: Option<Vec<u8>>,
+}
+
+impl GarbageCollector {
+    /// Creates a garbage collector for a store.
+    pub(super) fn new(store: &Arc<RwLock<Box<dyn KvStore>>>, batch_size: usize) -> Self {
+        Self {
+            store: Arc::downgrade(store),
+            batch_size: batch_size.max(1),
+            retention: Duration::ZERO,
+            cursor: None,
+        }
+    }
+
+    /// Keeps the versions needed by `Mode::AsOf` snapshots up to a retention period in the past.
+    /// Without one, only active transactions hold back collection.
+    pub fn retain(mut self, retention: Duration) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Collects the next batch of keys. Returns None if the store has been dropped. The retention
+    /// period is counted back from the local clock, or from the closed time if commits have been
+    /// moved past it.
+    pub fn step(&mut self) -> Result<Option<GcStats>> {
+        let store = match self.store.upgrade() {
+            Some(store) => store,
+            None => return Ok(None),
+        };
+        let session = store.write();
+        let now = now_millis().max(clock(&**session)?);
+        let cutoff = now.saturating_sub(self.retention.as_millis() as u64);
+        Ok(Some(self.collect(&**session, cutoff)?))
+    }
+
+    /// Runs the collector on a background thread, collecting a batch every interval until the
+    /// store is dropped.
+    pub(super) fn spawn(mut self, interval: Duration) -> Result<()> {
+        std::thread::Builder::new()
+            .name("mvcc-gc".into())
+            .spawn(move || loop {
+                std::thread::sleep(interval);
+                match self.step() {
+                    Ok(Some(_)) => { },
+                    Ok(None) => return,
+                    Err(err) => println!("MVCC garbage collection failed: {:?}", err),
+                }
+            })?;
+        Ok(())
+    }
+
+    /// Collects the next batch of keys from a locked store, keeping the versions needed by
+    /// `Mode::AsOf` snapshots of times after the cutoff.
+    pub(super) fn collect(&mut self, session: &dyn KvStore, cutoff: u64) -> Result<GcStats> {
+        let watermark = advance_watermark(session, cutoff)?.version;
+        let mut stats = GcStats { watermark, ..GcStats::default() };
+        let mut garbage = vec![];
+        // The versions below the watermark of the key being examined, in order.
+        let mut versions: Vec<(Vec<u8>, Vec<u8>)> = vec![];
+        let mut current: Option<Vec<u8>> = None;
+        let mut examined = 0;
+
+        let start = self.cursor.take().unwrap_or_default();
+        let mut scan = session.scan(Range::from(start..))?;
+        while let Some((key, value)) = scan.next().transpose()? {
+            let decoded = MvccKey::decode(&key)?;
+            // A batch ends between keys, so that all versions of a key are examined together.
+            let next_key = match &decoded {
+                MvccKey::Record(user_key, _) => current.as_deref() != Some(&**user_key),
+                _ => true,
+            };
+            if next_key {
+                collect_versions(&mut versions, &mut garbage, &mut stats)?;
+                if examined >= self.batch_size {
+                    self.cursor = Some(key);
+                    break;
+                }
+            }
+            examined += 1;
+            match decoded {
+                MvccKey::Record(user_key, version) => {
+                    if next_key {
+                        current = Some(user_key.into_owned());
+                    }
+                    if version < watermark {
+                        versions.push((key, value));
+                    }
+                },
+                MvccKey::TxnUpdate(id, _) if id < watermark => {
+                    garbage.push(key);
+                    stats.updates += 1;
+                },
+                MvccKey::TxnSnapshot(version) if version < watermark => {
+                    garbage.push(key);
+                    stats.snapshots += 1;
+                },
+                MvccKey::TxnCommitted(version) if version < watermark => {
+                    garbage.push(key);
+                    stats.commits += 1;
+                },
+                _ => { },
+            }
+        }
+        std::mem::drop(scan);
+        collect_versions(&mut versions, &mut garbage, &mut stats)?;
+        stats.complete = self.cursor.is_none();
+
+        for key in garbage {
+            session.delete(&key)?;
+        }
+        Ok(stats)
+    }
+
+    /// Collects the next batch of keys from a locked store like `collect()`, but continues the
+    /// pass recorded in the store rather than the collector's own, and records where it stopped.
+    /// The retention period is counted back from the latest commit time recorded in the store
+    /// rather than from the local clock, so replicas applying the same batches collect the same
+    /// keys and record the same watermark.
+    pub(super) fn collect_recorded(&mut self, session: &dyn KvStore, retention: Duration) -> Result<GcStats> {
+        let cutoff = clock(session)?.saturating_sub(retention.as_millis() as u64);
+        self.cursor = session.get(&MvccKey::GcCursor.encode())?;
+        let stats = self.collect(session, cutoff)?;
+        match self.cursor.take() {
+            Some(cursor) => session.set(&MvccKey::GcCursor.encode(), cursor)?,
+            None => session.delete(&MvccKey::GcCursor.encode())?,
+        }
+        Ok(stats)
+    }
+}
+
+/// Marks the obsolete versions of a key below the watermark as garbage, given in order: all but
+/// the latest, and the latest too if it is a deletion, since no reader can see an older version.
+fn collect_versions(
+    versions: &mut Vec<(Vec<u8>, Vec<u8>)>,
+    garbage: &mut Vec<Vec<u8>>,
+    stats: &mut GcStats,
+) -> Result<()> {
+    let latest = match versions.pop() {
+        Some(latest) => latest,
+        None => return Ok(()),
+    };
+    stats.versions += versions.len() as u64;
+    garbage.extend(versions.drain(..).map(|(key, _)| key));
+    if deserialize::<Option<Vec<u8>>>(&latest.1)?.is_none() {
+        garbage.push(latest.0);
+        stats.versions += 1;
+    }
+    Ok(())
+}
+
+/// The garbage collection low watermark.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(super) struct Watermark {
+    /// The lowest version that may not be visible to all readers. Of the versions below it,
+    /// only the latest of each key is kept.
+    pub version: u64,
+    /// The latest commit time of the transactions below the version, which all readers see.
+    pub time: u64,
+}
+
+/// Returns the low watermark last recorded by garbage collection, or a zero one if none. Versions below
+/// it may have been collected, so snapshots that could see them are rejected.
+pub(super) fn watermark(session: &dyn KvStore) -> Result<Watermark> {
+    match session.get(&MvccKey::GcWatermark.encode())? {
+        Some(ref v) => deserialize(v),
+        None => Ok(Watermark::default()),
+    }
+}
+
+/// Returns the latest commit time recorded in a store, or 0 if none.
+pub(super) fn clock(session: &dyn KvStore) -> Result<u64> {
+    match session.get(&MvccKey::TxnClock.encode())? {
+        Some(ref v) => deserialize(v),
+        None => Ok(0),
+    }
+}
+
+/// Advances the low watermark to the lowest version a reader may not see, and records it. A
+/// transaction sees the versions up to its snapshot version, except for those of the
+/// transactions active when the snapshot was taken, so all versions below both are visible to it.
+/// Without active transactions, the watermark is the next transaction ID.
+///
+/// `Mode::AsOf` snapshots of times after the cutoff, which may yet begin, do not see the versions
+/// committed after their time, so the watermark also stays at the lowest version committed after
+/// the cutoff. Commit times are indexed by version, but need not increase with it, so this is the
+/// first version after the last watermark found committed after the cutoff.
+fn advance_watermark(session: &dyn KvStore, cutoff: u64) -> Result<Watermark> {
+    let mut lowest = match session.get(&MvccKey::TxnNext.encode())? {
+        Some(ref v) => deserialize(v)?,
+        None => 1,
+    };
+    let active = session
+        .scan(Range::from(MvccKey::TxnActive(0).encode()..MvccKey::TxnActive(u64::MAX).encode()))?
+        .collect::<Result<Vec<_>>>()?;
+    for (key, value) in active {
+        let id = match MvccKey::decode(&key)? {
+            MvccKey::TxnActive(id) => id,
+            k => return Err(Error::Internal(format!("Expected TxnActive, got {:?}", k))),
+        };
+        let version = match deserialize(&value)? {
+            Mode::Snapshot { version } => version,
+            _ => id,
+        };
+        let invisible: HashSet<u64> = match session.get(&MvccKey::TxnSnapshot(version).encode())? {
+            Some(ref v) => deserialize(v)?,
+            None => HashSet::new(),
+        };
+        lowest = invisible.into_iter().chain([version]).fold(lowest, u64::min);
+    }
+
+    // Collected versions are gone, so the watermark never moves back. The commit times of the
+    // versions it moves past are all at or before the cutoff.
+    let previous = watermark(session)?;
+    if lowest <= previous.version {
+        return Ok(previous);
+    }
+    let mut time = previous.time;
+    let mut scan = session.scan(Range::from(
+        MvccKey::TxnCommitted(previous.version).encode()..MvccKey::TxnCommitted(lowest).encode()
+    ))?;
+    while let Some((key, value)) = scan.next().transpose()? {
+        let version = match MvccKey::decode(&key)? {
+            MvccKey::TxnCommitted(version) => version,
+            k => return Err(Error::Internal(format!("Expected TxnCommitted, got {:?}", k))),
+        };
+        let committed: u64 = deserialize(&value)?;
+        if committed > cutoff {
+            lowest = version;
+            break;
+        }
+        time = time.max(committed);
+    }
+    std::mem::drop(scan);
+
+    if lowest <= previous.version {
+        return Ok(previous);
+    }
+    let watermark = Watermark { version: lowest, time };
+    session.set(&MvccKey::GcWatermark.encode(), serialize(&watermark)?)?;
+    Ok(watermark)
+}
+
+/// Serializes garbage collection metadata.
+fn serialize<V: Serialize>(value: &V) -> Result<Vec<u8>> {
+    Ok(bincode::serialize(value)?)
+}
+
+/// Deserializes garbage collection metadata.
+fn deserialize<'a, V: Deserialize<'a>>(bytes: &'a [u8]) -> Result<V> {
+    Ok(bincode::deserialize(bytes)?)
+}
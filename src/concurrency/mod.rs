@@ -1,7 +1,9 @@
+pub mod gc;
 pub mod mvcc;
 pub mod tests;
 pub mod transaction;
 
+pub use gc::{GarbageCollector, GcOptions, GcStats};
 pub use mvcc::MVCC;
-pub use transaction::Transaction;
+pub use transaction::{now_millis, Transaction};
 pub use transaction::Mode;
\ No newline at end of file
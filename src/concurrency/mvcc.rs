@@ -1,12 +1,17 @@
 use std::sync::Arc;
+use std::time::Duration;
 use std::collections::HashSet;
+use std::ops::Bound;
 
 use dashmap::DashMap;
 use parking_lot::RwLock;
 
+use serde::{Deserialize, Serialize};
+
 use crate::error::{Result, Error};
-use crate::storage::kv::KvStore;
-use super::{Mode, Transaction};
+use crate::storage::kv::{KvStore, Range, Stats};
+use super::gc::Watermark;
+use super::{now_millis, GarbageCollector, GcOptions, GcStats, Mode, Transaction};
 
 /// An MVCC-based transactional key-value store.
 #[derive(Clone)]
@@ -44,6 +49,51 @@ impl MVCC {
         Transaction::resume(self.store.clone(), id, self.lock_manager.clone())
     }
 
+    /// Returns the prepared transactions, by ID, along with the references to their primaries
+    /// given to `Transaction::prepare()`.
+    pub fn prepared(&self) -> Result<Vec<(u64, Vec<u8>)>> {
+        let session = self.store.read();
+        let prepared = super::transaction::prepared(&**session)?;
+        Ok(prepared.into_iter().map(|(id, (_, primary))| (id, primary)).collect())
+    }
+
+    /// Fetches the record of a decided distributed transaction, if any.
+    pub fn get_txn_record(&self, id: u64) -> Result<Option<Vec<u8>>> {
+        use super::transaction::MvccKey;
+        let session = self.store.read();
+        session.get(&MvccKey::TxnRecord(id).encode())
+    }
+
+    /// Records the outcome of a decided distributed transaction.
+    pub fn set_txn_record(&self, id: u64, record: Vec<u8>) -> Result<()> {
+        use super::transaction::MvccKey;
+        let session = self.store.write();
+        session.set(&MvccKey::TxnRecord(id).encode(), record)
+    }
+
+    /// Deletes the record of a decided distributed transaction.
+    pub fn delete_txn_record(&self, id: u64) -> Result<()> {
+        use super::transaction::MvccKey;
+        let session = self.store.write();
+        session.delete(&MvccKey::TxnRecord(id).encode())
+    }
+
+    /// Returns the IDs of the decided distributed transactions that have a record.
+    pub fn txn_records(&self) -> Result<Vec<u64>> {
+        use super::transaction::MvccKey;
+        let session = self.store.read();
+        let scan = session.scan(Range::from(
+            MvccKey::TxnRecord(0).encode()..=MvccKey::TxnRecord(u64::MAX).encode()
+        ))?;
+        scan.map(|item| {
+            let (key, _) = item?;
+            match MvccKey::decode(&key)? {
+                MvccKey::TxnRecord(id) => Ok(id),
+                key => Err(Error::Internal(format!("Expected TxnRecord, got {:?}", key))),
+            }
+        }).collect()
+    }
+
     /// Fetches an unversioned metadata value
     pub fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
         use super::transaction::MvccKey;
@@ -57,6 +107,239 @@ impl MVCC {
         let session = self.store.write();
         session.set(&MvccKey::Metadata(key.into()).encode(), value)
     }
+
+    /// Returns an incremental garbage collector for the store, examining up to `batch_size` keys
+    /// per step. Only for stores that are not replicated, see [`GarbageCollector`].
+    pub fn garbage_collector(&self, batch_size: usize) -> GarbageCollector {
+        GarbageCollector::new(&self.store, batch_size)
+    }
+
+    /// Garbage collects the obsolete versions and transaction state in the entire store at once.
+    /// Only for stores that are not replicated, since it uses the local clock, or the closed time
+    /// if commits have been moved past it.
+    pub fn gc(&self) -> Result<GcStats> {
+        let session = self.store.write();
+        let cutoff = now_millis().max(super::gc::clock(&**session)?);
+        GarbageCollector::new(&self.store, usize::MAX).collect(&**session, cutoff)
+    }
+
+    /// Garbage collects the next batch of up to `batch_size` keys, keeping the versions needed
+    /// by `Mode::AsOf` snapshots within the retention period before the latest commit time. The
+    /// pass is recorded in the store, and nothing depends on the local clock, so replicas applying
+    /// the same batches from their log stay identical.
+    pub fn collect_garbage(&self, retention: Duration, batch_size: usize) -> Result<GcStats> {
+        let session = self.store.write();
+        GarbageCollector::new(&self.store, batch_size).collect_recorded(&**session, retention)
+    }
+
+    /// Starts garbage collecting the store incrementally on a background thread, which stops once
+    /// the store is dropped. Only for stores that are not replicated, see [`GarbageCollector`].
+    pub fn start_gc(&self, opts: GcOptions) -> Result<()> {
+        GarbageCollector::new(&self.store, opts.batch_size).retain(opts.retention).spawn(opts.interval)
+    }
+
+    /// Flushes the underlying KV store to durable storage.
+    pub fn flush(&self) -> Result<()> {
+        self.store.read().flush()
+    }
+
+    /// Exports the entire store, including all versions and transaction metadata, as a
+    /// serialized snapshot.
+    pub fn export(&self) -> Result<Vec<u8>> {
+        let session = self.store.read();
+        let pairs = session.scan(Range::from(..))?.collect::<Result<Vec<_>>>()?;
+        serialize(&pairs)
+    }
+
+    /// Replaces the entire store with a snapshot produced by `export()`. Any in-memory
+    /// serializability tracking is reset, and is rebuilt as transactions are resumed.
+    pub fn import(&self, snapshot: &[u8]) -> Result<()> {
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = deserialize(snapshot)?;
+        let session = self.store.write();
+        let keys = session
+            .scan(Range::from(..))?
+            .map(|r| r.map(|(key, _)| key))
+            .collect::<Result<Vec<_>>>()?;
+        for key in keys {
+            session.delete(&key)?;
+        }
+        for (key, value) in pairs {
+            session.set(&key, value)?;
+        }
+        if let Some(ref lock_manager) = self.lock_manager {
+            lock_manager.clear();
+        }
+        session.flush()
+    }
+
+    /// Returns statistics about the entire store.
+    pub fn stats(&self) -> Result<Stats> {
+        self.store.read().stats(Range::from(..))
+    }
+
+    /// Returns the total size of the store's keys and values, scanning `batch_size` keys at a
+    /// time so that writers are only held up for one batch. The size may mix states before and
+    /// after concurrent writes.
+    pub fn size(&self, batch_size: usize) -> Result<u64> {
+        let (mut size, mut cursor) = (0, Bound::Unbounded);
+        loop {
+            let session = self.store.read();
+            let mut scanned = 0;
+            for item in session.scan(Range::from((cursor.clone(), Bound::Unbounded)))?.take(batch_size) {
+                let (key, value) = item?;
+                size += (key.len() + value.len()) as u64;
+                scanned += 1;
+                cursor = Bound::Excluded(key);
+            }
+            if scanned < batch_size {
+                return Ok(size);
+            }
+        }
+    }
+
+    /// Picks a key to split the store at, such that roughly half of the versioned data is before
+    /// it. Only keys accepted by `boundary` are considered, and all versions of a key stay on the
+    /// same side. Returns None if there is no such key after the first one.
+    pub fn split_key(&self, boundary: impl Fn(&[u8]) -> bool) -> Result<Option<Vec<u8>>> {
+        use super::transaction::MvccKey;
+        let session = self.store.read();
+        let half = session.stats(Range::from(vec![0xff]..))?.size / 2;
+        let mut size = 0;
+        let mut first = None;
+        for item in session.scan(Range::from(vec![0xff]..))? {
+            let (key, value) = item?;
+            let user_key = match MvccKey::decode(&key)? {
+                MvccKey::Record(user_key, _) => user_key.into_owned(),
+                key => return Err(Error::Internal(format!("Unexpected MVCC key {:?}", key))),
+            };
+            match &first {
+                None => first = Some(user_key),
+                Some(first) if size >= half && *first != user_key && boundary(&user_key) => {
+                    return Ok(Some(user_key));
+                }
+                Some(_) => { },
+            }
+            size += (key.len() + value.len()) as u64;
+        }
+        Ok(None)
+    }
+
+    /// Removes all versions of the keys at or after a split key, and returns them as a snapshot
+    /// that `import()` can load. The snapshot also contains the unversioned transaction state and
+    /// metadata, which both sides keep.
+    pub fn split_off(&self, key: &[u8]) -> Result<Vec<u8>> {
+        self.split_off_sharing(key, |_| false)
+    }
+
+    /// Like `split_off()`, but the snapshot also contains all versions of the keys before the
+    /// split key that are accepted by `shared`, which both sides keep.
+    pub fn split_off_sharing(&self, key: &[u8], shared: impl Fn(&[u8]) -> bool) -> Result<Vec<u8>> {
+        use super::transaction::MvccKey;
+        let session = self.store.write();
+        let mut pairs = session.scan(Range::from(..vec![0xff]))?.collect::<Result<Vec<_>>>()?;
+        for item in session.scan(Range::from(vec![0xff]..MvccKey::Record(key.into(), 0).encode()))? {
+            let (record, value) = item?;
+            if let MvccKey::Record(user_key, _) = MvccKey::decode(&record)? {
+                if shared(&user_key) {
+                    pairs.push((record, value));
+                }
+            }
+        }
+        let records = session
+            .scan(Range::from(MvccKey::Record(key.into(), 0).encode()..))?
+            .collect::<Result<Vec<_>>>()?;
+        for (key, _) in &records {
+            session.delete(key)?;
+        }
+        pairs.extend(records);
+        session.flush()?;
+        serialize(&pairs)
+    }
+
+    /// Exports the entire store like `export()`, to be merged into the store of the adjacent
+    /// left-hand key range by `merge()`. Errors if a transaction is active, or the record of a
+    /// decided distributed transaction is kept, since the merge changes their IDs.
+    pub fn export_merge(&self) -> Result<Vec<u8>> {
+        use super::transaction::MvccKey;
+        let session = self.store.read();
+        for (start, end) in [
+            (MvccKey::TxnActive(0), MvccKey::TxnActive(u64::MAX)),
+            (MvccKey::TxnRecord(0), MvccKey::TxnRecord(u64::MAX)),
+        ] {
+            if let Some((key, _)) = session.scan(Range::from(start.encode()..=end.encode()))?.next().transpose()? {
+                return Err(Error::Value(format!("Cannot merge a store with unresolved {:?}", MvccKey::decode(&key)?)));
+            }
+        }
+        let pairs = session.scan(Range::from(..))?.collect::<Result<Vec<_>>>()?;
+        serialize(&pairs)
+    }
+
+    /// Merges a snapshot of an adjacent key range, produced by `export_merge()`, into the store.
+    ///
+    /// Both sides of a split allocate transaction IDs from the same next ID, so the snapshot's
+    /// versions and commit times are shifted above this store's IDs, and its next ID is shifted
+    /// along. Its snapshots and update markers are dropped, since none of its transactions is
+    /// active. The latest commit time and the garbage collection watermark's time are the later
+    /// of the two, while the watermark's version is this store's, since the shifted versions are
+    /// above it. Other metadata is only added where missing, e.g. keeping this store's applied
+    /// index.
+    pub fn merge(&self, snapshot: &[u8]) -> Result<()> {
+        use super::transaction::MvccKey;
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = deserialize(snapshot)?;
+        let session = self.store.write();
+        let offset = match session.get(&MvccKey::TxnNext.encode())? {
+            Some(ref v) => deserialize::<u64>(v)? - 1,
+            None => 0,
+        };
+        let mut merged = vec![];
+        for (key, value) in pairs {
+            match MvccKey::decode(&key)? {
+                key @ (MvccKey::TxnActive(_) | MvccKey::TxnPrepared(_) | MvccKey::TxnRecord(_)) => {
+                    return Err(Error::Value(format!("Cannot merge a store with unresolved {:?}", key)));
+                },
+                MvccKey::TxnNext => {
+                    let next: u64 = deserialize(&value)?;
+                    merged.push((key, serialize(&(next + offset))?));
+                },
+                MvccKey::Record(user_key, version) => {
+                    merged.push((MvccKey::Record(user_key, version + offset).encode(), value));
+                },
+                MvccKey::TxnCommitted(version) => {
+                    merged.push((MvccKey::TxnCommitted(version + offset).encode(), value));
+                },
+                MvccKey::TxnSnapshot(_) | MvccKey::TxnUpdate(..) => { },
+                MvccKey::TxnClock => {
+                    let time: u64 = deserialize(&value)?;
+                    let current = super::gc::clock(&**session)?;
+                    merged.push((key, serialize(&time.max(current))?));
+                },
+                MvccKey::GcWatermark => {
+                    let watermark: Watermark = deserialize(&value)?;
+                    let current = super::gc::watermark(&**session)?;
+                    merged.push((key, serialize(&Watermark { time: watermark.time.max(current.time), ..current })?));
+                },
+                MvccKey::Metadata(_) | MvccKey::GcCursor => {
+                    if session.get(&key)?.is_none() {
+                        merged.push((key, value));
+                    }
+                },
+            }
+        }
+        for (key, value) in merged {
+            session.set(&key, value)?;
+        }
+        session.flush()
+    }
+}
+
+/// Serializes an MVCC snapshot.
+fn serialize<V: Serialize>(value: &V) -> Result<Vec<u8>> {
+    Ok(bincode::serialize(value)?)
+}
+
+/// Deserializes an MVCC snapshot.
+fn deserialize<'a, V: Deserialize<'a>>(bytes: &'a [u8]) -> Result<V> {
+    Ok(bincode::deserialize(bytes)?)
 }
 
 #[derive(Clone, Copy)]
@@ -96,6 +379,13 @@ impl LockManager {
         );
     }
 
+    /// Removes all locks and transaction statuses.
+    fn clear(&self) {
+        self.read_locks.clear();
+        self.write_locks.clear();
+        self.txn_status.clear();
+    }
+
     /// Releases all SIREAD locks acquired by the transaction.
     fn release_read_lock(&self, txn_id: u64) {
         self.read_locks.alter_all(|_, mut readers| {
@@ -1,4 +1,6 @@
 #![cfg(test)]
+use std::time::Duration;
+
 use tempfile::{tempdir, TempDir};
 
 use super::*;
@@ -674,4 +676,517 @@ fn test_metadata() -> Result<()> {
     mvcc.set_metadata(b"foo", b"baz".to_vec())?;
     assert_eq!(Some(b"baz".to_vec()), mvcc.get_metadata(b"foo")?);
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_txn_records() -> Result<()> {
+    let (mvcc, _dir) = setup()?;
+    mvcc.set_metadata(b"foo", b"bar".to_vec())?;
+    mvcc.set_txn_record(3, b"committed".to_vec())?;
+    mvcc.set_txn_record(1, b"aborted".to_vec())?;
+
+    assert_eq!(Some(b"committed".to_vec()), mvcc.get_txn_record(3)?);
+    assert_eq!(None, mvcc.get_txn_record(2)?);
+    assert_eq!(vec![1, 3], mvcc.txn_records()?);
+
+    mvcc.delete_txn_record(3)?;
+    assert_eq!(None, mvcc.get_txn_record(3)?);
+    assert_eq!(vec![1], mvcc.txn_records()?);
+    Ok(())
+}
+#[test]
+fn test_export_import() -> Result<()> {
+    let (mvcc, _dir) = setup()?;
+
+    let t1 = mvcc.begin()?;
+    t1.set(b"a", b"1".to_vec())?;
+    t1.set(b"b", b"1".to_vec())?;
+    t1.commit()?;
+
+    let t2 = mvcc.begin()?;
+    t2.set(b"a", b"2".to_vec())?;
+    mvcc.set_metadata(b"foo", b"bar".to_vec())?;
+    let snapshot = mvcc.export()?;
+
+    // Writes after the export should be discarded by the import.
+    t2.delete(b"b")?;
+    t2.commit()?;
+    let t3 = mvcc.begin()?;
+    t3.set(b"c", b"3".to_vec())?;
+    t3.commit()?;
+
+    let (restored, _dir) = setup()?;
+    restored.begin()?.set(b"x", b"x".to_vec())?;
+    restored.import(&snapshot)?;
+
+    // The exported transaction is still active, and its writes are invisible to others.
+    let t = restored.begin_with_mode(Mode::ReadOnly)?;
+    assert_eq!(Some(b"1".to_vec()), t.get(b"a")?);
+    assert_eq!(Some(b"1".to_vec()), t.get(b"b")?);
+    assert_eq!(None, t.get(b"c")?);
+    assert_eq!(None, t.get(b"x")?);
+    t.commit()?;
+
+    let t2 = restored.resume(2)?;
+    assert_eq!(Some(b"2".to_vec()), t2.get(b"a")?);
+    t2.commit()?;
+    assert_eq!(Some(b"bar".to_vec()), restored.get_metadata(b"foo")?);
+
+    Ok(())
+}
+
+#[test]
+fn test_split_merge() -> Result<()> {
+    let (mvcc, _dir) = setup()?;
+    for key in [b"a", b"b", b"c", b"d"] {
+        let txn = mvcc.begin()?;
+        txn.set(key, key.to_vec())?;
+        txn.set(key, [key.as_slice(), b"2"].concat())?;
+        txn.commit()?;
+    }
+    mvcc.set_metadata(b"foo", b"left".to_vec())?;
+    assert_eq!(Some(b"c".to_vec()), mvcc.split_key(|_| true)?);
+    assert_eq!(Some(b"d".to_vec()), mvcc.split_key(|key| key == b"d")?);
+    assert_eq!(None, mvcc.split_key(|key| key == b"a")?);
+
+    // The split moves all versions at or after the split key to the right-hand store.
+    let (right, _right_dir) = setup()?;
+    right.import(&mvcc.split_off(b"c")?)?;
+    right.set_metadata(b"foo", b"right".to_vec())?;
+    let t = mvcc.begin_with_mode(Mode::ReadOnly)?;
+    assert_eq!(Some(b"b2".to_vec()), t.get(b"b")?);
+    assert_eq!(None, t.get(b"c")?);
+    t.commit()?;
+    let t = right.begin_with_mode(Mode::ReadOnly)?;
+    assert_eq!(None, t.get(b"b")?);
+    assert_eq!(Some(b"c2".to_vec()), t.get(b"c")?);
+    t.commit()?;
+
+    // Transactions continue independently on each side, and the merge shifts the right-hand IDs
+    // above the left-hand ones.
+    for _ in 0..3 {
+        right.begin()?.commit()?;
+    }
+    let txn = right.begin()?;
+    txn.set(b"e", b"e".to_vec())?;
+    txn.commit()?;
+    mvcc.merge(&right.export_merge()?)?;
+    let t = mvcc.begin_with_mode(Mode::ReadOnly)?;
+    assert_eq!(15, t.id());
+    for (key, value) in [(b"a", b"a2".as_slice()), (b"c", b"c2"), (b"e", b"e")] {
+        assert_eq!(Some(value.to_vec()), t.get(key)?);
+    }
+    t.commit()?;
+    assert_eq!(Some(b"left".to_vec()), mvcc.get_metadata(b"foo")?);
+    assert!(mvcc.stats()?.size > right.stats()?.size);
+    Ok(())
+}
+
+#[test]
+fn test_merge_overlapping_ids() -> Result<()> {
+    let (mvcc, _dir) = setup()?;
+    let txn = mvcc.begin()?;
+    txn.set(b"a", vec![1])?;
+    txn.set(b"n", vec![1])?;
+    txn.commit()?;
+    let (right, _right_dir) = setup()?;
+    right.import(&mvcc.split_off(b"m")?)?;
+
+    // Both sides allocate IDs 2 and 3 after the split. On the left, 2 is still active, and a
+    // snapshot lists it as invisible.
+    let active = mvcc.begin()?;
+    active.set(b"b", vec![2])?;
+    let snapshot = mvcc.begin_with_mode(Mode::ReadOnly)?;
+    for key in [b"x", b"y"] {
+        let txn = right.begin()?;
+        txn.set(key, key.to_vec())?;
+        txn.commit()?;
+    }
+    assert_eq!((2, 3), (active.id(), snapshot.id()));
+
+    // The right-hand side is not merged while it has an active transaction.
+    let txn = right.begin()?;
+    assert!(matches!(right.export_merge(), Err(Error::Value(_))));
+    txn.rollback()?;
+    mvcc.merge(&right.export_merge()?)?;
+
+    // The right-hand versions are visible regardless of the left-hand transactions sharing their
+    // IDs, and a rollback of those keeps them.
+    let t = mvcc.begin_with_mode(Mode::ReadOnly)?;
+    assert_eq!((Some(b"x".to_vec()), Some(b"y".to_vec()), None), (t.get(b"x")?, t.get(b"y")?, t.get(b"b")?));
+    t.commit()?;
+    active.rollback()?;
+    snapshot.commit()?;
+    let t = mvcc.begin_with_mode(Mode::Snapshot { version: 3 })?;
+    assert_eq!((Some(vec![1]), None), (t.get(b"a")?, t.get(b"x")?));
+    t.commit()?;
+    let t = mvcc.begin_with_mode(Mode::ReadOnly)?;
+    assert_eq!((Some(b"x".to_vec()), Some(b"y".to_vec())), (t.get(b"x")?, t.get(b"y")?));
+    t.commit()?;
+    Ok(())
+}
+
+#[test]
+fn test_prepare() -> Result<()> {
+    let (mvcc, _dir) = setup()?;
+    assert_eq!(Err(Error::ReadOnly), mvcc.begin_with_mode(Mode::ReadOnly)?.prepare(vec![]));
+
+    // A prepared transaction is listed with its primary, and its writes stay invisible.
+    let t1 = mvcc.begin()?;
+    t1.set(b"a", b"1".to_vec())?;
+    t1.prepare(b"primary".to_vec())?;
+    let t2 = mvcc.begin()?;
+    t2.set(b"b", b"2".to_vec())?;
+    t2.prepare(b"other".to_vec())?;
+    assert_eq!(vec![(t1.id(), b"primary".to_vec()), (t2.id(), b"other".to_vec())], mvcc.prepared()?);
+    let t = mvcc.begin_with_mode(Mode::ReadOnly)?;
+    assert_eq!(None, t.get(b"a")?);
+    t.commit()?;
+
+    // Resolving the transactions removes them from the prepared set, also after a resume.
+    mvcc.resume(t1.id())?.commit()?;
+    t2.rollback()?;
+    assert!(mvcc.prepared()?.is_empty());
+    let t = mvcc.begin_with_mode(Mode::ReadOnly)?;
+    assert_eq!(Some(b"1".to_vec()), t.get(b"a")?);
+    assert_eq!(None, t.get(b"b")?);
+    t.commit()?;
+    Ok(())
+}
+
+#[test]
+fn test_size() -> Result<()> {
+    let (mvcc, _dir) = setup()?;
+    for key in [b"a", b"b", b"c"] {
+        let txn = mvcc.begin()?;
+        txn.set(key, key.to_vec())?;
+        txn.commit()?;
+    }
+
+    // Sizing in batches adds up to the size of the whole store, however the batches fall.
+    let size = mvcc.stats()?.size;
+    for batch_size in [1, 2, 1000] {
+        assert_eq!(size, mvcc.size(batch_size)?);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_split_off_sharing() -> Result<()> {
+    let (mvcc, _dir) = setup()?;
+    for key in [b"a", b"b", b"c"] {
+        let txn = mvcc.begin()?;
+        txn.set(key, key.to_vec())?;
+        txn.commit()?;
+    }
+
+    // Shared keys before the split key are kept by both sides.
+    let (right, _right_dir) = setup()?;
+    right.import(&mvcc.split_off_sharing(b"c", |key| key == b"a")?)?;
+    let t = mvcc.begin_with_mode(Mode::ReadOnly)?;
+    assert_eq!(vec![Some(b"a".to_vec()), Some(b"b".to_vec()), None], [t.get(b"a")?, t.get(b"b")?, t.get(b"c")?]);
+    t.commit()?;
+    let t = right.begin_with_mode(Mode::ReadOnly)?;
+    assert_eq!(vec![Some(b"a".to_vec()), None, Some(b"c".to_vec())], [t.get(b"a")?, t.get(b"b")?, t.get(b"c")?]);
+    t.commit()?;
+    Ok(())
+}
+
+#[test]
+fn test_gc() -> Result<()> {
+    let (mvcc, _dir) = setup()?;
+    for value in [b"1", b"2"] {
+        let txn = mvcc.begin()?;
+        txn.set(b"a", value.to_vec())?;
+        txn.set(b"b", value.to_vec())?;
+        txn.commit()?;
+    }
+
+    // A reader that began before the third write holds back collection of the second versions.
+    let reader = mvcc.begin_with_mode(Mode::ReadOnly)?;
+    let txn = mvcc.begin()?;
+    txn.set(b"a", b"3".to_vec())?;
+    txn.delete(b"b")?;
+    txn.commit()?;
+    let stats = mvcc.gc()?;
+    assert_eq!((reader.id(), 2, 4, true), (stats.watermark, stats.versions, stats.updates, stats.complete));
+    assert_eq!(Some(b"2".to_vec()), reader.get(b"a")?);
+    assert_eq!(Some(b"2".to_vec()), reader.get(b"b")?);
+    reader.commit()?;
+
+    // Once the reader is done, only the latest versions are left, and the deleted key is gone.
+    let keys = mvcc.stats()?.keys;
+    let stats = mvcc.gc()?;
+    assert_eq!((5, 3, 2), (stats.watermark, stats.versions, stats.updates));
+    assert_eq!(keys - stats.versions - stats.updates - stats.snapshots - stats.commits, mvcc.stats()?.keys);
+    let txn = mvcc.begin_with_mode(Mode::ReadOnly)?;
+    assert_eq!((Some(b"3".to_vec()), None), (txn.get(b"a")?, txn.get(b"b")?));
+    txn.commit()?;
+
+    // Snapshots below the watermark are rejected, without leaving their transaction active.
+    assert_eq!(
+        mvcc.begin_with_mode(Mode::Snapshot { version: 2 }).err(),
+        Some(Error::Value("Version 2 has been garbage collected".into()))
+    );
+    let txn = mvcc.begin_with_mode(Mode::Snapshot { version: 5 })?;
+    assert_eq!(Some(b"3".to_vec()), txn.get(b"a")?);
+    txn.commit()?;
+    assert_eq!(8, mvcc.gc()?.watermark);
+    Ok(())
+}
+
+#[test]
+fn test_gc_incremental() -> Result<()> {
+    let (mvcc, _dir) = setup()?;
+    for version in 0..3 {
+        let txn = mvcc.begin()?;
+        for key in [b"a", b"b", b"c", b"d"] {
+            txn.set(key, vec![version])?;
+        }
+        txn.commit()?;
+    }
+
+    // Each batch examines a few keys, but all versions of a key together, until a pass completes.
+    let mut gc = mvcc.garbage_collector(4);
+    let mut versions = 0;
+    let mut batches = 0;
+    loop {
+        let stats = gc.step()?.unwrap();
+        versions += stats.versions;
+        batches += 1;
+        if stats.complete {
+            break;
+        }
+    }
+    assert_eq!(8, versions);
+    assert!(batches > 2);
+    let txn = mvcc.begin_with_mode(Mode::ReadOnly)?;
+    let scan = txn.scan(..)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(vec![(b"a".to_vec(), vec![2]), (b"b".to_vec(), vec![2]), (b"c".to_vec(), vec![2]), (b"d".to_vec(), vec![2])], scan);
+    txn.commit()?;
+
+    // The collector stops once the store is dropped.
+    std::mem::drop(mvcc);
+    assert_eq!(None, gc.step()?);
+    Ok(())
+}
+
+#[test]
+fn test_collect_garbage() -> Result<()> {
+    let (mvcc, _dir) = setup()?;
+    let (replica, _replica_dir) = setup()?;
+    for kv in [&mvcc, &replica] {
+        for version in 0..3 {
+            let txn = kv.begin()?;
+            for key in [b"a", b"b", b"c", b"d"] {
+                txn.set(key, vec![version])?;
+            }
+            txn.commit_at(1000 * (version as u64 + 1))?;
+        }
+    }
+
+    // Batches continue the pass recorded in the store, keeping the versions committed within the
+    // retention period before the latest commit, so stores collecting the same batches stay
+    // identical.
+    let mut versions = 0;
+    for kv in [&mvcc, &replica] {
+        loop {
+            let stats = kv.collect_garbage(Duration::from_millis(500), 4)?;
+            assert_eq!(3, stats.watermark);
+            versions += stats.versions;
+            if stats.complete {
+                break;
+            }
+        }
+    }
+    assert_eq!(8, versions);
+    assert_eq!(mvcc.export()?, replica.export()?);
+
+    // Snapshots as of times before the latest collected commit are rejected.
+    assert!(matches!(mvcc.begin_with_mode(Mode::AsOf { time: 1500 }), Err(Error::Value(_))));
+    let txn = mvcc.begin_with_mode(Mode::AsOf { time: 2000 })?;
+    assert_eq!(Some(vec![1]), txn.get(b"a")?);
+    txn.commit()?;
+    Ok(())
+}
+
+#[test]
+fn test_begin_with_mode_as_of() -> Result<()> {
+    let (mvcc, _dir) = setup()?;
+    let txn = mvcc.begin()?;
+    txn.set(b"a", vec![1])?;
+    txn.commit_at(1000)?;
+
+    // A transaction commits after one that began later.
+    let early = mvcc.begin()?;
+    early.set(b"b", vec![2])?;
+    let late = mvcc.begin()?;
+    late.set(b"a", vec![3])?;
+    late.commit_at(2000)?;
+    let reader = mvcc.begin_with_mode(Mode::AsOf { time: 5000 })?;
+    early.commit_at(3000)?;
+
+    // Snapshots see the versions committed by their time, in any order, and so do resumed ones.
+    // The commit after the reader's time was taken is moved past it.
+    for (time, a, b) in [
+        (500, None, None),
+        (1000, Some(vec![1]), None),
+        (2500, Some(vec![3]), None),
+        (3000, Some(vec![3]), None),
+        (5001, Some(vec![3]), Some(vec![2])),
+    ] {
+        let txn = mvcc.begin_with_mode(Mode::AsOf { time })?;
+        assert_eq!(Mode::AsOf { time }, txn.mode());
+        assert_eq!((a.clone(), b.clone()), (txn.get(b"a")?, txn.get(b"b")?));
+        let resumed = mvcc.resume(txn.id())?;
+        assert_eq!((a, b), (resumed.get(b"a")?, resumed.get(b"b")?));
+        assert_eq!(Some(Error::ReadOnly), txn.set(b"a", vec![0]).err());
+        txn.commit()?;
+    }
+
+    // A transaction active when the snapshot was taken stays invisible.
+    assert_eq!((Some(vec![3]), None), (reader.get(b"a")?, reader.get(b"b")?));
+    reader.commit()?;
+    Ok(())
+}
+
+#[test]
+fn test_as_of_prepared() -> Result<()> {
+    let (primary, _primary_dir) = setup()?;
+    let (secondary, _secondary_dir) = setup()?;
+    let txn = secondary.begin()?;
+    txn.set(b"b", vec![1])?;
+    txn.commit_at(1000)?;
+
+    // A transaction prepared on both stores commits no earlier than either's closed time. The
+    // primary is resolved first, and the secondary only later.
+    let (p, s) = (primary.begin()?, secondary.begin()?);
+    p.set(b"a", vec![2])?;
+    s.set(b"b", vec![2])?;
+    let time = p.prepare(b"a".to_vec())?.max(s.prepare(b"a".to_vec())?);
+    assert_eq!(1001, time);
+    p.commit_at(time)?;
+
+    // Snapshots the secondary could still commit by are rejected there, so that a read across
+    // both stores cannot see only the primary's half. Earlier snapshots are fine.
+    assert_eq!(
+        secondary.begin_with_mode(Mode::AsOf { time: 2000 }).err(),
+        Some(Error::Value(format!("Time 2000 is not closed yet, transaction {} may commit by then", s.id()))),
+    );
+    let txn = secondary.begin_with_mode(Mode::AsOf { time: 1000 })?;
+    assert_eq!(Some(vec![1]), txn.get(b"b")?);
+    txn.commit()?;
+    let txn = primary.begin_with_mode(Mode::AsOf { time: 2000 })?;
+    assert_eq!(Some(vec![2]), txn.get(b"a")?);
+    txn.commit()?;
+
+    // Once resolved, the snapshot sees both halves.
+    secondary.resume(s.id())?.commit_at(time)?;
+    let txn = secondary.begin_with_mode(Mode::AsOf { time: 2000 })?;
+    assert_eq!(Some(vec![2]), txn.get(b"b")?);
+    txn.commit()?;
+
+    // Snapshots close their time, so a commit at or below it is moved past it instead of
+    // changing what they see.
+    let txn = secondary.begin()?;
+    txn.set(b"b", vec![3])?;
+    txn.commit_at(1500)?;
+    let txn = secondary.begin_with_mode(Mode::AsOf { time: 2000 })?;
+    assert_eq!(Some(vec![2]), txn.get(b"b")?);
+    txn.commit()?;
+    let txn = secondary.begin_with_mode(Mode::AsOf { time: 2001 })?;
+    assert_eq!(Some(vec![3]), txn.get(b"b")?);
+    txn.commit()?;
+    Ok(())
+}
+
+#[test]
+fn test_as_of_last_time() -> Result<()> {
+    let (mvcc, _dir) = setup()?;
+
+    // A snapshot as of the last time could not be closed, so it is rejected.
+    assert_eq!(
+        mvcc.begin_with_mode(Mode::AsOf { time: u64::MAX }).err(),
+        Some(Error::Value(format!("Time {} cannot be closed", u64::MAX))),
+    );
+
+    // A commit at the last time closes the store there, and later transactions still prepare
+    // and commit, at that time.
+    let txn = mvcc.begin()?;
+    txn.set(b"a", vec![1])?;
+    txn.commit_at(u64::MAX)?;
+    let txn = mvcc.begin()?;
+    txn.set(b"a", vec![2])?;
+    assert_eq!(u64::MAX, txn.prepare(b"a".to_vec())?);
+    txn.commit_at(u64::MAX)?;
+    let txn = mvcc.begin()?;
+    txn.set(b"b", vec![3])?;
+    txn.commit()?;
+    let txn = mvcc.begin()?;
+    assert_eq!((Some(vec![2]), Some(vec![3])), (txn.get(b"a")?, txn.get(b"b")?));
+    txn.commit()?;
+    Ok(())
+}
+
+#[test]
+fn test_gc_retention() -> Result<()> {
+    let (mvcc, _dir) = setup()?;
+    let (now, hour) = (now_millis(), 3_600_000);
+    for (value, time) in [(1, now - 3 * hour), (2, now - 2 * hour), (3, now - hour / 2)] {
+        let txn = mvcc.begin()?;
+        txn.set(b"a", vec![value])?;
+        txn.commit_at(time)?;
+    }
+
+    // The version superseded within the retention period is kept for snapshots as of then.
+    let mut gc = mvcc.garbage_collector(usize::MAX).retain(Duration::from_secs(3600));
+    let stats = gc.step()?.unwrap();
+    assert_eq!((3, 1, 2), (stats.watermark, stats.versions, stats.commits));
+    let txn = mvcc.begin_with_mode(Mode::AsOf { time: now - hour })?;
+    assert_eq!(Some(vec![2]), txn.get(b"a")?);
+    txn.commit()?;
+
+    // Snapshots before the latest collected commit are rejected.
+    let time = now - 5 * hour / 2;
+    assert_eq!(
+        mvcc.begin_with_mode(Mode::AsOf { time }).err(),
+        Some(Error::Value(format!("Time {} has been garbage collected", time)))
+    );
+
+    // Without a retention period, only the latest version is left.
+    let stats = mvcc.gc()?;
+    assert_eq!((6, 1, 1), (stats.watermark, stats.versions, stats.commits));
+    assert!(mvcc.begin_with_mode(Mode::AsOf { time: now - hour }).is_err());
+    let txn = mvcc.begin_with_mode(Mode::AsOf { time: now })?;
+    assert_eq!(Some(vec![3]), txn.get(b"a")?);
+    txn.commit()?;
+    Ok(())
+}
+
+#[test]
+fn test_merge_as_of() -> Result<()> {
+    let (mvcc, _dir) = setup()?;
+    let txn = mvcc.begin()?;
+    txn.set(b"a", vec![1])?;
+    txn.set(b"n", vec![1])?;
+    txn.commit_at(1000)?;
+    let (right, _right_dir) = setup()?;
+    right.import(&mvcc.split_off(b"m")?)?;
+
+    // Both sides commit a transaction 2, at different times.
+    let (left_txn, right_txn) = (mvcc.begin()?, right.begin()?);
+    assert_eq!(left_txn.id(), right_txn.id());
+    left_txn.set(b"a", vec![2])?;
+    left_txn.commit_at(5000)?;
+    right_txn.set(b"n", vec![2])?;
+    right_txn.commit_at(2000)?;
+    mvcc.merge(&right.export_merge()?)?;
+
+    // Snapshots as of a time see each side's versions as of their own commit times.
+    for (time, a, n) in [(1500, 1, 1), (3000, 1, 2), (5000, 2, 2)] {
+        let txn = mvcc.begin_with_mode(Mode::AsOf { time })?;
+        assert_eq!((Some(vec![a]), Some(vec![n])), (txn.get(b"a")?, txn.get(b"n")?));
+        txn.commit()?;
+    }
+    Ok(())
+}
@@ -2,8 +2,9 @@ use std::iter::Peekable;
 use std::ops::{RangeBounds, Bound};
 use std::{sync::Arc, borrow::Cow};
 use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use parking_lot::{RwLock, RwLockWriteGuard, RwLockReadGuard};
+use parking_lot::{RwLock, RwLockWriteGuard};
 use serde::{Deserialize, Serialize};
 
 use crate::error::{Error, Result};
@@ -43,11 +44,16 @@ impl Transaction {
         // We always take a new snapshot, even for snapshot transactions, because all transactions
         // increment the transaction ID and we need to properly record currently active transactions
         // for any future snapshot transactions looking at this one.
-        let mut snapshot = Snapshot::take(&session, id)?;
+        let snapshot = match Self::restore_snapshot(&**session, mode, Snapshot::take(&session, id)?) {
+            Ok(snapshot) => snapshot,
+            // The transaction ID stays allocated, but the transaction is not left active, which
+            // would hold back garbage collection.
+            Err(err) => {
+                session.delete(&MvccKey::TxnActive(id).encode())?;
+                return Err(err);
+            }
+        };
         std::mem::drop(session);
-        if let Mode::Snapshot { version } = &mode {
-            snapshot = Snapshot::restore(&store.read(), *version)?
-        }
 
         // Initializes the transaction status for SSI on beginning.
         if let Some(ref lock_manager) = lock_manager {
@@ -57,6 +63,48 @@ impl Transaction {
         Ok(Self { store, id, mode, snapshot, lock_manager })
     }
 
+    /// Returns the snapshot that a transaction in the given mode runs in, given the snapshot
+    /// taken at its beginning. A snapshot that could see garbage collected versions is rejected.
+    ///
+    /// A snapshot as of a time closes the store up to that time, so that every later commit is
+    /// after it and the snapshot stays repeatable. It is rejected while a prepared transaction may
+    /// still commit by then, since that transaction's other participants may already have, and as
+    /// of the last representable time, since no commit could be after it.
+    fn restore_snapshot(session: &dyn KvStore, mode: Mode, taken: Snapshot) -> Result<Snapshot> {
+        if let Mode::AsOf { time } = mode {
+            if time == u64::MAX {
+                return Err(Error::Value(format!("Time {} cannot be closed", time)));
+            }
+            for (id, (min_time, _)) in prepared(session)? {
+                if min_time <= time {
+                    return Err(Error::Value(format!(
+                        "Time {} is not closed yet, transaction {} may commit by then", time, id,
+                    )));
+                }
+            }
+        }
+        let watermark = super::gc::watermark(session)?;
+        let collected = match mode {
+            Mode::Snapshot { version } => format!("Version {} has been garbage collected", version),
+            Mode::AsOf { time } => format!("Time {} has been garbage collected", time),
+            _ => return Ok(taken),
+        };
+        let snapshot = match mode {
+            Mode::Snapshot { version } if version >= watermark.version => Snapshot::restore(session, version)?,
+            Mode::AsOf { time } if time >= watermark.time => taken.committed_by(session, time)?,
+            _ => return Err(Error::Value(collected)),
+        };
+        if snapshot.lowest() < watermark.version {
+            return Err(Error::Value(collected));
+        }
+        if let Mode::AsOf { time } = mode {
+            if time > super::gc::clock(session)? {
+                session.set(&MvccKey::TxnClock.encode(), serialize(&time)?)?;
+            }
+        }
+        Ok(snapshot)
+    }
+
     /// Resumes an active transaction with the given ID. Errors if the transaction is not active.
     pub(super) fn resume(
         store: Arc<RwLock<Box<dyn KvStore>>>, 
@@ -71,10 +119,10 @@ impl Transaction {
         };
 
         // If the txn's mode is `Snapshot`, then restore that particular one.
-        // Otherwise restore the one with the txn id.
+        // Otherwise restore the one with the txn id, which `AsOf` txns have replaced.
         let snapshot = match &mode {
-            Mode::Snapshot { version } => Snapshot::restore(&session, *version)?,
-            _ => Snapshot::restore(&session, id)?,
+            Mode::Snapshot { version } => Snapshot::restore(&**session, *version)?,
+            _ => Snapshot::restore(&**session, id)?,
         };
 
         std::mem::drop(session);
@@ -97,14 +145,56 @@ impl Transaction {
         self.mode
     }
 
-    /// Commits the transaction, by removing the txn from the active set.
+    /// Prepares the transaction for a two-phase commit, given an opaque reference to the
+    /// transaction's primary, which decides whether it commits. Checks that the transaction can
+    /// commit, after which a commit cannot fail. The transaction stays active, so its writes stay
+    /// invisible until it is committed or rolled back.
+    ///
+    /// Returns the earliest time the transaction may commit at, just after the store's closed
+    /// time. The coordinator must commit all participants at the same time, no earlier than any
+    /// of theirs, and snapshots as of that time or later are rejected until the transaction is
+    /// resolved.
+    pub fn prepare(&self, primary: Vec<u8>) -> Result<u64> {
+        if !self.mode.allows_write() {
+            return Err(Error::ReadOnly);
+        }
+        let session = self.store.write();
+        if let Some(lock_manager) = &self.lock_manager {
+            lock_manager.check_abort(self.id)?;
+        }
+        let min_time = super::gc::clock(&**session)?.saturating_add(1);
+        session.set(&MvccKey::TxnPrepared(self.id).encode(), serialize(&(min_time, primary))?)?;
+        session.flush()?;
+        Ok(min_time)
+    }
+
+    /// Commits the transaction at the current time, see `commit_at()`.
     pub fn commit(self) -> Result<()> {
+        self.commit_at(now_millis())
+    }
+
+    /// Commits the transaction, by removing the txn from the active set. Read-write transactions
+    /// record the commit time, in milliseconds since the Unix epoch, for `Mode::AsOf` snapshots,
+    /// and advance the store's closed time. Replicas of a store must be given the same time.
+    ///
+    /// The time is moved past the store's closed time, so that snapshots as of a closed time
+    /// never see later commits. A prepared transaction instead commits no earlier than the time
+    /// returned by `prepare()`, which snapshots could not be taken as of in the meantime.
+    pub fn commit_at(self, time: u64) -> Result<()> {
         let session = self.store.write();
+        let prepared = MvccKey::TxnPrepared(self.id).encode();
+        let min_time = match session.get(&prepared)? {
+            Some(ref v) => Some(deserialize::<(u64, Vec<u8>)>(v)?.0),
+            None => None,
+        };
 
-        // Checks if this transaction has double RW-dependencies.
-        // Returns `Error::Serialization` if positive; otherwise, updates the lock manager.
+        // Checks if this transaction has double RW-dependencies, unless it was already checked
+        // when the transaction was prepared. Returns `Error::Serialization` if positive;
+        // otherwise, updates the lock manager.
         if let (Some(lock_manager), true) = (&self.lock_manager, self.mode.allows_write()) {
-            lock_manager.check_abort(self.id)?;
+            if min_time.is_none() {
+                lock_manager.check_abort(self.id)?;
+            }
             let commit_timestamp = match session.get(&MvccKey::TxnNext.encode())? {
                 Some(ref v) => deserialize(v)?,
                 None => 1,
@@ -112,7 +202,16 @@ impl Transaction {
             lock_manager.commit_txn(self.id, commit_timestamp)?;
         }
 
+        session.delete(&prepared)?;
         session.delete(&MvccKey::TxnActive(self.id).encode())?;
+        if self.mode.allows_write() {
+            let clock = super::gc::clock(&**session)?;
+            let time = time.max(min_time.unwrap_or(clock.saturating_add(1)));
+            session.set(&MvccKey::TxnCommitted(self.id).encode(), serialize(&time)?)?;
+            if time > clock {
+                session.set(&MvccKey::TxnClock.encode(), serialize(&time)?)?;
+            }
+        }
         session.flush()
     }
 
@@ -145,6 +244,7 @@ impl Transaction {
                 session.delete(&key)?;
             }
         }
+        session.delete(&MvccKey::TxnPrepared(self.id).encode())?;
         session.delete(&MvccKey::TxnActive(self.id).encode())
     }
 
@@ -296,6 +396,14 @@ pub enum Mode {
     /// transaction will be visible in the snapshot (i.e. transactions that had not committed before
     /// the snapshot transaction started will not be visible, even though they have a lower version).
     Snapshot { version: u64 },
+    /// A read-only transaction running in a snapshot of the versions committed at or before a
+    /// time, in milliseconds since the Unix epoch. Later commits are after the time, so snapshots
+    /// as of a time are repeatable, and they are rejected while a prepared transaction may still
+    /// commit by then.
+    ///
+    /// Unlike versions, commit times are comparable between stores given the same times, e.g. the
+    /// Raft groups of different key ranges.
+    AsOf { time: u64 },
 }
 
 impl Mode {
@@ -312,6 +420,7 @@ impl Mode {
         match (self, other) {
             (Mode::ReadWrite, Mode::ReadOnly) => true,
             (Mode::Snapshot { .. }, Mode::ReadOnly) => true,
+            (Mode::AsOf { .. }, Mode::ReadOnly) => true,
             (_, _) if self == other => true,
             (_, _) => false,
         }
@@ -347,17 +456,44 @@ impl Snapshot {
     }
 
     /// Restores an existing snapshot from `Key::TxnSnapshot(version)`, or errors if not found.
-    fn restore(session: &RwLockReadGuard<Box<dyn KvStore>>, version: u64) -> Result<Self> {
+    fn restore(session: &dyn KvStore, version: u64) -> Result<Self> {
         match session.get(&MvccKey::TxnSnapshot(version).encode())? {
             Some(ref v) => Ok(Self { version, invisible: deserialize(v)? }),
             None => Err(Error::Value(format!("Snapshot not found for version {}", version))),
         }
     }
 
+    /// Hides the versions committed after a given time as well, and persists the snapshot in
+    /// place. Versions below the snapshot version committed by then are visible, unless they were
+    /// still active when the snapshot was taken; higher versions were not committed yet.
+    fn committed_by(mut self, session: &dyn KvStore, time: u64) -> Result<Self> {
+        let mut scan = session.scan(Range::from(
+            MvccKey::TxnCommitted(0).encode()..MvccKey::TxnCommitted(self.version).encode()
+        ))?;
+        while let Some((key, value)) = scan.next().transpose()? {
+            match MvccKey::decode(&key)? {
+                MvccKey::TxnCommitted(version) if deserialize::<u64>(&value)? > time => {
+                    self.invisible.insert(version);
+                },
+                MvccKey::TxnCommitted(_) => { },
+                k => return Err(Error::Internal(format!("Expected TxnCommitted, got {:?}", k))),
+            }
+        }
+        std::mem::drop(scan);
+        session.set(&MvccKey::TxnSnapshot(self.version).encode(), serialize(&self.invisible)?)?;
+        Ok(self)
+    }
+
     /// Checks whether the given version is visible in this snapshot.
     fn can_access(&self, version: u64) -> bool {
         version <= self.version && self.invisible.get(&version).is_none()
     }
+
+    /// Returns the lowest version that may be invisible in this snapshot. All lower versions are
+    /// visible.
+    fn lowest(&self) -> u64 {
+        self.invisible.iter().copied().chain([self.version]).min().unwrap_or(self.version)
+    }
 }
 
 /// MVCC keys. The encoding preserves the grouping and ordering of keys. 
@@ -376,6 +512,22 @@ pub(super) enum MvccKey<'a> {
     Record(Cow<'a, [u8]>, u64),
     /// Arbitrary unversioned metadata.
     Metadata(Cow<'a, [u8]>),
+    /// Prepared txn markers, containing the earliest time the txn may commit at and a reference
+    /// to the txn's primary. Used to resolve prepared txns whose coordinator has died.
+    TxnPrepared(u64),
+    /// The garbage collection low watermark. Versions below it may have been collected.
+    GcWatermark,
+    /// The commit time of a read-write txn ID, in milliseconds since the Unix epoch.
+    TxnCommitted(u64),
+    /// The record of a decided distributed txn ID, kept at its primary until none of its
+    /// participants is prepared. Contains the outcome.
+    TxnRecord(u64),
+    /// The key the garbage collection pass of a replicated store continues from.
+    GcCursor,
+    /// The closed time: the latest commit time recorded, or time a `Mode::AsOf` snapshot was
+    /// taken as of. Only prepared txns may commit by then. The retention period of a replicated
+    /// store's garbage collection is counted back from it.
+    TxnClock,
 }
 
 impl<'a> MvccKey<'a> {
@@ -390,6 +542,12 @@ impl<'a> MvccKey<'a> {
                 [&[0x04][..], &encode_u64(id), &encode_bytes(&key)].concat()
             }
             Self::Metadata(key) => [&[0x05][..], &encode_bytes(&key)].concat(),
+            Self::TxnPrepared(id) => [&[0x06][..], &encode_u64(id)].concat(),
+            Self::GcWatermark => vec![0x07],
+            Self::TxnCommitted(version) => [&[0x08][..], &encode_u64(version)].concat(),
+            Self::TxnRecord(id) => [&[0x09][..], &encode_u64(id)].concat(),
+            Self::GcCursor => vec![0x0a],
+            Self::TxnClock => vec![0x0b],
             Self::Record(key, version) => {
                 [&[0xff][..], &encode_bytes(&key), &encode_u64(version)].concat()
             }
@@ -397,7 +555,7 @@ impl<'a> MvccKey<'a> {
     }
 
     /// Decodes a key from a byte representation.
-    fn decode(mut bytes: &[u8]) -> Result<Self> {
+    pub(super) fn decode(mut bytes: &[u8]) -> Result<Self> {
         use crate::encoding::*;
         let bytes = &mut bytes;
         let key = match take_byte(bytes)? {
@@ -406,6 +564,12 @@ impl<'a> MvccKey<'a> {
             0x03 => Self::TxnSnapshot(take_u64(bytes)?),
             0x04 => Self::TxnUpdate(take_u64(bytes)?, take_bytes(bytes)?.into()),
             0x05 => Self::Metadata(take_bytes(bytes)?.into()),
+            0x06 => Self::TxnPrepared(take_u64(bytes)?),
+            0x07 => Self::GcWatermark,
+            0x08 => Self::TxnCommitted(take_u64(bytes)?),
+            0x09 => Self::TxnRecord(take_u64(bytes)?),
+            0x0a => Self::GcCursor,
+            0x0b => Self::TxnClock,
             0xff => Self::Record(take_bytes(bytes)?.into(), take_u64(bytes)?),
             b => return Err(Error::Internal(format!("Unknown MVCC key prefix {:x?}", b))),
         };
@@ -416,6 +580,29 @@ impl<'a> MvccKey<'a> {
     }
 }
 
+/// A prepared txn's ID, along with the earliest time it may commit at and the reference to its
+/// primary.
+pub(super) type Prepared = (u64, (u64, Vec<u8>));
+
+/// Returns the prepared txns, by ID.
+pub(super) fn prepared(session: &dyn KvStore) -> Result<Vec<Prepared>> {
+    let scan = session.scan(Range::from(
+        MvccKey::TxnPrepared(0).encode()..=MvccKey::TxnPrepared(u64::MAX).encode()
+    ))?;
+    scan.map(|item| {
+        let (key, value) = item?;
+        match MvccKey::decode(&key)? {
+            MvccKey::TxnPrepared(id) => Ok((id, deserialize(&value)?)),
+            key => Err(Error::Internal(format!("Expected TxnPrepared, got {:?}", key))),
+        }
+    }).collect()
+}
+
+/// Returns the current wall-clock time, in milliseconds since the Unix epoch.
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
 /// Serializes MVCC metadata.
 fn serialize<V: Serialize>(value: &V) -> Result<Vec<u8>> {
     Ok(bincode::serialize(value)?)
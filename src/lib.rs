@@ -1,6 +1,7 @@
 pub mod concurrency;
 pub mod error;
 pub mod encoding;
+pub mod placement;
 pub mod proto;
 pub mod raft;
 pub mod server;
@@ -0,0 +1,214 @@
+mod scheduler;
+
+pub use self::scheduler::{Operator, Scheduler};
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Result, Error};
+use crate::raft::{self, Membership, RangeDescriptor, RangeTable};
+
+/// The number of placement clock ticks without a heartbeat after which a store is declared dead.
+pub const DEAD_TICKS: u64 = 10;
+
+/// A FeatherKV store, i.e. a node running replicas of the Raft groups, as tracked by the
+/// placement driver.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Store {
+    /// The store's Raft node ID.
+    pub id: u64,
+    /// The address the store serves FeatherKV requests on.
+    pub kv_addr: String,
+    /// The address the store serves Raft RPCs on.
+    pub raft_addr: String,
+    /// The maximum number of replicas the store may hold, or 0 if unlimited.
+    pub capacity: u64,
+    /// The placement clock tick of the store's last heartbeat.
+    pub last_heartbeat: u64,
+    /// Whether the store has missed heartbeats for `DEAD_TICKS` ticks. A dead store is revived by
+    /// its next heartbeat.
+    pub dead: bool,
+}
+
+impl Store {
+    /// Checks whether the store can take another replica, given the number it holds.
+    pub fn has_room(&self, replicas: u64) -> bool {
+        !self.dead && (self.capacity == 0 || replicas < self.capacity)
+    }
+}
+
+/// A store's report of one of its replicas.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ReplicaReport {
+    /// The replica's range descriptor.
+    pub range: RangeDescriptor,
+    /// Whether the replica is the group's leader.
+    pub leader: bool,
+    /// The replica's cluster configuration.
+    pub membership: Membership,
+    /// The log index of the replica's cluster configuration.
+    pub membership_index: u64,
+}
+
+/// A store's periodic heartbeat, announcing its addresses and capacity and reporting its replicas.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Heartbeat {
+    pub store: u64,
+    pub kv_addr: String,
+    pub raft_addr: String,
+    pub capacity: u64,
+    pub replicas: Vec<ReplicaReport>,
+}
+
+/// The placement of a Raft group's replicas, as last reported by its leader.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GroupPlacement {
+    /// The store running the group's leader.
+    pub leader: u64,
+    /// The group's cluster configuration.
+    pub membership: Membership,
+    /// The log index of the group's cluster configuration.
+    pub membership_index: u64,
+}
+
+/// The routing information clients need to reach the ranges.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Routes {
+    /// The range descriptor table.
+    pub ranges: RangeTable,
+    /// The FeatherKV addresses of the live stores, by store ID.
+    pub stores: BTreeMap<u64, String>,
+    /// The store running each group's leader, by group ID.
+    pub leaders: BTreeMap<u64, u64>,
+}
+
+/// A placement state machine mutation.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Mutation {
+    /// Records a store's heartbeat. Returns the IDs of the groups whose replicas the store should
+    /// drop, since they were removed from the groups.
+    Heartbeat(Heartbeat),
+    /// Advances the placement clock, declaring stores dead once they miss heartbeats.
+    Tick,
+}
+
+/// A placement state machine query.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Query {
+    /// Returns the stores, by ID.
+    Stores,
+    /// Returns the placement of the groups, by group ID.
+    Groups,
+    /// Returns the routes to the ranges.
+    Routes,
+}
+
+/// The placement driver's state machine, replicated by its own Raft group. It tracks the stores
+/// and the placement of the Raft groups' replicas across them, as reported by store heartbeats.
+#[derive(Default, Serialize, Deserialize)]
+pub struct PlacementState {
+    applied_index: u64,
+    /// The placement clock, advanced by the driver's leader.
+    clock: u64,
+    stores: BTreeMap<u64, Store>,
+    groups: BTreeMap<u64, GroupPlacement>,
+    ranges: RangeTable,
+}
+
+impl PlacementState {
+    /// Creates an empty placement state machine.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a store's heartbeat, and returns the IDs of the groups the store should drop.
+    fn heartbeat(&mut self, heartbeat: Heartbeat) -> Vec<u64> {
+        let Heartbeat { store, kv_addr, raft_addr, capacity, replicas } = heartbeat;
+        self.stores.insert(store, Store {
+            id: store,
+            kv_addr,
+            raft_addr,
+            capacity,
+            last_heartbeat: self.clock,
+            dead: false,
+        });
+
+        let mut dropped = vec![];
+        for ReplicaReport { range, leader, membership, membership_index } in replicas {
+            let group_id = range.group_id;
+            if leader && self.groups.get(&group_id).is_none_or(|group| group.membership_index <= membership_index) {
+                for merged in self.ranges.apply(range) {
+                    self.groups.remove(&merged);
+                }
+                self.groups.insert(group_id, GroupPlacement { leader: store, membership, membership_index });
+                continue;
+            }
+            // A replica behind a configuration that no longer includes it was removed from the
+            // group. A new replica that has not caught up yet is still included.
+            if let Some(group) = self.groups.get(&group_id) {
+                if !group.membership.contains(store) && membership_index < group.membership_index {
+                    dropped.push(group_id);
+                }
+            }
+        }
+        dropped
+    }
+
+    /// Advances the placement clock, and declares the stores that missed heartbeats dead.
+    fn tick(&mut self) {
+        self.clock += 1;
+        for store in self.stores.values_mut() {
+            if self.clock - store.last_heartbeat > DEAD_TICKS {
+                store.dead = true;
+            }
+        }
+    }
+
+    /// Returns the routes to the ranges.
+    fn routes(&self) -> Routes {
+        Routes {
+            ranges: self.ranges.clone(),
+            stores: self.stores.values()
+                .filter(|store| !store.dead)
+                .map(|store| (store.id, store.kv_addr.clone()))
+                .collect(),
+            leaders: self.groups.iter().map(|(&group_id, group)| (group_id, group.leader)).collect(),
+        }
+    }
+}
+
+impl raft::State for PlacementState {
+    fn applied_index(&self) -> u64 {
+        self.applied_index
+    }
+
+    fn mutate(&mut self, index: u64, mutation: Vec<u8>) -> Result<Vec<u8>> {
+        self.applied_index = index;
+        match bincode::deserialize(&mutation)? {
+            Mutation::Heartbeat(heartbeat) => Ok(bincode::serialize(&self.heartbeat(heartbeat))?),
+            Mutation::Tick => {
+                self.tick();
+                Ok(vec![])
+            },
+        }
+    }
+
+    fn query(&self, query: Vec<u8>) -> Result<Vec<u8>> {
+        match bincode::deserialize(&query)? {
+            Query::Stores => Ok(bincode::serialize(&self.stores)?),
+            Query::Groups => Ok(bincode::serialize(&self.groups)?),
+            Query::Routes => Ok(bincode::serialize(&self.routes())?),
+        }
+    }
+
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    fn restore(&mut self, snapshot: Vec<u8>) -> Result<()> {
+        *self = bincode::deserialize(&snapshot)
+            .map_err(|e| Error::Internal(format!("Invalid placement snapshot: {}", e)))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Duration;
+
+use crate::error::Result;
+use crate::raft::{Client, FeatherKV};
+use crate::server::{deserialize, serialize};
+use super::{GroupPlacement, Mutation, Query, Store};
+
+/// How often the placement driver's leader advances the placement clock and schedules operators.
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(1);
+/// How long an operator may take before it is abandoned. It is scheduled again if still needed.
+const OPERATOR_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A change to the placement of a group's replicas.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Operator {
+    /// Adds a replica of a group on a store, as a learner promoted once it has caught up.
+    AddReplica { group_id: u64, store: u64, raft_addr: String },
+    /// Removes a store's replica of a group.
+    RemoveReplica { group_id: u64, store: u64 },
+    /// Transfers leadership of a group to a store's replica.
+    TransferLeader { group_id: u64, store: u64 },
+}
+
+/// Schedules operators to keep every group at the configured number of replicas on live stores,
+/// and to balance replicas and leaders across the stores. Replicas on dead stores are replaced
+/// by adding a replica elsewhere before removing them, so that the group never loses redundancy
+/// it could have kept.
+#[derive(Clone, Copy, Debug)]
+pub struct Scheduler {
+    /// The number of replicas of each group.
+    replicas: usize,
+}
+
+impl Scheduler {
+    /// Creates a scheduler keeping the given number of replicas of each group.
+    pub fn new(replicas: usize) -> Self {
+        Self { replicas }
+    }
+
+    /// Schedules the operators for the next round, at most one per group. Groups with a learner
+    /// are left alone until it has been promoted, so that changes are applied one at a time.
+    pub fn schedule(&self, stores: &BTreeMap<u64, Store>, groups: &BTreeMap<u64, GroupPlacement>) -> Vec<Operator> {
+        // The number of replicas and leaders on each store, updated as operators are scheduled.
+        let mut replicas: BTreeMap<u64, u64> = stores.keys().map(|&id| (id, 0)).collect();
+        let mut leaders = replicas.clone();
+        for group in groups.values() {
+            group.membership.members().for_each(|id| *replicas.entry(id).or_default() += 1);
+            *leaders.entry(group.leader).or_default() += 1;
+        }
+        // Stores that have not sent a heartbeat yet are not known to be dead.
+        let is_dead = |id: u64| stores.get(&id).is_some_and(|store| store.dead);
+
+        let mut operators = vec![];
+        for (&group_id, group) in groups {
+            let GroupPlacement { leader, membership, .. } = group;
+            if !membership.learners.is_empty() || is_dead(*leader) {
+                continue;
+            }
+            let voters = membership.voters.len();
+            let dead = membership.voters.iter().copied().find(|&id| is_dead(id) && id != *leader);
+            // The least loaded live store without a replica that has room for one.
+            let target = stores.values()
+                .filter(|store| !membership.contains(store.id) && store.has_room(replicas[&store.id]))
+                .min_by_key(|store| (replicas[&store.id], store.id));
+            // The most loaded replica other than the leader, to move or remove.
+            let source = membership.voters.iter().copied()
+                .filter(|id| id != leader)
+                .max_by_key(|id| (replicas.get(id).copied().unwrap_or(0), *id));
+
+            let operator = if voters > self.replicas || (dead.is_some() && target.is_none()) {
+                dead.or(source).map(|store| Operator::RemoveReplica { group_id, store })
+            } else if voters < self.replicas || dead.is_some() {
+                target.map(|store| Operator::AddReplica { group_id, store: store.id, raft_addr: store.raft_addr.clone() })
+            } else {
+                match (source, target) {
+                    // Moves a replica by adding it first, the next round removes the extra one.
+                    (Some(source), Some(target)) if replicas.get(&source).copied().unwrap_or(0) > replicas[&target.id] + 1 => {
+                        Some(Operator::AddReplica { group_id, store: target.id, raft_addr: target.raft_addr.clone() })
+                    },
+                    _ => membership.voters.iter().copied()
+                        .filter(|&id| !is_dead(id) && !membership.is_witness(id))
+                        .min_by_key(|id| (leaders.get(id).copied().unwrap_or(0), *id))
+                        .filter(|id| leaders[leader] > leaders.get(id).copied().unwrap_or(0) + 1)
+                        .map(|store| Operator::TransferLeader { group_id, store }),
+                }
+            };
+
+            match &operator {
+                Some(Operator::AddReplica { store, .. }) => *replicas.entry(*store).or_default() += 1,
+                Some(Operator::RemoveReplica { store, .. }) => *replicas.entry(*store).or_default() -= 1,
+                Some(Operator::TransferLeader { store, .. }) => {
+                    *leaders.entry(*leader).or_default() -= 1;
+                    *leaders.entry(*store).or_default() += 1;
+                },
+                None => { },
+            }
+            operators.extend(operator);
+        }
+        operators
+    }
+
+    /// Runs the scheduler on a placement driver node, given the FeatherKV addresses of the
+    /// placement driver's nodes. While the node leads the placement group, it advances the
+    /// placement clock and executes the scheduled operators against the stores every
+    /// `SCHEDULE_INTERVAL`. Operators that fail are logged, and scheduled again if still needed.
+    /// Other failures, e.g. while the placement driver elects a leader, are logged and retried on
+    /// the next tick.
+    pub async fn run(self, server: Arc<FeatherKV>, placement: BTreeMap<u64, String>) -> Result<()> {
+        let mut client = Client::new(placement.clone()).await?;
+        let mut interval = tokio::time::interval(SCHEDULE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.step(&server, &mut client, &placement).await {
+                println!("Placement driver failed to schedule: {}", e);
+            }
+        }
+    }
+
+    /// Advances the placement clock and executes the scheduled operators, if the node leads the
+    /// placement group.
+    async fn step(&self, server: &FeatherKV, client: &mut Client, placement: &BTreeMap<u64, String>) -> Result<()> {
+        if !server.is_leader(0)? {
+            return Ok(());
+        }
+        client.mutate(serialize(&Mutation::Tick)?).await?;
+        let stores: BTreeMap<u64, Store> = deserialize(&client.query(serialize(&Query::Stores)?).await?)?;
+        let groups: BTreeMap<u64, GroupPlacement> = deserialize(&client.query(serialize(&Query::Groups)?).await?)?;
+        let operators = self.schedule(&stores, &groups);
+        if operators.is_empty() {
+            return Ok(());
+        }
+
+        let mut stores = Client::placed(placement.clone()).await?;
+        for operator in operators {
+            println!("Placement driver executing {:?}", operator);
+            let result = match operator {
+                Operator::AddReplica { group_id, store, raft_addr } => {
+                    tokio::time::timeout(OPERATOR_TIMEOUT, stores.add_group_node(group_id, store, raft_addr)).await
+                },
+                Operator::RemoveReplica { group_id, store } => {
+                    tokio::time::timeout(OPERATOR_TIMEOUT, stores.remove_group_node(group_id, store)).await
+                },
+                Operator::TransferLeader { group_id, store } => {
+                    tokio::time::timeout(OPERATOR_TIMEOUT, stores.transfer_group_leader(group_id, store)).await
+                },
+            };
+            match result {
+                Ok(Ok(())) => { },
+                Ok(Err(e)) => println!("Placement driver operator failed: {}", e),
+                Err(_) => println!("Placement driver operator timed out"),
+            }
+        }
+        Ok(())
+    }
+}
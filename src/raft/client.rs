@@ -1,58 +1,121 @@
+use std::collections::{BTreeMap, HashMap};
+
 use serde::{Deserialize, Serialize};
-use tonic::transport::Channel;
+use tonic::transport::{Channel, Endpoint};
 
 use crate::error::{Result, Error};
+use crate::placement;
 use crate::proto::featherkv::{ExecutionReply, ExecutionRequest};
 use crate::proto::featherkv::{FeatherKvClient, RegistrationRequest, RegistrationReply};
-use super::RpcStatus;
+use crate::proto::featherkv::{AddNodeRequest, AdminReply, RemoveNodeRequest, TransferLeaderRequest};
+use crate::proto::featherkv::{RangesReply, RangesRequest, StatusReply, StatusRequest};
+use super::{Consistency, RangeDescriptor, RangeTable, RpcStatus, Status};
+
+/// The maximum number of mutations a client has in flight at once. Kept well below the server's
+/// session window, so that in-flight mutations are never rejected for lack of acknowledgements.
+const MAX_IN_FLIGHT: usize = 128;
 
-/// A Raft-based key-value client.
+/// A cluster administration request, sent to the leader.
+enum AdminRequest {
+    AddNode(AddNodeRequest),
+    RemoveNode(RemoveNodeRequest),
+    TransferLeader(TransferLeaderRequest),
+}
+
+/// A Raft-based key-value client. Requests are routed by key to the Raft group replicating the
+/// key's range, using a cached range descriptor table that is refreshed whenever a server rejects a
+/// request with `WrongRange`. Requests without a key go to the range containing the empty key. A
+/// client created with [`Client::placed`] looks the servers and ranges up in the placement driver
+/// instead of asking a fixed set of servers.
 #[derive(Clone)]
 pub struct Client {
-    servers: Vec<FeatherKvClient<Channel>>,
+    /// The clients of the FeatherKV servers, by node ID.
+    servers: BTreeMap<u64, FeatherKvClient<Channel>>,
+    /// The cached range descriptor table.
+    ranges: RangeTable,
+    /// The client's session with each Raft group, by group ID.
+    groups: HashMap<u64, GroupSession>,
+    /// The next replica to send a non-linearizable read to.
+    next_replica: u64,
+    /// The client of the placement driver, if the servers and ranges are looked up there.
+    placement: Option<Box<Client>>,
+}
+
+/// A client's session with a Raft group.
+#[derive(Clone)]
+struct GroupSession {
     session_id: u64,
     sequence_number: u64,
     last_leader: u64,
+    /// The Raft address of the last known leader, as reported by the servers.
+    last_leader_addr: String,
+    /// The highest log index observed in a reply, used for reads that must see earlier writes.
+    last_index: u64,
 }
 
 impl Client {
-    /// Creates a new Raft client.
-    pub async fn new(servers: Vec<String>) -> Result<Self> {
-        let servers = {
-            let mut clients = Vec::new();
-            for addr in servers {
-                let addr = format!("http://{}", addr).to_string();
-                let client = FeatherKvClient::connect(addr).await.unwrap();
-                clients.push(client);
-            }
-            clients
-        };
+    /// Creates a new Raft client, given the FeatherKV server addresses by node ID. Servers are
+    /// connected to on first use, so some of them may be down.
+    pub async fn new(servers: BTreeMap<u64, String>) -> Result<Self> {
+        if servers.is_empty() {
+            return Err(Error::Config("No FeatherKV servers given".into()));
+        }
+        let servers = servers.into_iter()
+            .map(|(id, addr)| Ok((id, Self::connect(&addr)?)))
+            .collect::<Result<_>>()?;
         Ok(Self {
             servers,
-            session_id: 0,
-            sequence_number: 1,
-            last_leader: 0,
+            ranges: RangeTable::default(),
+            groups: HashMap::new(),
+            next_replica: 0,
+            placement: None,
         })
     }
 
-    /// Registers a new session. This method will keep retrying until getting a valid reply.
-    async fn register(&mut self) -> Result<()> {
+    /// Creates a new Raft client that routes requests via the placement driver, given the
+    /// FeatherKV addresses of the placement driver's nodes. Waits until the placement driver knows
+    /// of a live store.
+    pub async fn placed(placement: BTreeMap<u64, String>) -> Result<Self> {
+        let mut client = Self {
+            servers: BTreeMap::new(),
+            ranges: RangeTable::default(),
+            groups: HashMap::new(),
+            next_replica: 0,
+            placement: Some(Box::new(Self::new(placement).await?)),
+        };
+        client.load_ranges().await?;
+        Ok(client)
+    }
+
+    /// Returns a lazily connected client of a FeatherKV server.
+    fn connect(addr: &str) -> Result<FeatherKvClient<Channel>> {
+        let endpoint = Endpoint::from_shared(format!("http://{}", addr))
+            .map_err(|e| Error::Config(format!("Invalid FeatherKV address {}: {}", addr, e)))?;
+        Ok(FeatherKvClient::new(endpoint.connect_lazy()))
+    }
+
+    /// Registers a new session with a group. This method will keep retrying until getting a valid
+    /// reply. If the group no longer exists, refreshes the range descriptor table instead.
+    async fn register(&mut self, group_id: u64) -> Result<()> {
         loop {
-            match self.servers[self.last_leader as usize].register(RegistrationRequest { }).await {
+            match self.leader_server(group_id).register(RegistrationRequest { group_id }).await {
                 Ok(response) => {
-                    let RegistrationReply { status, session_id, leader_hint } = response.into_inner();
-                    self.last_leader = leader_hint;
+                    let RegistrationReply { status, session_id, leader_hint, leader_addr } = response.into_inner();
+                    let group = self.group(group_id);
+                    group.last_leader = leader_hint;
+                    group.last_leader_addr = leader_addr;
 
                     match Self::deserialize::<RpcStatus>(&status)? {
                         RpcStatus::Ok => {
-                            self.session_id = session_id;
-                            self.sequence_number = 1;
+                            group.session_id = session_id;
+                            group.sequence_number = 1;
                             return Ok(());
                         },
                         RpcStatus::NotLeader => { continue; },
                         RpcStatus::SessionExpired => {
                             return Err(Error::Internal("Should not get SessionExpired".into()));
                         },
+                        RpcStatus::WrongRange => return self.refresh_ranges().await,
                     }
                 },
 
@@ -64,25 +127,139 @@ impl Client {
 
     /// Mutates the Raft state machine. This method will keep retrying until getting a valid reply.
     pub async fn mutate(&mut self, mutation: Vec<u8>) -> Result<Vec<u8>> {
+        self.mutate_key(&[], mutation).await
+    }
+
+    /// Mutates the Raft state machine of the range containing a key. This method will keep
+    /// retrying until getting a valid reply.
+    pub async fn mutate_key(&mut self, key: &[u8], mutation: Vec<u8>) -> Result<Vec<u8>> {
+        self.mutate_pipelined(key, vec![mutation]).await?.remove(0)
+    }
+
+    /// Mutates the Raft state machine of the range containing a key with several mutations in
+    /// flight at once, and returns their results in order. The mutations may be applied in any
+    /// order. Up to `MAX_IN_FLIGHT` are sent concurrently, and each is retried until getting a
+    /// valid reply.
+    pub async fn mutate_pipelined(
+        &mut self,
+        key: &[u8],
+        mutations: Vec<Vec<u8>>,
+    ) -> Result<Vec<Result<Vec<u8>>>> {
+        let mut results: Vec<Option<Result<Vec<u8>>>> = vec![None; mutations.len()];
+        let mut group_id = self.ranges.lookup(key).group_id;
+        let mut sequence_numbers = self.next_sequence_numbers(group_id, mutations.len());
+        // The mutations without a reply yet, in increasing order of sequence number.
+        let mut pending: Vec<usize> = (0..mutations.len()).collect();
+
+        while !pending.is_empty() {
+            // All mutations below the first pending one have been replied to.
+            let lowest_unacked = sequence_numbers[pending[0]];
+            let session_id = self.group(group_id).session_id;
+            let server = self.leader_server(group_id);
+            let requests = pending.iter().take(MAX_IN_FLIGHT).map(|&i| {
+                let mut server = server.clone();
+                let execution_request = ExecutionRequest {
+                    session_id,
+                    sequence_number: sequence_numbers[i],
+                    operation: mutations[i].clone(),
+                    consistency: vec![],
+                    lowest_unacked,
+                    group_id,
+                    key: key.to_vec(),
+                };
+                async move { (i, server.mutate(execution_request).await) }
+            });
+
+            let (mut expired, mut wrong_range) = (false, false);
+            for (i, reply) in futures::future::join_all(requests).await {
+                // Timeouts and other failures are retried.
+                let ExecutionReply { status, response, leader_hint, index, leader_addr } = match reply {
+                    Ok(reply) => reply.into_inner(),
+                    Err(_) => continue,
+                };
+
+                match Self::deserialize::<RpcStatus>(&status)? {
+                    RpcStatus::Ok => {
+                        self.observe(group_id, leader_hint, leader_addr, index);
+                        results[i] = Some(Self::deserialize::<Result<Vec<u8>>>(&response)?);
+                    },
+                    RpcStatus::NotLeader => self.observe(group_id, leader_hint, leader_addr, 0),
+                    RpcStatus::SessionExpired => expired = true,
+                    RpcStatus::WrongRange => wrong_range = true,
+                }
+            }
+            pending.retain(|&i| results[i].is_none());
+
+            // Resends the remaining mutations to the key's current group, in a new session if the
+            // group or the session has changed. A group split off from the old one inherits its
+            // sessions, so the client keeps its session there, and a mutation applied before the
+            // split is not applied again. If the new group does not know the session, it is
+            // reported as expired and the mutations are resent in a new one.
+            if wrong_range {
+                self.refresh_ranges().await?;
+            }
+            let current_group_id = self.ranges.lookup(key).group_id;
+            if !expired && current_group_id != group_id && self.group(current_group_id).session_id == 0 {
+                let GroupSession { session_id, sequence_number, .. } = *self.group(group_id);
+                group_id = current_group_id;
+                let group = self.group(group_id);
+                group.session_id = session_id;
+                group.sequence_number = sequence_number;
+            } else if expired || current_group_id != group_id {
+                group_id = current_group_id;
+                self.register(group_id).await?;
+                let renumbered = self.next_sequence_numbers(group_id, pending.len());
+                for (&i, sequence_number) in pending.iter().zip(renumbered) {
+                    sequence_numbers[i] = sequence_number;
+                }
+            }
+        }
+
+        Ok(results.into_iter().map(Option::unwrap).collect())
+    }
+
+    /// Allocates the next `n` sequence numbers of the session with a group.
+    fn next_sequence_numbers(&mut self, group_id: u64, n: usize) -> Vec<u64> {
+        let group = self.group(group_id);
+        let start = group.sequence_number;
+        group.sequence_number += n as u64;
+        (start..group.sequence_number).collect()
+    }
+
+    /// Queries the Raft state machine. This method will keep retrying until getting a valid reply.
+    pub async fn query(&mut self, query: Vec<u8>) -> Result<Vec<u8>> {
+        self.query_key(&[], query).await
+    }
+
+    /// Queries the Raft state machine of the range containing a key. This method will keep
+    /// retrying until getting a valid reply.
+    pub async fn query_key(&mut self, key: &[u8], query: Vec<u8>) -> Result<Vec<u8>> {
         loop {
+            let group_id = self.ranges.lookup(key).group_id;
+            let group = self.group(group_id);
             let execution_request = ExecutionRequest {
-                session_id: self.session_id,
-                sequence_number: self.sequence_number,
-                operation: mutation.clone(),
+                session_id: group.session_id,
+                sequence_number: group.sequence_number,
+                operation: query.clone(),
+                consistency: vec![],
+                lowest_unacked: 0,
+                group_id,
+                key: key.to_vec(),
             };
 
-            match self.servers[self.last_leader as usize].mutate(execution_request).await {
+            match self.leader_server(group_id).query(execution_request).await {
                 Ok(reply) => {
-                    let ExecutionReply { status, response, leader_hint } = reply.into_inner();
-                    self.last_leader = leader_hint;
+                    let ExecutionReply { status, response, leader_hint, index, leader_addr } = reply.into_inner();
 
                     match Self::deserialize::<RpcStatus>(&status)? {
                         RpcStatus::Ok => {
-                            self.sequence_number += 1;
+                            self.observe(group_id, leader_hint, leader_addr, index);
+                            self.group(group_id).sequence_number += 1;
                             return Self::deserialize::<Result<Vec<u8>>>(&response)?;
                         },
-                        RpcStatus::NotLeader => { continue; },
-                        RpcStatus::SessionExpired => { self.register().await?; },
+                        RpcStatus::NotLeader => { self.observe(group_id, leader_hint, leader_addr, 0); },
+                        RpcStatus::SessionExpired => { self.register(group_id).await?; },
+                        RpcStatus::WrongRange => { self.refresh_ranges().await?; },
                     }
                 },
 
@@ -92,34 +269,343 @@ impl Client {
         }
     }
 
-    /// Queries the Raft state machine. This method will keep retrying until getting a valid reply.
-    pub async fn query(&mut self, query: Vec<u8>) -> Result<Vec<u8>> {
-        loop {
+    /// Queries the Raft state machine with the given consistency. Non-linearizable queries are
+    /// spread across replicas in turn; if no replica can serve the query, falls back to a
+    /// linearizable query on the leader.
+    pub async fn query_with(&mut self, query: Vec<u8>, consistency: Consistency) -> Result<Vec<u8>> {
+        self.query_key_with(&[], query, consistency).await
+    }
+
+    /// Queries the Raft state machine of the range containing a key with the given consistency,
+    /// like [`Client::query_with`].
+    pub async fn query_key_with(
+        &mut self,
+        key: &[u8],
+        query: Vec<u8>,
+        consistency: Consistency,
+    ) -> Result<Vec<u8>> {
+        if consistency == Consistency::Linearizable {
+            return self.query_key(key, query).await;
+        }
+        let group_id = self.ranges.lookup(key).group_id;
+        for _ in 0..self.servers.len() {
+            let replica = self.next_server(self.next_replica);
+            self.next_replica = replica.wrapping_add(1);
+            let group = self.group(group_id);
             let execution_request = ExecutionRequest {
-                session_id: self.session_id,
-                sequence_number: self.sequence_number,
+                session_id: group.session_id,
+                sequence_number: group.sequence_number,
                 operation: query.clone(),
+                consistency: Self::serialize(&consistency)?,
+                lowest_unacked: 0,
+                group_id,
+                key: key.to_vec(),
+            };
+
+            // Tries the next replica if this one is unreachable or too stale.
+            let server = self.servers.get_mut(&replica).unwrap();
+            if let Ok(reply) = server.query(execution_request).await {
+                let ExecutionReply { status, response, index, .. } = reply.into_inner();
+                if let RpcStatus::Ok = Self::deserialize::<RpcStatus>(&status)? {
+                    let group = self.group(group_id);
+                    group.last_index = group.last_index.max(index);
+                    return Self::deserialize::<Result<Vec<u8>>>(&response)?;
+                }
+            }
+        }
+        self.query_key(key, query).await
+    }
+
+    /// Returns the highest log index observed in a reply from the range containing the empty key.
+    /// A query with [`Consistency::AtLeast`] this index observes all of the client's earlier
+    /// mutations without a key.
+    pub fn last_index(&self) -> u64 {
+        self.last_key_index(&[])
+    }
+
+    /// Returns the highest log index observed in a reply from the range containing a key, like
+    /// [`Client::last_index`].
+    pub fn last_key_index(&self, key: &[u8]) -> u64 {
+        let group_id = self.ranges.lookup(key).group_id;
+        self.groups.get(&group_id).map_or(0, |group| group.last_index)
+    }
+
+    /// Returns the ID and Raft address of the last known leader of group 0. The address is empty
+    /// until a server has reported it.
+    pub fn leader(&self) -> (u64, &str) {
+        match self.groups.get(&0) {
+            Some(group) => (group.last_leader, &group.last_leader_addr),
+            None => (self.next_server(0), ""),
+        }
+    }
+
+    /// Adds a node with the given Raft address to the Raft group of every range.
+    pub async fn add_node(&mut self, id: u64, addr: String) -> Result<()> {
+        for group_id in self.group_ids().await? {
+            self.add_group_node(group_id, id, addr.clone()).await?;
+        }
+        Ok(())
+    }
+
+    /// Adds a witness with the given Raft address to the Raft group of every range.
+    pub async fn add_witness(&mut self, id: u64, addr: String) -> Result<()> {
+        for group_id in self.group_ids().await? {
+            self.add_group_witness(group_id, id, addr.clone()).await?;
+        }
+        Ok(())
+    }
+
+    /// Removes a node from the Raft group of every range.
+    pub async fn remove_node(&mut self, id: u64) -> Result<()> {
+        for group_id in self.group_ids().await? {
+            self.remove_group_node(group_id, id).await?;
+        }
+        Ok(())
+    }
+
+    /// Transfers leadership of the Raft group of every range to the given node.
+    pub async fn transfer_leader(&mut self, id: u64) -> Result<()> {
+        for group_id in self.group_ids().await? {
+            self.transfer_group_leader(group_id, id).await?;
+        }
+        Ok(())
+    }
+
+    /// Adds a node with the given Raft address to a Raft group.
+    pub async fn add_group_node(&mut self, group_id: u64, id: u64, addr: String) -> Result<()> {
+        self.admin(group_id, AdminRequest::AddNode(AddNodeRequest { id, addr, group_id, witness: false })).await
+    }
+
+    /// Adds a witness with the given Raft address to a Raft group.
+    pub async fn add_group_witness(&mut self, group_id: u64, id: u64, addr: String) -> Result<()> {
+        self.admin(group_id, AdminRequest::AddNode(AddNodeRequest { id, addr, group_id, witness: true })).await
+    }
+
+    /// Removes a node from a Raft group.
+    pub async fn remove_group_node(&mut self, group_id: u64, id: u64) -> Result<()> {
+        self.admin(group_id, AdminRequest::RemoveNode(RemoveNodeRequest { id, group_id })).await
+    }
+
+    /// Transfers leadership of a Raft group to the given node.
+    pub async fn transfer_group_leader(&mut self, group_id: u64, id: u64) -> Result<()> {
+        self.admin(group_id, AdminRequest::TransferLeader(TransferLeaderRequest { id, group_id })).await
+    }
+
+    /// Returns the IDs of the Raft groups of all ranges, from a refreshed range descriptor table.
+    async fn group_ids(&mut self) -> Result<Vec<u64>> {
+        Ok(self.ranges().await?.into_iter().map(|range| range.group_id).collect())
+    }
+
+    /// Returns the ranges in key order, from a freshly loaded range descriptor table.
+    pub async fn ranges(&mut self) -> Result<Vec<RangeDescriptor>> {
+        self.load_ranges().await?;
+        Ok(self.ranges.ranges().cloned().collect())
+    }
+
+    /// Returns the Raft status of each server's replicas, by node ID and group ID. Unreachable
+    /// servers are left out. A client routed via the placement driver asks the live stores.
+    pub async fn status(&mut self) -> Result<BTreeMap<u64, BTreeMap<u64, Status>>> {
+        if self.placement.is_some() {
+            self.load_routes().await?;
+        }
+        let mut status = BTreeMap::new();
+        for (&id, server) in self.servers.iter_mut() {
+            if let Ok(reply) = server.status(StatusRequest { }).await {
+                let StatusReply { groups } = reply.into_inner();
+                status.insert(id, Self::deserialize(&groups)?);
+            }
+        }
+        Ok(status)
+    }
+
+    /// Returns the ID of the Raft group that the cached range descriptor table routes a key to.
+    /// After a request for the key, this is the group that served it.
+    pub fn group_id(&self, key: &[u8]) -> u64 {
+        self.ranges.lookup(key).group_id
+    }
+
+    /// Returns a client with the same servers and range descriptor table, but its own sessions,
+    /// e.g. to send requests concurrently with this client.
+    pub fn fork(&self) -> Self {
+        Self {
+            servers: self.servers.clone(),
+            ranges: self.ranges.clone(),
+            groups: HashMap::new(),
+            next_replica: 0,
+            placement: self.placement.clone(),
+        }
+    }
+
+    /// Sends an admin request to the leader of a group, following leader hints. This method will
+    /// keep retrying until the leader accepts or rejects the request. Unreachable servers are
+    /// skipped.
+    async fn admin(&mut self, group_id: u64, request: AdminRequest) -> Result<()> {
+        loop {
+            let mut server = self.leader_server(group_id);
+            let reply = match &request {
+                AdminRequest::AddNode(request) => server.add_node(request.clone()).await,
+                AdminRequest::RemoveNode(request) => server.remove_node(request.clone()).await,
+                AdminRequest::TransferLeader(request) => server.transfer_leader(request.clone()).await,
             };
 
-            match self.servers[self.last_leader as usize].query(execution_request).await {
+            match reply {
                 Ok(reply) => {
-                    let ExecutionReply { status, response, leader_hint } = reply.into_inner();
-                    self.last_leader = leader_hint;
+                    let AdminReply { status, leader_hint } = reply.into_inner();
+                    self.group(group_id).last_leader = leader_hint;
 
                     match Self::deserialize::<RpcStatus>(&status)? {
-                        RpcStatus::Ok => {
-                            self.sequence_number += 1;
-                            return Self::deserialize::<Result<Vec<u8>>>(&response)?;
+                        RpcStatus::Ok => return Ok(()),
+                        RpcStatus::NotLeader => {
+                            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+                        },
+                        RpcStatus::SessionExpired => {
+                            return Err(Error::Internal("Should not get SessionExpired".into()));
+                        },
+                        RpcStatus::WrongRange => {
+                            return Err(Error::Value(format!("Unknown Raft group {}", group_id)));
                         },
-                        RpcStatus::NotLeader => { continue; },
-                        RpcStatus::SessionExpired => { self.register().await?; },
                     }
                 },
 
-                // Timeout, retries the request.
-                Err(e) => { continue; },
+                // The server is unreachable, tries the next one.
+                Err(e) if e.code() == tonic::Code::Unavailable => {
+                    let last_leader = self.group(group_id).last_leader;
+                    self.group(group_id).last_leader = self.next_server(last_leader.wrapping_add(1));
+                },
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Refreshes the range descriptor table after a server rejected a request with `WrongRange`.
+    async fn refresh_ranges(&mut self) -> Result<()> {
+        let previous = self.ranges.clone();
+        self.load_ranges().await?;
+        // If no server has applied the change the client ran into yet, waits a bit before the
+        // caller retries.
+        if self.ranges == previous {
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        }
+        Ok(())
+    }
+
+    /// Loads the range descriptor table from the servers. Servers learn of splits and merges as
+    /// they apply them, so the tables of all reachable servers are combined, keeping the newest
+    /// descriptor of each range. This method will keep retrying until a server replies. A client
+    /// routed via the placement driver loads its routes from there instead.
+    async fn load_ranges(&mut self) -> Result<()> {
+        if self.placement.is_some() {
+            return self.load_routes().await;
+        }
+        loop {
+            let mut refreshed: Option<RangeTable> = None;
+            for server in self.servers.values_mut() {
+                if let Ok(reply) = server.get_ranges(RangesRequest { }).await {
+                    let RangesReply { ranges } = reply.into_inner();
+                    let ranges: RangeTable = Self::deserialize(&ranges)?;
+                    match &mut refreshed {
+                        Some(table) => ranges.ranges().for_each(|range| { table.apply(range.clone()); }),
+                        None => refreshed = Some(ranges),
+                    }
+                }
+            }
+            if let Some(ranges) = refreshed {
+                self.ranges = ranges;
+                return Ok(());
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Loads the servers, the range descriptor table and the groups' leaders from the placement
+    /// driver. This method will keep retrying until the placement driver knows of a live store.
+    async fn load_routes(&mut self) -> Result<()> {
+        // The query is sent directly to the placement driver's leader, rather than via
+        // `Client::query`, which may itself refresh the routes.
+        let placement = self.placement.as_mut().unwrap();
+        let request = ExecutionRequest {
+            session_id: 0,
+            sequence_number: 0,
+            operation: Self::serialize(&placement::Query::Routes)?,
+            consistency: vec![],
+            lowest_unacked: 0,
+            group_id: 0,
+            key: vec![],
+        };
+        let routes = loop {
+            match placement.leader_server(0).query(request.clone()).await {
+                Ok(reply) => {
+                    let ExecutionReply { status, response, leader_hint, leader_addr, .. } = reply.into_inner();
+                    match Self::deserialize::<RpcStatus>(&status)? {
+                        RpcStatus::Ok => {
+                            let routes: placement::Routes =
+                                Self::deserialize(&Self::deserialize::<Result<Vec<u8>>>(&response)??)?;
+                            if !routes.stores.is_empty() {
+                                break routes;
+                            }
+                        },
+                        _ => placement.observe(0, leader_hint, leader_addr, 0),
+                    }
+                },
+                // The node is unreachable, tries the next one.
+                Err(_) => {
+                    let last_leader = placement.group(0).last_leader;
+                    placement.group(0).last_leader = placement.next_server(last_leader.wrapping_add(1));
+                },
             }
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        };
+        let mut servers = BTreeMap::new();
+        for (id, addr) in routes.stores {
+            let server = match self.servers.remove(&id) {
+                Some(server) => server,
+                None => Self::connect(&addr)?,
+            };
+            servers.insert(id, server);
         }
+        self.servers = servers;
+        self.ranges = routes.ranges;
+        for (group_id, leader) in routes.leaders {
+            if !self.groups.contains_key(&group_id) {
+                self.group(group_id).last_leader = leader;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the client's session with a group, creating an unregistered one if there is none.
+    fn group(&mut self, group_id: u64) -> &mut GroupSession {
+        let first_server = *self.servers.keys().next().unwrap();
+        self.groups.entry(group_id).or_insert_with(|| GroupSession {
+            session_id: 0,
+            sequence_number: 1,
+            last_leader: first_server,
+            last_leader_addr: String::new(),
+            last_index: 0,
+        })
+    }
+
+    /// Records the leader hint and log index of a reply from a group.
+    fn observe(&mut self, group_id: u64, leader_hint: u64, leader_addr: String, index: u64) {
+        let group = self.group(group_id);
+        group.last_leader = leader_hint;
+        group.last_leader_addr = leader_addr;
+        group.last_index = group.last_index.max(index);
+    }
+
+    /// Returns the client of the last known leader of a group. If the leader has no known
+    /// FeatherKV address, e.g. because it joined the cluster after the client was created, tries
+    /// the next server.
+    fn leader_server(&mut self, group_id: u64) -> FeatherKvClient<Channel> {
+        let last_leader = self.group(group_id).last_leader;
+        let leader = self.next_server(last_leader);
+        self.group(group_id).last_leader = leader;
+        self.servers[&leader].clone()
+    }
+
+    /// Returns the ID of the first server at or after the given ID, wrapping around.
+    fn next_server(&self, id: u64) -> u64 {
+        *self.servers.range(id..).chain(self.servers.iter()).next().unwrap().0
     }
 
     /// Serializes a value for the Raft client.
@@ -131,4 +617,4 @@ impl Client {
     fn deserialize<'a, V: Deserialize<'a>>(bytes: &'a [u8]) -> Result<V> {
         Ok(bincode::deserialize(bytes)?)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,63 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// The clock that drives a node's ticks and times its leader lease and stale reads.
+#[derive(Clone, Debug, Default)]
+pub enum Clock {
+    /// The system clock, ticking every `Options::tick_interval`.
+    #[default]
+    System,
+    /// A clock that only advances when told to, so that tests can drive ticks deterministically.
+    Manual(ManualClock),
+}
+
+impl Clock {
+    /// Returns the current time.
+    pub fn now(&self) -> Instant {
+        match self {
+            Self::System => Instant::now(),
+            Self::Manual(clock) => clock.now(),
+        }
+    }
+}
+
+/// A manually advanced clock, which can be shared by several nodes. A node ticks once for every
+/// `Options::tick_interval` the clock is advanced by.
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    /// The time the clock started at.
+    start: Instant,
+    /// The time elapsed since the start, published to the nodes driven by the clock.
+    elapsed: Arc<watch::Sender<Duration>>,
+}
+
+impl ManualClock {
+    /// Creates a manual clock, starting at the current system time.
+    pub fn new() -> Self {
+        Self { start: Instant::now(), elapsed: Arc::new(watch::channel(Duration::ZERO).0) }
+    }
+
+    /// Returns the current time of the clock.
+    pub fn now(&self) -> Instant {
+        self.start + *self.elapsed.borrow()
+    }
+
+    /// Advances the clock, waking up the nodes it drives.
+    pub fn advance(&self, duration: Duration) {
+        self.elapsed.send_modify(|elapsed| *elapsed += duration);
+    }
+
+    /// Returns a receiver notified whenever the clock advances.
+    pub(super) fn subscribe(&self) -> watch::Receiver<Duration> {
+        self.elapsed.subscribe()
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
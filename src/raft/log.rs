@@ -3,7 +3,7 @@ use std::ops::RangeBounds;
 use serde::{Deserialize, Serialize};
 
 use crate::{storage::log::{LogStore, Range}, error::{Result, Error}};
-use super::Command;
+use super::{Command, Membership};
 
 /// A replicated log entry
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -16,8 +16,35 @@ pub struct Entry {
     pub command: Command,
 }
 
+impl Entry {
+    /// Returns the entry as replicated to a witness. Configurations, which Raft itself interprets,
+    /// are kept, while other commands are replaced by noops.
+    pub fn header(self) -> Entry {
+        match self.command {
+            Command::Membership(_) => self,
+            _ => Entry { command: Command::Noop, ..self },
+        }
+    }
+}
+
+/// A snapshot of the state machine, replacing all log entries up to and including `index`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// The index of the last entry included in the snapshot.
+    pub index: u64,
+    /// The term of the last entry included in the snapshot.
+    pub term: u64,
+    /// The serialized state machine.
+    pub data: Vec<u8>,
+    /// The configuration in effect at `index`, if known.
+    pub membership: Option<Membership>,
+}
+
 pub type Scan<'a> = Box<dyn Iterator<Item = Result<Entry>> + 'a>;
 
+/// The log store metadata key of the latest snapshot.
+const SNAPSHOT_KEY: &[u8] = b"snapshot";
+
 pub struct Log {
     /// The underlying log store.
     pub(super) store: Box<dyn LogStore>,
@@ -29,13 +56,25 @@ pub struct Log {
     pub(super) commit_index: u64,
     /// The term of the last committed entry.
     pub(super) commit_term: u64,
+    /// The index of the last entry covered by the snapshot, or 0 if none.
+    pub(super) snapshot_index: u64,
+    /// The term of the last entry covered by the snapshot, or 0 if none.
+    pub(super) snapshot_term: u64,
 }
 
 impl Log {
     /// Creates a new log, using a LogStore for storage.
     pub fn new(store: Box<dyn LogStore>) -> Result<Log> {
+        let (snapshot_index, snapshot_term) = match store.get_metadata(SNAPSHOT_KEY)? {
+            Some(bytes) => {
+                let snapshot = Self::deserialize::<Snapshot>(&bytes)?;
+                (snapshot.index, snapshot.term)
+            }
+            None => (0, 0),
+        };
         let (commit_index, commit_term) = match store.commit_index() {
             0 => (0, 0),
+            index if index == snapshot_index => (snapshot_index, snapshot_term),
             index => store
                 .get(index)?
                 .map(|v| Self::deserialize::<Entry>(&v))
@@ -45,6 +84,7 @@ impl Log {
         };
         let (last_index, last_term) = match store.len() {
             0 => (0, 0),
+            index if index == snapshot_index => (snapshot_index, snapshot_term),
             index => store
                 .get(index)?
                 .map(|v| Self::deserialize::<Entry>(&v))
@@ -52,7 +92,15 @@ impl Log {
                 .map(|e| (e.index, e.term))
                 .ok_or_else(|| Error::Internal("Last entry not found".into()))?,
         };
-        Ok(Log { store, last_index, last_term, commit_index, commit_term })
+        Ok(Log {
+            store,
+            last_index,
+            last_term,
+            commit_index,
+            commit_term,
+            snapshot_index,
+            snapshot_term,
+        })
     }
 
     /// Appends a command to the log, returning the entry.
@@ -64,22 +112,89 @@ impl Log {
         Ok(entry)
     }
 
+    /// Flushes appended entries to durable storage.
+    pub fn flush(&mut self) -> Result<()> {
+        self.store.flush()
+    }
+
     /// Commits entries up to and including an index.
     pub fn commit(&mut self, index: u64) -> Result<u64> {
-        let entry = self
-            .get(index)?
+        let term = self
+            .term(index)?
             .ok_or_else(|| Error::Internal(format!("Entry {} not found", index)))?;
         self.store.commit(index)?;
-        self.commit_index = entry.index;
-        self.commit_term = entry.term;
+        self.commit_index = index;
+        self.commit_term = term;
         Ok(index)
     }
 
-    /// Fetches an entry at an index.
+    /// Fetches an entry at an index. Returns None for entries that have been compacted.
     pub fn get(&self, index: u64) -> Result<Option<Entry>> {
         self.store.get(index)?.map(|v| Self::deserialize(&v)).transpose()
     }
 
+    /// Fetches the term of the entry at an index, including the last compacted entry. Returns
+    /// None if the entry does not exist or has been compacted into the snapshot.
+    pub fn term(&self, index: u64) -> Result<Option<u64>> {
+        match index {
+            0 => Ok(Some(0)),
+            i if i == self.snapshot_index => Ok(Some(self.snapshot_term)),
+            i => Ok(self.get(i)?.map(|e| e.term)),
+        }
+    }
+
+    /// Fetches the latest snapshot, if any.
+    pub fn snapshot(&self) -> Result<Option<Snapshot>> {
+        self.store.get_metadata(SNAPSHOT_KEY)?.map(|v| Self::deserialize(&v)).transpose()
+    }
+
+    /// Stores a snapshot taken locally by the state machine, and compacts all entries up to and
+    /// including the snapshot index. The snapshot must only cover committed entries.
+    pub fn compact(&mut self, snapshot: Snapshot) -> Result<u64> {
+        if snapshot.index <= self.snapshot_index {
+            return Ok(self.snapshot_index);
+        }
+        if snapshot.index > self.commit_index {
+            return Err(Error::Internal(format!(
+                "Cannot compact uncommitted index {}",
+                snapshot.index
+            )));
+        }
+        self.store.set_metadata(SNAPSHOT_KEY, Self::serialize(&snapshot)?)?;
+        self.store.compact(snapshot.index)?;
+        self.snapshot_index = snapshot.index;
+        self.snapshot_term = snapshot.term;
+        Ok(snapshot.index)
+    }
+
+    /// Installs a snapshot received from the leader. If the log contains an entry matching the
+    /// snapshot's last index and term, the entries following it are retained; otherwise the whole
+    /// log is discarded. Either way, the snapshot is considered committed.
+    pub fn install(&mut self, snapshot: Snapshot) -> Result<u64> {
+        if snapshot.index <= self.snapshot_index {
+            return Ok(self.snapshot_index);
+        }
+        let retain = snapshot.index < self.last_index
+            && self.term(snapshot.index)? == Some(snapshot.term);
+        self.store.set_metadata(SNAPSHOT_KEY, Self::serialize(&snapshot)?)?;
+        if !retain {
+            // Discards the whole log, including any conflicting entries past the snapshot.
+            if self.last_index > snapshot.index {
+                self.store.truncate(snapshot.index)?;
+            }
+            self.last_index = snapshot.index;
+            self.last_term = snapshot.term;
+        }
+        self.store.compact(snapshot.index)?;
+        if snapshot.index > self.commit_index {
+            self.commit_index = snapshot.index;
+            self.commit_term = snapshot.term;
+        }
+        self.snapshot_index = snapshot.index;
+        self.snapshot_term = snapshot.term;
+        Ok(snapshot.index)
+    }
+
     /// Iterates over log entries
     pub fn scan(&self, range: impl RangeBounds<u64>) -> Scan {
         Box::new(
@@ -104,6 +219,10 @@ impl Log {
             }
         }
         for entry in entries {
+            // Entries covered by the snapshot are committed, and thus known to match.
+            if entry.index <= self.snapshot_index {
+                continue;
+            }
             if let Some(ref current) = self.get(entry.index)? {
                 if current.term == entry.term {
                     continue;
@@ -120,6 +239,7 @@ impl Log {
     pub fn truncate(&mut self, index: u64) -> Result<u64> {
         let (index, term) = match self.store.truncate(index)? {
             0 => (0, 0),
+            i if i == self.snapshot_index => (self.snapshot_index, self.snapshot_term),
             i => self
                 .store
                 .get(i)?
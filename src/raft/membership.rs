@@ -0,0 +1,118 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Result, Error};
+
+/// A Raft cluster configuration. Changes are replicated as log entries, one server at a time,
+/// and take effect on each node as soon as they are appended to its log.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Membership {
+    /// The voting members, which make up the quorum.
+    pub voters: BTreeSet<u64>,
+    /// The non-voting members, which receive the log but do not vote or count towards quorum.
+    pub learners: BTreeSet<u64>,
+    /// The voters and learners that are witnesses. A witness votes and acknowledges appends like
+    /// any other member, but only stores entry headers, does not apply them to a state machine,
+    /// and never leads.
+    pub witnesses: BTreeSet<u64>,
+    /// The Raft addresses of all members.
+    pub addrs: BTreeMap<u64, String>,
+}
+
+impl Membership {
+    /// Creates the initial configuration of a cluster from a map of node IDs to addresses, where
+    /// every peer is a voter.
+    pub fn bootstrap(peers: &BTreeMap<u64, String>) -> Self {
+        Self {
+            voters: peers.keys().copied().collect(),
+            learners: BTreeSet::new(),
+            witnesses: BTreeSet::new(),
+            addrs: peers.clone(),
+        }
+    }
+
+    /// The number of votes or acknowledgements required for a majority of voters.
+    pub fn quorum(&self) -> u64 {
+        self.voters.len() as u64 / 2 + 1
+    }
+
+    /// Checks whether the node is a voter.
+    pub fn is_voter(&self, id: u64) -> bool {
+        self.voters.contains(&id)
+    }
+
+    /// Checks whether the node is a witness.
+    pub fn is_witness(&self, id: u64) -> bool {
+        self.witnesses.contains(&id)
+    }
+
+    /// Checks whether the node may campaign for leadership, i.e. it is a voter but not a witness.
+    pub fn can_lead(&self, id: u64) -> bool {
+        self.is_voter(id) && !self.is_witness(id)
+    }
+
+    /// Checks whether the node is a voter or a learner.
+    pub fn contains(&self, id: u64) -> bool {
+        self.voters.contains(&id) || self.learners.contains(&id)
+    }
+
+    /// Iterates over the IDs of all members, voters and learners alike.
+    pub fn members(&self) -> impl Iterator<Item = u64> + '_ {
+        self.voters.iter().chain(self.learners.iter()).copied()
+    }
+
+    /// Returns a configuration with a new learner.
+    pub fn add_learner(&self, id: u64, addr: String) -> Result<Self> {
+        if self.contains(id) {
+            return Err(Error::Value(format!("Node {} is already a member", id)));
+        }
+        let mut membership = self.clone();
+        membership.learners.insert(id);
+        membership.addrs.insert(id, addr);
+        Ok(membership)
+    }
+
+    /// Returns a configuration with a new witness, which joins as a learner like any other node.
+    pub fn add_witness(&self, id: u64, addr: String) -> Result<Self> {
+        let mut membership = self.add_learner(id, addr)?;
+        membership.witnesses.insert(id);
+        Ok(membership)
+    }
+
+    /// Returns a configuration where a learner has been promoted to voter.
+    pub fn promote(&self, id: u64) -> Result<Self> {
+        let mut membership = self.clone();
+        if !membership.learners.remove(&id) {
+            return Err(Error::Value(format!("Node {} is not a learner", id)));
+        }
+        membership.voters.insert(id);
+        Ok(membership)
+    }
+
+    /// Returns a configuration without the given voter or learner.
+    pub fn remove(&self, id: u64) -> Result<Self> {
+        if !self.contains(id) {
+            return Err(Error::Value(format!("Node {} is not a member", id)));
+        }
+        let mut membership = self.clone();
+        membership.voters.remove(&id);
+        membership.learners.remove(&id);
+        membership.witnesses.remove(&id);
+        membership.addrs.remove(&id);
+        if membership.voters.is_empty() {
+            return Err(Error::Value("Cannot remove the last voter".into()));
+        }
+        Ok(membership)
+    }
+}
+
+impl std::fmt::Display for Membership {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "voters {:?}, learners {:?}", self.voters, self.learners)?;
+        if !self.witnesses.is_empty() {
+            write!(f, ", witnesses {:?}", self.witnesses)?;
+        }
+        Ok(())
+    }
+}
@@ -2,37 +2,135 @@
 #![allow(unused_variables)]
 
 mod client;
+mod clock;
 mod log;
+mod membership;
 mod node;
+mod range;
 mod server;
+#[cfg(any(test, feature = "sim"))]
+mod sim;
 mod state;
+mod status;
+mod transport;
 
 pub use self::client::Client;
+pub use self::clock::{Clock, ManualClock};
 pub use self::node::Node;
-pub use self::log::{Log, Entry};
-pub use self::state::{ApplyMsg, ApplyResult, Driver, State};
-pub use self::server::{Command, FeatherKV, Session, RpcStatus, Task};
+pub use self::log::{Log, Entry, Snapshot};
+pub use self::membership::Membership;
+pub use self::state::{ApplyMsg, ApplyResult, Driver, ReadRequest, Sizer, State, Waiters};
+pub use self::status::{Progress, Status};
+pub use self::server::{Command, Consistency, FeatherKV, RpcStatus};
+#[cfg(any(test, feature = "sim"))]
+pub use self::sim::{Faults, SimNetwork};
+pub use self::range::{RangeDescriptor, RangeTable, SplitOptions};
+pub use self::transport::{GrpcTransport, RaftRouter, Transport};
 
-use crate::error::{Result, Error, RpcResult};
-use crate::proto::raft::{RequestVoteArgs, RequestVoteReply, AppendEntriesArgs};
-use crate::proto::raft::raft_service_client::RaftServiceClient;
+use crate::error::{Result, Error};
+use crate::proto::raft::{RequestVoteArgs, RequestVoteReply, AppendEntriesArgs, InstallSnapshotArgs};
+use crate::server::{deserialize, serialize};
 use crate::storage;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use futures::Future;
 use futures::stream::FuturesUnordered;
-use rand::Rng;
-use tokio::sync::mpsc;
-use tonic::transport::Channel;
+use rand::{Rng, SeedableRng};
+use rand::rngs::SmallRng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, Notify};
+use tokio::time::Instant;
 
-/// The interval between leader heartbeats, in ticks.
+/// The default interval between ticks.
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+/// The default interval between leader heartbeats, in ticks.
 const HEARTBEAT_INTERVAL: u64 = 1;
-/// The minimum election timeout, in ticks.
+/// The default minimum election timeout, in ticks.
 const ELECTION_TIMEOUT_MIN: u64 = 8 * HEARTBEAT_INTERVAL;
-/// The maximum election timeout, in ticks.
+/// The default maximum election timeout, in ticks.
 const ELECTION_TIMEOUT_MAX: u64 = 15 * HEARTBEAT_INTERVAL;
+/// The default interval at which a FeatherKV leader advances the replicated session clock.
+const SESSION_CLOCK_INTERVAL: Duration = Duration::from_secs(1);
+/// The margin subtracted from the minimum election timeout when computing a leader lease, to
+/// account for clock drift between nodes.
+const LEASE_DRIFT_MARGIN: Duration = Duration::from_millis(200);
+/// The maximum size of a snapshot chunk sent in a single InstallSnapshot message, in bytes.
+const SNAPSHOT_CHUNK_SIZE: usize = 64 * 1024;
+/// The maximum size of the entries sent in a single AppendEntries message, in bytes. A single
+/// larger entry is still sent on its own.
+const MAX_APPEND_SIZE: usize = 64 * 1024;
+/// The maximum number of AppendEntries messages in flight to a peer.
+const MAX_INFLIGHT: u64 = 4;
+/// The number of entries a witness applies between compactions of its log. Witnesses have no
+/// state machine to snapshot, so they compact the log on their own.
+const WITNESS_SNAPSHOT_INTERVAL: u64 = 1000;
+
+/// Raft protocol options.
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    /// Whether to run a pre-vote round before campaigning, so that a node only increments its
+    /// term if a quorum would vote for it. Prevents partitioned nodes from disrupting the cluster
+    /// when they rejoin.
+    pub pre_vote: bool,
+    /// Whether a leader steps down if it has not heard from a quorum within an election timeout.
+    pub check_quorum: bool,
+    /// Whether the leader serves reads locally while it holds a lease, instead of confirming its
+    /// leadership with a heartbeat round for every read. A lease lasts for the minimum election
+    /// timeout minus a clock drift margin, from the latest heartbeat acknowledged by a quorum.
+    /// Requires `pre_vote`, since followers only refuse to vote for other candidates while they
+    /// have recently heard from the leader during pre-votes.
+    pub lease_reads: bool,
+    /// The interval between ticks of the node's clock.
+    pub tick_interval: Duration,
+    /// The interval between leader heartbeats, in ticks.
+    pub heartbeat_interval: u64,
+    /// The minimum election timeout, in ticks. Election timeouts are drawn at random between the
+    /// minimum (inclusive) and the maximum (exclusive).
+    pub election_timeout_min: u64,
+    /// The maximum election timeout, in ticks.
+    pub election_timeout_max: u64,
+    /// The interval at which a FeatherKV leader advances the replicated clock that idle client
+    /// sessions expire by.
+    pub session_clock_interval: Duration,
+}
+
+impl Options {
+    /// Checks that the heartbeat interval is below the election timeouts.
+    pub fn validate(&self) -> Result<()> {
+        if self.tick_interval.is_zero() || self.heartbeat_interval == 0 {
+            return Err(Error::Config("The tick and heartbeat intervals must be positive".into()));
+        }
+        if self.heartbeat_interval >= self.election_timeout_min
+            || self.election_timeout_min >= self.election_timeout_max {
+            return Err(Error::Config(format!(
+                "Expected heartbeat interval {} < minimum election timeout {} < maximum election timeout {}",
+                self.heartbeat_interval, self.election_timeout_min, self.election_timeout_max,
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            pre_vote: true,
+            check_quorum: true,
+            lease_reads: false,
+            tick_interval: TICK_INTERVAL,
+            heartbeat_interval: HEARTBEAT_INTERVAL,
+            election_timeout_min: ELECTION_TIMEOUT_MIN,
+            election_timeout_max: ELECTION_TIMEOUT_MAX,
+            session_clock_interval: SESSION_CLOCK_INTERVAL,
+        }
+    }
+}
 
 /// The local Raft node state machine.
+#[allow(clippy::large_enum_variant)] // There is only one per node.
 pub enum Role {
     Follower {
         /// The leader, or None if just initialized.
@@ -41,6 +139,16 @@ pub enum Role {
         leader_seen_ticks: u64,
         /// The timeout before triggering an election.
         leader_seen_timeout: u64,
+        /// When the leader was last heard from, and its commit index at that time.
+        leader_contact: Option<(Instant, u64)>,
+    },
+    /// A follower whose election timeout has elapsed, checking whether a quorum would vote for it
+    /// before becoming a candidate.
+    PreCandidate {
+        /// Ticks elapsed since the pre-vote started.
+        election_ticks: u64,
+        /// Election timeout, in ticks.
+        election_timeout: u64,
     },
     Candidate {
         /// Ticks elapsed since election start.
@@ -57,62 +165,121 @@ pub enum Role {
         next_index: HashMap<u64, u64>,
         /// The last index known to be replicated on a peer.
         match_index: HashMap<u64, u64>,
-        /// The channel to send work to.
-        work_txs: HashMap<u64, mpsc::UnboundedSender<u64>>,
+        /// The number of AppendEntries messages in flight to a peer.
+        inflight: HashMap<u64, u64>,
+        /// The peers whose log position is unknown. They are sent one message at a time, until
+        /// one is accepted.
+        probing: HashSet<u64>,
+        /// The channel to wake up the replicator of a peer.
+        work_txs: HashMap<u64, mpsc::UnboundedSender<()>>,
+        /// The peer that leadership is being transferred to, and the ticks since the transfer
+        /// began. New commands are rejected while a transfer is in progress.
+        transfer: Option<(u64, u64)>,
+        /// The peers that have replied since the last quorum check.
+        recent_active: HashSet<u64>,
+        /// The send time of the latest message acknowledged by each peer, for leases.
+        last_acks: HashMap<u64, Instant>,
+        /// Number of ticks since the last quorum check.
+        check_quorum_ticks: u64,
     },
 }
 
 impl Role {
-    fn init_follower(leader: Option<u64>) -> Role {
+    fn init_follower(leader: Option<u64>, election_timeout: u64) -> Role {
         Role::Follower {
-            leader: None,
+            leader,
             leader_seen_ticks: 0,
-            leader_seen_timeout: rand::thread_rng().gen_range(
-                ELECTION_TIMEOUT_MIN..ELECTION_TIMEOUT_MAX
-            ),
+            leader_seen_timeout: election_timeout,
+            leader_contact: None,
         }
     }
 
-    fn init_candidate() -> Role {
+    fn init_pre_candidate(election_timeout: u64) -> Role {
+        Role::PreCandidate {
+            election_ticks: 0,
+            election_timeout,
+        }
+    }
+
+    fn init_candidate(election_timeout: u64) -> Role {
         Role::Candidate {
             election_ticks: 0,
-            election_timeout: rand::thread_rng().gen_range(
-                ELECTION_TIMEOUT_MIN..ELECTION_TIMEOUT_MAX
-            ),
+            election_timeout,
             votes: 1,
         }
     }
 
     fn init_leader(
         me: u64,
-        num_peers: usize,
+        membership: &Membership,
         last_index: u64,
-        work_txs: HashMap<u64, mpsc::UnboundedSender<u64>>
+        work_txs: HashMap<u64, mpsc::UnboundedSender<()>>
     ) -> Role {
         let mut next_index = HashMap::new();
         let mut match_index = HashMap::new();
-        for i in 0..num_peers as u64 {
-            if i == me {
+        let mut inflight = HashMap::new();
+        let mut probing = HashSet::new();
+        for id in membership.members() {
+            if id == me {
                 continue;
             }
-            next_index.insert(i, last_index + 1);
-            match_index.insert(i, 0);
+            next_index.insert(id, last_index + 1);
+            match_index.insert(id, 0);
+            inflight.insert(id, 0);
+            probing.insert(id);
         };
         Role::Leader {
             heartbeat_ticks: 0,
             next_index,
             match_index,
+            inflight,
+            probing,
             work_txs,
+            transfer: None,
+            recent_active: HashSet::new(),
+            last_acks: HashMap::new(),
+            check_quorum_ticks: 0,
         }
     }
 }
 
+/// The log store metadata key of the node's persistent state.
+const HARD_STATE_KEY: &[u8] = b"hard_state";
+
+/// The state a node must persist before replying to RPCs, so that it never votes twice in a term
+/// or forgets its configuration across restarts. Later configurations are recovered from the
+/// log and snapshot, which are flushed before replying as well.
+#[derive(Debug, Serialize, Deserialize)]
+struct HardState {
+    current_term: u64,
+    voted_for: Option<u64>,
+    bootstrap: Membership,
+}
+
 /// A single Raft node.
 pub struct Raft {
-    peers: Vec<RaftServiceClient<Channel>>,
-    apply_tx: mpsc::UnboundedSender<ApplyMsg>,
+    /// The transport to send messages to the other members on.
+    transport: Arc<dyn Transport>,
+    /// The bounded channel to send committed entries to the state machine on.
+    apply_tx: mpsc::Sender<ApplyMsg>,
+    /// Notified when the commit index advances, to send the new entries to the state machine.
+    commit_notify: Arc<Notify>,
     me: u64,
     // persister
+    opts: Options,
+    /// Whether the node is connected to the network. Used to simulate partitions in tests.
+    connected: Arc<AtomicBool>,
+    /// The source of randomized election timeouts. Seeded for reproducible simulations.
+    rng: SmallRng,
+    /// The clock that drives ticks and times the lease and stale reads.
+    clock: Clock,
+
+    /// The configuration used before any membership entry is appended.
+    bootstrap: Membership,
+    /// The latest configuration in the log, which is in effect regardless of commitment.
+    membership: Membership,
+    /// The index of the entry holding the latest configuration, or 0 for `bootstrap`.
+    membership_index: u64,
 
     /// Persistent state on all servers:
     current_term: u64,
@@ -121,6 +288,7 @@ pub struct Raft {
 
     /// Volatile state on all servers:
     commit_index: u64,
+    /// The index of the last entry sent to the state machine.
     last_applied: u64,
 
     /// Volatile state as different roles:
@@ -139,26 +307,60 @@ impl Raft {
     /// TODO: improve the function signature
     pub fn new(
         me: u64,
-        apply_tx: mpsc::UnboundedSender<ApplyMsg>,
+        bootstrap: Membership,
+        opts: Options,
+        apply_tx: mpsc::Sender<ApplyMsg>,
         log_store: Box<dyn storage::log::LogStore>,
+        transport: Arc<dyn Transport>,
+        seed: Option<u64>,
         // peers: Vec<RaftClient>,
         // persister: Box<dyn Persister>,
     ) -> Result<Raft> {
-        let raft = Raft {
-            peers: vec![],
+        let log = Log::new(log_store)?;
+
+        // Entries covered by a snapshot are committed, so starts from the snapshot if any.
+        let snapshot = log.snapshot()?;
+        let snapshot_index = snapshot.as_ref().map_or(0, |s| s.index);
+
+        let mut rng = match seed {
+            Some(seed) => SmallRng::seed_from_u64(seed),
+            None => SmallRng::from_entropy(),
+        };
+        opts.validate()?;
+        let election_timeout = rng.gen_range(opts.election_timeout_min..opts.election_timeout_max);
+
+        let mut raft = Raft {
+            transport,
             // persister,
             apply_tx,
+            commit_notify: Arc::new(Notify::new()),
             me,
+            opts,
+            connected: Arc::new(AtomicBool::new(true)),
+            rng,
+            clock: Clock::System,
+
+            membership: bootstrap.clone(),
+            membership_index: 0,
+            bootstrap,
 
             current_term: 0,
             voted_for: None,
-            log: Log::new(log_store)?,
+            log,
 
-            commit_index: 0,
-            last_applied: 0,
+            commit_index: snapshot_index,
+            last_applied: snapshot_index,
 
-            role: Role::init_follower(None),
+            role: Role::init_follower(None, election_timeout),
         };
+        raft.restore()?;
+        raft.reload_membership()?;
+        // Witnesses have no state machine to restore.
+        if let Some(Snapshot { index, term, data, .. }) = snapshot {
+            if !raft.membership.is_witness(me) {
+                raft.apply_tx.try_send(ApplyMsg::Snapshot { index, term, data })?;
+            }
+        }
 
         Ok(raft)
     }
@@ -173,7 +375,7 @@ impl Raft {
     pub fn leader_id(&self) -> u64 {
         match self.role {
             Role::Leader { .. } => self.me,
-            Role::Candidate { .. } => self.me,
+            Role::PreCandidate { .. } | Role::Candidate { .. } => self.me,
             Role::Follower { leader, .. } => {
                 match leader {
                     Some(leader) => leader,
@@ -183,134 +385,616 @@ impl Raft {
         }
     }
 
-    /// Saves Raft's persistent state to stable storage,
-    /// where it can later be retrieved after a crash and restart.
-    fn persist(&mut self) {
-        // Your code here (2C).
-        // Example:
-        // labcodec::encode(&self.xxx, &mut data).unwrap();
-        // labcodec::encode(&self.yyy, &mut data).unwrap();
-        // self.persister.save_raft_state(data);
+    /// Saves a new term and vote along with the bootstrap configuration to the log store, and
+    /// flushes it. The node only adopts them once persisted, so that it never acts on a term or
+    /// vote it could forget.
+    fn persist(&mut self, current_term: u64, voted_for: Option<u64>) -> Result<()> {
+        let state = HardState { current_term, voted_for, bootstrap: self.bootstrap.clone() };
+        self.log.store.set_metadata(HARD_STATE_KEY, serialize(&state)?)?;
+        self.log.flush()?;
+        self.current_term = current_term;
+        self.voted_for = voted_for;
+        Ok(())
     }
 
-    /// Restores previously persisted state.
-    fn restore(&mut self, data: &[u8]) {
-        if data.is_empty() {
-            // bootstrap without any state?
+    /// Restores the persisted term, vote and bootstrap configuration, if any. A node starting
+    /// for the first time persists its bootstrap configuration instead, so that it keeps it
+    /// across restarts.
+    fn restore(&mut self) -> Result<()> {
+        match self.log.store.get_metadata(HARD_STATE_KEY)? {
+            Some(bytes) => {
+                let state: HardState = deserialize(&bytes)?;
+                self.current_term = state.current_term;
+                self.voted_for = state.voted_for;
+                self.bootstrap = state.bootstrap;
+                Ok(())
+            }
+            None => self.persist(self.current_term, self.voted_for),
         }
-        // Your code here (2C).
-        // Example:
-        // match labcodec::decode(data) {
-        //     Ok(o) => {
-        //         self.xxx = o.xxx;
-        //         self.yyy = o.yyy;
-        //     }
-        //     Err(e) => {
-        //         panic!("{:?}", e);
-        //     }
-        // }
     }
 
     fn start(&mut self, command: Command) -> Result<(u64, u64)> {
-        let index = self.log.last_index + 1;
+        Ok(self.start_batch(vec![command])?[0])
+    }
+
+    /// Appends a batch of commands to the log as the leader, flushing the log and waking up the
+    /// replicators once for the whole batch. Returns the index and term of each command.
+    fn start_batch(&mut self, commands: Vec<Command>) -> Result<Vec<(u64, u64)>> {
         let term = self.current_term;
-        self.log.append(term, command.clone())?;
+        let work_txs = match self.role {
+            Role::Leader { transfer: Some((id, _)), .. } => {
+                println!("Node {} rejecting command during leadership transfer to {}", self.me, id);
+                return Err(Error::NotLeader);
+            }
+            Role::Leader { ref work_txs, .. } => work_txs.values().cloned().collect::<Vec<_>>(),
+            _ => return Err(Error::Internal(format!("{} is not leader", self.me))),
+        };
+        let mut entries = Vec::with_capacity(commands.len());
+        for command in commands {
+            let entry = self.log.append(term, command)?;
+            entries.push((entry.index, entry.term));
+        }
+        self.log.flush()?;
 
-        // If there is only one server, commits the log entry and apply it immediately.
-        if self.peers.len() == 1 {
-            self.commit_index = index;
-            self.last_applied = index;
-            self.apply_tx.send(ApplyMsg { log_index: index, command })?;
-            return Ok((index, term));
+        // If this is the only voter, commits the log entries and applies them immediately.
+        self.maybe_commit()?;
+
+        // Wakes up the replicator worker for each peer.
+        for tx in work_txs {
+            tx.send(())?;
         }
 
-        // Sends the log entry to the replicator worker for each peer.
-        for id in 0..self.peers.len() as u64 {
-            if id == self.me {
-                continue;
+        Ok(entries)
+    }
+
+    /// Appends a new configuration to the log as the leader, which takes effect immediately.
+    /// Only one change may be in progress at a time, so the previous one must be committed. A new
+    /// leader must also have committed an entry in its term, since a change appended by a previous
+    /// leader may still be in progress without this leader knowing it is committed. Returns the
+    /// IDs of new members that need a replicator.
+    fn change_membership(&mut self, membership: Membership) -> Result<Vec<u64>> {
+        if !self.is_leader() {
+            return Err(Error::NotLeader);
+        }
+        if self.membership_index > self.commit_index {
+            return Err(Error::Value("A membership change is already in progress".into()));
+        }
+        if !self.committed_in_term()? {
+            return Err(Error::Value("The leader has not committed an entry in its term yet".into()));
+        }
+        let (index, _) = self.start(Command::Membership(membership.clone()))?;
+        self.apply_membership(index, membership)
+    }
+
+    /// Proposes to promote a learner to voter, once it has caught up with the commit index and
+    /// a membership change may be made, as for [`Raft::change_membership`].
+    fn maybe_promote(&mut self, id: u64) -> Result<()> {
+        if !self.membership.learners.contains(&id) || self.membership_index > self.commit_index {
+            return Ok(());
+        }
+        if !self.committed_in_term()? {
+            return Ok(());
+        }
+        if let Role::Leader { ref match_index, transfer: None, .. } = self.role {
+            if match_index.get(&id).copied().unwrap_or(0) >= self.commit_index {
+                println!("Promoting caught-up learner {} to voter", id);
+                let membership = self.membership.promote(id)?;
+                self.change_membership(membership)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if an entry in the current term has been committed, e.g. the leader's
+    /// term-start noop.
+    fn committed_in_term(&self) -> Result<bool> {
+        Ok(self.log.term(self.commit_index)? == Some(self.current_term))
+    }
+
+    /// Starts transferring leadership to a voter. Returns the transferee's replication channel
+    /// if it is already caught up, in which case the caller should send it a TimeoutNow;
+    /// otherwise the replicator sends it once the transferee has caught up.
+    fn transfer_leadership(&mut self, id: u64) -> Result<Option<mpsc::UnboundedSender<()>>> {
+        if id == self.me {
+            return Err(Error::Value(format!("Node {} is already the leader", id)));
+        }
+        if !self.membership.is_voter(id) {
+            return Err(Error::Value(format!("Node {} is not a voter", id)));
+        }
+        if self.membership.is_witness(id) {
+            return Err(Error::Value(format!("Node {} is a witness", id)));
+        }
+        let last_index = self.log.last_index;
+        match self.role {
+            Role::Leader { ref mut transfer, ref match_index, ref work_txs, .. } => {
+                if let Some((transferee, _)) = transfer {
+                    return Err(Error::Value(format!(
+                        "Leadership transfer to {} is already in progress", transferee,
+                    )));
+                }
+                println!("Node {} transferring leadership to {}", self.me, id);
+                *transfer = Some((id, 0));
+                let work_tx = work_txs.get(&id).cloned()
+                    .ok_or_else(|| Error::Internal(format!("No replicator for node {}", id)))?;
+                if match_index.get(&id) == Some(&last_index) {
+                    Ok(Some(work_tx))
+                } else {
+                    work_tx.send(())?;
+                    Ok(None)
+                }
+            }
+            _ => Err(Error::NotLeader),
+        }
+    }
+
+    /// Checks whether a peer is the target of a leadership transfer and has caught up with the
+    /// whole log, i.e. it is ready to receive a TimeoutNow.
+    fn transfer_ready(&self, id: u64) -> bool {
+        match self.role {
+            Role::Leader { transfer: Some((transferee, _)), ref match_index, .. } => {
+                transferee == id && match_index.get(&id) == Some(&self.log.last_index)
             }
-            if let Role::Leader { ref work_txs, .. } = self.role {
-                let tx = work_txs.get(&id).unwrap();
-                tx.send(index)?;
-            } else {
-                return Err(Error::Internal(format!("{} is not leader", self.me)));
+            _ => false,
+        }
+    }
+
+    /// Makes a configuration the current one, connecting to any new members. For leaders, starts
+    /// tracking the progress of new members and stops tracking removed ones. Returns the IDs of
+    /// new members that need a replicator.
+    fn apply_membership(&mut self, index: u64, membership: Membership) -> Result<Vec<u64>> {
+        let addrs = membership.addrs.iter()
+            .filter(|(id, _)| **id != self.me)
+            .map(|(id, addr)| (*id, addr.clone()))
+            .collect();
+        self.transport.set_peers(&addrs)?;
+
+        let mut added = vec![];
+        if let Role::Leader {
+            ref mut next_index, ref mut match_index, ref mut inflight, ref mut probing,
+            ref mut work_txs, ref mut transfer, ..
+        } = self.role {
+            if transfer.is_some_and(|(id, _)| !membership.is_voter(id)) {
+                *transfer = None;
+            }
+            next_index.retain(|id, _| membership.contains(*id));
+            match_index.retain(|id, _| membership.contains(*id));
+            inflight.retain(|id, _| membership.contains(*id));
+            probing.retain(|id| membership.contains(*id));
+            work_txs.retain(|id, _| membership.contains(*id));
+            for id in membership.members() {
+                if id != self.me && !next_index.contains_key(&id) {
+                    next_index.insert(id, self.log.last_index + 1);
+                    match_index.insert(id, 0);
+                    inflight.insert(id, 0);
+                    probing.insert(id);
+                    added.push(id);
+                }
             }
         }
-        
-        Ok((index, term))
+
+        if membership != self.membership {
+            println!("Node {} using membership {} from index {}", self.me, membership, index);
+        }
+        self.membership = membership;
+        self.membership_index = index;
+        Ok(added)
+    }
+
+    /// Finds the configuration in effect at an index: the latest one in the log up to the index,
+    /// or in the snapshot, or the bootstrap configuration. Returns it with its entry index.
+    fn membership_at(&self, index: u64) -> Result<(u64, Membership)> {
+        let mut latest = None;
+        for entry in self.log.scan((self.log.snapshot_index + 1)..=index) {
+            if let Entry { index, command: Command::Membership(membership), .. } = entry? {
+                latest = Some((index, membership));
+            }
+        }
+        if let Some(latest) = latest {
+            return Ok(latest);
+        }
+        match self.log.snapshot()? {
+            Some(snapshot) if snapshot.index <= index && snapshot.membership.is_some() => {
+                Ok((snapshot.index, snapshot.membership.unwrap()))
+            }
+            _ => Ok((0, self.bootstrap.clone())),
+        }
+    }
+
+    /// Reloads the configuration from the log, e.g. after appending or truncating entries.
+    fn reload_membership(&mut self) -> Result<Vec<u64>> {
+        let (index, membership) = self.membership_at(self.log.last_index)?;
+        self.apply_membership(index, membership)
+    }
+
+    /// Advances the commit index to the highest index replicated on a quorum of voters, if the
+    /// entry is from the current term. A leader that has been removed from the voters steps down once its removal is
+    /// committed.
+    fn maybe_commit(&mut self) -> Result<()> {
+        let mut match_indexes = match self.role {
+            Role::Leader { ref match_index, .. } => self.membership.voters.iter()
+                .filter(|id| **id != self.me)
+                .map(|id| match_index.get(id).copied().unwrap_or(0))
+                .collect::<Vec<_>>(),
+            _ => return Ok(()),
+        };
+        if self.membership.is_voter(self.me) {
+            match_indexes.push(self.log.last_index);
+        }
+        if match_indexes.is_empty() {
+            return Ok(());
+        }
+        match_indexes.sort_unstable_by(|a, b| b.cmp(a));
+        let quorum_index = match_indexes[self.membership.quorum() as usize - 1];
+
+        if quorum_index > self.commit_index
+            && self.log.term(quorum_index)? == Some(self.current_term)
+        {
+            self.commit(quorum_index)?;
+        }
+
+        if !self.membership.is_voter(self.me) && self.membership_index <= self.commit_index {
+            println!("Node {} removed from voters, stepping down", self.me);
+            self.become_follower(self.current_term, None)?;
+        }
+        Ok(())
+    }
+
+    /// Advances the commit index, and wakes up the dispatcher to send the newly committed entries
+    /// to the state machine.
+    fn commit(&mut self, index: u64) -> Result<()> {
+        self.commit_index = index;
+        self.log.commit(index)?;
+        self.commit_notify.notify_one();
+        Ok(())
+    }
+
+    /// Returns the number of committed entries waiting to be sent to the state machine.
+    fn commit_backlog(&self) -> u64 {
+        self.commit_index - self.last_applied
+    }
+
+    /// Returns the number of committed entries not yet applied by the state machine: those
+    /// waiting to be sent to it, and those queued in the apply channel.
+    fn apply_lag(&self) -> u64 {
+        let queued = self.apply_tx.max_capacity() - self.apply_tx.capacity();
+        self.commit_backlog() + queued as u64
+    }
+
+    /// Returns a snapshot of the node's state, including the peers' replication progress if the
+    /// node is the leader.
+    fn status(&self) -> Status {
+        let (role, progress) = match self.role {
+            Role::Leader { ref next_index, ref match_index, ref last_acks, .. } => {
+                let now = self.clock.now();
+                let progress = next_index.iter()
+                    .map(|(&id, &next_index)| (id, Progress {
+                        next_index,
+                        match_index: match_index.get(&id).copied().unwrap_or(0),
+                        last_contact: last_acks.get(&id).map(|&ack| now.saturating_duration_since(ack)),
+                    }))
+                    .collect();
+                ("leader", progress)
+            },
+            Role::Follower { .. } if self.membership.is_witness(self.me) => ("witness", BTreeMap::new()),
+            Role::Follower { .. } => ("follower", BTreeMap::new()),
+            Role::PreCandidate { .. } => ("pre-candidate", BTreeMap::new()),
+            Role::Candidate { .. } => ("candidate", BTreeMap::new()),
+        };
+        Status {
+            id: self.me,
+            role: role.to_string(),
+            term: self.current_term,
+            leader: self.leader_id(),
+            commit_index: self.commit_index,
+            applied_index: self.last_applied,
+            last_index: self.log.last_index,
+            snapshot_index: self.log.snapshot_index,
+            log_size: self.log.store.size(),
+            progress,
+        }
+    }
+
+    /// Compacts the log up to and including an applied index, replacing the entries with a
+    /// snapshot of the state machine taken at that index.
+    fn compact(&mut self, index: u64, data: Vec<u8>) -> Result<()> {
+        if index > self.last_applied {
+            return Err(Error::Internal(format!("Cannot snapshot unapplied index {}", index)));
+        }
+        let term = self.log.term(index)?
+            .ok_or_else(|| Error::Internal(format!("Entry {} not found", index)))?;
+        let (_, membership) = self.membership_at(index)?;
+        self.log.compact(Snapshot { index, term, data, membership: Some(membership) })?;
+        Ok(())
     }
 }
 
 /// State transition functions.
 impl Raft {
     fn quorum(&self) -> u64 {
-        self.peers.len() as u64 / 2 + 1
+        self.membership.quorum()
     }
 
-    pub fn become_follower(&mut self, term: u64, leader_id: Option<u64>) {
-        self.current_term = term;
-        self.voted_for = None;
-        self.role = Role::init_follower(leader_id);
-        self.persist();
+    pub fn become_follower(&mut self, term: u64, leader_id: Option<u64>) -> Result<()> {
+        self.role = Role::init_follower(leader_id, self.election_timeout());
+        // The vote is only reset in a new term, since a node must vote at most once per term.
+        if term > self.current_term {
+            self.persist(term, None)?;
+        }
+        Ok(())
+    }
+
+    pub fn become_pre_candidate(&mut self) {
+        self.role = Role::init_pre_candidate(self.election_timeout());
     }
 
-    pub fn become_candidate(&mut self) {
-        self.current_term += 1;
-        self.role = Role::init_candidate();
-        self.voted_for = Some(self.me);
-        self.persist();
+    pub fn become_candidate(&mut self) -> Result<()> {
+        self.persist(self.current_term + 1, Some(self.me))?;
+        self.role = Role::init_candidate(self.election_timeout());
+        Ok(())
     }
 
-    pub fn become_leader(&mut self, work_txs: HashMap<u64, mpsc::UnboundedSender<u64>>) {
+    /// Draws a randomized election timeout, in ticks.
+    fn election_timeout(&mut self) -> u64 {
+        self.rng.gen_range(self.opts.election_timeout_min..self.opts.election_timeout_max)
+    }
+
+    pub fn become_leader(&mut self, work_txs: HashMap<u64, mpsc::UnboundedSender<()>>) {
         self.role = Role::init_leader(
             self.me,
-            self.peers.len(), 
+            &self.membership,
             self.log.last_index,
             work_txs,
         );
-        self.persist();
     }
 
-    /// Solicits votes from other nodes.
-    pub fn solicit_votes(&self) -> 
-        FuturesUnordered<impl Future<Output = RpcResult<RequestVoteReply>>> {
+    /// Solicits votes from other voters. A pre-vote asks for votes in the next term, without
+    /// changing the term of either node.
+    pub fn solicit_votes(&self, pre_vote: bool) ->
+        FuturesUnordered<impl Future<Output = Result<RequestVoteReply>>> {
         let futures = FuturesUnordered::new();
-        for i in 0..self.peers.len() {
-            if i as u64 == self.me {
-                continue;
-            }
-            let mut client = self.peers[i].clone();
+        for id in self.membership.voters.iter() {
+            let transport = match self.peer(*id) {
+                Some(transport) => transport,
+                None => continue,
+            };
             let args = RequestVoteArgs {
-                term: self.current_term,
+                term: if pre_vote { self.current_term + 1 } else { self.current_term },
                 candidate_id: self.me,
-                last_log_index: 0,
-                last_log_term: 0,
+                last_log_index: self.log.last_index,
+                last_log_term: self.log.last_term,
+                pre_vote,
             };
+            let id = *id;
             futures.push(async move {
-                client.request_vote(args).await
+                transport.request_vote(id, args).await
             });
         }
         futures
     }
 
-    /// Sends heartbeats to other nodes.
-    pub fn send_heartbeats(&self) {
-        for i in 0..self.peers.len() {
-            if i as u64 == self.me {
-                continue;
-            }
-            let mut client = self.peers[i].clone();
-            let args = AppendEntriesArgs {
-                term: self.current_term,
-                leader_id: self.me,
-                prev_log_index: 0,
-                prev_log_term: 0,
-                entries: vec![],
-                leader_commit: self.commit_index,
+    /// Splits a snapshot into InstallSnapshot messages for the current term. There is always at
+    /// least one message, and the last one is marked as done and carries the configuration.
+    pub fn snapshot_chunks(&self, snapshot: Snapshot) -> Result<Vec<InstallSnapshotArgs>> {
+        let Snapshot { index, term, data, membership } = snapshot;
+        let membership = match membership {
+            Some(membership) => bincode::serialize(&membership)?,
+            None => vec![],
+        };
+        let chunk = |offset: usize, data: &[u8], done: bool| InstallSnapshotArgs {
+            term: self.current_term,
+            leader_id: self.me,
+            last_included_index: index,
+            last_included_term: term,
+            offset: offset as u64,
+            data: data.to_vec(),
+            done,
+            membership: if done { membership.clone() } else { vec![] },
+        };
+        let count = data.len().div_ceil(SNAPSHOT_CHUNK_SIZE).max(1);
+        Ok((0..count)
+            .map(|i| {
+                let start = i * SNAPSHOT_CHUNK_SIZE;
+                let end = (start + SNAPSHOT_CHUNK_SIZE).min(data.len());
+                chunk(start, &data[start..end], i == count - 1)
+            })
+            .collect())
+    }
+
+    /// Builds an empty AppendEntries request for a peer, anchored at the last index known to be
+    /// replicated on it, so that the peer can safely commit up to that index.
+    fn heartbeat_args(&self, id: u64) -> Result<AppendEntriesArgs> {
+        let matched = match self.role {
+            Role::Leader { ref match_index, .. } => match_index.get(&id).copied().unwrap_or(0),
+            _ => 0,
+        };
+        // Falls back to the start of the log if the matched entry has been compacted.
+        let (prev_log_index, prev_log_term) = match self.log.term(matched)? {
+            Some(term) => (matched, term),
+            None => (0, 0),
+        };
+        Ok(AppendEntriesArgs {
+            term: self.current_term,
+            leader_id: self.me,
+            prev_log_index,
+            prev_log_term,
+            entries: vec![],
+            leader_commit: self.commit_index,
+        })
+    }
+
+    /// Builds an AppendEntries request for a peer carrying entries from `next_index` onwards, up
+    /// to `MAX_APPEND_SIZE` bytes. The request is empty if `next_index` follows the log. Returns
+    /// None if `next_index` is past that, or if the entry preceding it has been compacted and the
+    /// peer needs a snapshot instead. Witnesses are only sent the entries' headers.
+    fn append_args(&self, id: u64, next_index: u64) -> Result<Option<AppendEntriesArgs>> {
+        if next_index > self.log.last_index + 1 {
+            return Ok(None);
+        }
+        let prev_log_index = next_index - 1;
+        let prev_log_term = match self.log.term(prev_log_index)? {
+            Some(term) => term,
+            None => return Ok(None),
+        };
+        let mut entries = vec![];
+        let mut size = 0;
+        let witness = self.membership.is_witness(id);
+        for entry in self.log.scan(next_index..=self.log.last_index) {
+            let entry = match witness {
+                true => serialize(&entry?.header())?,
+                false => serialize(&entry?)?,
             };
-            tokio::spawn(async move {
-                client.append_entries(args).await
-            });
+            if !entries.is_empty() && size + entry.len() > MAX_APPEND_SIZE {
+                break;
+            }
+            size += entry.len();
+            entries.push(entry);
+        }
+        Ok(Some(AppendEntriesArgs {
+            term: self.current_term,
+            leader_id: self.me,
+            prev_log_index,
+            prev_log_term,
+            entries,
+            leader_commit: self.commit_index,
+        }))
+    }
+
+    /// Returns a hint for the leader when this node rejects entries following `prev_log_index`:
+    /// the conflicting term and the first index of that term in the log, or term 0 and the index
+    /// following the log if the log is too short.
+    fn conflict_hint(&self, prev_log_index: u64) -> Result<(u64, u64)> {
+        let conflict_term = match self.log.term(prev_log_index)? {
+            Some(term) => term,
+            None => return Ok((self.log.last_index + 1, 0)),
+        };
+        let mut conflict_index = prev_log_index;
+        while conflict_index - 1 > self.log.snapshot_index
+            && self.log.term(conflict_index - 1)? == Some(conflict_term) {
+            conflict_index -= 1;
+        }
+        Ok((conflict_index, conflict_term))
+    }
+
+    /// Returns the next index to send to a peer that rejected entries following
+    /// `prev_log_index`, skipping a whole conflicting term at a time using the peer's hint. If
+    /// this node has entries in the conflicting term, resumes after its last one; otherwise
+    /// resumes at the first index of the term in the peer's log.
+    fn backtrack(&self, prev_log_index: u64, conflict_index: u64, conflict_term: u64) -> Result<u64> {
+        if conflict_term > 0 {
+            let mut index = prev_log_index.min(self.log.last_index);
+            while index > self.log.snapshot_index {
+                match self.log.term(index)? {
+                    Some(term) if term == conflict_term => return Ok(index + 1),
+                    Some(term) if term < conflict_term => break,
+                    _ => index -= 1,
+                }
+            }
+        }
+        Ok(conflict_index.min(prev_log_index).max(1))
+    }
+
+    /// Returns the transport to send messages to a peer on, or None if the peer is not a member
+    /// or the node is disconnected.
+    fn peer(&self, id: u64) -> Option<Arc<dyn Transport>> {
+        if id == self.me || !self.membership.contains(id) || !self.connected.load(Ordering::SeqCst) {
+            return None;
+        }
+        Some(self.transport.clone())
+    }
+
+    /// Checks whether a candidate's log is at least as up-to-date as this node's log.
+    fn log_up_to_date(&self, last_log_index: u64, last_log_term: u64) -> bool {
+        last_log_term > self.log.last_term
+            || (last_log_term == self.log.last_term && last_log_index >= self.log.last_index)
+    }
+
+    /// Checks whether this node has recently heard from a leader, or is the leader itself. Such a
+    /// node rejects pre-votes, so that a node cannot disrupt a healthy leader.
+    fn leader_recently_seen(&self) -> bool {
+        match self.role {
+            Role::Leader { .. } => true,
+            Role::Follower { leader: Some(_), leader_seen_ticks, .. } => {
+                leader_seen_ticks < self.opts.election_timeout_min
+            }
+            _ => false,
+        }
+    }
+
+    /// Records that a peer has replied to a message sent by the leader at `sent`, for the quorum
+    /// check and the lease.
+    fn record_ack(&mut self, id: u64, sent: Instant) {
+        if let Role::Leader { ref mut recent_active, ref mut last_acks, .. } = self.role {
+            recent_active.insert(id);
+            let last_ack = last_acks.entry(id).or_insert(sent);
+            *last_ack = (*last_ack).max(sent);
+        }
+    }
+
+    /// Checks whether the leader holds a lease, during which no other leader can be elected, so
+    /// that it can serve reads locally. The lease starts when the latest message acknowledged by a
+    /// quorum was sent. There is no lease during a leadership transfer, since the transferee
+    /// campaigns without a pre-vote, or before the leader has committed an entry in its term.
+    fn has_lease(&self) -> bool {
+        if !self.opts.lease_reads || !self.opts.pre_vote {
+            return false;
+        }
+        if !matches!(self.role, Role::Leader { transfer: None, .. }) {
+            return false;
+        }
+        if self.log.term(self.commit_index).ok().flatten() != Some(self.current_term) {
+            return false;
+        }
+        let lease_duration = (self.opts.tick_interval * self.opts.election_timeout_min as u32)
+            .saturating_sub(LEASE_DRIFT_MARGIN);
+        self.quorum_ack().is_some_and(|ack| self.clock.now() < ack + lease_duration)
+    }
+
+    /// Returns the latest time by which a quorum of voters had acknowledged the leader, or None
+    /// if this node is not the leader or no quorum has acknowledged it yet.
+    fn quorum_ack(&self) -> Option<Instant> {
+        let last_acks = match self.role {
+            Role::Leader { ref last_acks, .. } => last_acks,
+            _ => return None,
+        };
+        let now = self.clock.now();
+        let mut acks = self.membership.voters.iter()
+            .filter_map(|id| if *id == self.me { Some(now) } else { last_acks.get(id).copied() })
+            .collect::<Vec<_>>();
+        let quorum = self.quorum() as usize;
+        if acks.len() < quorum {
+            return None;
+        }
+        acks.sort_unstable_by(|a, b| b.cmp(a));
+        Some(acks[quorum - 1])
+    }
+
+    /// Returns an index that reflects all entries committed more than `max_staleness` ago, or
+    /// None if this node cannot tell. A leader uses its commit index while a quorum has recently
+    /// acknowledged it; a follower uses the commit index of its last contact with the leader.
+    fn fresh_index(&self, max_staleness: Duration) -> Option<u64> {
+        let (contact, index) = match self.role {
+            Role::Leader { .. } => (self.quorum_ack()?, self.commit_index),
+            Role::Follower { leader_contact, .. } => leader_contact?,
+            _ => return None,
+        };
+        (self.clock.now().saturating_duration_since(contact) <= max_staleness).then_some(index)
+    }
+
+    /// Checks whether the leader has heard from a quorum of voters since the last check, and
+    /// resets the check.
+    fn check_quorum(&mut self) -> bool {
+        let me = self.me;
+        let quorum = self.quorum() as usize;
+        let voters = &self.membership.voters;
+        match self.role {
+            Role::Leader { ref mut recent_active, .. } => {
+                let active = voters.iter()
+                    .filter(|id| **id == me || recent_active.contains(id))
+                    .count();
+                recent_active.clear();
+                active >= quorum
+            }
+            _ => true,
         }
     }
 }
\ No newline at end of file
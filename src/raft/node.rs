@@ -1,123 +1,352 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
 use std::time::Duration;
 
 use futures::{stream::FuturesUnordered, Future};
-use rand::Rng;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot, Notify};
 use tokio_stream::StreamExt;
-use tonic::transport::Server;
-use tonic::{Response, Status, Request};
+use tokio_util::sync::CancellationToken;
+use tonic::{Response, Status, Request, Streaming};
 
 use crate::error::{Result, Error, RpcResult};
-use crate::proto::raft::raft_service_client::RaftServiceClient;
-use crate::proto::raft::raft_service_server::{RaftService, RaftServiceServer};
+use crate::proto::raft::raft_service_server::RaftService;
 use crate::proto::raft::{RequestVoteReply, RequestVoteArgs, AppendEntriesArgs, AppendEntriesReply};
-use crate::server::{deserialize, serialize};
+use crate::proto::raft::{InstallSnapshotArgs, InstallSnapshotReply, TimeoutNowArgs, TimeoutNowReply};
+use crate::server::deserialize;
 use crate::storage::log::LogStore;
-use super::{HEARTBEAT_INTERVAL, Raft, Role, ApplyMsg, Command, Entry};
+use super::{Clock, MAX_INFLIGHT, WITNESS_SNAPSHOT_INTERVAL, Raft, Role, ApplyMsg, Command, Entry, Snapshot, Membership, Options, Transport, GrpcTransport, RaftRouter};
+#[cfg(any(test, feature = "sim"))]
+use super::SimNetwork;
 
-// An interceptor function. TODO: use layer instead.
-fn intercept(req: Request<()>) -> core::result::Result<Request<()>, Status> {
-    let mut rng = rand::thread_rng();
-    if rng.gen_bool(0.0) {
-        Err(Status::unavailable("RPC got intercepted."))
-    } else {
-        Ok(req)
-    }
+/// The window during which proposed commands are coalesced into a single log append.
+const PROPOSAL_WINDOW: Duration = Duration::from_millis(1);
+/// The maximum number of proposed commands coalesced into a single log append.
+const MAX_PROPOSAL_BATCH: usize = 256;
+/// The number of committed entries that may wait to be sent to the state machine before the leader
+/// holds back proposals. Beyond the apply channel's capacity, entries wait in the log.
+const MAX_APPLY_BACKLOG: u64 = 1024;
+/// The file in a node's data directory that holds its ID.
+const NODE_ID_FILE: &str = "node_id";
+
+/// A command proposed to the leader, with the channel to send its index and term to.
+struct Proposal {
+    command: Command,
+    reply_tx: oneshot::Sender<Result<(u64, u64)>>,
 }
 
 #[derive(Clone)]
 pub struct Node {
     // Your code here.
     raft: Arc<Mutex<Raft>>,
+    /// The channel to queue proposed commands on.
+    propose_tx: mpsc::UnboundedSender<Proposal>,
+    /// Cancelled to shut the node down.
+    shutdown: CancellationToken,
+    /// Cancelled once the node has shut down and sent all committed entries to the state machine.
+    stopped: CancellationToken,
+    /// Notified when committed entries are sent to the state machine, to resume held back
+    /// proposals.
+    dispatched: Arc<Notify>,
 }
 
 impl Node {
     /// Create a new raft service. TODO: Set up the raft server according to the config.
+    /// The peers, a map of node IDs to Raft addresses, form the initial cluster configuration.
     pub async fn new(
         me: u64,
-        peers: Vec<String>,
-        apply_tx: mpsc::UnboundedSender<ApplyMsg>,
+        peers: BTreeMap<u64, String>,
+        opts: Options,
+        apply_tx: mpsc::Sender<ApplyMsg>,
         log_store: Box<dyn LogStore>,
     ) -> Result<Node> {
-        let node = Node { raft: Arc::new(Mutex::new(Raft::new(
-            me,
-            apply_tx,
-            log_store,
-        )?)) };
-        let node_clone = node.clone();
+        let addr = peers.get(&me)
+            .ok_or_else(|| Error::Config(format!("No address for node {}", me)))?
+            .clone();
+        Self::with_membership(me, addr, Membership::bootstrap(&peers), opts, apply_tx, log_store).await
+    }
 
-        let layer = tower::ServiceBuilder::new()
-            .layer(tonic::service::interceptor(intercept))
-            .into_inner();
+    /// Creates a raft service that joins an existing cluster. The node starts with an empty
+    /// configuration, and waits for the cluster leader to add it via [`Node::add_node`].
+    pub async fn join(
+        me: u64,
+        addr: String,
+        opts: Options,
+        apply_tx: mpsc::Sender<ApplyMsg>,
+        log_store: Box<dyn LogStore>,
+    ) -> Result<Node> {
+        Self::with_membership(me, addr, Membership::default(), opts, apply_tx, log_store).await
+    }
 
-        let my_addr = peers[me as usize].parse()?;
-        tokio::spawn(async move {
-            match Server::builder()
-                .layer(layer)
-                .add_service(RaftServiceServer::new(node_clone))
-                .serve(my_addr)
-                .await {
-                    Ok(_) => { println!("Raft server built on addr {:?}", my_addr) },
-                    Err(err) => println!("Raft server failed on addr {:?}: {:?}", my_addr, err),
-                };
-        });
+    /// Creates a raft node with a bootstrap configuration, and serves its RPCs on the address.
+    async fn with_membership(
+        me: u64,
+        addr: String,
+        bootstrap: Membership,
+        opts: Options,
+        apply_tx: mpsc::Sender<ApplyMsg>,
+        log_store: Box<dyn LogStore>,
+    ) -> Result<Node> {
+        let node = Self::build(me, bootstrap, opts, apply_tx, log_store, Arc::new(GrpcTransport::new()), None)?;
+        let router = RaftRouter::new();
+        router.add(0, node.clone())?;
+        router.serve(&addr, node.shutdown.clone())?;
+        Ok(node)
+    }
 
-        let mut conns = vec![false; peers.len()];
-        'outer: loop {
-            let mut conn_count = 0;
-            for i in 0..peers.len() {
-                if conns[i] {
-                    conn_count += 1;
-                    if conn_count == peers.len() {
-                        break 'outer;
-                    }
-                    continue;
-                }
-                let mut addr = "http://".to_string();
-                addr.push_str(&peers[i]);
-                let client = match RaftServiceClient::connect(addr).await {
-                    Ok(channel) => channel,
-                    Err(_) => { continue; },
-                };
-                let mut raft = node.raft.lock()?;
-                raft.peers.push(client);
-                conns[i] = true;
-            }
-            tokio::time::sleep(Duration::from_millis(1000)).await;
-        }
+    /// Creates a raft node of one of several Raft groups sharing the node's address. Its RPCs are
+    /// served by the router, which the caller serves on the address. If `join` is set, the node
+    /// waits to be added to the group like [`Node::join`], instead of bootstrapping it with `peers`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn grouped(
+        me: u64,
+        group_id: u64,
+        peers: &BTreeMap<u64, String>,
+        join: bool,
+        opts: Options,
+        apply_tx: mpsc::Sender<ApplyMsg>,
+        log_store: Box<dyn LogStore>,
+        router: &RaftRouter,
+    ) -> Result<Node> {
+        let bootstrap = match join {
+            true => Membership::default(),
+            false => Membership::bootstrap(peers),
+        };
+        let transport = Arc::new(GrpcTransport::for_group(group_id));
+        let node = Self::build(me, bootstrap, opts, apply_tx, log_store, transport, None)?;
+        router.add(group_id, node.clone())?;
+        Ok(node)
+    }
+
+    /// Creates a raft node on a simulated network, which delivers RPCs between nodes in-process
+    /// instead of serving them on an address. The cluster consists of nodes 0 to `cluster_size`.
+    #[cfg(any(test, feature = "sim"))]
+    pub fn simulated(
+        me: u64,
+        cluster_size: u64,
+        opts: Options,
+        apply_tx: mpsc::Sender<ApplyMsg>,
+        log_store: Box<dyn LogStore>,
+        network: &SimNetwork,
+    ) -> Result<Node> {
+        let peers = (0..cluster_size).map(|id| (id, format!("sim-{}", id))).collect();
+        let bootstrap = Membership::bootstrap(&peers);
+        let transport = Arc::new(network.transport(me));
+        let seed = network.node_seed(me)?;
+        let node = Self::build(me, bootstrap, opts, apply_tx, log_store, transport, Some(seed))?;
+        network.register(me, node.clone())?;
+        Ok(node)
+    }
 
+    /// Creates a raft node, without serving its RPCs.
+    fn build(
+        me: u64,
+        bootstrap: Membership,
+        opts: Options,
+        apply_tx: mpsc::Sender<ApplyMsg>,
+        log_store: Box<dyn LogStore>,
+        transport: Arc<dyn Transport>,
+        seed: Option<u64>,
+    ) -> Result<Node> {
+        let raft = Raft::new(me, bootstrap, opts, apply_tx.clone(), log_store, transport, seed)?;
+        let commit_notify = raft.commit_notify.clone();
+        let (propose_tx, propose_rx) = mpsc::unbounded_channel();
+        let node = Node {
+            raft: Arc::new(Mutex::new(raft)),
+            propose_tx,
+            shutdown: CancellationToken::new(),
+            stopped: CancellationToken::new(),
+            dispatched: Arc::new(Notify::new()),
+        };
+        tokio::spawn(Self::batch_proposals(node.raft.clone(), propose_rx, node.dispatched.clone()));
+        tokio::spawn(Self::dispatch_applies(
+            node.raft.clone(),
+            apply_tx,
+            commit_notify,
+            node.dispatched.clone(),
+            node.shutdown.clone(),
+            node.stopped.clone(),
+        ));
         Ok(node)
     }
 
-    /// Start the Raft server. This method should not return until shutdown.
+    /// Replaces the node's clock, e.g. with a [`super::ManualClock`] to drive its ticks from a
+    /// test. Must be called before the node is served.
+    pub fn set_clock(&self, clock: Clock) -> Result<()> {
+        self.raft.lock()?.clock = clock;
+        Ok(())
+    }
+
+    /// Start the Raft server, which ticks every `Options::tick_interval` of the node's clock. This
+    /// method returns once the node is shut down via [`Node::shutdown`], or if a tick fails.
     pub async fn serve(self) -> Result<()> {
-        loop {
-            tokio::time::sleep(Duration::from_millis(100)).await;
-            self.tick().unwrap();
+        let (clock, tick_interval) = {
+            let raft = self.raft.lock()?;
+            (raft.clock.clone(), raft.opts.tick_interval)
+        };
+        match clock {
+            Clock::System => loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(tick_interval) => self.tick()?,
+                    _ = self.shutdown.cancelled() => return Ok(()),
+                }
+            },
+            // Catches up on all ticks the clock was advanced by, and waits for it to advance.
+            Clock::Manual(clock) => {
+                let mut advanced = clock.subscribe();
+                let mut next_tick = clock.now() + tick_interval;
+                loop {
+                    while clock.now() >= next_tick {
+                        self.tick()?;
+                        next_tick += tick_interval;
+                    }
+                    tokio::select! {
+                        changed = advanced.changed() => if changed.is_err() {
+                            return Ok(());
+                        },
+                        _ = self.shutdown.cancelled() => return Ok(()),
+                    }
+                }
+            },
         }
     }
 
-    /// The service using Raft (e.g. a k/v server) wants to start
-    /// agreement on the next command to be appended to Raft's log. If this
-    /// server isn't the leader, returns [`Error::NotLeader`]. Otherwise start
-    /// the agreement and return immediately. There is no guarantee that this
-    /// command will ever be committed to the Raft log, since the leader
-    /// may fail or lose an election. Even if the Raft instance has been killed,
-    /// this function should return gracefully.
-    ///
-    /// The first value of the tuple is the index that the command will appear
-    /// at if it's ever committed; the second is the current term.
-    ///
-    /// This method must return without blocking on the raft.
-    pub fn start(&self, command: Command) -> Result<(u64, u64)> {
+    /// Shuts the node down: it steps down, stops ticking, is cut off from its peers, and flushes
+    /// its log. The entries it has already committed are still sent to the state machine, see
+    /// [`Node::stopped`].
+    pub fn shutdown(&self) -> Result<()> {
         let mut raft = self.raft.lock()?;
-        if !raft.is_leader() {
-            return Err(Error::NotLeader);
+        println!("Node {} shutting down", raft.me);
+        self.shutdown.cancel();
+        raft.connected.store(false, Ordering::SeqCst);
+        let term = raft.current_term;
+        raft.become_follower(term, None)?;
+        raft.log.flush()
+    }
+
+    /// Waits until the node has shut down and sent all committed entries to the state machine.
+    pub fn stopped(&self) -> impl Future<Output = ()> {
+        self.stopped.clone().cancelled_owned()
+    }
+
+    /// Proposes a command to the leader, to be appended to the log. Commands proposed within a
+    /// short window are coalesced, so that the whole batch is appended to the log, flushed, and
+    /// replicated together. Returns the index the command will appear at if it is ever
+    /// committed, and the current term, once the command has been appended. There is no
+    /// guarantee that it will be committed, since the leader may fail or lose an election.
+    ///
+    /// If the node isn't the leader, returns [`Error::NotLeader`]. While the state machine falls
+    /// behind, waits for it to catch up before appending the command.
+    pub async fn propose(&self, command: Command) -> Result<(u64, u64)> {
+        if let Command::Membership(_) = command {
+            return Err(Error::Value("Use add_node or remove_node to change membership".into()));
+        }
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.propose_tx.send(Proposal { command, reply_tx })?;
+        reply_rx.await?
+    }
+
+    /// Appends queued proposals to the log in batches.
+    async fn batch_proposals(
+        arc_raft: Arc<Mutex<Raft>>,
+        mut propose_rx: mpsc::UnboundedReceiver<Proposal>,
+        dispatched: Arc<Notify>,
+    ) -> Result<()> {
+        while let Some(proposal) = propose_rx.recv().await {
+            tokio::time::sleep(PROPOSAL_WINDOW).await;
+            let mut proposals = vec![proposal];
+            while proposals.len() < MAX_PROPOSAL_BATCH {
+                match propose_rx.try_recv() {
+                    Ok(proposal) => proposals.push(proposal),
+                    Err(_) => break,
+                }
+            }
+            // Holds back proposals while the state machine falls behind, so that a slow state
+            // machine throttles writers instead of building up a backlog of committed entries.
+            loop {
+                // Registers for the notification before checking, so that none is missed.
+                let notified = dispatched.notified();
+                tokio::pin!(notified);
+                notified.as_mut().enable();
+                if arc_raft.lock()?.commit_backlog() <= MAX_APPLY_BACKLOG {
+                    break;
+                }
+                notified.await;
+            }
+            let (commands, reply_txs): (Vec<_>, Vec<_>) = proposals.into_iter()
+                .map(|p| (p.command, p.reply_tx))
+                .unzip();
+
+            let result = {
+                let mut raft = arc_raft.lock()?;
+                match raft.is_leader() {
+                    true => raft.start_batch(commands),
+                    false => Err(Error::NotLeader),
+                }
+            };
+            match result {
+                Ok(entries) => for (reply_tx, entry) in reply_txs.into_iter().zip(entries) {
+                    let _ = reply_tx.send(Ok(entry));
+                },
+                Err(e) => for reply_tx in reply_txs {
+                    let _ = reply_tx.send(Err(e.clone()));
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends committed entries to the state machine in order, waiting for room in the bounded
+    /// apply channel. Entries the state machine is not ready for yet wait in the log. Once the
+    /// node is shut down, returns after sending the remaining committed entries.
+    async fn dispatch_applies(
+        arc_raft: Arc<Mutex<Raft>>,
+        apply_tx: mpsc::Sender<ApplyMsg>,
+        commit_notify: Arc<Notify>,
+        dispatched: Arc<Notify>,
+        shutdown: CancellationToken,
+        stopped: CancellationToken,
+    ) -> Result<()> {
+        loop {
+            if arc_raft.lock()?.commit_backlog() == 0 {
+                if shutdown.is_cancelled() {
+                    stopped.cancel();
+                    return Ok(());
+                }
+                tokio::select! {
+                    _ = commit_notify.notified() => { },
+                    _ = shutdown.cancelled() => { },
+                }
+                continue;
+            }
+            // Witnesses only store entry headers, so they skip the state machine and compact the
+            // log on their own.
+            {
+                let mut raft = arc_raft.lock()?;
+                if raft.membership.is_witness(raft.me) {
+                    raft.last_applied = raft.commit_index;
+                    dispatched.notify_waiters();
+                    if raft.last_applied >= raft.log.snapshot_index + WITNESS_SNAPSHOT_INTERVAL {
+                        let index = raft.last_applied;
+                        raft.compact(index, vec![])?;
+                    }
+                    continue;
+                }
+            }
+            // Only reserves a slot once there is an entry to send, so that idle slots do not
+            // count as queued.
+            let permit = apply_tx.reserve().await?;
+            let mut raft = arc_raft.lock()?;
+            if raft.last_applied < raft.commit_index {
+                let index = raft.last_applied + 1;
+                let Entry { command, .. } = raft.log.get(index)?
+                    .ok_or_else(|| Error::Internal(format!("Expected entry at index {}", index)))?;
+                permit.send(ApplyMsg::Command { log_index: index, command });
+                raft.last_applied = index;
+                dispatched.notify_waiters();
+            }
         }
-        let (index, term) = raft.start(command)?;
-        Ok((index, term))
     }
 
     /// The current term of this peer.
@@ -125,6 +354,12 @@ impl Node {
         Ok(self.raft.lock()?.current_term)
     }
 
+    /// Whether this peer is a witness, which has no state machine.
+    pub fn is_witness(&self) -> Result<bool> {
+        let raft = self.raft.lock()?;
+        Ok(raft.membership.is_witness(raft.me))
+    }
+
     /// Whether this peer believes it is the leader.
     pub fn is_leader(&self) -> Result<bool> {
         Ok(self.raft.lock()?.is_leader())
@@ -140,44 +375,176 @@ impl Node {
         Ok(self.raft.lock()?.leader_id())
     }
 
+    /// A snapshot of the node's state, including the peers' replication progress if the node is
+    /// the leader.
+    pub fn status(&self) -> Result<super::Status> {
+        Ok(self.raft.lock()?.status())
+    }
+
+    /// The number of committed entries that the state machine has not applied yet.
+    pub fn apply_lag(&self) -> Result<u64> {
+        Ok(self.raft.lock()?.apply_lag())
+    }
+
+    /// The Raft address of the peer that this peer believes is the current leader, or an empty
+    /// string if the leader is not a member of its configuration.
+    pub fn leader_addr(&self) -> Result<String> {
+        let raft = self.raft.lock()?;
+        Ok(raft.membership.addrs.get(&raft.leader_id()).cloned().unwrap_or_default())
+    }
+
+    /// Checks that the node ID is the one persisted in the data directory, persisting it on the
+    /// node's first boot. This prevents a node from rejoining its cluster under another ID, e.g.
+    /// after its configuration was edited.
+    pub fn check_id(data_dir: &Path, me: u64) -> Result<()> {
+        let path = data_dir.join(NODE_ID_FILE);
+        match std::fs::read_to_string(&path) {
+            Ok(id) => {
+                let id = id.trim().parse::<u64>()?;
+                if id != me {
+                    return Err(Error::Config(format!(
+                        "Data directory {} belongs to node {}, not node {}",
+                        data_dir.display(), id, me,
+                    )));
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                std::fs::create_dir_all(data_dir)?;
+                std::fs::write(&path, me.to_string())?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+        Ok(())
+    }
+
+    /// Compacts the Raft log up to and including an applied index, replacing the compacted
+    /// entries with a snapshot of the state machine taken at that index.
+    pub fn compact(&self, index: u64, data: Vec<u8>) -> Result<()> {
+        self.raft.lock()?.compact(index, data)
+    }
+
+    /// Disconnects the node from the network: incoming RPCs are rejected and outgoing RPCs are
+    /// not sent. Used to simulate network partitions.
+    pub fn disconnect(&self) -> Result<()> {
+        self.raft.lock()?.connected.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Reconnects the node to the network after [`Node::disconnect`].
+    pub fn reconnect(&self) -> Result<()> {
+        self.raft.lock()?.connected.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Checks whether the node is connected to the network.
+    pub(super) fn is_connected(&self) -> bool {
+        self.raft.lock().unwrap().connected.load(Ordering::SeqCst)
+    }
+
+    /// The current cluster configuration, which may not be committed yet.
+    pub fn membership(&self) -> Result<Membership> {
+        Ok(self.raft.lock()?.membership.clone())
+    }
+
+    /// The log index of the current cluster configuration, or 0 for the initial one.
+    pub fn membership_index(&self) -> Result<u64> {
+        Ok(self.raft.lock()?.membership_index)
+    }
+
+    /// Adds a node to the cluster as a learner. The leader promotes it to voter once it has caught
+    /// up with the log. Returns [`Error::NotLeader`] if this node is not the leader, and fails if
+    /// another membership change is still in progress.
+    pub fn add_node(&self, id: u64, addr: String) -> Result<()> {
+        let mut raft = self.raft.lock()?;
+        let membership = raft.membership.add_learner(id, addr)?;
+        let added = raft.change_membership(membership)?;
+        Self::start_replicators(&self.raft, &mut raft, added)
+    }
+
+    /// Adds a witness to the cluster, as a learner promoted to voter once it has caught up like
+    /// [`Node::add_node`]. Witnesses count towards quorum, but only store entry headers and never
+    /// lead.
+    pub fn add_witness(&self, id: u64, addr: String) -> Result<()> {
+        let mut raft = self.raft.lock()?;
+        let membership = raft.membership.add_witness(id, addr)?;
+        let added = raft.change_membership(membership)?;
+        Self::start_replicators(&self.raft, &mut raft, added)
+    }
+
+    /// Removes a voter or learner from the cluster. Returns [`Error::NotLeader`] if this node is
+    /// not the leader, and fails if another membership change is still in progress. A leader that
+    /// removes itself steps down once the change is committed.
+    pub fn remove_node(&self, id: u64) -> Result<()> {
+        let mut raft = self.raft.lock()?;
+        let membership = raft.membership.remove(id)?;
+        raft.change_membership(membership)?;
+        Ok(())
+    }
+
     /// Tick the underlying Raft node to the next state.
     pub fn tick(&self) -> Result<()> {
         let mut raft = self.raft.lock()?;
+        let opts = raft.opts;
+        let pre_vote = opts.pre_vote;
 
         match raft.role {
             Role::Follower { ref mut leader_seen_ticks, leader_seen_timeout, .. } => {
                 *leader_seen_ticks += 1;
-                if *leader_seen_ticks >= leader_seen_timeout {
-                    raft.become_candidate();
-                    let request_vote_replies = raft.solicit_votes();
-
-                    let quorum = raft.quorum();
-                    let current_term = raft.current_term;
-                    let raft = self.raft.clone();
-                    tokio::spawn(async move {
-                        Self::count_votes(raft, quorum, current_term, request_vote_replies).await.unwrap();
-                    });
+                // Learners, witnesses and removed nodes never campaign.
+                if *leader_seen_ticks >= leader_seen_timeout && raft.membership.can_lead(raft.me) {
+                    if pre_vote {
+                        Self::pre_campaign(&self.raft, &mut raft);
+                    } else {
+                        Self::campaign(&self.raft, &mut raft)?;
+                    }
+                }
+            }
+            Role::PreCandidate { ref mut election_ticks, election_timeout } => {
+                *election_ticks += 1;
+                if *election_ticks >= election_timeout && raft.membership.can_lead(raft.me) {
+                    Self::pre_campaign(&self.raft, &mut raft);
                 }
             }
             Role::Candidate {ref mut election_ticks, election_timeout, .. } => {
                 *election_ticks += 1;
-                if *election_ticks >= election_timeout {
-                    raft.become_candidate();
-                    let request_vote_replies = raft.solicit_votes();
-
-                    let quorum = raft.quorum();
-                    let current_term = raft.current_term;
-                    let raft = self.raft.clone();
-                    tokio::spawn(async move {
-                        Self::count_votes(raft, quorum, current_term, request_vote_replies).await.unwrap();
-                    });
+                // A candidate whose election timed out, e.g. because it was partitioned after
+                // winning a pre-vote, must win another pre-vote before incrementing its term again.
+                if *election_ticks >= election_timeout && raft.membership.can_lead(raft.me) {
+                    if pre_vote {
+                        Self::pre_campaign(&self.raft, &mut raft);
+                    } else {
+                        Self::campaign(&self.raft, &mut raft)?;
+                    }
                 }
             }
-            Role::Leader { ref mut heartbeat_ticks, .. } => {
-            *heartbeat_ticks += 1;
-                if *heartbeat_ticks >= HEARTBEAT_INTERVAL {
+            Role::Leader { ref mut heartbeat_ticks, ref mut transfer, ref mut check_quorum_ticks, .. } => {
+                // Aborts a leadership transfer that has not completed within an election timeout.
+                if let Some((id, ref mut ticks)) = transfer {
+                    *ticks += 1;
+                    if *ticks >= opts.election_timeout_max {
+                        println!("Leadership transfer to {} timed out", id);
+                        *transfer = None;
+                    }
+                }
+
+                *check_quorum_ticks += 1;
+                let check_quorum = *check_quorum_ticks >= opts.election_timeout_max;
+                if check_quorum {
+                    *check_quorum_ticks = 0;
+                }
+                *heartbeat_ticks += 1;
+                let heartbeat = *heartbeat_ticks >= opts.heartbeat_interval;
+                if heartbeat {
                     *heartbeat_ticks = 0;
-                    raft.send_heartbeats();
+                }
+
+                // Steps down if a quorum has not replied within an election timeout.
+                if check_quorum && !raft.check_quorum() && raft.opts.check_quorum {
+                    println!("Node {} lost contact with quorum, stepping down", raft.me);
+                    let term = raft.current_term;
+                    raft.become_follower(term, None)?;
+                } else if heartbeat {
+                    Self::send_heartbeats(&self.raft, &raft)?;
                 }
             }
         }
@@ -185,153 +552,528 @@ impl Node {
         Ok(())
     }
 
-    /// Counts the number of votes for a candidate.
+    /// Starts a pre-vote, and campaigns in the background if a quorum would grant its vote.
+    fn pre_campaign(arc_raft: &Arc<Mutex<Raft>>, raft: &mut Raft) {
+        raft.become_pre_candidate();
+        let request_vote_replies = raft.solicit_votes(true);
+
+        let (me, quorum, current_term) = (raft.me, raft.quorum(), raft.current_term);
+        let raft = arc_raft.clone();
+        tokio::spawn(async move {
+            // Errors, e.g. while shutting down, end the election; the node campaigns again later.
+            if let Err(err) = Self::count_votes(raft, quorum, current_term, true, request_vote_replies).await {
+                println!("Node {} stopped counting votes in term {}: {:?}", me, current_term, err);
+            }
+        });
+    }
+
+    /// Starts a new election, and counts the votes in the background.
+    fn campaign(arc_raft: &Arc<Mutex<Raft>>, raft: &mut Raft) -> Result<()> {
+        raft.become_candidate()?;
+        let request_vote_replies = raft.solicit_votes(false);
+
+        let (me, quorum, current_term) = (raft.me, raft.quorum(), raft.current_term);
+        let raft = arc_raft.clone();
+        tokio::spawn(async move {
+            // Errors, e.g. while shutting down, end the election; the node campaigns again later.
+            if let Err(err) = Self::count_votes(raft, quorum, current_term, false, request_vote_replies).await {
+                println!("Node {} stopped counting votes in term {}: {:?}", me, current_term, err);
+            }
+        });
+        Ok(())
+    }
+
+    /// Steps down on a reply from a later term. Replies are handled in the background, so a
+    /// failure to persist the term is only logged, and the node keeps its current term.
+    fn step_down(raft: &mut Raft, term: u64) {
+        if let Err(err) = raft.become_follower(term, None) {
+            println!("Node {} failed to persist term {}: {:?}", raft.me, term, err);
+        }
+    }
+
+    /// Sends heartbeats to all other members, recording which peers reply.
+    fn send_heartbeats(arc_raft: &Arc<Mutex<Raft>>, raft: &Raft) -> Result<()> {
+        // Wakes up the replicators of lagging peers, retrying any failed replication.
+        if let Role::Leader { ref match_index, ref work_txs, .. } = raft.role {
+            for (id, work_tx) in work_txs {
+                if match_index.get(id).is_some_and(|index| *index < raft.log.last_index) {
+                    work_tx.send(())?;
+                }
+            }
+        }
+        for id in raft.membership.members() {
+            let transport = match raft.peer(id) {
+                Some(transport) => transport,
+                None => continue,
+            };
+            let args = raft.heartbeat_args(id)?;
+            let arc_raft = arc_raft.clone();
+            let sent = raft.clock.now();
+            tokio::spawn(async move {
+                let current_term = args.term;
+                if let Ok(reply) = transport.append_entries(id, args).await {
+                    let mut raft = arc_raft.lock().unwrap();
+                    let term = reply.term;
+                    if term > raft.current_term {
+                        Self::step_down(&mut raft, term);
+                    } else if raft.current_term == current_term {
+                        raft.record_ack(id, sent);
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Confirms leadership for a linearizable read via the ReadIndex protocol. Returns the index
+    /// the state machine must apply before serving the read: the commit index when the read
+    /// arrived, once a heartbeat round has confirmed that no newer leader exists. Returns
+    /// [`Error::NotLeader`] if this node is not the leader, has not yet committed an entry in its
+    /// term, or fails to confirm its leadership with a quorum.
+    ///
+    /// With lease reads enabled, a leader holding a valid lease skips the heartbeat round.
+    pub async fn read_index(&self) -> Result<u64> {
+        let (read_index, term, quorum, mut acks, mut replies) = {
+            let raft = self.raft.lock()?;
+            if !raft.is_leader() {
+                return Err(Error::NotLeader);
+            }
+            if raft.has_lease() {
+                return Ok(raft.commit_index);
+            }
+            // Until the leader commits an entry in its term, it may not know the latest commit.
+            if raft.log.term(raft.commit_index)? != Some(raft.current_term) {
+                return Err(Error::NotLeader);
+            }
+            let replies = FuturesUnordered::new();
+            for id in raft.membership.voters.iter() {
+                if let Some(transport) = raft.peer(*id) {
+                    let (id, args) = (*id, raft.heartbeat_args(*id)?);
+                    replies.push(async move { transport.append_entries(id, args).await });
+                }
+            }
+            let acks = u64::from(raft.membership.is_voter(raft.me));
+            (raft.commit_index, raft.current_term, raft.quorum(), acks, replies)
+        };
+
+        while acks < quorum {
+            match replies.next().await {
+                Some(Ok(reply)) if reply.term > term => {
+                    let mut raft = self.raft.lock()?;
+                    if reply.term > raft.current_term {
+                        raft.become_follower(reply.term, None)?;
+                    }
+                    return Err(Error::NotLeader);
+                },
+                Some(Ok(_)) => acks += 1,
+                Some(Err(_)) => continue,
+                None => return Err(Error::NotLeader),
+            }
+        }
+
+        let raft = self.raft.lock()?;
+        if raft.current_term != term || !raft.is_leader() {
+            return Err(Error::NotLeader);
+        }
+        Ok(read_index)
+    }
+
+    /// Returns the index a bounded-staleness read must wait for, so that it reflects all entries
+    /// committed more than `max_staleness` ago. Any replica can serve such a read. Returns
+    /// [`Error::NotLeader`] if this node has not been in contact with a leader recently enough.
+    pub fn stale_read_index(&self, max_staleness: Duration) -> Result<u64> {
+        self.raft.lock()?.fresh_index(max_staleness).ok_or(Error::NotLeader)
+    }
+
+    /// Transfers leadership to a voter. The leader rejects new commands, brings the transferee up
+    /// to date with its log, and then tells it to start an election immediately. The transfer is
+    /// aborted if the transferee has not taken over within an election timeout.
+    pub fn transfer_leader(&self, id: u64) -> Result<()> {
+        let mut raft = self.raft.lock()?;
+        if raft.transfer_leadership(id)?.is_some() {
+            Self::send_timeout_now(&self.raft, &raft, id);
+        }
+        Ok(())
+    }
+
+    /// Sends a TimeoutNow to a peer in the background.
+    fn send_timeout_now(arc_raft: &Arc<Mutex<Raft>>, raft: &Raft, id: u64) {
+        let transport = match raft.peer(id) {
+            Some(transport) => transport,
+            None => return,
+        };
+        let args = TimeoutNowArgs { term: raft.current_term, leader_id: raft.me };
+        let arc_raft = arc_raft.clone();
+        tokio::spawn(async move {
+            let term = args.term;
+            if let Ok(reply) = transport.timeout_now(id, args).await {
+                let mut raft = arc_raft.lock().unwrap();
+                if reply.term > term && reply.term > raft.current_term {
+                    Self::step_down(&mut raft, reply.term);
+                }
+            }
+        });
+    }
+
+    /// Counts the number of votes for a candidate. If the votes are pre-votes, the node campaigns
+    /// once a quorum would vote for it.
     async fn count_votes(
-        arc_raft: Arc<Mutex<Raft>>, 
+        arc_raft: Arc<Mutex<Raft>>,
         quorum: u64,
         current_term: u64,
+        pre_vote: bool,
         mut request_vote_replies: FuturesUnordered<impl 
-            Future<Output = RpcResult<RequestVoteReply>>>,
+            Future<Output = Result<RequestVoteReply>>>,
     ) -> Result<()> {
         let mut vote_count = 1;
-        
-        // If there is only myself in the cluster, we can become leader immediately.
-        if vote_count >= quorum {
-            let mut raft = arc_raft.lock()?;
-            let work_txs = HashMap::new();
-            raft.become_leader(work_txs);
-            return Ok(());
-        }
 
-        while let Some(res) = request_vote_replies.next().await {
-            let (term, vote_granted) = match res {
-                Ok(res) => (res.get_ref().term, res.get_ref().vote_granted),
-                Err(_) => continue,
+        while vote_count < quorum {
+            let (term, vote_granted) = match request_vote_replies.next().await {
+                Some(Ok(reply)) => (reply.term, reply.vote_granted),
+                Some(Err(_)) => continue,
+                None => return Ok(()),
             };
+            if term > current_term {
+                let mut raft = arc_raft.lock()?;
+                if term > raft.current_term {
+                    raft.become_follower(term, None)?;
+                }
+                return Ok(());
+            }
             if vote_granted {
                 vote_count += 1;
-                if vote_count >= quorum {
-                    let mut raft = arc_raft.lock()?;
-                    let mut work_txs = HashMap::new();
-                    for id in 0..raft.peers.len() as u64 {
-                        if id == raft.me {
-                            continue;
-                        }
-                        let (work_tx, work_rx) = mpsc::unbounded_channel();
-                        work_txs.insert(id, work_tx);
-                        tokio::spawn(Self::replicator(arc_raft.clone(), work_rx, id));
-                    }
-                    raft.become_leader(work_txs);
-                    return Ok(());
-                }
             }
-            if term > current_term {
-                arc_raft.lock()?.become_follower(term, None);
+        }
+
+        // The votes only count if this node is still a candidate in the same term.
+        let mut raft = arc_raft.lock()?;
+        if raft.current_term != current_term {
+            return Ok(());
+        }
+        match raft.role {
+            Role::PreCandidate { .. } if pre_vote => {
+                Self::campaign(&arc_raft, &mut raft)?;
                 return Ok(());
             }
+            Role::Candidate { .. } if !pre_vote => {},
+            _ => return Ok(()),
+        }
+        let peers = raft.membership.members().filter(|id| *id != raft.me).collect::<Vec<_>>();
+        let work_txs = peers.into_iter()
+            .map(|id| (id, Self::spawn_replicator(&arc_raft, id)))
+            .collect();
+        raft.become_leader(work_txs);
+
+        // Commits an entry in the new term, which also commits all entries from previous terms.
+        raft.start(Command::Noop)?;
+        Ok(())
+    }
+
+    /// Spawns a replicator task for a peer, returning the channel to wake it up on. The leader
+    /// role holds the only sender, so the task exits once the role is replaced or the peer removed.
+    fn spawn_replicator(arc_raft: &Arc<Mutex<Raft>>, id: u64) -> mpsc::UnboundedSender<()> {
+        let (work_tx, work_rx) = mpsc::unbounded_channel();
+        tokio::spawn(Self::replicator(arc_raft.clone(), work_rx, work_tx.downgrade(), id));
+        work_tx
+    }
+
+    /// Starts replicating the log to peers that were added while this node is the leader.
+    fn start_replicators(arc_raft: &Arc<Mutex<Raft>>, raft: &mut Raft, ids: Vec<u64>) -> Result<()> {
+        if let Role::Leader { ref mut work_txs, .. } = raft.role {
+            for id in ids {
+                let work_tx = Self::spawn_replicator(arc_raft, id);
+                work_tx.send(())?;
+                work_txs.insert(id, work_tx);
+            }
         }
         Ok(())
     }
 
+    /// Replicates the log to a peer whenever woken up. Entries are sent in batches, advancing
+    /// the peer's next index optimistically so that several batches can be in flight at once.
+    /// While the peer's log position is unknown, only one batch is in flight at a time. Failed
+    /// RPCs are retried when the leader next sends heartbeats. Replies wake the replicator up
+    /// through a weak sender, which does not keep it running once the leader role drops its own.
     async fn replicator(
         arc_raft: Arc<Mutex<Raft>>,
-        mut work_rx: mpsc::UnboundedReceiver<u64>,
+        mut work_rx: mpsc::UnboundedReceiver<()>,
+        work_tx: mpsc::WeakUnboundedSender<()>,
         id: u64
     ) -> Result<()> {
-        while let Some(log_index) = work_rx.recv().await {
-            let raft = arc_raft.lock()?;
+        while work_rx.recv().await.is_some() {
+            let mut raft = arc_raft.lock()?;
+            // The peer may have been removed from the cluster, or this node may have lost
+            // leadership.
+            let next = match raft.role {
+                Role::Leader { ref next_index, .. } => match next_index.get(&id) {
+                    Some(next) => *next,
+                    None => break,
+                },
+                _ => break,
+            };
+            let transport = match raft.peer(id) {
+                Some(transport) => transport,
+                None => continue,
+            };
 
-            if log_index < raft.log.last_index {
-                continue;
-            }
+            let mut next = next;
+            loop {
+                let (inflight, probing) = match raft.role {
+                    Role::Leader { ref inflight, ref probing, .. } => {
+                        (inflight.get(&id).copied().unwrap_or(0), probing.contains(&id))
+                    }
+                    _ => break,
+                };
+                if inflight >= if probing { 1 } else { MAX_INFLIGHT } {
+                    break;
+                }
+
+                // If the entries have been compacted, sends the snapshot instead. No other
+                // messages are sent to the peer until the snapshot has been installed.
+                if next <= raft.log.snapshot_index {
+                    if inflight > 0 {
+                        break;
+                    }
+                    let mut snapshot = raft.log.snapshot()?.ok_or_else(|| {
+                        Error::Internal("Compacted log has no snapshot".into())
+                    })?;
+                    // Witnesses have no state machine, so they only need the snapshot's metadata.
+                    if raft.membership.is_witness(id) {
+                        snapshot.data = vec![];
+                    }
+                    let chunks = raft.snapshot_chunks(snapshot)?;
+                    if let Role::Leader { ref mut inflight, ref mut probing, .. } = raft.role {
+                        inflight.insert(id, 1);
+                        probing.insert(id);
+                    }
+                    tokio::spawn(Self::send_snapshot(
+                        arc_raft.clone(), transport.clone(), id, chunks, work_tx.clone(),
+                    ));
+                    break;
+                }
 
-            if let Role::Leader { ref next_index, ref work_txs, .. } = raft.role {
-                let prev_log_index = next_index.get(&id).unwrap() - 1;
-                let prev_log_term = raft.log.get(prev_log_index)?.map_or(0, |e| e.term);
-                let entries = raft.log
-                    .scan((prev_log_index+1)..=log_index)
-                    .collect::<Result<Vec<_>>>()?
-                    .into_iter()
-                    .map(|e| serialize(&e))
-                    .collect::<Result<Vec<_>>>()?;
-
-                let args = AppendEntriesArgs {
-                    term: raft.current_term,
-                    leader_id: raft.me,
-                    prev_log_index,
-                    prev_log_term,
-                    entries,
-                    leader_commit: raft.commit_index,
+                // Empty requests are only sent to find the log position of a probed peer.
+                let args = match raft.append_args(id, next)? {
+                    Some(args) if !args.entries.is_empty() || probing => args,
+                    _ => break,
                 };
+                let last_index = args.prev_log_index + args.entries.len() as u64;
+                next = last_index + 1;
+                if let Role::Leader { ref mut next_index, ref mut inflight, .. } = raft.role {
+                    next_index.insert(id, next);
+                    *inflight.entry(id).or_insert(0) += 1;
+                }
+                tokio::spawn(Self::send_append_entries(
+                    arc_raft.clone(), transport.clone(), id, args, work_tx.clone(),
+                ));
+            }
+        }
 
-                let current_term = raft.current_term;
-                let work_tx = work_txs.get(&id).unwrap().clone();
-                let mut client = raft.peers[id as usize].clone();
-                let raft = arc_raft.clone();
-                tokio::spawn(async move {
-                    let (term, success) = match client.append_entries(args).await {
-                        Ok(res) => (res.get_ref().term, res.get_ref().success),
-                        Err(_) => {
-                            work_tx.send(log_index).unwrap();
-                            return;
-                        },
-                    };
-                    if term > current_term {
-                        raft.lock().unwrap().become_follower(term, None);
-                        return;
+        Ok(())
+    }
+
+    /// Sends a batch of entries to a peer, and updates the peer's progress with the reply.
+    async fn send_append_entries(
+        arc_raft: Arc<Mutex<Raft>>,
+        transport: Arc<dyn Transport>,
+        id: u64,
+        args: AppendEntriesArgs,
+        work_tx: mpsc::WeakUnboundedSender<()>,
+    ) {
+        let (current_term, prev_log_index) = (args.term, args.prev_log_index);
+        let last_index = prev_log_index + args.entries.len() as u64;
+        let sent = arc_raft.lock().unwrap().clock.now();
+        let reply = transport.append_entries(id, args).await;
+
+        let mut raft = arc_raft.lock().unwrap();
+        if let Ok(ref reply) = reply {
+            if reply.term > raft.current_term {
+                Self::step_down(&mut raft, reply.term);
+                return;
+            }
+        }
+        if raft.current_term != current_term {
+            return;
+        }
+        let reply = match reply {
+            Ok(reply) => reply,
+            // The entries may have been lost, so resends from the last known match.
+            Err(_) => {
+                if let Role::Leader {
+                    ref mut next_index, ref match_index, ref mut inflight, ref mut probing, ..
+                } = raft.role {
+                    if let (Some(next), Some(matched)) = (next_index.get_mut(&id), match_index.get(&id)) {
+                        *next = matched + 1;
+                        probing.insert(id);
                     }
-                    match success {
-                        true => {
-                            let mut raft = raft.lock().unwrap();
-                            let original_commit_index = raft.commit_index;
-                            if let Role::Leader { ref mut next_index, ref mut match_index, .. } = raft.role {
-                                if log_index > match_index[&id] {
-                                    next_index.entry(id).and_modify(|index| *index = log_index + 1);
-                                    match_index.entry(id).and_modify(|index| *index = log_index);
-                                }
-
-                                // Checks if there are entries ready to be committed.
-                                let mut match_indexes = match_index.values().cloned().collect::<Vec<_>>();
-                                match_indexes.push(raft.log.last_index);
-                                match_indexes.sort_unstable();
-                                let mut new_commit_index = match_indexes[raft.quorum() as usize - 1];
-                                while let Some(entry) = raft.log.get(new_commit_index).unwrap() {
-                                    if entry.term == raft.current_term {
-                                        break;
-                                    }
-                                    new_commit_index -= 1;
-                                }
-                                if new_commit_index > original_commit_index {
-                                    let entries = raft.log
-                                        .scan((original_commit_index+1)..=new_commit_index)
-                                        .collect::<Result<Vec<_>>>()
-                                        .unwrap();
-                                    for Entry { index, term, command } in entries {
-                                        let apply_msg = ApplyMsg {
-                                            log_index: index,
-                                            command,
-                                        };
-                                        raft.apply_tx.send(apply_msg).unwrap();
-                                    }
-                                    raft.commit_index = new_commit_index;
-                                }
-                                
-                            }
-                        },
+                    inflight.entry(id).and_modify(|n| *n = n.saturating_sub(1));
+                }
+                return;
+            }
+        };
+        raft.record_ack(id, sent);
 
-                        false => {
-                            let mut raft = raft.lock().unwrap();
-                            if let Role::Leader { ref mut next_index, .. } = raft.role {
-                                next_index.entry(id).and_modify(|index| *index -= 1);
-                            }
-                            work_tx.send(log_index).unwrap();
-                        },
+        if reply.success {
+            if let Role::Leader {
+                ref mut next_index, ref mut match_index, ref mut inflight, ref mut probing, ..
+            } = raft.role {
+                if let (Some(next), Some(matched)) = (next_index.get_mut(&id), match_index.get_mut(&id)) {
+                    *matched = last_index.max(*matched);
+                    *next = (*matched + 1).max(*next);
+                    probing.remove(&id);
+                }
+                inflight.entry(id).and_modify(|n| *n = n.saturating_sub(1));
+            }
+
+            // Checks if there are entries ready to be committed, and if a learner has caught up
+            // and can be promoted. Replies are handled in the background, so a failure is logged,
+            // and the node steps down rather than keep leading with a commit it cannot advance.
+            if let Err(err) = raft.maybe_commit().and_then(|_| raft.maybe_promote(id)) {
+                println!("Node {} failed to commit in term {}, stepping down: {:?}", raft.me, current_term, err);
+                Self::step_down(&mut raft, current_term);
+                return;
+            }
+            if raft.transfer_ready(id) {
+                Self::send_timeout_now(&arc_raft, &raft, id);
+            }
+        } else {
+            let backtrack = raft.backtrack(prev_log_index, reply.conflict_index, reply.conflict_term);
+            if let Role::Leader {
+                ref mut next_index, ref match_index, ref mut inflight, ref mut probing, ..
+            } = raft.role {
+                // Ignores rejections of batches past an index already backtracked to.
+                if let (Some(next), Some(matched)) = (next_index.get_mut(&id), match_index.get(&id)) {
+                    if prev_log_index < *next {
+                        *next = backtrack.unwrap_or(prev_log_index).max(matched + 1);
+                        probing.insert(id);
                     }
-                });
+                }
+                inflight.entry(id).and_modify(|n| *n = n.saturating_sub(1));
             }
         }
+        if let Some(work_tx) = work_tx.upgrade() {
+            let _ = work_tx.send(());
+        }
+    }
 
-        Ok(())
+    /// Streams a snapshot to a peer in chunks. On success, advances the peer's progress past the
+    /// snapshot and resumes replicating log entries.
+    async fn send_snapshot(
+        arc_raft: Arc<Mutex<Raft>>,
+        transport: Arc<dyn Transport>,
+        id: u64,
+        chunks: Vec<InstallSnapshotArgs>,
+        work_tx: mpsc::WeakUnboundedSender<()>,
+    ) {
+        let (current_term, index) = (chunks[0].term, chunks[0].last_included_index);
+        let sent = arc_raft.lock().unwrap().clock.now();
+        let reply_term = transport.install_snapshot(id, chunks).await.map(|reply| reply.term);
+
+        let mut raft = arc_raft.lock().unwrap();
+        if let Ok(reply_term) = reply_term {
+            if reply_term > raft.current_term {
+                Self::step_down(&mut raft, reply_term);
+                return;
+            }
+        }
+        if raft.current_term != current_term {
+            return;
+        }
+        if let Role::Leader {
+            ref mut next_index, ref mut match_index, ref mut inflight, ref mut probing, ..
+        } = raft.role {
+            inflight.insert(id, 0);
+            // Retries when the leader next sends heartbeats.
+            if reply_term.is_err() {
+                return;
+            }
+            if match_index.get(&id).is_some_and(|matched| index > *matched) {
+                next_index.insert(id, index + 1);
+                match_index.insert(id, index);
+                probing.remove(&id);
+            }
+        }
+        raft.record_ack(id, sent);
+        if let Some(work_tx) = work_tx.upgrade() {
+            let _ = work_tx.send(());
+        }
+    }
+
+    /// Receives a snapshot streamed in chunks. Buffers the chunks, then replaces the log and the
+    /// state machine with the snapshot, unless the snapshot is already covered by the log.
+    pub(super) async fn receive_snapshot<S>(
+        &self,
+        mut stream: S,
+    ) -> core::result::Result<InstallSnapshotReply, Status>
+    where
+        S: tokio_stream::Stream<Item = core::result::Result<InstallSnapshotArgs, Status>> + Unpin,
+    {
+        let mut data = vec![];
+        let mut header = None;
+        let mut membership = None;
+        while let Some(chunk) = stream.next().await.transpose()? {
+            // Rejects stale leaders before buffering the rest of the snapshot.
+            {
+                let raft = self.raft.lock().unwrap();
+                if chunk.term < raft.current_term {
+                    return Ok(InstallSnapshotReply { term: raft.current_term });
+                }
+            }
+            if chunk.offset != data.len() as u64 {
+                return Err(Status::invalid_argument(format!(
+                    "Expected snapshot chunk at offset {}, got {}",
+                    data.len(),
+                    chunk.offset,
+                )));
+            }
+            data.extend_from_slice(&chunk.data);
+            header = Some((chunk.term, chunk.leader_id, chunk.last_included_index, chunk.last_included_term));
+            if chunk.done {
+                if !chunk.membership.is_empty() {
+                    membership = Some(deserialize::<Membership>(&chunk.membership)?);
+                }
+                break;
+            }
+        }
+        let (term, leader_id, index, last_term) = header
+            .ok_or_else(|| Status::invalid_argument("Empty snapshot stream"))?;
+
+        // Waits for room in the apply channel before taking the lock, so that the snapshot is
+        // sent in order with the committed entries.
+        let apply_tx = self.raft.lock().unwrap().apply_tx.clone();
+        let permit = apply_tx.reserve().await
+            .map_err(|e| Status::internal(format!("Failed to send apply msg: {}", e)))?;
+
+        let mut raft = self.raft.lock().unwrap();
+        if term < raft.current_term {
+            return Ok(InstallSnapshotReply { term: raft.current_term });
+        }
+        if term > raft.current_term || !matches!(raft.role, Role::Follower { .. }) {
+            raft.become_follower(term, Some(leader_id))?;
+        }
+        let now = raft.clock.now();
+        if let Role::Follower {
+            ref mut leader, ref mut leader_seen_ticks, ref mut leader_contact, ..
+        } = raft.role {
+            *leader_seen_ticks = 0;
+            *leader = Some(leader_id);
+            *leader_contact = Some((now, index));
+        }
+
+        // The snapshot is stale if its entries have already been committed.
+        if index <= raft.commit_index {
+            return Ok(InstallSnapshotReply { term: raft.current_term });
+        }
+
+        raft.log.install(Snapshot { index, term: last_term, data: data.clone(), membership })?;
+        raft.commit_index = index;
+        raft.last_applied = index;
+        raft.reload_membership()?;
+        if !raft.membership.is_witness(raft.me) {
+            permit.send(ApplyMsg::Snapshot { index, term: last_term, data });
+        }
+
+        Ok(InstallSnapshotReply { term: raft.current_term })
     }
 }
 
@@ -345,6 +1087,16 @@ impl RaftService for Node {
         let mut raft = self.raft.lock().unwrap();
         let args = request.into_inner();
 
+        // A pre-vote is granted if the candidate could win an election in the next term, without
+        // changing the term or the vote. It is rejected while a leader is known to be alive.
+        if args.pre_vote {
+            let vote_granted = args.term > raft.current_term
+                && !raft.leader_recently_seen()
+                && raft.log_up_to_date(args.last_log_index, args.last_log_term);
+            let reply = RequestVoteReply { term: raft.current_term, vote_granted };
+            return Ok(Response::new(reply));
+        }
+
         if args.term < raft.current_term {
             let reply = RequestVoteReply {
                 term: raft.current_term,
@@ -354,23 +1106,22 @@ impl RaftService for Node {
         }
 
         if args.term > raft.current_term {
-            raft.become_follower(args.term, None);
+            raft.become_follower(args.term, None)?;
         }
 
-        if raft.voted_for.is_none() || raft.voted_for == Some(args.candidate_id) {
-            raft.voted_for = Some(args.candidate_id);
-            raft.persist();
-        } else {
-            let reply = RequestVoteReply {
-                term: raft.current_term,
-                vote_granted: false
-            };
-            return Ok(Response::new(reply));
+        let vote_granted = (raft.voted_for.is_none() || raft.voted_for == Some(args.candidate_id))
+            && raft.log_up_to_date(args.last_log_index, args.last_log_term);
+        if vote_granted {
+            let term = raft.current_term;
+            raft.persist(term, Some(args.candidate_id))?;
+            if let Role::Follower { ref mut leader_seen_ticks, .. } = raft.role {
+                *leader_seen_ticks = 0;
+            }
         }
 
         let reply = RequestVoteReply {
             term: raft.current_term,
-            vote_granted: true
+            vote_granted,
         };
         Ok(Response::new(reply))
     }
@@ -386,56 +1137,97 @@ impl RaftService for Node {
         if args.term < raft.current_term {
             let reply = AppendEntriesReply {
                 term: raft.current_term,
-                success: false
+                success: false,
+                conflict_index: 0,
+                conflict_term: 0,
             };
             return Ok(Response::new(reply));
         }
 
-        if let Role::Candidate { .. } | Role::Leader { .. } = raft.role {
-            raft.become_follower(args.term, None);
+        if args.term > raft.current_term || !matches!(raft.role, Role::Follower { .. }) {
+            raft.become_follower(args.term, Some(args.leader_id))?;
         }
 
-        if let Role::Follower { ref mut leader, ref mut leader_seen_ticks, .. } = raft.role {
+        let now = raft.clock.now();
+        if let Role::Follower {
+            ref mut leader, ref mut leader_seen_ticks, ref mut leader_contact, ..
+        } = raft.role {
             *leader_seen_ticks = 0;
             *leader = Some(args.leader_id);
+            *leader_contact = Some((now, args.leader_commit));
         }
 
-        if args.prev_log_index != 0 && 
-            raft.log.get(args.prev_log_index)?.map_or(true, |e| e.term != args.prev_log_term) {
+        // Entries up to the snapshot are committed, and thus known to match the leader's.
+        if args.prev_log_index > raft.log.snapshot_index
+            && raft.log.term(args.prev_log_index)? != Some(args.prev_log_term) {
+            let (conflict_index, conflict_term) = raft.conflict_hint(args.prev_log_index)?;
             let reply = AppendEntriesReply {
                 term: raft.current_term,
-                success: false
+                success: false,
+                conflict_index,
+                conflict_term,
             };
             return Ok(Response::new(reply));
         }
 
-        raft.log.splice(args.entries.iter().map(|e| deserialize(e)).collect::<Result<_>>()?)?;
+        let entries = args.entries.iter().map(|e| deserialize(e)).collect::<Result<Vec<Entry>>>()?;
+        // Only entries known to match the leader's log may be committed.
+        let last_new_index = args.prev_log_index + entries.len() as u64;
+        // The configuration must be reloaded if it may be replaced or truncated by the entries.
+        let reload = entries.iter().any(|e| {
+            e.index <= raft.membership_index || matches!(e.command, Command::Membership(_))
+        });
+        if !entries.is_empty() {
+            raft.log.splice(entries)?;
+            raft.log.flush()?;
+        }
+        if reload {
+            raft.reload_membership()?;
+        }
 
         // Commits entries if necessary.
-        if args.leader_commit > raft.commit_index {
-            let commit_index = std::cmp::min(args.leader_commit, raft.log.last_index);
-            for index in (raft.commit_index + 1)..=commit_index {
-                let Entry {index, term, command} = raft.log.get(index)?
-                    .ok_or(Error::Internal(format!("Expected entry at index {}", index)))?;
-                let apply_msg = ApplyMsg {
-                    log_index: index,
-                    command,
-                };
-                match raft.apply_tx.send(apply_msg) {
-                    Ok(_) => {},
-                    Err(e) => {
-                        return Err(Status::internal(format!("Failed to send apply msg: {}", e)));
-                    },
-                }
-            }
-            raft.commit_index = commit_index;
-            raft.log.commit(commit_index)?;
+        if args.leader_commit.min(last_new_index) > raft.commit_index {
+            raft.commit(args.leader_commit.min(last_new_index))?;
         }
 
         let reply = AppendEntriesReply {
             term: raft.current_term,
-            success: true
+            success: true,
+            conflict_index: 0,
+            conflict_term: 0,
         };
         Ok(Response::new(reply))
     }
-}
\ No newline at end of file
+
+    /// InstallSnapshot RPC handler.
+    async fn install_snapshot(
+        &self,
+        request: Request<Streaming<InstallSnapshotArgs>>,
+    ) -> RpcResult<InstallSnapshotReply> {
+        self.receive_snapshot(request.into_inner()).await.map(Response::new)
+    }
+
+    /// TimeoutNow RPC handler. Starts an election immediately, as part of a leadership transfer
+    /// from the current leader.
+    async fn timeout_now(
+        &self,
+        request: Request<TimeoutNowArgs>,
+    ) -> RpcResult<TimeoutNowReply> {
+        let mut raft = self.raft.lock().unwrap();
+        let args = request.into_inner();
+
+        if args.term < raft.current_term {
+            return Ok(Response::new(TimeoutNowReply { term: raft.current_term }));
+        }
+        if args.term > raft.current_term {
+            raft.become_follower(args.term, Some(args.leader_id))?;
+        }
+        if let Role::Follower { .. } = raft.role {
+            if raft.membership.can_lead(raft.me) {
+                println!("Node {} received TimeoutNow from {}, campaigning", raft.me, args.leader_id);
+                Self::campaign(&self.raft, &mut raft)?;
+            }
+        }
+        Ok(Response::new(TimeoutNowReply { term: raft.current_term }))
+    }
+}
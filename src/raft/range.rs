@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Result, Error};
+
+/// A contiguous range of keys, replicated by its own Raft group.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RangeDescriptor {
+    /// The ID of the Raft group replicating the range.
+    pub group_id: u64,
+    /// The first key of the range, inclusive.
+    pub start: Vec<u8>,
+    /// The end of the range, exclusive, or None if the range is unbounded.
+    pub end: Option<Vec<u8>>,
+    /// Incremented by every split or merge of the range, so that a newer descriptor of
+    /// overlapping ranges can be told from a stale one.
+    pub generation: u64,
+}
+
+impl RangeDescriptor {
+    /// Checks whether the range contains a key.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        key >= self.start.as_slice() && self.end.as_deref().is_none_or(|end| key < end)
+    }
+
+    /// Checks whether the range overlaps another.
+    fn overlaps(&self, other: &RangeDescriptor) -> bool {
+        self.end.as_deref().is_none_or(|end| other.start.as_slice() < end)
+            && other.end.as_deref().is_none_or(|end| self.start.as_slice() < end)
+    }
+}
+
+/// Thresholds for splitting and merging ranges. A zero threshold disables the corresponding
+/// split or merge.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SplitOptions {
+    /// The size of a range's state machine, in bytes, above which the range is split.
+    pub max_size: u64,
+    /// The number of mutations applied per replicated clock tick above which the range is split.
+    pub max_load: u64,
+    /// The combined size of two adjacent ranges, in bytes, below which they are merged. The ranges
+    /// must also have less than half of `max_load` combined, if set.
+    pub merge_size: u64,
+}
+
+/// The range descriptor table, which maps keys to the Raft groups replicating them. The ranges
+/// partition the key space: every key belongs to exactly one range.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RangeTable {
+    /// The ranges, by start key.
+    ranges: BTreeMap<Vec<u8>, RangeDescriptor>,
+}
+
+impl Default for RangeTable {
+    /// A single range covering the whole key space, replicated by group 0.
+    fn default() -> Self {
+        Self::new(vec![]).unwrap()
+    }
+}
+
+impl RangeTable {
+    /// Creates a range table that splits the key space at the given keys, which must be strictly
+    /// increasing and non-empty. The ranges are replicated by groups 0 to `split_keys.len()`, in
+    /// key order.
+    pub fn new(split_keys: Vec<Vec<u8>>) -> Result<Self> {
+        if split_keys.first().is_some_and(|key| key.is_empty()) {
+            return Err(Error::Config("Split keys must not be empty".into()));
+        }
+        if split_keys.windows(2).any(|keys| keys[0] >= keys[1]) {
+            return Err(Error::Config("Split keys must be strictly increasing".into()));
+        }
+        let starts = std::iter::once(vec![]).chain(split_keys.iter().cloned());
+        let ends = split_keys.iter().cloned().map(Some).chain(std::iter::once(None));
+        let ranges = starts.zip(ends).enumerate()
+            .map(|(group_id, (start, end))| {
+                (start.clone(), RangeDescriptor { group_id: group_id as u64, start, end, generation: 0 })
+            })
+            .collect();
+        Ok(Self { ranges })
+    }
+
+    /// Returns the range containing a key.
+    pub fn lookup(&self, key: &[u8]) -> &RangeDescriptor {
+        // The first range starts at the empty key, so every key has a range at or before it.
+        self.ranges.range::<[u8], _>((Bound::Unbounded, Bound::Included(key)))
+            .next_back()
+            .unwrap()
+            .1
+    }
+
+    /// Returns the range replicated by a group.
+    pub fn get(&self, group_id: u64) -> Option<&RangeDescriptor> {
+        self.ranges.values().find(|range| range.group_id == group_id)
+    }
+
+    /// Iterates over the ranges in key order.
+    pub fn ranges(&self) -> impl Iterator<Item = &RangeDescriptor> {
+        self.ranges.values()
+    }
+
+    /// Records a range's descriptor after a split or merge, unless a newer descriptor of an
+    /// overlapping range is already known. Older ranges starting inside the range were merged into
+    /// it and are removed, and their group IDs returned, while an older range starting before it is
+    /// cut short where it starts. Since a group's start key never changes, applying the
+    /// descriptors of all groups in any order converges on the latest table.
+    pub fn apply(&mut self, range: RangeDescriptor) -> Vec<u64> {
+        let stale = self.ranges.values().any(|other| {
+            (other.group_id == range.group_id || other.overlaps(&range))
+                && other.generation > range.generation
+        });
+        if stale {
+            return vec![];
+        }
+        let mut merged = vec![];
+        self.ranges.retain(|_, other| {
+            if other.group_id == range.group_id {
+                return false;
+            }
+            if !other.overlaps(&range) {
+                return true;
+            }
+            if other.start < range.start {
+                other.end = Some(range.start.clone());
+                return true;
+            }
+            merged.push(other.group_id);
+            false
+        });
+        self.ranges.insert(range.start.clone(), range);
+        merged
+    }
+}
@@ -1,15 +1,34 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
 use serde::{Deserialize, Serialize};
 use tokio::sync::{mpsc, oneshot};
+use tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
 use tonic::{Request, Response};
 
 use crate::error::{Result, RpcResult, Error};
 use crate::proto::featherkv::{FeatherKv, RegistrationRequest, RegistrationReply, ExecutionReply, ExecutionRequest};
+use crate::proto::featherkv::{AddNodeRequest, RemoveNodeRequest, TransferLeaderRequest, AdminReply};
+use crate::proto::featherkv::{RangesRequest, RangesReply, StatusRequest, StatusReply};
+use crate::placement::{Heartbeat, Mutation as PlacementMutation, ReplicaReport};
 use crate::sql::engine;
 use crate::storage::log::LogStore;
-use super::{Node, Driver, State, ApplyResult};
+use super::{Client, Node, Driver, State, ApplyResult, Log, Membership, Options, RaftRouter, ReadRequest, Snapshot, Waiters};
+use super::{RangeDescriptor, RangeTable, SplitOptions, Status};
+use super::state::RangeEvent;
+
+/// How long a replica waits to apply the index of a non-linearizable read before rejecting it.
+const STALE_READ_TIMEOUT: Duration = Duration::from_secs(1);
+/// The number of committed entries that may be queued for the state machine. Once full, further
+/// entries wait in the Raft log until the state machine catches up.
+const APPLY_CHANNEL_CAPACITY: usize = 256;
+/// How long a registration or mutation waits to be applied before replying `NotLeader`, so that
+/// the client retries it, possibly on a new leader.
+const APPLY_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often the node reports its replicas to the placement driver.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 /// A Raft session command.
@@ -17,84 +36,264 @@ pub enum Command {
     Mutation {
         session_id: u64,
         sequence_number: u64,
+        /// The lowest sequence number the client has not received a reply for. The results of
+        /// earlier mutations can be discarded.
+        lowest_unacked: u64,
+        /// The key the mutation is routed by, which must be in the group's range when applied.
+        key: Vec<u8>,
         mutation: Vec<u8>,
     },
-    Query {
-        session_id: u64,
-        sequence_number: u64,
-        query: Vec<u8>,
-    },
     Registration {
         session_id: u64,
     },
+    /// A new cluster configuration, replacing the previous one.
+    Membership(Membership),
+    /// An empty entry appended by a new leader, to commit an entry in its term.
+    Noop,
+    /// A tick of the replicated clock, appended periodically by the leader to expire idle
+    /// sessions deterministically on all replicas.
+    Clock,
+    /// Splits the group's range at a key. The keys from `split_key` on move to a new group, whose
+    /// replicas start out with the given configuration.
+    Split {
+        split_key: Vec<u8>,
+        group_id: u64,
+        membership: Membership,
+    },
+    /// Freezes the group's range, rejecting further mutations, so that it can be merged into its
+    /// left-hand neighbor.
+    Freeze,
+    /// Merges the frozen right-hand neighbor `group_id` into the group's range, given its range
+    /// descriptor and state machine snapshot as replied to [`Command::Freeze`].
+    Merge {
+        group_id: u64,
+        data: Vec<u8>,
+    },
 }
 
 impl std::fmt::Display for Command {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self {
-            Command::Mutation { session_id, sequence_number, mutation } => {
-                write!(
-                    f,
-                    "Mutation {{ session_id: {}, sequence_number: {}, mutation: {} }}",
-                    session_id, sequence_number, FeatherKV::deserialize::<engine::raft::Mutation>(&mutation).unwrap(),
-                )
-            },
-            Command::Query { session_id, sequence_number, query } => {
+            Command::Mutation { session_id, sequence_number, lowest_unacked, key, mutation } => {
                 write!(
                     f,
-                    "Query {{ session_id: {}, sequence_number: {}, query: {} }}",
-                    session_id, sequence_number, FeatherKV::deserialize::<engine::raft::Query>(&query).unwrap(),
-                )
+                    "Mutation {{ session_id: {}, sequence_number: {}, lowest_unacked: {}, key: {:?}, mutation: ",
+                    session_id, sequence_number, lowest_unacked, key,
+                )?;
+                match FeatherKV::deserialize::<engine::raft::Mutation>(mutation) {
+                    Ok(mutation) => write!(f, "{} }}", mutation),
+                    Err(_) => write!(f, "{:?} }}", mutation),
+                }
             },
             Command::Registration { session_id } => {
                 write!(f, "Registration {{ session_id: {} }}", session_id)
             },
+            Command::Membership(membership) => {
+                write!(f, "Membership {{ {} }}", membership)
+            },
+            Command::Noop => write!(f, "Noop"),
+            Command::Clock => write!(f, "Clock"),
+            Command::Split { split_key, group_id, .. } => {
+                write!(f, "Split {{ split_key: {:?}, group_id: {} }}", split_key, group_id)
+            },
+            Command::Freeze => write!(f, "Freeze"),
+            Command::Merge { group_id, data } => {
+                write!(f, "Merge {{ group_id: {}, data: {} bytes }}", group_id, data.len())
+            },
         }
     }
 }
 
+/// The consistency of a query.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Consistency {
+    /// Served by the leader, reflecting all mutations committed before the query.
+    Linearizable,
+    /// Served by any replica that was up to date with the leader at most this long ago.
+    MaxStaleness(Duration),
+    /// Served by any replica once it has applied at least the given log index.
+    AtLeast(u64),
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum RpcStatus {
     Ok,
     NotLeader,
     SessionExpired,
+    /// The key is not in the range of the requested Raft group, or the group does not exist. The
+    /// client should refresh its range descriptor table.
+    WrongRange,
 }
 
-/// A Raft-based FeatherKV.
+/// A Raft-based FeatherKV. The key space is split into ranges, each replicated by its own Raft
+/// group, and the node runs a replica of every group. Ranges are split when they grow too large or
+/// too busy, and merged with their right-hand neighbor when both are small and idle.
 pub struct FeatherKV {
+    /// The node's replicas and range descriptor table, shared with the background tasks.
+    replicas: Arc<Replicas>,
+}
+
+/// Opens the state machine and log store of a group's local replica, given the group ID.
+type OpenGroup = Box<dyn FnMut(u64) -> Result<(Box<dyn State>, Box<dyn LogStore>)> + Send>;
+
+/// The local replicas of the node's Raft groups, and the range descriptor table routing keys to
+/// them. Both change as ranges are split and merged.
+struct Replicas {
+    me: u64,
+    peers: BTreeMap<u64, String>,
+    opts: Options,
+    split: SplitOptions,
+    /// Routes the groups' Raft RPCs to the local replicas.
+    router: RaftRouter,
+    open_group: Mutex<OpenGroup>,
+    /// The channel the groups' drivers report range changes and stats on.
+    range_tx: mpsc::UnboundedSender<RangeEvent>,
+    /// The local replica of each range's Raft group, by group ID.
+    groups: RwLock<BTreeMap<u64, Arc<Group>>>,
+    /// The range descriptor table, mapping keys to Raft groups.
+    ranges: RwLock<RangeTable>,
+    /// The groups merged into their left-hand neighbor, whose replicas must not be created again.
+    merged: Mutex<HashSet<u64>>,
+    /// Whether this node is merging two ranges. Merges run one at a time.
+    merging: AtomicBool,
+    /// Cancelled when the node shuts down, stopping the Raft server and the background tasks.
+    shutdown: CancellationToken,
+}
+
+/// The local replica of a range's Raft group.
+struct Group {
+    group_id: u64,
     /// The underlying Raft node.
     node: Node,
-    /// The next session ID, incrementing from 1.
-    next_session_id: Arc<Mutex<u64>>,
-    /// The channel to receive registration result from.
-    registration_status: Arc<Mutex<HashMap<u64, mpsc::UnboundedSender<mpsc::UnboundedSender<Task>>>>>,
-    /// The sending channels of the ongoing sessions.
-    session_txs: Arc<Mutex<HashMap<u64, mpsc::UnboundedSender<Task>>>>,
+    /// The callers waiting for their registrations and mutations to be applied.
+    waiters: Waiters,
+    /// The channel to send linearizable reads to the driver.
+    read_tx: mpsc::UnboundedSender<ReadRequest>,
+    /// The thread driving the state machine, joined on shutdown.
+    driver: Mutex<Option<std::thread::JoinHandle<Result<()>>>>,
 }
 
 impl FeatherKV {
-    /// Creates a new Raft FeatherKV. Spawns one background task to serve the Raft node and one to
-    /// drive the state machine. Assumes that the caller will be long running.
+    /// Creates a new Raft FeatherKV, with a Raft group for each range in `ranges`. The state machine
+    /// and log store of each group are opened by `open_group`, given the group ID, including
+    /// those of the groups created later by splits. The groups' Raft RPCs are served together on
+    /// the node's address in `peers`. Assumes that the caller will be long running. If `join` is
+    /// set, the node waits to be added to each group of an existing cluster, instead of
+    /// bootstrapping a cluster of `peers`.
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         me: u64,
-        peers: Vec<String>,
-        state: Box<dyn State>,
-        log_store: Box<dyn LogStore>,
+        peers: BTreeMap<u64, String>,
+        join: bool,
+        opts: Options,
+        ranges: RangeTable,
+        split: SplitOptions,
+        open_group: impl FnMut(u64) -> Result<(Box<dyn State>, Box<dyn LogStore>)> + Send + 'static,
     ) -> Result<Self> {
-        let (apply_tx, apply_rx) = mpsc::unbounded_channel();
-        let registration_status = Arc::new(Mutex::new(HashMap::new()));
+        let addr = peers.get(&me)
+            .ok_or_else(|| Error::Config(format!("No address for node {}", me)))?
+            .clone();
+        let (range_tx, range_rx) = mpsc::unbounded_channel();
+        let replicas = Arc::new(Replicas {
+            me,
+            peers,
+            opts,
+            split,
+            router: RaftRouter::new(),
+            open_group: Mutex::new(Box::new(open_group)),
+            range_tx,
+            groups: RwLock::new(BTreeMap::new()),
+            ranges: RwLock::new(ranges.clone()),
+            merged: Mutex::new(HashSet::new()),
+            merging: AtomicBool::new(false),
+            shutdown: CancellationToken::new(),
+        });
+        for range in ranges.ranges() {
+            replicas.start_group(range.clone(), join, None)?;
+        }
 
-        let node = Node::new(me, peers, apply_tx, log_store).await?;
-        let driver = Driver::new(node.clone(), state, apply_rx, registration_status.clone());
+        // A replica that missed a split, e.g. because it caught up from a snapshot, is created
+        // once the new group's leader contacts it, and then receives a snapshot of the range.
+        let weak = Arc::downgrade(&replicas);
+        replicas.router.on_unknown_group(move |group_id| match weak.upgrade() {
+            Some(replicas) => replicas.create_replica(group_id),
+            None => Ok(None),
+        })?;
+        replicas.router.serve(&addr, replicas.shutdown.clone())?;
+        tokio::spawn(replicas.clone().handle_events(range_rx));
+        Ok(Self { replicas })
+    }
 
-        tokio::spawn(driver.drive());
-        tokio::spawn(node.clone().serve());
-        
-        Ok(Self {
-            node,
-            next_session_id: Arc::new(Mutex::new(1)),
-            registration_status,
-            session_txs: Arc::new(Mutex::new(HashMap::new())),
+    /// Checks whether the node leads a group.
+    pub fn is_leader(&self, group_id: u64) -> Result<bool> {
+        match self.replicas.group(group_id)? {
+            Some(group) => group.node.is_leader(),
+            None => Ok(false),
+        }
+    }
+
+    /// Shuts the node down: stops serving Raft RPCs and the background tasks, shuts down each
+    /// replica, and waits for their state machines to apply the committed entries and flush.
+    pub async fn shutdown(&self) -> Result<()> {
+        self.replicas.shutdown.cancel();
+        let groups: Vec<_> = self.replicas.groups.read()?.values().cloned().collect();
+        for group in &groups {
+            group.node.shutdown()?;
+        }
+        for group in groups {
+            group.join().await?;
+        }
+        Ok(())
+    }
+
+    /// Returns the Raft status of each of the node's replicas, by group ID.
+    pub fn status(&self) -> Result<BTreeMap<u64, Status>> {
+        let groups = self.replicas.groups.read()?.clone();
+        groups.into_iter().map(|(group_id, group)| Ok((group_id, group.node.status()?))).collect()
+    }
+
+    /// Reports the node to the placement driver, given the FeatherKV addresses of the placement
+    /// driver's nodes, the address this node serves FeatherKV requests on, and the maximum number
+    /// of replicas it may hold (0 for unlimited). Spawns a task that sends a heartbeat with the
+    /// node's replicas every `HEARTBEAT_INTERVAL`, and drops the replicas that the placement
+    /// driver has removed from their groups.
+    pub fn report_to(&self, placement: BTreeMap<u64, String>, kv_addr: String, capacity: u64) {
+        tokio::spawn(self.replicas.clone().heartbeat(placement, kv_addr, capacity));
+    }
+
+    /// Returns the group of a request for a key, if the key is in the group's range.
+    fn route(&self, group_id: u64, key: &[u8]) -> Result<Option<Arc<Group>>> {
+        if !self.replicas.ranges.read()?.get(group_id).is_some_and(|range| range.contains(key)) {
+            return Ok(None);
+        }
+        self.replicas.group(group_id)
+    }
+
+    /// Replies to an admin request for a group with the result of a cluster change.
+    fn admin(&self, group_id: u64, change: impl FnOnce(&Node) -> Result<()>) -> Result<AdminReply> {
+        let group = match self.replicas.group(group_id)? {
+            Some(group) => group,
+            None => return Ok(AdminReply { status: Self::serialize(&RpcStatus::WrongRange)?, leader_hint: 0 }),
+        };
+        let status = match change(&group.node) {
+            Err(Error::NotLeader) => RpcStatus::NotLeader,
+            Err(e) => return Err(e),
+            Ok(()) => RpcStatus::Ok,
+        };
+        Ok(AdminReply {
+            status: Self::serialize(&status)?,
+            leader_hint: group.node.leader_id()?,
+        })
+    }
+
+    /// Returns a reply rejecting an execution request for a key outside the group's range.
+    fn wrong_range_reply() -> Result<ExecutionReply> {
+        Ok(ExecutionReply {
+            status: Self::serialize(&RpcStatus::WrongRange)?,
+            response: vec![],
+            leader_hint: 0,
+            leader_addr: String::new(),
+            index: 0,
         })
     }
 
@@ -109,280 +308,479 @@ impl FeatherKV {
     }
 }
 
-#[tonic::async_trait]
-impl FeatherKv for FeatherKV {
-    async fn register(&self, _request: Request<RegistrationRequest>) -> RpcResult<RegistrationReply> {
-        let not_leader_reply = RegistrationReply {
-            status: Self::serialize(&RpcStatus::NotLeader)?,
-            session_id: 0,
-            leader_hint: self.node.leader_id()?,
-        };
+impl Replicas {
+    /// Returns the local replica of a group, if any.
+    fn group(&self, group_id: u64) -> Result<Option<Arc<Group>>> {
+        Ok(self.groups.read()?.get(&group_id).cloned())
+    }
 
-        // If the node is not the leader, returns `NotLeader` to the client.
-        if !self.node.is_leader()? {
-            return Ok(Response::new(not_leader_reply));
+    /// Starts the local replica of a range's group, unless it already exists. If given, the
+    /// group's log starts from `snapshot` instead of being empty.
+    fn start_group(&self, range: RangeDescriptor, join: bool, snapshot: Option<Snapshot>) -> Result<Arc<Group>> {
+        let mut groups = self.groups.write()?;
+        if let Some(group) = groups.get(&range.group_id) {
+            return Ok(group.clone());
         }
-
-        // Fetches and increments the next session ID.
-        let session_id = {
-            let mut guard = self.next_session_id.lock().unwrap();
-            *guard += 1;
-            *guard - 1
+        let group_id = range.group_id;
+        let (state, log_store) = (self.open_group.lock()?)(group_id)?;
+        let log_store = match snapshot {
+            Some(snapshot) => {
+                let mut log = Log::new(log_store)?;
+                log.install(snapshot)?;
+                log.store
+            },
+            None => log_store,
         };
+        let group = Arc::new(Group::start(self, range, join, state, log_store)?);
+        groups.insert(group_id, group.clone());
+        Ok(group)
+    }
 
-        // The channel to receive the registration result from the driver.
-        let (task_ch_tx, mut task_ch_rx) = mpsc::unbounded_channel();
-        {
-            self.registration_status.lock().unwrap().insert(session_id, task_ch_tx);
+    /// Creates an empty replica of a group unknown to the node, which waits for the group's leader
+    /// to send it a snapshot. Returns None for groups that were merged away.
+    fn create_replica(&self, group_id: u64) -> Result<Option<Node>> {
+        if self.merged.lock()?.contains(&group_id) {
+            return Ok(None);
         }
+        // The range is unknown until the snapshot arrives, so the replica owns no keys until then.
+        let range = RangeDescriptor { group_id, start: vec![], end: Some(vec![]), generation: 0 };
+        Ok(Some(self.start_group(range, true, None)?.node.clone()))
+    }
 
-        // Starts the command. If the node has lost leadership, replies `NotLeader`.
-        // Returns other errors to the client as internal errors.
-        match self.node.start(Command::Registration { session_id }) {
-            Err(Error::NotLeader) => {
-                return Ok(Response::new(not_leader_reply));
-            },
-            Err(e) => return Err(e.into()),
-            Ok(_) => { },
+    /// Removes the local replica of a group, e.g. after it was removed from the group.
+    async fn drop_group(&self, group_id: u64) -> Result<()> {
+        self.router.remove(group_id)?;
+        let group = self.groups.write()?.remove(&group_id);
+        if let Some(group) = group {
+            println!("Node {} dropping its replica of group {}", self.me, group_id);
+            self.stop_group(&group).await;
         }
+        Ok(())
+    }
+
+    /// Shuts down a replica removed from the groups, and waits for its driver to flush and drop
+    /// the state machine. Failures are only logged, since the replica is gone either way.
+    async fn stop_group(&self, group: &Group) {
+        if let Err(err) = group.stop().await {
+            println!("Node {} failed to stop its replica of group {}: {:?}", self.me, group.group_id, err);
+        }
+    }
 
-        // Waits for the replica group to apply the registration command. Retries if timeout.
+    /// Sends heartbeats to the placement driver, see [`FeatherKV::report_to`]. Failed heartbeats,
+    /// e.g. while the placement driver elects a leader, are logged and retried on the next tick,
+    /// so that the store is not marked dead. Only returns once the node is shut down.
+    async fn heartbeat(self: Arc<Self>, placement: BTreeMap<u64, String>, kv_addr: String, capacity: u64) -> Result<()> {
+        let mut client = Client::new(placement).await?;
+        let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
         loop {
             tokio::select! {
-                task_tx = task_ch_rx.recv() => {
-                    let task_tx = task_tx.unwrap();
-                    self.session_txs.lock().unwrap().insert(session_id, task_tx);
-
-                    // Session registered successfully. Returns `Ok` to the client.
-                    let ok_reply = RegistrationReply {
-                        status: Self::serialize(&RpcStatus::Ok)?,
-                        session_id,
-                        leader_hint: self.node.leader_id()?,
-                    };
-                    return Ok(Response::new(ok_reply));
-                },
-
-                _ = tokio::time::sleep(tokio::time::Duration::from_secs(1)) => {
-                    match self.node.start(Command::Registration { session_id }) {
-                        Err(Error::NotLeader) => return Ok(Response::new(not_leader_reply)),
-                        Err(e) => return Err(e.into()),
-                        Ok(_) => { },
-                    }
-                }
+                _ = interval.tick() => { },
+                _ = self.shutdown.cancelled() => return Ok(()),
+            }
+            if let Err(err) = self.send_heartbeat(&mut client, &kv_addr, capacity).await {
+                println!("Node {} failed to heartbeat the placement driver: {:?}", self.me, err);
             }
         }
     }
 
-    async fn mutate(&self, request: Request<ExecutionRequest>) -> RpcResult<ExecutionReply> {
-        let ExecutionRequest { session_id, sequence_number, operation } = request.into_inner();
-        let (reply_tx, reply_rx) = oneshot::channel();
-        let task = Task {
-            reply_tx,
-            command: Command::Mutation {
-                session_id,
-                sequence_number,
-                mutation: operation,
-            },
+    /// Sends a heartbeat with the node's replicas, and drops those the placement driver removed.
+    async fn send_heartbeat(&self, client: &mut Client, kv_addr: &str, capacity: u64) -> Result<()> {
+        let heartbeat = Heartbeat {
+            store: self.me,
+            kv_addr: kv_addr.to_string(),
+            raft_addr: self.peers[&self.me].clone(),
+            capacity,
+            replicas: self.reports()?,
         };
+        let dropped: Vec<u64> = FeatherKV::deserialize(
+            &client.mutate(FeatherKV::serialize(&PlacementMutation::Heartbeat(heartbeat))?).await?,
+        )?;
+        for group_id in dropped {
+            self.drop_group(group_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Reports the node's replicas to the placement driver.
+    fn reports(&self) -> Result<Vec<ReplicaReport>> {
+        let groups: Vec<_> = self.groups.read()?.values().cloned().collect();
+        let ranges = self.ranges.read()?;
+        groups.iter()
+            .map(|group| {
+                // A replica waiting for its first snapshot does not know its range yet.
+                let range = ranges.get(group.group_id).cloned().unwrap_or(RangeDescriptor {
+                    group_id: group.group_id,
+                    start: vec![],
+                    end: Some(vec![]),
+                    generation: 0,
+                });
+                Ok(ReplicaReport {
+                    range,
+                    leader: group.node.is_leader()?,
+                    membership: group.node.membership()?,
+                    membership_index: group.node.membership_index()?,
+                })
+            })
+            .collect()
+    }
 
-        {
-            // Sends the task to the corresponding session.
-            let mut session_txs = self.session_txs.lock().unwrap();
-            match session_txs.get_mut(&session_id) {
-                Some(session_tx) => {
-                    session_tx.send(task).unwrap();
+    /// Handles the range events reported by the local replicas' drivers: updates the range
+    /// descriptor table, creates the replicas of new ranges and removes those of merged ones, and
+    /// starts merges.
+    async fn handle_events(self: Arc<Self>, mut range_rx: mpsc::UnboundedReceiver<RangeEvent>) -> Result<()> {
+        let mut stats = HashMap::new();
+        loop {
+            let event = tokio::select! {
+                event = range_rx.recv() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+                _ = self.shutdown.cancelled() => break,
+            };
+            match event {
+                RangeEvent::Changed(range) => self.apply(range).await?,
+                RangeEvent::Split { left, right, snapshot } => {
+                    self.apply(left).await?;
+                    self.start_group(right.clone(), true, Some(snapshot))?;
+                    self.apply(right).await?;
                 },
-                None => {
-                    let reply = ExecutionReply {
-                        status: Self::serialize(&RpcStatus::SessionExpired)?,
-                        response: vec![],
-                        leader_hint: self.node.leader_id()?,
-                    };
-                    return Ok(Response::new(reply));
+                RangeEvent::Stats { group_id, size, load, frozen } => {
+                    stats.insert(group_id, (size, load, frozen));
+                    self.maybe_merge(group_id, &stats)?;
                 },
             }
         }
-        
-        // Waits for the session to reply.
-        let reply = reply_rx.await.unwrap();
-        Ok(Response::new(reply))
+        Ok(())
     }
 
-    async fn query(&self, request: Request<ExecutionRequest>) -> RpcResult<ExecutionReply> {
-        let ExecutionRequest { session_id, sequence_number, operation } = request.into_inner();
-        let (reply_tx, reply_rx) = oneshot::channel();
-        let task = Task {
-            reply_tx,
-            command: Command::Query {
-                session_id,
-                sequence_number,
-                query: operation,
-            },
-        };
+    /// Records a range descriptor in the table, and removes the replicas of the ranges merged
+    /// into it.
+    async fn apply(&self, range: RangeDescriptor) -> Result<()> {
+        let merged = self.ranges.write()?.apply(range);
+        for group_id in merged {
+            self.merged.lock()?.insert(group_id);
+            self.router.remove(group_id)?;
+            let group = self.groups.write()?.remove(&group_id);
+            if let Some(group) = group {
+                self.stop_group(&group).await;
+            }
+        }
+        Ok(())
+    }
 
-        {
-            // Sends the task to the corresponding session.
-            let mut session_txs = self.session_txs.lock().unwrap();
-            match session_txs.get_mut(&session_id) {
-                Some(session_tx) => {
-                    session_tx.send(task).unwrap();
-                },
-                None => {
-                    let reply = ExecutionReply {
-                        status: Self::serialize(&RpcStatus::SessionExpired)?,
-                        response: vec![],
-                        leader_hint: self.node.leader_id()?,
-                    };
-                    return Ok(Response::new(reply));
-                },
+    /// Merges a range's right-hand neighbor into it if both are small and idle, or if the
+    /// neighbor is frozen by an earlier merge that did not complete. This node must lead both
+    /// groups, since the merge proposes to both.
+    fn maybe_merge(self: &Arc<Self>, group_id: u64, stats: &HashMap<u64, (u64, u64, bool)>) -> Result<()> {
+        let SplitOptions { max_load, merge_size, .. } = self.split;
+        if merge_size == 0 || self.merging.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let right_id = {
+            let ranges = self.ranges.read()?;
+            match ranges.get(group_id).and_then(|range| range.end.as_deref()) {
+                Some(end) => ranges.lookup(end).group_id,
+                None => return Ok(()),
             }
+        };
+        let (left_size, left_load, right_size, right_load, right_frozen) = match (stats.get(&group_id), stats.get(&right_id)) {
+            (Some(&(left_size, left_load, false)), Some(&(right_size, right_load, right_frozen))) => {
+                (left_size, left_load, right_size, right_load, right_frozen)
+            },
+            _ => return Ok(()),
+        };
+        let idle = max_load == 0 || (left_load + right_load) * 2 < max_load;
+        let small = left_size + right_size < merge_size && idle;
+        if !small && !right_frozen {
+            return Ok(());
         }
-        
-        // Waits for the session to reply.
-        let reply = reply_rx.await.unwrap();
-        Ok(Response::new(reply))
+        let (left, right) = match (self.group(group_id)?, self.group(right_id)?) {
+            (Some(left), Some(right)) if left.node.is_leader()? && right.node.is_leader()? => (left, right),
+            _ => return Ok(()),
+        };
+
+        self.merging.store(true, Ordering::SeqCst);
+        let replicas = self.clone();
+        tokio::spawn(async move {
+            let result = Group::merge(&left, &right).await;
+            replicas.merging.store(false, Ordering::SeqCst);
+            result
+        });
+        Ok(())
     }
 }
 
-#[derive(Debug)]
-/// A task for a key-value session.
-pub struct Task {
-    reply_tx: oneshot::Sender<ExecutionReply>,
-    command: Command,
-}
+impl Group {
+    /// Starts the local replica of a range's Raft group. Spawns background tasks to serve the Raft
+    /// node and to advance the replicated clock, and a thread to drive the state machine.
+    fn start(
+        replicas: &Replicas,
+        range: RangeDescriptor,
+        join: bool,
+        state: Box<dyn State>,
+        log_store: Box<dyn LogStore>,
+    ) -> Result<Self> {
+        let Replicas { me, opts, .. } = *replicas;
+        let group_id = range.group_id;
+        let (apply_tx, apply_rx) = mpsc::channel(APPLY_CHANNEL_CAPACITY);
+        let (read_tx, read_rx) = mpsc::unbounded_channel();
+        let waiters: Waiters = Arc::new(Mutex::new(HashMap::new()));
+
+        let node = Node::grouped(me, group_id, &replicas.peers, join, opts, apply_tx, log_store, &replicas.router)?;
+        let driver = Driver::new(node.clone(), state, apply_rx, read_rx, waiters.clone())
+            .with_range(range, replicas.split, replicas.range_tx.clone());
+
+        // The state machine may block, so it is driven on a dedicated thread rather than on the
+        // runtime's workers.
+        let runtime = tokio::runtime::Handle::current();
+        let driver = std::thread::Builder::new()
+            .name(format!("raft-apply-{}-{}", me, group_id))
+            .spawn(move || runtime.block_on(driver.drive()))?;
+        tokio::spawn(node.clone().serve());
+        tokio::spawn(Self::tick(node.clone(), opts.session_clock_interval));
 
-/// A Raft-based key-value session.
-pub struct Session {
-    /// The underlying Raft node.
-    node: Node,
-    /// The session ID.
-    session_id: u64,
-    /// The last applied sequence number.
-    last_applied_sequence_number: u64,
-    /// The result of the last operation.
-    stored_result: Option<Result<Vec<u8>>>,
-    /// The channel to receive task from.
-    task_rx: mpsc::UnboundedReceiver<Task>,
-    /// The channel to receive state machine result from.
-    result_rx: mpsc::UnboundedReceiver<ApplyResult>,
-}
+        Ok(Self { group_id, node, waiters, read_tx, driver: Mutex::new(Some(driver)) })
+    }
 
-impl Session {
-    /// Creates a new Raft session.
-    pub fn new(
-        node: Node,
-        session_id: u64,
-        task_rx: mpsc::UnboundedReceiver<Task>,
-        result_rx: mpsc::UnboundedReceiver<ApplyResult>,
-    ) -> Self {
-        Self {
-            node,
-            session_id,
-            last_applied_sequence_number: 0,
-            stored_result: None,
-            task_rx,
-            result_rx,
-        }
+    /// Shuts down the replica, and waits for its driver to exit, see [`Group::join`].
+    async fn stop(&self) -> Result<()> {
+        self.node.shutdown()?;
+        self.join().await
     }
 
-    /// Starts the Raft session.
-    pub async fn serve(mut self) -> Result<()> {
-        while let Some(Task { reply_tx, command }) = self.task_rx.recv().await {
-            match command {
-                Command::Mutation { session_id, sequence_number, .. } 
-                | Command::Query { session_id, sequence_number, .. } => {
-                    assert!(sequence_number >= self.last_applied_sequence_number);
-                    assert_eq!(session_id, self.session_id);
-
-                    let not_leader_reply = ExecutionReply {
-                        status: Self::serialize(&RpcStatus::NotLeader)?,
-                        response: vec![],
-                        leader_hint: self.node.leader_id()?,
-                    };
-
-                    // If the command is already executed, returns the stored result.
-                    if sequence_number == self.last_applied_sequence_number {
-                        let reply = ExecutionReply {
-                            status: Self::serialize(&RpcStatus::Ok)?,
-                            response: Self::serialize(&self.stored_result
-                                .clone()
-                                .ok_or_else(|| Error::Internal(format!(
-                                    "No stored result for session {}",
-                                    session_id,
-                                )))?)?,
-                            leader_hint: self.node.leader_id()?,
-                        };
-                        reply_tx.send(reply).unwrap();
-                        continue;
-                    }
-
-                    // Starts the command. If the node has lost leadership, replies `NotLeader`.
-                    // Returns other errors to the client as internal errors.
-                    match self.node.start(command.clone()) {
-                        Err(Error::NotLeader) => {
-                            reply_tx.send(not_leader_reply).unwrap();
-                            break;
-                        },
-                        Err(e) => return Err(e),
-                        Ok(_) => { },
-                    }
-
-                    // Waits for the replica group to apply the execution command. Retries if timeout.
-                    loop {
-                        tokio::select! {
-                            Some(apply_result) = self.result_rx.recv() => {
-                                // Discards the result if it is outdated.
-                                if apply_result.sequence_number < sequence_number {
-                                    continue;
-                                }
-
-                                // Updates the corresponding metadata.
-                                self.stored_result = Some(apply_result.result);
-                                self.last_applied_sequence_number = sequence_number;
-
-                                // Sends the reply to the server's RPC handler.
-                                let reply = ExecutionReply {
-                                    status: Self::serialize(&RpcStatus::Ok)?,
-                                    response: Self::serialize(&self.stored_result.clone().unwrap())?,
-                                    leader_hint: self.node.leader_id()?,
-                                };
-                                reply_tx.send(reply).unwrap();
-                                break;
-                            },
-
-                            _ = tokio::time::sleep(tokio::time::Duration::from_secs(1)) => {
-                                match self.node.start(command.clone()) {
-                                    Err(Error::NotLeader) => {
-                                        reply_tx.send(not_leader_reply).unwrap();
-                                        break;
-                                    },
-                                    Err(e) => return Err(e),
-                                    Ok(_) => { },
-                                }
-                            }
-                        }
-                    }
-                },
+    /// Waits for the driver of a replica that was shut down to apply the last committed entries,
+    /// flush the state machine and drop it.
+    async fn join(&self) -> Result<()> {
+        let driver = self.driver.lock()?.take();
+        if let Some(driver) = driver {
+            tokio::task::spawn_blocking(move || driver.join())
+                .await
+                .map_err(|e| Error::Internal(e.to_string()))?
+                .map_err(|_| Error::Internal(format!("Driver of group {} panicked", self.group_id)))??;
+        }
+        Ok(())
+    }
 
-                Command::Registration { .. } => {
-                    return Err(Error::Internal(format!(
-                        "Unexpected registration command {:?}",
-                        command,
-                    )));
+    /// Advances the replicated clock while this node is the leader, until it is shut down. Only
+    /// the leader proposes ticks, so the clock advances at roughly one tick per `interval` across
+    /// leader changes.
+    async fn tick(node: Node, interval: Duration) -> Result<()> {
+        let mut interval = tokio::time::interval(interval);
+        let stopped = node.stopped();
+        tokio::pin!(stopped);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => { },
+                _ = &mut stopped => return Ok(()),
+            }
+            if node.is_leader()? {
+                match node.propose(Command::Clock).await {
+                    Ok(_) | Err(Error::NotLeader) => { },
+                    Err(e) => return Err(e),
                 }
             }
         }
+    }
+
+    /// Proposes a session command and waits for the state machine to apply it. Returns None if the
+    /// node is not the leader, or if the command is not applied in time.
+    async fn execute(&self, command: Command, key: (u64, u64)) -> Result<Option<ApplyResult>> {
+        let (result_tx, result_rx) = oneshot::channel();
+        self.waiters.lock()?.insert(key, result_tx);
+        let result = match self.node.propose(command).await {
+            Ok(_) => match tokio::time::timeout(APPLY_TIMEOUT, result_rx).await {
+                Ok(result) => Ok(result.ok()),
+                Err(_) => Ok(None),
+            },
+            Err(Error::NotLeader) => Ok(None),
+            Err(e) => Err(e),
+        };
+        self.waiters.lock()?.remove(&key);
+        result
+    }
+
+    /// Returns a reply rejecting an execution request because this node is not the leader.
+    fn not_leader_reply(&self) -> Result<ExecutionReply> {
+        Ok(ExecutionReply {
+            status: FeatherKV::serialize(&RpcStatus::NotLeader)?,
+            response: vec![],
+            leader_hint: self.node.leader_id()?,
+            leader_addr: self.node.leader_addr()?,
+            index: 0,
+        })
+    }
 
+    /// Merges the right-hand neighbor of a range into it: freezes the neighbor, then proposes its
+    /// frozen state to the left-hand group. A merge that fails after the freeze is retried by the
+    /// next check, which merges frozen neighbors regardless of their size. A freeze refused by the
+    /// neighbor's state machine is retried by the next check that finds both ranges small and idle.
+    async fn merge(left: &Group, right: &Group) -> Result<()> {
+        let data = match right.execute(Command::Freeze, (0, 0)).await? {
+            Some(ApplyResult { result: Some(Ok(data)), .. }) => data,
+            _ => return Ok(()),
+        };
+        left.execute(Command::Merge { group_id: right.group_id, data }, (0, 0)).await?;
         Ok(())
     }
+}
 
-    /// Serializes a value for the Raft session.
-    fn serialize<V: Serialize>(value: &V) -> Result<Vec<u8>> {
-        Ok(bincode::serialize(value)?)
+#[tonic::async_trait]
+impl FeatherKv for FeatherKV {
+    async fn register(&self, request: Request<RegistrationRequest>) -> RpcResult<RegistrationReply> {
+        let RegistrationRequest { group_id } = request.into_inner();
+        let group = match self.replicas.group(group_id)? {
+            Some(group) => group,
+            None => return Ok(Response::new(RegistrationReply {
+                status: Self::serialize(&RpcStatus::WrongRange)?,
+                session_id: 0,
+                leader_hint: 0,
+                leader_addr: String::new(),
+            })),
+        };
+        let not_leader_reply = RegistrationReply {
+            status: Self::serialize(&RpcStatus::NotLeader)?,
+            session_id: 0,
+            leader_hint: group.node.leader_id()?,
+            leader_addr: group.node.leader_addr()?,
+        };
+
+        // If the node is not the leader, returns `NotLeader` to the client.
+        if !group.node.is_leader()? {
+            return Ok(Response::new(not_leader_reply));
+        }
+
+        // Session IDs are random, so that they are unique across leaders without coordination.
+        let session_id = loop {
+            let session_id = rand::random();
+            if session_id != 0 {
+                break session_id;
+            }
+        };
+
+        // Waits for the registration to be applied. If the node has lost leadership or the
+        // registration is not applied in time, replies `NotLeader` so that the client retries.
+        match group.execute(Command::Registration { session_id }, (session_id, 0)).await? {
+            Some(_) => Ok(Response::new(RegistrationReply {
+                status: Self::serialize(&RpcStatus::Ok)?,
+                session_id,
+                leader_hint: group.node.leader_id()?,
+                leader_addr: group.node.leader_addr()?,
+            })),
+            None => Ok(Response::new(not_leader_reply)),
+        }
     }
 
-    /// Deserializes a value from the Raft session.
-    fn deserialize<'a, V: Deserialize<'a>>(bytes: &'a [u8]) -> Result<V> {
-        Ok(bincode::deserialize(bytes)?)
+    async fn mutate(&self, request: Request<ExecutionRequest>) -> RpcResult<ExecutionReply> {
+        let ExecutionRequest { session_id, sequence_number, lowest_unacked, operation, group_id, key, .. } =
+            request.into_inner();
+        let group = match self.route(group_id, &key)? {
+            Some(group) => group,
+            None => return Ok(Response::new(Self::wrong_range_reply()?)),
+        };
+        let mut reply = group.not_leader_reply()?;
+        if !group.node.is_leader()? {
+            return Ok(Response::new(reply));
+        }
+
+        // Deduplication happens in the replicated session table, so a retry is proposed again and
+        // answered with the original result once applied.
+        let command = Command::Mutation { session_id, sequence_number, lowest_unacked, key, mutation: operation };
+        match group.execute(command, (session_id, sequence_number)).await? {
+            Some(ApplyResult { wrong_range: true, .. }) => return Ok(Response::new(Self::wrong_range_reply()?)),
+            Some(ApplyResult { result: None, .. }) => {
+                reply.status = Self::serialize(&RpcStatus::SessionExpired)?;
+            },
+            Some(ApplyResult { index, result: Some(result), .. }) => {
+                reply.status = Self::serialize(&RpcStatus::Ok)?;
+                reply.response = Self::serialize(&result)?;
+                reply.index = index;
+            },
+            None => { },
+        }
+        Ok(Response::new(reply))
     }
-}
\ No newline at end of file
+
+    /// Serves a query without appending it to the Raft log. Queries are read-only, so they need
+    /// no session deduplication. Linearizable queries are served by the leader via the ReadIndex
+    /// protocol; other queries are served by any replica once it has applied the required index,
+    /// and are rejected with `NotLeader` if it cannot do so in time.
+    async fn query(&self, request: Request<ExecutionRequest>) -> RpcResult<ExecutionReply> {
+        let ExecutionRequest { operation, consistency, group_id, key, .. } = request.into_inner();
+        let group = match self.route(group_id, &key)? {
+            Some(group) => group,
+            None => return Ok(Response::new(Self::wrong_range_reply()?)),
+        };
+        let consistency = match consistency.is_empty() {
+            true => Consistency::Linearizable,
+            false => Self::deserialize(&consistency)?,
+        };
+        let not_leader_reply = group.not_leader_reply()?;
+        // Witnesses have no state machine to query.
+        if group.node.is_witness()? {
+            return Ok(Response::new(not_leader_reply));
+        }
+
+        let index = match consistency {
+            Consistency::Linearizable => group.node.read_index().await,
+            Consistency::MaxStaleness(max_staleness) => group.node.stale_read_index(max_staleness),
+            Consistency::AtLeast(index) => Ok(index),
+        };
+        let index = match index {
+            Err(Error::NotLeader) => return Ok(Response::new(not_leader_reply)),
+            Err(e) => return Err(e.into()),
+            Ok(index) => index,
+        };
+
+        // Waits for the state machine to apply the read index and run the query.
+        let (reply_tx, reply_rx) = oneshot::channel();
+        group.read_tx.send(ReadRequest { index, key, query: operation, reply_tx }).map_err(Error::from)?;
+        let (index, result) = match consistency {
+            Consistency::Linearizable => reply_rx.await.map_err(Error::from)?,
+            _ => match tokio::time::timeout(STALE_READ_TIMEOUT, reply_rx).await {
+                Ok(reply) => reply.map_err(Error::from)?,
+                Err(_) => return Ok(Response::new(not_leader_reply)),
+            },
+        };
+        let result = match result {
+            Some(result) => result,
+            None => return Ok(Response::new(Self::wrong_range_reply()?)),
+        };
+
+        let reply = ExecutionReply {
+            status: Self::serialize(&RpcStatus::Ok)?,
+            response: Self::serialize(&result)?,
+            leader_hint: group.node.leader_id()?,
+            leader_addr: group.node.leader_addr()?,
+            index,
+        };
+        Ok(Response::new(reply))
+    }
+
+    async fn add_node(&self, request: Request<AddNodeRequest>) -> RpcResult<AdminReply> {
+        let AddNodeRequest { id, addr, group_id, witness } = request.into_inner();
+        Ok(Response::new(self.admin(group_id, |node| match witness {
+            true => node.add_witness(id, addr),
+            false => node.add_node(id, addr),
+        })?))
+    }
+
+    async fn remove_node(&self, request: Request<RemoveNodeRequest>) -> RpcResult<AdminReply> {
+        let RemoveNodeRequest { id, group_id } = request.into_inner();
+        Ok(Response::new(self.admin(group_id, |node| node.remove_node(id))?))
+    }
+
+    async fn transfer_leader(&self, request: Request<TransferLeaderRequest>) -> RpcResult<AdminReply> {
+        let TransferLeaderRequest { id, group_id } = request.into_inner();
+        Ok(Response::new(self.admin(group_id, |node| node.transfer_leader(id))?))
+    }
+
+    async fn get_ranges(&self, _request: Request<RangesRequest>) -> RpcResult<RangesReply> {
+        let ranges = Self::serialize(&*self.replicas.ranges.read().map_err(Error::from)?)?;
+        Ok(Response::new(RangesReply { ranges }))
+    }
+
+    async fn status(&self, _request: Request<StatusRequest>) -> RpcResult<StatusReply> {
+        let groups = Self::serialize(&FeatherKV::status(self)?)?;
+        Ok(Response::new(StatusReply { groups }))
+    }
+}
@@ -0,0 +1,234 @@
+//! An in-process simulated network for Raft nodes, for reproducible tests with fault injection.
+//!
+//! Nodes created with [`Node::simulated`] send messages to each other through an in-memory
+//! [`Transport`], which calls the receiving node's handlers directly. Every message is subject to
+//! faults drawn from a seeded random number generator: it may be dropped, delayed (which reorders
+//! it relative to other messages), or delivered twice, and its reply may be lost. Links between
+//! nodes can also be cut to form partitions. The nodes' election timeouts are drawn from the same
+//! seed.
+//!
+//! Only built for tests and with the `sim` feature, which the integration tests enable.
+//!
+//! Run on a single-threaded runtime with a paused clock, a simulation advances in virtual time
+//! rather than waiting for timeouts. Since the fault schedule is derived from the seed, rerunning a
+//! failing seed usually reproduces the failure, although the tokio scheduler itself may still
+//! interleave tasks differently.
+
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use tonic::Request;
+
+use crate::error::{Error, Result, RpcResult};
+use crate::proto::raft::raft_service_server::RaftService;
+use crate::proto::raft::{RequestVoteArgs, RequestVoteReply, AppendEntriesArgs, AppendEntriesReply};
+use crate::proto::raft::{InstallSnapshotArgs, InstallSnapshotReply, TimeoutNowArgs, TimeoutNowReply};
+use super::{Node, Transport};
+
+/// The faults injected into a simulated network.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Faults {
+    /// The probability that a message is dropped.
+    pub drop: f64,
+    /// The probability that the reply to a delivered message is dropped.
+    pub drop_reply: f64,
+    /// The probability that a message is delivered twice. Snapshots are never duplicated.
+    pub duplicate: f64,
+    /// The maximum delay of a message. Delays are drawn uniformly up to this.
+    pub max_delay: Duration,
+}
+
+/// A simulated network connecting Raft nodes in-process.
+#[derive(Clone)]
+pub struct SimNetwork {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    seed: u64,
+    rng: SmallRng,
+    faults: Faults,
+    /// The cut links, as (from, to) pairs.
+    cut: HashSet<(u64, u64)>,
+    /// The nodes on the network, by ID.
+    nodes: HashMap<u64, Node>,
+}
+
+/// The fate of a message, decided when it is sent.
+struct Fate {
+    deliver: bool,
+    delay: Duration,
+    duplicate: bool,
+    drop_reply: bool,
+}
+
+impl SimNetwork {
+    /// Creates a simulated network without faults, whose randomness is derived from the seed.
+    pub fn new(seed: u64) -> Self {
+        let inner = Inner {
+            seed,
+            rng: SmallRng::seed_from_u64(seed),
+            faults: Faults::default(),
+            cut: HashSet::new(),
+            nodes: HashMap::new(),
+        };
+        Self { inner: Arc::new(Mutex::new(inner)) }
+    }
+
+    /// Sets the faults injected into subsequent messages.
+    pub fn set_faults(&self, faults: Faults) -> Result<()> {
+        self.inner.lock()?.faults = faults;
+        Ok(())
+    }
+
+    /// Partitions the network into groups, cutting all links between nodes in different groups.
+    /// Nodes not in any group are isolated. Replaces any previous partition.
+    pub fn partition(&self, groups: &[Vec<u64>]) -> Result<()> {
+        let mut inner = self.inner.lock()?;
+        let group_of = |id: u64| groups.iter().position(|group| group.contains(&id));
+        let ids = inner.nodes.keys().copied().collect::<Vec<_>>();
+        inner.cut.clear();
+        for from in ids.iter().copied() {
+            for to in ids.iter().copied() {
+                if from != to && (group_of(from).is_none() || group_of(from) != group_of(to)) {
+                    inner.cut.insert((from, to));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Cuts a one-way link, dropping all messages and replies sent from one node to another.
+    pub fn cut(&self, from: u64, to: u64) -> Result<()> {
+        self.inner.lock()?.cut.insert((from, to));
+        Ok(())
+    }
+
+    /// Heals all partitions and cut links.
+    pub fn heal(&self) -> Result<()> {
+        self.inner.lock()?.cut.clear();
+        Ok(())
+    }
+
+    /// Adds a node to the network.
+    pub(super) fn register(&self, id: u64, node: Node) -> Result<()> {
+        self.inner.lock()?.nodes.insert(id, node);
+        Ok(())
+    }
+
+    /// Returns the seed for a node's own randomness.
+    pub(super) fn node_seed(&self, id: u64) -> Result<u64> {
+        Ok(self.inner.lock()?.seed ^ id.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15))
+    }
+
+    /// Returns the transport for a node's messages to the other nodes on the network. Nodes need
+    /// not be on the network yet when the transport is created.
+    pub(super) fn transport(&self, from: u64) -> SimTransport {
+        SimTransport { network: self.clone(), from }
+    }
+
+    /// Returns a node on the network.
+    fn node(&self, id: u64) -> Result<Node> {
+        self.inner.lock()?.nodes.get(&id).cloned()
+            .ok_or_else(|| Error::Internal(format!("Node {} is not on the network", id)))
+    }
+
+    /// Decides the fate of a message sent from one node to another.
+    fn fate(&self, from: u64, to: u64) -> Result<Fate> {
+        let mut inner = self.inner.lock()?;
+        let Faults { drop, drop_reply, duplicate, max_delay } = inner.faults;
+        let cut = inner.cut.contains(&(from, to));
+        let rng = &mut inner.rng;
+        Ok(Fate {
+            deliver: !rng.gen_bool(drop) && !cut,
+            delay: rng.gen_range(Duration::ZERO..=max_delay),
+            duplicate: rng.gen_bool(duplicate),
+            drop_reply: rng.gen_bool(drop_reply),
+        })
+    }
+
+    /// Checks whether a link is cut.
+    fn is_cut(&self, from: u64, to: u64) -> Result<bool> {
+        Ok(self.inner.lock()?.cut.contains(&(from, to)))
+    }
+}
+
+/// The transport of a node on a simulated network, which injects faults into the messages it
+/// sends before handing them to the receiving node in-process.
+pub(super) struct SimTransport {
+    network: SimNetwork,
+    from: u64,
+}
+
+impl SimTransport {
+    /// Delivers a message to a node according to its fate, and returns the reply.
+    async fn deliver<T, R, F, Fut>(&self, to: u64, args: T, call: F) -> Result<R>
+    where
+        T: Clone + Send,
+        F: Fn(Node, T) -> Fut + Send + Sync,
+        Fut: Future<Output = RpcResult<R>> + Send,
+    {
+        let node = self.network.node(to)?;
+        let fate = self.network.fate(self.from, to)?;
+        tokio::time::sleep(fate.delay).await;
+        if !fate.deliver {
+            return Err(Error::Internal("Message dropped by the simulated network".into()));
+        }
+        if fate.duplicate {
+            let _ = call(node.clone(), args.clone()).await;
+        }
+        let reply = call(node, args).await;
+        if fate.drop_reply || self.network.is_cut(to, self.from)? {
+            return Err(Error::Internal("Reply dropped by the simulated network".into()));
+        }
+        Ok(reply?.into_inner())
+    }
+}
+
+#[tonic::async_trait]
+impl Transport for SimTransport {
+    fn set_peers(&self, _: &HashMap<u64, String>) -> Result<()> {
+        Ok(())
+    }
+
+    async fn request_vote(&self, to: u64, args: RequestVoteArgs) -> Result<RequestVoteReply> {
+        self.deliver(to, args, |node, args| async move {
+            node.request_vote(Request::new(args)).await
+        }).await
+    }
+
+    async fn append_entries(&self, to: u64, args: AppendEntriesArgs) -> Result<AppendEntriesReply> {
+        self.deliver(to, args, |node, args| async move {
+            node.append_entries(Request::new(args)).await
+        }).await
+    }
+
+    /// Snapshots are never duplicated.
+    async fn install_snapshot(
+        &self,
+        to: u64,
+        chunks: Vec<InstallSnapshotArgs>,
+    ) -> Result<InstallSnapshotReply> {
+        let node = self.network.node(to)?;
+        let fate = self.network.fate(self.from, to)?;
+        tokio::time::sleep(fate.delay).await;
+        if !fate.deliver {
+            return Err(Error::Internal("Message dropped by the simulated network".into()));
+        }
+        let reply = node.receive_snapshot(tokio_stream::iter(chunks.into_iter().map(Ok))).await;
+        if fate.drop_reply || self.network.is_cut(to, self.from)? {
+            return Err(Error::Internal("Reply dropped by the simulated network".into()));
+        }
+        Ok(reply?)
+    }
+
+    async fn timeout_now(&self, to: u64, args: TimeoutNowArgs) -> Result<TimeoutNowReply> {
+        self.deliver(to, args, |node, args| async move {
+            node.timeout_now(Request::new(args)).await
+        }).await
+    }
+}
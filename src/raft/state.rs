@@ -1,12 +1,21 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::{Arc, Mutex};
 
-use tokio::sync::{mpsc};
-use tokio_stream::StreamExt;
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
 
 use crate::error::{Result, Error};
-use super::{Command, Node, Session, Task};
+use crate::server::{deserialize, serialize};
+use super::{Command, Node, RangeDescriptor, Snapshot, SplitOptions};
+
+/// The number of applied entries after which the driver snapshots the state machine and
+/// compacts the Raft log.
+const SNAPSHOT_INTERVAL: u64 = 1000;
+/// The number of replicated clock ticks after which an idle session expires.
+const SESSION_EXPIRY_TICKS: u64 = 600;
+/// The maximum number of unacknowledged results cached per session. Further mutations are rejected
+/// until the client acknowledges earlier ones.
+pub const SESSION_WINDOW: usize = 1024;
 
 /// A Raft-managed state machine.
 pub trait State: Send + Sync {
@@ -19,40 +28,204 @@ pub trait State: Send + Sync {
 
     /// Queries the state machine. All errors are propagated to the caller.
     fn query(&self, query: Vec<u8>) -> Result<Vec<u8>>;
+
+    /// Serializes the entire state machine into a snapshot, covering all applied entries.
+    fn snapshot(&self) -> Result<Vec<u8>>;
+
+    /// Replaces the entire state machine with the contents of a snapshot.
+    fn restore(&mut self, snapshot: Vec<u8>) -> Result<()>;
+
+    /// Returns a function that sizes the state machine in bytes, used to decide when to split
+    /// its range, or None if it is never split by size. The driver calls it on a background
+    /// thread while applying further commands, so it must not hold up mutations for long.
+    fn sizer(&self) -> Option<Sizer> {
+        None
+    }
+
+    /// Picks a key to split the state machine's range at, roughly in the middle of its data, or
+    /// None if it cannot be split.
+    fn split_key(&self) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// Removes the data at and after a split key, returned by `split_key()` on the leader, and
+    /// returns it as a snapshot to restore the right-hand range's state machine from.
+    fn split(&mut self, key: &[u8]) -> Result<Vec<u8>> {
+        Err(Error::Internal("State machine does not support splits".into()))
+    }
+
+    /// Serializes the state machine into a snapshot to merge into the adjacent left-hand range, as
+    /// the range is frozen. If it returns an error other than Error::Internal, the range is not
+    /// frozen, e.g. because the state machine cannot be merged yet.
+    fn freeze(&self) -> Result<Vec<u8>> {
+        self.snapshot()
+    }
+
+    /// Merges in a snapshot of the state machine of the adjacent right-hand range, returned by
+    /// `freeze()`. If it returns an error other than Error::Internal, it must not have changed the
+    /// state machine, and the ranges are not merged.
+    fn merge(&mut self, snapshot: Vec<u8>) -> Result<()> {
+        Err(Error::Internal("State machine does not support merges".into()))
+    }
+
+    /// Flushes the state machine to durable storage, called when the node shuts down.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
 /// A Raft state machine apply message.
 #[derive(Debug)]
-pub struct ApplyMsg {
-    pub log_index: u64,
-    pub command: Command,
+pub enum ApplyMsg {
+    /// A committed command to apply.
+    Command {
+        log_index: u64,
+        command: Command,
+    },
+    /// A snapshot installed from the leader, replacing the state machine.
+    Snapshot {
+        index: u64,
+        term: u64,
+        data: Vec<u8>,
+    },
 }
 
+/// A linearizable read, served by the state machine once it has applied the read index.
+#[derive(Debug)]
+pub struct ReadRequest {
+    /// The commit index confirmed by the leader when the read arrived.
+    pub index: u64,
+    /// The key the query is routed by, which must be in the group's range when served.
+    pub key: Vec<u8>,
+    /// The query to run against the state machine.
+    pub query: Vec<u8>,
+    /// The channel to send the applied index and the query result to. The result is None if the
+    /// key is no longer in the group's range.
+    pub reply_tx: oneshot::Sender<(u64, Option<Result<Vec<u8>>>)>,
+}
+
+/// The result of applying a mutation or registration, sent to the caller waiting for it.
+#[derive(Debug)]
 pub struct ApplyResult {
-    pub sequence_number: u64,
-    pub result: Result<Vec<u8>>,
+    /// The log index the command was applied at. For a retried mutation, this is the index of the
+    /// original.
+    pub index: u64,
+    /// The result of the command, or None if its session has expired or never existed.
+    pub result: Option<Result<Vec<u8>>>,
+    /// Whether the mutation was rejected because its key is not in the group's range, e.g.
+    /// because the range was split or frozen for a merge after the mutation was proposed.
+    pub wrong_range: bool,
+}
+
+impl ApplyResult {
+    /// Creates the result of a command applied at an index.
+    fn new(index: u64, result: Option<Result<Vec<u8>>>) -> Self {
+        Self { index, result, wrong_range: false }
+    }
+}
+
+/// The callers waiting for commands to be applied, by session ID and sequence number. A
+/// registration has sequence number 0.
+pub type Waiters = Arc<Mutex<HashMap<(u64, u64), oneshot::Sender<ApplyResult>>>>;
+
+/// A function returning the size of a state machine in bytes, see [`State::sizer`].
+pub type Sizer = Arc<dyn Fn() -> Result<u64> + Send + Sync>;
+
+/// The size of a range's state machine, sampled in the background.
+#[derive(Default)]
+struct SizeSample {
+    /// Bumped whenever the range is split, merged or restored, discarding earlier samples.
+    generation: u64,
+    /// The last size sampled since the range last changed, if any.
+    size: Option<u64>,
+    /// Whether a sample is being taken.
+    sampling: bool,
+}
+
+/// A client session in the replicated session table. Caches the results of the mutations the client
+/// has not acknowledged yet, so that a retried mutation returns the original result instead of
+/// being applied again, even if it is retried on a new leader. A client may have many mutations in
+/// flight, and acknowledges their results with the lowest sequence number it has not yet received
+/// a reply for, as in RIFL.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct SessionEntry {
+    /// The log index and result of each unacknowledged mutation, by sequence number.
+    results: BTreeMap<u64, (u64, Result<Vec<u8>>)>,
+    /// The lowest sequence number the client has not acknowledged. Results below it are discarded.
+    lowest_unacked: u64,
+    /// The replicated clock tick at which the session was last active.
+    last_active: u64,
 }
 
-/// The meta-info for a client session.
-pub struct SesstionMeta {
-    pub session_id: u64,
-    pub last_applied_sequence_number: u64,
-    pub stored_result: Option<Result<Vec<u8>>>,
-    pub result_tx: mpsc::UnboundedSender<ApplyResult>,
+/// A snapshot of a driven state machine, along with the replicated session table, clock and
+/// range descriptor.
+#[derive(Serialize, Deserialize)]
+struct DriverSnapshot {
+    clock: u64,
+    sessions: BTreeMap<u64, SessionEntry>,
+    range: Option<RangeDescriptor>,
+    frozen: bool,
+    state: Vec<u8>,
 }
 
-/// Drives a state machine, taking operations from `apply_rx` and sending results via `dispatcher_tx`.
+/// A change to a group's range, or a report of its size and load, sent by the driver so that the
+/// node can update its range descriptor table and its replicas.
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)] // Splits are rare.
+pub(super) enum RangeEvent {
+    /// The range's descriptor changed, by a merge or by restoring a snapshot.
+    Changed(RangeDescriptor),
+    /// The range was split. The right-hand range's replica must be created, starting from the
+    /// given snapshot, which holds its share of the state machine.
+    Split {
+        left: RangeDescriptor,
+        right: RangeDescriptor,
+        snapshot: Snapshot,
+    },
+    /// The range's size and load, reported by the leader on every replicated clock tick.
+    Stats {
+        group_id: u64,
+        size: u64,
+        load: u64,
+        frozen: bool,
+    },
+}
+
+/// Drives a state machine, taking operations from `apply_rx` and sending results to the waiters.
 pub struct Driver {
     /// The underlying Raft node.
     node: Node,
     /// The state machine.
     state: Box<dyn State>,
     /// The channel to receive state machine operations from.
-    apply_rx: UnboundedReceiverStream<ApplyMsg>,
-    /// The channel to send registration results to.
-    registration_status: Arc<Mutex<HashMap<u64, mpsc::UnboundedSender<mpsc::UnboundedSender<Task>>>>>,
-    /// The ongoing sessions.
-    sessions: HashMap<u64, SesstionMeta>,
+    apply_rx: mpsc::Receiver<ApplyMsg>,
+    /// The channel to receive linearizable reads from.
+    read_rx: mpsc::UnboundedReceiver<ReadRequest>,
+    /// Reads waiting for the state machine to apply their read index.
+    pending_reads: Vec<ReadRequest>,
+    /// The callers waiting for commands proposed on this node to be applied.
+    waiters: Waiters,
+    /// The replicated session table, by session ID.
+    sessions: BTreeMap<u64, SessionEntry>,
+    /// The replicated clock, advanced by one tick per applied [`Command::Clock`].
+    clock: u64,
+    /// The replicated range descriptor, or None if the state machine is not split into ranges.
+    range: Option<RangeDescriptor>,
+    /// Whether the range has been frozen for a merge into its left-hand neighbor, rejecting all
+    /// further mutations.
+    frozen: bool,
+    /// The thresholds for splitting the range.
+    split: SplitOptions,
+    /// The channel to report range changes and stats on.
+    range_tx: Option<mpsc::UnboundedSender<RangeEvent>>,
+    /// The number of mutations applied since the last clock tick.
+    load: u64,
+    /// The range's size, sampled off the driver's thread.
+    size: Arc<Mutex<SizeSample>>,
+    /// The index of the last applied entry.
+    applied_index: u64,
+    /// The index covered by the last snapshot taken or installed.
+    snapshot_index: u64,
 }
 
 impl Driver {
@@ -60,145 +233,438 @@ impl Driver {
     pub fn new(
         node: Node,
         state: Box<dyn State>,
-        apply_rx: mpsc::UnboundedReceiver<ApplyMsg>,
-        registration_status: Arc<Mutex<HashMap<u64, mpsc::UnboundedSender<mpsc::UnboundedSender<Task>>>>>,
+        apply_rx: mpsc::Receiver<ApplyMsg>,
+        read_rx: mpsc::UnboundedReceiver<ReadRequest>,
+        waiters: Waiters,
     ) -> Self {
         Self {
             node,
             state,
-            apply_rx: UnboundedReceiverStream::new(apply_rx),
-            registration_status,
-            sessions: HashMap::new(),
+            apply_rx,
+            read_rx,
+            pending_reads: Vec::new(),
+            waiters,
+            sessions: BTreeMap::new(),
+            clock: 0,
+            range: None,
+            frozen: false,
+            split: SplitOptions::default(),
+            range_tx: None,
+            load: 0,
+            size: Arc::new(Mutex::new(SizeSample::default())),
+            applied_index: 0,
+            snapshot_index: 0,
         }
     }
 
-    /// Drives a state machine.
+    /// Drives the state machine of a range, rejecting mutations of keys outside it. Range changes
+    /// and stats are reported on `range_tx`, and the leader proposes a split once the range
+    /// exceeds the thresholds in `split`. The descriptor is replaced by the one in the initial
+    /// snapshot, if any.
+    pub(super) fn with_range(
+        mut self,
+        range: RangeDescriptor,
+        split: SplitOptions,
+        range_tx: mpsc::UnboundedSender<RangeEvent>,
+    ) -> Self {
+        self.range = Some(range);
+        self.split = split;
+        self.range_tx = Some(range_tx);
+        self
+    }
+
+    /// Drives a state machine. Since the state machine may block, this should run on a dedicated
+    /// thread, e.g. via [`tokio::runtime::Handle::block_on`].
     pub async fn drive(mut self) -> Result<()> {
-        while let Some(msg) = self.apply_rx.next().await {
-            println!("Applying cmd {}: {}", msg.log_index, msg.command);
-            if let Err(e) = self.execute(msg) {
-                println!("Error applying: {:?}", e);
-                return Err(e);
+        let stopped = self.node.stopped();
+        tokio::pin!(stopped);
+        loop {
+            let msg = tokio::select! {
+                msg = self.apply_rx.recv() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                Some(read) = self.read_rx.recv() => {
+                    self.pending_reads.push(read);
+                    self.serve_reads();
+                    continue;
+                },
+                // The node has sent its last committed entries: apply them, and flush the state.
+                _ = &mut stopped => {
+                    while let Ok(msg) = self.apply_rx.try_recv() {
+                        self.apply(msg)?;
+                    }
+                    self.state.flush()?;
+                    break;
+                },
+            };
+            self.apply(msg)?;
+        }
+        Ok(())
+    }
+
+    /// Applies a message to the state machine, and serves the reads it makes ready.
+    fn apply(&mut self, msg: ApplyMsg) -> Result<()> {
+        let result = match msg {
+            ApplyMsg::Command { log_index, command } => {
+                println!("Applying cmd {}: {}", log_index, command);
+                self.execute(log_index, command).and_then(|_| self.maybe_snapshot())
+            }
+            ApplyMsg::Snapshot { index, term, data } => {
+                println!("Restoring snapshot at index {} term {}", index, term);
+                self.restore(index, data)
             }
+        };
+        if let Err(e) = result {
+            println!("Error applying: {:?}", e);
+            return Err(e);
         }
+        self.serve_reads();
         Ok(())
     }
 
-    /// Executes a state machine apply message.
-    fn execute(&mut self, apply_msg: ApplyMsg) -> Result<()> {
-        let ApplyMsg { log_index, command } = apply_msg;
-        match command {
-            Command::Mutation { session_id, sequence_number, mutation } => {
-                let session_meta = self.sessions.get_mut(&session_id)
-                    .ok_or_else(|| Error::Internal(format!("Session {} not found", session_id)))?;
-
-                let result = 
-                    // If the operation has already been applied, returns the stored result.
-                    if session_meta.last_applied_sequence_number == sequence_number {
-                        session_meta.stored_result
-                            .clone()
-                            .ok_or_else(|| Error::Internal(format!(
-                                "No stored result for session {}",
-                                session_id,
-                            )))?
-                    }
-                    // If the operation has not been applied, applies it and stores the result.
-                    else if session_meta.last_applied_sequence_number < sequence_number {
-                        let result = self.state.mutate(log_index, mutation);
-                        session_meta.stored_result = Some(result.clone());
-                        session_meta.last_applied_sequence_number = sequence_number;
-                        result
-                    }
-                    // If the sequence number is smaller, returns an error.
-                    else {
-                        return Err(Error::Internal(format!(
-                            "Sequence number {} is smaller than last applied sequence number {}",
-                            sequence_number,
-                            session_meta.last_applied_sequence_number,
-                        )));
-                    };
-
-                // If the server is the leader, sends the result to the corresponding session.
-                if self.node.is_leader()? {
-                    let apply_result = ApplyResult {
-                        sequence_number,
-                        result,
-                    };
-                    session_meta.result_tx.send(apply_result)?;
-                }
-            },
+    /// Serves the pending reads whose read index has been applied.
+    fn serve_reads(&mut self) {
+        let applied_index = self.applied_index;
+        let (ready, pending) = std::mem::take(&mut self.pending_reads)
+            .into_iter()
+            .partition::<Vec<_>, _>(|read| read.index <= applied_index);
+        self.pending_reads = pending;
+        for ReadRequest { key, query, reply_tx, .. } in ready {
+            let in_range = self.range.as_ref().is_none_or(|range| range.contains(&key));
+            let result = in_range.then(|| self.state.query(query));
+            // The reader may have given up, in which case the result is dropped.
+            let _ = reply_tx.send((applied_index, result));
+        }
+    }
 
-            // TODO: Currently identical to Mutation; could be refactored for better performance.
-            Command::Query { session_id, sequence_number, query } => {
-                let session_meta = self.sessions.get_mut(&session_id)
-                    .ok_or_else(|| Error::Internal(format!("Session {} not found", session_id)))?;
-
-                let result = 
-                    // If the operation has already been applied, returns the stored result.
-                    if session_meta.last_applied_sequence_number == sequence_number {
-                        session_meta.stored_result
-                            .clone()
-                            .ok_or_else(|| Error::Internal(format!(
-                                "No stored result for session {}",
-                                session_id,
-                            )))?
-                    }
-                    // If the operation has not been applied, applies it and stores the result.
-                    else if session_meta.last_applied_sequence_number < sequence_number {
-                        let result = self.state.query(query);
-                        session_meta.stored_result = Some(result.clone());
-                        session_meta.last_applied_sequence_number = sequence_number;
-                        result
-                    }
-                    // If the sequence number is smaller, returns an error.
-                    else {
-                        return Err(Error::Internal(format!(
-                            "Sequence number {} is smaller than last applied sequence number {}",
-                            sequence_number,
-                            session_meta.last_applied_sequence_number,
-                        )));
-                    };
-
-                // If the server is the leader, sends the result to the corresponding session.
-                if self.node.is_leader()? {
-                    let apply_result = ApplyResult {
-                        sequence_number,
-                        result,
-                    };
-                    session_meta.result_tx.send(apply_result)?;
-                }
+    /// Snapshots the state machine and the session table, and compacts the Raft log, once enough
+    /// entries have been applied since the last snapshot.
+    fn maybe_snapshot(&mut self) -> Result<()> {
+        if self.applied_index < self.snapshot_index + SNAPSHOT_INTERVAL {
+            return Ok(());
+        }
+        let data = serialize(&DriverSnapshot {
+            clock: self.clock,
+            sessions: self.sessions.clone(),
+            range: self.range.clone(),
+            frozen: self.frozen,
+            state: self.state.snapshot()?,
+        })?;
+        self.node.compact(self.applied_index, data)?;
+        self.snapshot_index = self.applied_index;
+        Ok(())
+    }
+
+    /// Restores the state machine and the session table from a snapshot installed by Raft.
+    fn restore(&mut self, index: u64, data: Vec<u8>) -> Result<()> {
+        if index <= self.applied_index {
+            return Ok(());
+        }
+        let DriverSnapshot { clock, sessions, range, frozen, state } = deserialize(&data)?;
+        self.state.restore(state)?;
+        Self::resize(&self.size)?;
+        self.clock = clock;
+        self.sessions = sessions;
+        self.frozen = frozen;
+        if let Some(range) = range {
+            self.report(RangeEvent::Changed(range.clone()));
+            self.range = Some(range);
+        }
+        self.applied_index = index;
+        self.snapshot_index = index;
+        Ok(())
+    }
+
+    /// Executes a committed command.
+    fn execute(&mut self, log_index: u64, command: Command) -> Result<()> {
+        self.applied_index = log_index;
+        match command {
+            Command::Mutation { session_id, sequence_number, lowest_unacked, key, mutation } => {
+                let result = match self.owns(&key) {
+                    true => self.mutate(log_index, session_id, sequence_number, lowest_unacked, mutation),
+                    false => ApplyResult { index: log_index, result: None, wrong_range: true },
+                };
+                self.notify(session_id, sequence_number, result);
             },
 
             Command::Registration { session_id } => {
-                // // If the server is not the leader, simply ignores the command.
-                // if !self.node.is_leader()? {
-                //     return Ok(());
-                // }
-                
-                // Records the meta-data of the session. Ovewrites the existing session if any.
-                let (task_tx, task_rx) = mpsc::unbounded_channel();
-                let (result_tx, result_rx) = mpsc::unbounded_channel();
-                self.sessions.insert(session_id, SesstionMeta {
-                    session_id,
-                    last_applied_sequence_number: 0,
-                    stored_result: None,
-                    result_tx,
+                // Overwrites the existing session if any.
+                self.sessions.insert(session_id, SessionEntry {
+                    results: BTreeMap::new(),
+                    lowest_unacked: 0,
+                    last_active: self.clock,
                 });
+                self.notify(session_id, 0, ApplyResult::new(log_index, Some(Ok(vec![]))));
+            },
 
-                // Spawns a new session.
-                let node = self.node.clone();
-                tokio::spawn(Session::new(node, session_id, task_rx, result_rx).serve());
+            // Expires the sessions that have been idle for too long, and checks whether the range
+            // should be split.
+            Command::Clock => {
+                self.clock += 1;
+                let clock = self.clock;
+                self.sessions.retain(|_, session| clock.saturating_sub(session.last_active) < SESSION_EXPIRY_TICKS);
+                self.maybe_split()?;
+                self.load = 0;
+            },
+
+            Command::Split { split_key, group_id, membership } => {
+                let range = match &mut self.range {
+                    Some(range) if !self.frozen && range.start < split_key && range.contains(&split_key) => range,
+                    // A duplicate or outdated split, e.g. proposed again before the first applied.
+                    _ => return Ok(()),
+                };
+                let state = self.state.split(&split_key)?;
+                Self::resize(&self.size)?;
+                range.generation += 1;
+                let right = RangeDescriptor {
+                    group_id,
+                    start: split_key.clone(),
+                    end: range.end.replace(split_key),
+                    generation: range.generation,
+                };
+                let left = range.clone();
 
-                // Notifies the server that the session has been registered.
-                if self.node.is_leader()? {
-                    let mut registration_status = self.registration_status.lock().unwrap();
-                    let registration_tx = registration_status.remove(&session_id)
-                        .ok_or_else(|| Error::Internal(format!("Registration {} not found", session_id)))?;
-                    registration_tx.send(task_tx).unwrap();
+                // The right-hand group starts from a snapshot rather than an empty log, so that
+                // replicas which missed the split receive the data from its leader. It inherits the
+                // session table, so that a mutation retried there after the split is not applied
+                // again. The cached results are applied as of the snapshot's index in its log.
+                let mut sessions = self.sessions.clone();
+                for session in sessions.values_mut() {
+                    session.results.values_mut().for_each(|(index, _)| *index = 1);
                 }
+                let data = serialize(&DriverSnapshot {
+                    clock: self.clock,
+                    sessions,
+                    range: Some(right.clone()),
+                    frozen: false,
+                    state,
+                })?;
+                let snapshot = Snapshot { index: 1, term: 0, data, membership: Some(membership) };
+                self.report(RangeEvent::Split { left, right, snapshot });
+            },
+
+            // Freezes the range and replies with its descriptor, session table and state, to merge
+            // it into its left-hand neighbor.
+            Command::Freeze => {
+                let result = match &self.range {
+                    Some(range) => match self.state.freeze() {
+                        Ok(state) => {
+                            self.frozen = true;
+                            Ok(serialize(&(range, &self.sessions, self.clock, state))?)
+                        },
+                        Err(e @ Error::Internal(_)) => return Err(e),
+                        Err(e) => Err(e),
+                    },
+                    None => Err(Error::Value("The state machine is not split into ranges".into())),
+                };
+                self.notify(0, 0, ApplyResult::new(log_index, Some(result)));
+            },
+
+            Command::Merge { group_id, data } => {
+                let (right, sessions, right_clock, state): (RangeDescriptor, BTreeMap<u64, SessionEntry>, u64, Vec<u8>) =
+                    deserialize(&data)?;
+                let result = match &mut self.range {
+                    Some(range) if !self.frozen && right.group_id == group_id
+                        && range.end.as_ref() == Some(&right.start) => match self.state.merge(state) {
+                        Ok(()) => {
+                            Self::merge_sessions(&mut self.sessions, sessions, log_index, self.clock, right_clock);
+                            Self::resize(&self.size)?;
+                            range.end = right.end;
+                            range.generation = range.generation.max(right.generation) + 1;
+                            let range = range.clone();
+                            self.report(RangeEvent::Changed(range));
+                            Ok(vec![])
+                        },
+                        Err(e @ Error::Internal(_)) => return Err(e),
+                        Err(e) => Err(e),
+                    },
+                    _ => Err(Error::Value(format!("Range {} is not adjacent", group_id))),
+                };
+                self.notify(0, 0, ApplyResult::new(log_index, Some(result)));
             },
+
+            // Membership changes are handled by Raft when appended to the log, and noops are only
+            // used by new leaders to commit an entry in their term.
+            Command::Membership(_) | Command::Noop => { },
+        }
+
+        Ok(())
+    }
+
+    /// Applies a mutation at most once per session and sequence number. A retry of an
+    /// unacknowledged mutation returns its original result, and a retry of an acknowledged one is
+    /// rejected.
+    fn mutate(
+        &mut self,
+        log_index: u64,
+        session_id: u64,
+        sequence_number: u64,
+        lowest_unacked: u64,
+        mutation: Vec<u8>,
+    ) -> ApplyResult {
+        let session = match self.sessions.get_mut(&session_id) {
+            Some(session) => session,
+            None => return ApplyResult::new(log_index, None),
+        };
+        session.last_active = self.clock;
+
+        // Discards the results the client has acknowledged.
+        if lowest_unacked > session.lowest_unacked {
+            session.results = session.results.split_off(&lowest_unacked);
+            session.lowest_unacked = lowest_unacked;
+        }
+
+        if let Some((index, result)) = session.results.get(&sequence_number) {
+            return ApplyResult::new(*index, Some(result.clone()));
+        }
+        let result = if sequence_number < session.lowest_unacked {
+            Err(Error::Value(format!(
+                "Sequence number {} has already been acknowledged by session {}",
+                sequence_number, session_id,
+            )))
+        } else if session.results.len() >= SESSION_WINDOW {
+            Err(Error::Value(format!(
+                "Session {} has more than {} unacknowledged mutations",
+                session_id, SESSION_WINDOW,
+            )))
+        } else {
+            let result = self.state.mutate(log_index, mutation);
+            session.results.insert(sequence_number, (log_index, result.clone()));
+            self.load += 1;
+            result
+        };
+        ApplyResult::new(log_index, Some(result))
+    }
+
+    /// Merges the session table of a right-hand range into this one, so that a mutation it applied
+    /// is not applied again when retried after the merge. A session in both keeps the higher
+    /// acknowledgement and the results of both, this range's first. The right-hand range's results
+    /// are applied as of the merge's index in this range's log. The ranges' replicated clocks
+    /// differ, so a right-hand session's last activity is rebased onto this range's clock, keeping
+    /// how long it has been idle.
+    fn merge_sessions(
+        sessions: &mut BTreeMap<u64, SessionEntry>,
+        right: BTreeMap<u64, SessionEntry>,
+        log_index: u64,
+        clock: u64,
+        right_clock: u64,
+    ) {
+        for (session_id, mut other) in right {
+            other.results.values_mut().for_each(|(index, _)| *index = log_index);
+            other.last_active = clock.saturating_sub(right_clock.saturating_sub(other.last_active));
+            let session = sessions.entry(session_id).or_insert_with(|| SessionEntry {
+                results: BTreeMap::new(),
+                lowest_unacked: 0,
+                last_active: 0,
+            });
+            for (sequence_number, result) in other.results {
+                session.results.entry(sequence_number).or_insert(result);
+            }
+            session.lowest_unacked = session.lowest_unacked.max(other.lowest_unacked);
+            session.results = session.results.split_off(&session.lowest_unacked);
+            session.last_active = session.last_active.max(other.last_active);
+        }
+    }
+
+    /// Checks whether a mutation of a key may be applied: the key must be in the range, and the
+    /// range must not be frozen for a merge.
+    fn owns(&self, key: &[u8]) -> bool {
+        match &self.range {
+            Some(range) => !self.frozen && range.contains(key),
+            None => true,
+        }
+    }
+
+    /// On the leader, reports the range's size and load, and proposes a split if either exceeds
+    /// its threshold. Followers skip this, since sizing the state machine may be expensive. The
+    /// size is the one last sampled in the background, so nothing is reported until the first
+    /// sample after the range changed.
+    fn maybe_split(&mut self) -> Result<()> {
+        let group_id = match &self.range {
+            Some(range) if self.node.is_leader()? => range.group_id,
+            _ => return Ok(()),
+        };
+        let size = match self.sample_size()? {
+            Some(size) => size,
+            None => return Ok(()),
+        };
+        self.report(RangeEvent::Stats { group_id, size, load: self.load, frozen: self.frozen });
+
+        let SplitOptions { max_size, max_load, .. } = self.split;
+        let exceeded = (max_size > 0 && size > max_size) || (max_load > 0 && self.load > max_load);
+        if self.frozen || !exceeded {
+            return Ok(());
+        }
+        if let Some(split_key) = self.state.split_key()? {
+            // Group IDs are random, so that they are unique across leaders without coordination.
+            let group_id = loop {
+                let group_id = rand::random();
+                if group_id != 0 {
+                    break group_id;
+                }
+            };
+            let membership = self.node.membership()?;
+            // Proposes in the background, since the proposal may wait for this state machine to
+            // catch up with the log.
+            let node = self.node.clone();
+            tokio::spawn(async move {
+                match node.propose(Command::Split { split_key, group_id, membership }).await {
+                    Ok(_) | Err(Error::NotLeader) => { },
+                    Err(e) => println!("Failed to propose split of group {}: {:?}", group_id, e),
+                }
+            });
         }
+        Ok(())
+    }
 
+    /// Returns the last sampled size of the state machine, and starts taking another sample on a
+    /// blocking thread unless one is already being taken. A state machine without a sizer has
+    /// size 0.
+    fn sample_size(&self) -> Result<Option<u64>> {
+        let sizer = match self.state.sizer() {
+            Some(sizer) => sizer,
+            None => return Ok(Some(0)),
+        };
+        let mut sample = self.size.lock()?;
+        if !sample.sampling {
+            sample.sampling = true;
+            let (generation, shared) = (sample.generation, self.size.clone());
+            tokio::task::spawn_blocking(move || {
+                let size = sizer();
+                let mut sample = shared.lock().unwrap();
+                sample.sampling = false;
+                match size {
+                    Ok(size) if sample.generation == generation => sample.size = Some(size),
+                    Ok(_) => { },
+                    Err(e) => println!("Failed to size state machine: {:?}", e),
+                }
+            });
+        }
+        Ok(sample.size)
+    }
+
+    /// Discards the sampled size after the range changed.
+    fn resize(size: &Mutex<SizeSample>) -> Result<()> {
+        let mut sample = size.lock()?;
+        sample.generation += 1;
+        sample.size = None;
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Reports a range event to the node, if it is split into ranges.
+    fn report(&self, event: RangeEvent) {
+        if let Some(range_tx) = &self.range_tx {
+            // The node may be shutting down, in which case the event is dropped.
+            let _ = range_tx.send(event);
+        }
+    }
+
+    /// Sends the result of a command to the caller waiting for it on this node, if any.
+    fn notify(&mut self, session_id: u64, sequence_number: u64, result: ApplyResult) {
+        if let Some(waiter) = self.waiters.lock().unwrap().remove(&(session_id, sequence_number)) {
+            // The caller may have given up, in which case the result is dropped.
+            let _ = waiter.send(result);
+        }
+    }
+}
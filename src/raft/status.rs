@@ -0,0 +1,63 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// A snapshot of a Raft node's state, for introspection.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Status {
+    /// The node's ID.
+    pub id: u64,
+    /// The node's role: leader, follower, witness, pre-candidate or candidate.
+    pub role: String,
+    pub term: u64,
+    /// The node the node believes is the leader, which is itself while campaigning.
+    pub leader: u64,
+    pub commit_index: u64,
+    /// The index of the last entry sent to the state machine.
+    pub applied_index: u64,
+    /// The index of the last entry in the log.
+    pub last_index: u64,
+    /// The index of the last entry covered by the snapshot, or 0 if none.
+    pub snapshot_index: u64,
+    /// The size of the log store, in bytes.
+    pub log_size: u64,
+    /// The replication progress of each peer, by node ID. Only reported by leaders.
+    pub progress: BTreeMap<u64, Progress>,
+}
+
+/// A leader's view of a peer's replication progress.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Progress {
+    /// The next index to replicate to the peer.
+    pub next_index: u64,
+    /// The last index known to be replicated on the peer.
+    pub match_index: u64,
+    /// The time elapsed since the sending of the latest message the peer acknowledged, or None if
+    /// it has not acknowledged one in the leader's term.
+    pub last_contact: Option<Duration>,
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Node {}: {} in term {}, leader {}",
+            self.id, self.role, self.term, self.leader,
+        )?;
+        write!(
+            f,
+            "  commit {}, applied {}, log {} to {} ({} bytes)",
+            self.commit_index, self.applied_index, self.snapshot_index + 1, self.last_index, self.log_size,
+        )?;
+        for (id, progress) in &self.progress {
+            write!(f, "\n  peer {}: next {}, match {}, ", id, progress.next_index, progress.match_index)?;
+            match progress.last_contact {
+                Some(contact) => write!(f, "last contact {}ms ago", contact.as_millis())?,
+                None => write!(f, "no contact")?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tokio_util::sync::CancellationToken;
+use tonic::transport::{Channel, Endpoint, Server};
+use tonic::{Request, Status, Streaming};
+
+use crate::error::{Error, Result, RpcResult};
+use crate::proto::raft::raft_service_client::RaftServiceClient;
+use crate::proto::raft::raft_service_server::{RaftService, RaftServiceServer};
+use crate::proto::raft::{RequestVoteArgs, RequestVoteReply, AppendEntriesArgs, AppendEntriesReply};
+use crate::proto::raft::{InstallSnapshotArgs, InstallSnapshotReply, TimeoutNowArgs, TimeoutNowReply};
+use super::Node;
+
+/// The gRPC metadata key carrying the Raft group a message is addressed to.
+const GROUP_KEY: &str = "raft-group";
+
+/// The transport of Raft messages between nodes. Messages are addressed by node ID, and may be
+/// lost; a transport need not retry them.
+#[tonic::async_trait]
+pub trait Transport: Send + Sync {
+    /// Sets the addresses of the peers, by ID. Messages can only be sent to these peers.
+    fn set_peers(&self, addrs: &HashMap<u64, String>) -> Result<()>;
+
+    /// Sends a RequestVote message.
+    async fn request_vote(&self, to: u64, args: RequestVoteArgs) -> Result<RequestVoteReply>;
+
+    /// Sends an AppendEntries message.
+    async fn append_entries(&self, to: u64, args: AppendEntriesArgs) -> Result<AppendEntriesReply>;
+
+    /// Sends a snapshot as a sequence of InstallSnapshot chunks.
+    async fn install_snapshot(
+        &self,
+        to: u64,
+        chunks: Vec<InstallSnapshotArgs>,
+    ) -> Result<InstallSnapshotReply>;
+
+    /// Sends a TimeoutNow message.
+    async fn timeout_now(&self, to: u64, args: TimeoutNowArgs) -> Result<TimeoutNowReply>;
+}
+
+/// A transport over gRPC. Peers are connected lazily on the first message, and reconnected
+/// whenever their connection fails, so a node can start while its peers are down. Messages are
+/// tagged with the transport's Raft group, so that several groups can share an address via a
+/// [`RaftRouter`].
+#[derive(Default)]
+pub struct GrpcTransport {
+    /// The Raft group the messages belong to.
+    group_id: u64,
+    /// The address and client of each peer, by ID.
+    peers: Mutex<HashMap<u64, (String, RaftServiceClient<Channel>)>>,
+}
+
+impl GrpcTransport {
+    /// Creates a gRPC transport for group 0 without any peers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a gRPC transport for the given Raft group without any peers.
+    pub fn for_group(group_id: u64) -> Self {
+        Self { group_id, ..Self::default() }
+    }
+
+    /// Wraps a message in a request addressed to the transport's group.
+    fn request<T>(&self, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        request.metadata_mut().insert(GROUP_KEY, self.group_id.into());
+        request
+    }
+
+    /// Returns the client of a peer.
+    fn client(&self, id: u64) -> Result<RaftServiceClient<Channel>> {
+        self.peers.lock()?.get(&id)
+            .map(|(_, client)| client.clone())
+            .ok_or_else(|| Error::Internal(format!("Unknown peer {}", id)))
+    }
+}
+
+#[tonic::async_trait]
+impl Transport for GrpcTransport {
+    fn set_peers(&self, addrs: &HashMap<u64, String>) -> Result<()> {
+        let mut peers = self.peers.lock()?;
+        peers.retain(|id, (addr, _)| addrs.get(id) == Some(addr));
+        for (id, addr) in addrs {
+            if !peers.contains_key(id) {
+                let channel = Endpoint::from_shared(format!("http://{}", addr))
+                    .map_err(|e| Error::Config(e.to_string()))?
+                    .connect_lazy();
+                peers.insert(*id, (addr.clone(), RaftServiceClient::new(channel)));
+            }
+        }
+        Ok(())
+    }
+
+    async fn request_vote(&self, to: u64, args: RequestVoteArgs) -> Result<RequestVoteReply> {
+        Ok(self.client(to)?.request_vote(self.request(args)).await?.into_inner())
+    }
+
+    async fn append_entries(&self, to: u64, args: AppendEntriesArgs) -> Result<AppendEntriesReply> {
+        Ok(self.client(to)?.append_entries(self.request(args)).await?.into_inner())
+    }
+
+    async fn install_snapshot(
+        &self,
+        to: u64,
+        chunks: Vec<InstallSnapshotArgs>,
+    ) -> Result<InstallSnapshotReply> {
+        let mut client = self.client(to)?;
+        Ok(client.install_snapshot(self.request(tokio_stream::iter(chunks))).await?.into_inner())
+    }
+
+    async fn timeout_now(&self, to: u64, args: TimeoutNowArgs) -> Result<TimeoutNowReply> {
+        Ok(self.client(to)?.timeout_now(self.request(args)).await?.into_inner())
+    }
+}
+
+/// Creates the local node of a group unknown to a router, or returns None to reject the message.
+type UnknownGroupHook = Arc<dyn Fn(u64) -> Result<Option<Node>> + Send + Sync>;
+
+/// Serves the Raft RPCs of several groups on a single address, dispatching each message to the
+/// node of the group it is addressed to. Messages without a group go to group 0.
+#[derive(Clone, Default)]
+pub struct RaftRouter {
+    /// The local node of each group, by group ID.
+    nodes: Arc<Mutex<HashMap<u64, Node>>>,
+    /// Called for messages from a leader of a group without a local node, if set.
+    on_unknown_group: Arc<Mutex<Option<UnknownGroupHook>>>,
+}
+
+impl RaftRouter {
+    /// Creates a router without any groups.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Routes a group's messages to a node, replacing any previous node of the group.
+    pub fn add(&self, group_id: u64, node: Node) -> Result<()> {
+        self.nodes.lock()?.insert(group_id, node);
+        Ok(())
+    }
+
+    /// Stops routing a group's messages.
+    pub fn remove(&self, group_id: u64) -> Result<()> {
+        self.nodes.lock()?.remove(&group_id);
+        Ok(())
+    }
+
+    /// Sets a hook to create the local node of a group when its leader first sends it entries or
+    /// a snapshot, e.g. for a group created by a split that this node missed. Other messages for
+    /// unknown groups are rejected.
+    pub fn on_unknown_group(&self, hook: impl Fn(u64) -> Result<Option<Node>> + Send + Sync + 'static) -> Result<()> {
+        *self.on_unknown_group.lock()? = Some(Arc::new(hook));
+        Ok(())
+    }
+
+    /// Serves the routed RPCs on an address, in a background task, until the token is cancelled.
+    pub fn serve(&self, addr: &str, shutdown: CancellationToken) -> Result<()> {
+        let addr = addr.parse()?;
+        let router = self.clone();
+        tokio::spawn(async move {
+            let server = Server::builder().add_service(RaftServiceServer::new(router));
+            match server.serve_with_shutdown(addr, shutdown.cancelled_owned()).await {
+                Ok(_) => println!("Raft server built on addr {:?}", addr),
+                Err(err) => println!("Raft server failed on addr {:?}: {:?}", addr, err),
+            };
+        });
+        Ok(())
+    }
+
+    /// Returns the node of the group a request is addressed to, creating it via the hook if
+    /// `create` is set. Rejects the request while the node is disconnected.
+    #[allow(clippy::result_large_err)] // The handlers reply with the status as is.
+    fn route<T>(&self, request: &Request<T>, create: bool) -> core::result::Result<Node, Status> {
+        let group_id = match request.metadata().get(GROUP_KEY) {
+            Some(value) => value.to_str().ok()
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| Status::invalid_argument("Invalid Raft group"))?,
+            None => 0,
+        };
+        let node = self.nodes.lock().unwrap().get(&group_id).cloned();
+        let hook = match create {
+            true => self.on_unknown_group.lock().unwrap().clone(),
+            false => None,
+        };
+        // The hook adds the node to the router, so it is called without holding the lock.
+        let node = match (node, hook) {
+            (Some(node), _) => Some(node),
+            (None, Some(hook)) => hook(group_id)?,
+            (None, None) => None,
+        };
+        let node = node.ok_or_else(|| Status::not_found(format!("Unknown Raft group {}", group_id)))?;
+        if !node.is_connected() {
+            return Err(Status::unavailable("RPC got intercepted."));
+        }
+        Ok(node)
+    }
+}
+
+#[tonic::async_trait]
+impl RaftService for RaftRouter {
+    async fn request_vote(&self, request: Request<RequestVoteArgs>) -> RpcResult<RequestVoteReply> {
+        self.route(&request, false)?.request_vote(request).await
+    }
+
+    async fn append_entries(&self, request: Request<AppendEntriesArgs>) -> RpcResult<AppendEntriesReply> {
+        self.route(&request, true)?.append_entries(request).await
+    }
+
+    async fn install_snapshot(
+        &self,
+        request: Request<Streaming<InstallSnapshotArgs>>,
+    ) -> RpcResult<InstallSnapshotReply> {
+        self.route(&request, true)?.install_snapshot(request).await
+    }
+
+    async fn timeout_now(&self, request: Request<TimeoutNowArgs>) -> RpcResult<TimeoutNowReply> {
+        self.route(&request, false)?.timeout_now(request).await
+    }
+}
@@ -1,13 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::{Mutex, Arc};
 
 use serde::{Serialize, Deserialize};
 use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 use tonic::{Request, Response};
 
 use crate::concurrency::Mode;
 use crate::error::{RpcResult, Result, Error};
 use crate::proto::featherdb::{RegistrationArgs, RegistrationReply, ExecutionReply, FeatherDb, ExecutionArgs};
+use crate::raft;
 use crate::sql::engine::{SqlEngine, SqlSession, RaftSqlEngine};
 use crate::sql::execution::ResultSet;
 use crate::sql::schema::{Table, Catalog};
@@ -29,7 +32,8 @@ pub enum ClientResponse {
     Row(Option<Row>),
     GetTable(Table),
     ListTables(Vec<String>),
-    // Status(sql::engine::Status),
+    /// The Raft status of each FeatherKV server's replicas, by node ID and group ID.
+    Status(BTreeMap<u64, BTreeMap<u64, raft::Status>>),
 }
 
 #[derive(Debug)]
@@ -42,24 +46,43 @@ pub struct Task {
 
 /// A featherDB server with Raft backend.
 pub struct FeatherDB {
-    /// The server's engine.
-    servers: Vec<String>,
+    /// The FeatherKV addresses of the placement driver's nodes, by node ID, used to look up the
+    /// FeatherKV servers and ranges.
+    placement: BTreeMap<u64, String>,
     /// The server's next client session id.
     next_session_id: Mutex<u64>,
     /// The sending channels of the ongoing sessions.
     session_txs: Arc<Mutex<HashMap<u64, mpsc::UnboundedSender<Task>>>>,
+    /// The tasks serving the sessions, awaited on shutdown.
+    sessions: Mutex<Vec<JoinHandle<Result<()>>>>,
+    /// Cancelled when the server shuts down, closing the sessions.
+    shutdown: CancellationToken,
 }
 
 impl FeatherDB {
-    /// Creates a new server.
-    pub fn new(servers: Vec<String>) -> Self {
+    /// Creates a new server, given the FeatherKV addresses of the placement driver's nodes.
+    pub fn new(placement: BTreeMap<u64, String>) -> Self {
         Self {
-            servers,
+            placement,
             next_session_id: Mutex::new(1),
             session_txs: Arc::new(Mutex::new(HashMap::new())),
+            sessions: Mutex::new(Vec::new()),
+            shutdown: CancellationToken::new(),
         }
     }
 
+    /// Shuts the server down: rejects new sessions and requests, and waits for the ongoing
+    /// sessions to finish their current request and roll back their open transactions.
+    pub async fn shutdown(&self) -> Result<()> {
+        self.shutdown.cancel();
+        self.session_txs.lock().unwrap().clear();
+        let sessions = std::mem::take(&mut *self.sessions.lock().unwrap());
+        for session in sessions {
+            session.await.map_err(|e| Error::Internal(e.to_string()))??;
+        }
+        Ok(())
+    }
+
     /// Serializes RPC arguments.
     pub fn serialize<V: Serialize>(value: &V) -> Result<Vec<u8>> {
         Ok(bincode::serialize(value)?)
@@ -74,6 +97,9 @@ impl FeatherDB {
 #[tonic::async_trait]
 impl FeatherDb for FeatherDB {
     async fn register(&self, _request: Request<RegistrationArgs>) -> RpcResult<RegistrationReply> {
+        if self.shutdown.is_cancelled() {
+            return Err(Error::Internal("Server is shutting down".into()).into());
+        }
         let session_id = {
             let mut next_session_id = self.next_session_id.lock().unwrap();
             let session_id = *next_session_id;
@@ -84,7 +110,8 @@ impl FeatherDb for FeatherDB {
 
         let (task_tx, task_rx) = mpsc::unbounded_channel();
         self.session_txs.lock().unwrap().insert(session_id, task_tx);
-        tokio::spawn(Session::new(self.servers.clone(), task_rx).await?.serve());
+        let session = Session::new(self.placement.clone(), task_rx, self.shutdown.clone()).await?;
+        self.sessions.lock().unwrap().push(tokio::spawn(session.serve()));
 
         Ok(Response::new(RegistrationReply { session_id }))
     }
@@ -98,24 +125,25 @@ impl FeatherDb for FeatherDB {
             client_request,
         };
 
+        // The session is gone once the server shuts down.
         {
-            let mut session_txs = self.session_txs.lock().unwrap();
-            match session_txs.get_mut(&session_id) {
-                Some(session_tx) => {
-                    session_tx.send(task).unwrap();
-                },
-                None => {
-                    todo!()
-                },
+            let session_txs = self.session_txs.lock().unwrap();
+            match session_txs.get(&session_id) {
+                Some(session_tx) => session_tx.send(task)
+                    .map_err(|_| Error::Internal(format!("Session {} is closed", session_id)))?,
+                None => return Err(Error::Internal(format!("Unknown session {}", session_id)).into()),
             }
         }
 
-        let reply = reply_rx.await.unwrap();
+        let reply = reply_rx.await
+            .map_err(|_| Error::Internal(format!("Session {} is closed", session_id)))?;
         Ok(Response::new(reply))
     }
 }
 
 pub struct Session {
+    /// The underlying engine.
+    engine: RaftSqlEngine,
     /// The session's engine.
     sql: SqlSession<RaftSqlEngine>,
     /// The last applied sequence number.
@@ -124,25 +152,39 @@ pub struct Session {
     stored_result: Option<Result<ClientResponse>>,
     /// The channel to receive task from.
     task_rx: mpsc::UnboundedReceiver<Task>,
+    /// Cancelled when the server shuts down.
+    shutdown: CancellationToken,
 }
 
 impl Session {
-    /// Creates a new session.
+    /// Creates a new session, routed via the placement driver.
     pub async fn new(
-        servers: Vec<String>,
+        placement: BTreeMap<u64, String>,
         task_rx: mpsc::UnboundedReceiver<Task>,
+        shutdown: CancellationToken,
     ) -> Result<Self> {
+        let engine = RaftSqlEngine::placed(placement).await?;
         Ok(Self {
-            sql: RaftSqlEngine::new(servers).await?.session()?,
+            sql: engine.session()?,
+            engine,
             last_applied_sequence_number: 0,
             stored_result: None,
             task_rx,
+            shutdown,
         })
     }
 
-    /// Starts the session.
+    /// Starts the session. Once the server shuts down, rolls back the session's transaction and
+    /// returns.
     pub async fn serve(mut self) -> Result<()> {
-        while let Some(Task { reply_tx, sequence_number, client_request }) = self.task_rx.recv().await {
+        loop {
+            let Task { reply_tx, sequence_number, client_request } = tokio::select! {
+                task = self.task_rx.recv() => match task {
+                    Some(task) => task,
+                    None => break,
+                },
+                _ = self.shutdown.cancelled() => break,
+            };
             if sequence_number == self.last_applied_sequence_number {
                 let reply = ExecutionReply {
                     result: FeatherDB::serialize(
@@ -166,7 +208,7 @@ impl Session {
             reply_tx.send(reply).unwrap();
         }
 
-        Ok(())
+        tokio::task::block_in_place(|| self.sql.close())
     }
 
     fn execute(&mut self, client_request: ClientRequest) -> Result<ClientResponse> {
@@ -193,13 +235,22 @@ impl Session {
                 )?;
                 ClientResponse::ListTables(result)
             },
-            ClientRequest::Status => {
-                todo!()
-            },
+            ClientRequest::Status => ClientResponse::Status(self.engine.status()?),
         })
     }
 }
 
+/// Waits for SIGTERM or Ctrl-C, upon which a server should shut down.
+pub async fn shutdown_signal() {
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("Failed to install SIGTERM handler");
+    tokio::select! {
+        _ = sigterm.recv() => { },
+        _ = tokio::signal::ctrl_c() => { },
+    }
+    println!("Received shutdown signal");
+}
+
 /// Serializes RPC arguments.
 pub fn serialize<V: Serialize>(value: &V) -> crate::error::Result<Vec<u8>> {
     Ok(bincode::serialize(value)?)
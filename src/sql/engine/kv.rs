@@ -33,6 +33,21 @@ impl KvSqlEngine {
     pub fn set_metadata(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
         self.kv.set_metadata(key, value)
     }
+
+    /// Picks a key to split the stored data at, roughly in the middle. The key is always a row
+    /// key, so table schemas and index entries, which sort before all rows, are on the left.
+    pub fn split_key(&self) -> Result<Option<Vec<u8>>> {
+        self.kv.split_key(|key| matches!(SqlKey::decode(key), Ok(SqlKey::Row(..))))
+    }
+
+    /// Removes the data at and after a split key, and returns it as a snapshot. The table schemas
+    /// and index entries are kept by both sides, so that either side can serve and index the rows
+    /// of any table. Each side then only maintains the index entries of its own rows.
+    pub fn split_off(&self, key: &[u8]) -> Result<Vec<u8>> {
+        self.kv.split_off_sharing(key, |key| {
+            matches!(SqlKey::decode(key), Ok(SqlKey::Table(..)) | Ok(SqlKey::Index(..)))
+        })
+    }
 }
 
 impl SqlEngine for KvSqlEngine {
@@ -68,6 +83,16 @@ impl KvSqlTxn {
         Self { txn }
     }
 
+    /// Prepares the transaction for a two-phase commit, see `Transaction::prepare()`.
+    pub fn prepare(&self, primary: Vec<u8>) -> Result<u64> {
+        self.txn.prepare(primary)
+    }
+
+    /// Commits the transaction at the given time, see `Transaction::commit_at()`.
+    pub fn commit_at(self, time: u64) -> Result<()> {
+        self.txn.commit_at(time)
+    }
+
     /// Loads an index entry. TODO: ????
     fn load_index(&self, table: &str, column: &str, value: &Value) -> Result<HashSet<Value>> {
         Ok(self
@@ -311,7 +336,7 @@ impl Catalog for KvSqlTxn {
 /// be None to get a keyspace prefix. We use table and column names directly as identifiers, to
 /// avoid additional indirection and associated overhead. It is not possible to change names, so
 /// this is ok. Uses Cows since we want to borrow when encoding but return owned when decoding.
-enum SqlKey<'a> {
+pub(super) enum SqlKey<'a> {
     /// A table schema key for the given table name
     Table(Option<Cow<'a, str>>),
     /// A key for an index entry
@@ -322,7 +347,7 @@ enum SqlKey<'a> {
 
 impl<'a> SqlKey<'a> {
     /// Encodes the key as a byte vector
-    fn encode(self) -> Vec<u8> {
+    pub(super) fn encode(self) -> Vec<u8> {
         use crate::encoding::*;
         match self {
             Self::Table(None) => vec![0x01],
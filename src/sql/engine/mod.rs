@@ -6,16 +6,17 @@
 mod kv;
 pub mod raft;
 pub use kv::KvSqlEngine;
-pub use raft::{RaftSqlEngine, StateMachine};
+pub use raft::{Collector, RaftSqlEngine, Resolver, StateMachine};
 pub use crate::concurrency::Mode;
 
 use std::collections::HashSet;
 use std::sync::Arc;
 use parking_lot::Mutex;
 
+use crate::concurrency::now_millis;
 use crate::error::{Error, Result};
 use super::execution::ResultSet;
-use super::parser::{Parser, ast};
+use super::parser::{format_timestamp, Parser, ast};
 use super::plan::Plan;
 use super::schema::Catalog;
 use super::types::{Row, Value, Expression};
@@ -81,25 +82,25 @@ impl <E: SqlEngine + 'static> SqlSession<E> {
             ast::Statement::Begin { .. } if guard.is_some() => {
                 Err(Error::Value("Already in a transaction".into()))
             },
-            ast::Statement::Begin { read_only: true, version: None } => {
+            ast::Statement::Begin { read_only: true, as_of: None } => {
                 let txn = self.engine.begin(Mode::ReadOnly)?;
                 let result = ResultSet::Begin { id: txn.id(), mode: txn.mode() };
                 *guard = Some(txn);
                 Ok(result)
             },
-            ast::Statement::Begin { read_only: false, version: None } => {
+            ast::Statement::Begin { read_only: false, as_of: None } => {
                 let txn = self.engine.begin(Mode::ReadWrite)?;
                 let result = ResultSet::Begin { id: txn.id(), mode: txn.mode() };
                 *guard = Some(txn);
                 Ok(result)
             },
-            ast::Statement::Begin { read_only: true, version: Some(version) } => {
-                let txn = self.engine.begin(Mode::Snapshot { version })?;
+            ast::Statement::Begin { read_only: true, as_of: Some(as_of) } => {
+                let txn = self.engine.begin(Self::as_of_mode(as_of)?)?;
                 let result = ResultSet::Begin { id: txn.id(), mode: txn.mode() };
                 *guard = Some(txn);
                 Ok(result)
             },
-            ast::Statement::Begin { read_only: false, version: Some(_) } => {
+            ast::Statement::Begin { read_only: false, as_of: Some(_) } => {
                 Err(Error::Value("Cannot specify version for read-write transactions".into()))
             },
 
@@ -135,6 +136,17 @@ impl <E: SqlEngine + 'static> SqlSession<E> {
                 Ok(ResultSet::Rollback { id })
             },
 
+            // A query as of a version or time runs in its own read-only transaction.
+            ast::Statement::Select { as_of: Some(_), .. } if guard.is_some() => {
+                Err(Error::Value("Cannot specify version for queries in a transaction".into()))
+            },
+            statement @ ast::Statement::Select { as_of: Some(as_of), .. } => {
+                let mut txn = self.engine.begin(Self::as_of_mode(as_of)?)?;
+                let result = Plan::build(statement, &mut txn).and_then(|plan| plan.execute(&mut txn));
+                txn.commit()?;
+                result
+            },
+
             statement if guard.is_some() => {
                 Plan::build(statement, guard.as_mut().unwrap())?
                     .execute(guard.as_mut().unwrap())
@@ -158,6 +170,27 @@ impl <E: SqlEngine + 'static> SqlSession<E> {
         }
     }
 
+    /// Returns the mode of a read-only transaction as of a version or time. Times in the future
+    /// are rejected, since they would hold back later commits. Each store also rejects times that
+    /// a transaction prepared there may still commit by.
+    fn as_of_mode(as_of: ast::AsOf) -> Result<Mode> {
+        match as_of {
+            ast::AsOf::Version(version) => Ok(Mode::Snapshot { version }),
+            ast::AsOf::Time(time) if time > now_millis() => {
+                Err(Error::Value(format!("Time {} is in the future", format_timestamp(time))))
+            },
+            ast::AsOf::Time(time) => Ok(Mode::AsOf { time }),
+        }
+    }
+
+    /// Closes the session, rolling back its transaction if any.
+    pub fn close(&self) -> Result<()> {
+        match self.txn.lock().take() {
+            Some(txn) => txn.rollback(),
+            None => Ok(()),
+        }
+    }
+
     /// Runs a closure in the session's transaction, or a new transaction if none is active.
     pub fn with_txn<R, F>(&self, mode: Mode, func: F) -> Result<R>
     where
@@ -1,24 +1,52 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 use serde::{Deserialize, Serialize};
+use tokio::time::Duration;
 
-use crate::concurrency::MVCC;
+use crate::concurrency::{now_millis, GcOptions, GcStats, MVCC};
 use crate::error::{Result, Error};
-use crate::raft;
+use crate::raft::{self, RangeDescriptor};
 use crate::sql::schema::{Catalog, Table, Tables};
 use crate::sql::types::{Row, Value, Expression};
+use super::kv::SqlKey;
 use super::{SqlEngine, Mode, SqlTxn, RowScan, IndexScan};
 
+/// How often the resolver looks for prepared transactions. A transaction that is still prepared
+/// a full interval later is assumed to have lost its coordinator.
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(5);
+/// The number of keys sized at a time while sampling a range's size, holding up writes to it.
+const SIZE_BATCH: usize = 1000;
+
 /// A Raft state machine mutation
 #[derive(Clone, Serialize, Deserialize)]
 pub(crate) enum Mutation {
     /// Begins a transaction in the given mode
     Begin(Mode),
-    /// Commits the transaction with the given ID
-    Commit(u64),
+    /// Commits the transaction with the given ID, at a time chosen by the proposer so that all
+    /// replicas record the same
+    Commit { txn_id: u64, time: u64 },
     /// Rolls back the transaction with the given ID
     Rollback(u64),
 
+    /// Prepares the transaction with the given ID for a two-phase commit, given its primary and
+    /// the other participants. Returns the earliest time it may commit at
+    Prepare { txn_id: u64, primary: TxnRef, secondaries: Vec<TxnRef> },
+    /// Decides the outcome of a distributed transaction at its primary, by committing the primary
+    /// at the given time or rolling it back, and recording the outcome along with the secondaries
+    /// to resolve. Returns the recorded `TxnStatus`, which is the earlier one if the transaction
+    /// was already decided.
+    Decide { txn_id: u64, commit: bool, time: u64, secondaries: Vec<TxnRef> },
+    /// Commits or rolls back a secondary of a decided distributed transaction according to the
+    /// primary's status, unless it was already resolved
+    Resolve { txn_id: u64, status: TxnStatus },
+    /// Deletes the transaction record of a decided distributed transaction at its primary, once
+    /// none of its participants is prepared
+    Forget(u64),
+    /// Garbage collects the next batch of keys, keeping the versions needed by `AS OF` snapshots
+    /// within the retention period before the latest commit time in the log, so that all replicas
+    /// collect the same. Returns the `GcStats`.
+    Gc { retention: Duration, batch_size: usize },
+
     /// Creates a new row
     Create { txn_id: u64, table: String, row: Row },
     /// Deletes a row
@@ -36,8 +64,13 @@ impl std::fmt::Display for Mutation {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Mutation::Begin(mode) => write!(f, "BEGIN {:#?}", mode),
-            Mutation::Commit(id) => write!(f, "COMMIT txn {}", id),
+            Mutation::Commit { txn_id, time } => write!(f, "COMMIT txn {} at {}", txn_id, time),
             Mutation::Rollback(id) => write!(f, "ROLLBACK txn {}", id),
+            Mutation::Prepare { txn_id, .. } => write!(f, "PREPARE txn {}", txn_id),
+            Mutation::Decide { txn_id, commit, .. } => write!(f, "DECIDE txn {} commit={}", txn_id, commit),
+            Mutation::Resolve { txn_id, status } => write!(f, "RESOLVE txn {} {:?}", txn_id, status),
+            Mutation::Forget(txn_id) => write!(f, "FORGET txn {}", txn_id),
+            Mutation::Gc { retention, .. } => write!(f, "GC retaining {:?}", retention),
             Mutation::Create { txn_id, table, row } => write!(f, "CREATE"),
             Mutation::Delete { txn_id, table, id } => write!(f, "DELETE"),
             Mutation::Update { txn_id, table, id, row } => write!(f, "UPDATE"),
@@ -68,6 +101,13 @@ pub(crate) enum Query {
     ScanTables { txn_id: u64 },
     /// Reads a table
     ReadTable { txn_id: u64, table: String },
+
+    /// Returns the prepared transactions, by ID, along with their primaries and the other
+    /// participants
+    Prepared,
+    /// Returns the decided distributed transactions whose record is kept, by ID, along with their
+    /// status and secondaries
+    Decided,
 }
 
 impl std::fmt::Display for Query {
@@ -81,10 +121,29 @@ impl std::fmt::Display for Query {
             Query::ScanIndex { txn_id, table, column } => write!(f, "SCAN INDEX"),
             Query::ScanTables { txn_id } => write!(f, "SCAN TABLES"),
             Query::ReadTable { txn_id, table } => write!(f, "READ TABLE"),
+            Query::Prepared => write!(f, "PREPARED"),
+            Query::Decided => write!(f, "DECIDED"),
         }
     }
 }
 
+
+/// A reference to a transaction in a Raft group's state machine: a key in the group's range,
+/// which requests are routed by, and the transaction's ID in the group.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct TxnRef {
+    pub key: Vec<u8>,
+    pub txn_id: u64,
+}
+
+/// The outcome of a distributed transaction, as recorded at its primary. All participants
+/// commit at the primary's commit time.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) enum TxnStatus {
+    Committed { time: u64 },
+    Aborted,
+}
+
 /// An SQL engine that wraps a Raft cluster.
 #[derive(Clone)]
 pub struct RaftSqlEngine {
@@ -92,11 +151,22 @@ pub struct RaftSqlEngine {
 }
 
 impl RaftSqlEngine {
-    /// Creates a new Raft SQL engine.
-    pub async fn new(servers: Vec<String>) -> Result<Self> {
+    /// Creates a new Raft SQL engine, given the FeatherKV server addresses by node ID.
+    pub async fn new(servers: BTreeMap<u64, String>) -> Result<Self> {
         Ok(Self { client: raft::Client::new(servers).await? })
     }
 
+    /// Creates a new Raft SQL engine that routes requests via the placement driver, given the
+    /// FeatherKV addresses of the placement driver's nodes.
+    pub async fn placed(placement: BTreeMap<u64, String>) -> Result<Self> {
+        Ok(Self { client: raft::Client::placed(placement).await? })
+    }
+
+    /// Returns the Raft status of each FeatherKV server's replicas, by node ID and group ID.
+    pub fn status(&self) -> Result<BTreeMap<u64, BTreeMap<u64, raft::Status>>> {
+        futures::executor::block_on(self.client.clone().status())
+    }
+
     /// Creates an underlying state machine for a Raft engine.
     pub fn new_state(kv: MVCC) -> Result<StateMachine> {
         StateMachine::new(kv)
@@ -125,46 +195,230 @@ impl SqlEngine for RaftSqlEngine {
     }
 }
 
-/// A Raft-based SQL transaction
+/// A transaction's participant in a Raft group.
+#[derive(Clone, Debug)]
+struct Participant {
+    /// The transaction in the group's state machine.
+    txn: TxnRef,
+    /// The consistency of queries. Read-only transactions can be served by any replica that has
+    /// applied the transaction's beginning.
+    consistency: raft::Consistency,
+}
+
+/// A Raft-based SQL transaction, which may span the Raft groups of several ranges. A transaction
+/// is begun in the group of the empty key, the primary, and in every other group on first access.
+/// Rows are stored in the group of their row key, while schemas are stored in every group.
+///
+/// A transaction that spans several groups commits with a two-phase commit: all participants are
+/// prepared in parallel, then the primary commits or rolls back and records the outcome in its
+/// transaction record, and the other participants are resolved accordingly in the background.
+/// Participants left prepared by a coordinator that died are resolved by the [`Resolver`].
+///
+/// All participants commit at the same time, chosen by the coordinator no earlier than any of them
+/// may commit at. Reads in different groups only see a single consistent snapshot as of a time,
+/// which a group rejects while a participant prepared there may still commit by then. Unique and
+/// foreign key constraints are only checked within a group. Snapshot transactions as of a version
+/// only read the primary's group, since versions are local to a group.
 #[derive(Clone)]
 pub struct RaftSqlTxn {
     /// The underlying Raft cluster
     /// FIXME: This is a workaround for the immutable borrow of the Self::query() method.
     /// Not sure whether this affects the performance.
     client: Arc<Mutex<raft::Client>>,
-    /// The transaction ID
+    /// The transaction ID, i.e. the ID of the primary
     id: u64,
     /// The transaction mode
     mode: Mode,
+    /// The participants by group ID, including the primary. Participants are begun by queries
+    /// too, hence the mutex.
+    participants: Arc<Mutex<BTreeMap<u64, Participant>>>,
 }
 
 impl RaftSqlTxn {
-    /// Begins a new transaction.
-    pub fn begin(client: raft::Client, mode: Mode) -> Result<Self> {
-        let client = Arc::new(Mutex::new(client));
-        let id = RaftSqlEngine::deserialize(&futures::executor::block_on(
-            client.lock()?.mutate(RaftSqlEngine::serialize(&Mutation::Begin(mode))?)
+    /// Begins a new transaction. Loads the current ranges first, so that participants are found
+    /// by key; a range split off later carries the transaction's state along with it.
+    pub fn begin(mut client: raft::Client, mode: Mode) -> Result<Self> {
+        futures::executor::block_on(client.ranges())?;
+        let txn = Self {
+            client: Arc::new(Mutex::new(client)),
+            id: 0,
+            mode,
+            participants: Arc::new(Mutex::new(BTreeMap::new())),
+        };
+        let id = txn.participant(&[])?.txn.txn_id;
+        Ok(Self { id, ..txn })
+    }
+
+    /// Resumes an active transaction. Only the primary is resumed, since the other participants
+    /// are not known.
+    pub fn resume(mut client: raft::Client, id: u64) -> Result<Self> {
+        futures::executor::block_on(client.ranges())?;
+        let (id, mode) = RaftSqlEngine::deserialize(&futures::executor::block_on(
+            client.query(RaftSqlEngine::serialize(&Query::Resume(id))?)
         )?)?;
-        Ok(Self { client, id, mode })
+        let primary = Participant {
+            txn: TxnRef { key: vec![], txn_id: id },
+            consistency: raft::Consistency::Linearizable,
+        };
+        let participants = BTreeMap::from([(client.group_id(&[]), primary)]);
+        Ok(Self {
+            client: Arc::new(Mutex::new(client)),
+            id,
+            mode,
+            participants: Arc::new(Mutex::new(participants)),
+        })
+    }
+
+    /// Returns the participant in the group containing a key, beginning it if needed. Snapshot
+    /// transactions as of a version cannot begin outside the primary, since versions are local to
+    /// a group, while transactions as of a time read at that time in every group.
+    fn participant(&self, key: &[u8]) -> Result<Participant> {
+        let mut client = self.client.lock()?;
+        let mut participants = self.participants.lock()?;
+        if let Some(participant) = participants.get(&client.group_id(key)) {
+            return Ok(participant.clone());
+        }
+        if matches!(self.mode, Mode::Snapshot { .. }) && !participants.is_empty() {
+            return Err(Error::Value(
+                "A snapshot as of a version cannot read across ranges, use a time instead".into(),
+            ));
+        }
+        let txn_id = RaftSqlEngine::deserialize(&futures::executor::block_on(
+            client.mutate_key(key, RaftSqlEngine::serialize(&Mutation::Begin(self.mode))?)
+        )?)?;
+        let consistency = match self.mode.allows_write() {
+            true => raft::Consistency::Linearizable,
+            false => raft::Consistency::AtLeast(client.last_key_index(key)),
+        };
+        let participant = Participant { txn: TxnRef { key: key.to_vec(), txn_id }, consistency };
+        participants.insert(client.group_id(key), participant.clone());
+        Ok(participant)
+    }
+
+    /// Executes a mutation in the participant of the group containing a key, given the
+    /// participant's transaction ID. If the key turns out to have moved to another group, the
+    /// split copied the transaction there, which then joins the participants.
+    fn mutate(&self, key: &[u8], mutation: impl FnOnce(u64) -> Mutation) -> Result<Vec<u8>> {
+        let participant = self.participant(key)?;
+        let mut client = self.client.lock()?;
+        let result = futures::executor::block_on(
+            client.mutate_key(key, RaftSqlEngine::serialize(&mutation(participant.txn.txn_id))?)
+        );
+        let txn = TxnRef { key: key.to_vec(), txn_id: participant.txn.txn_id };
+        self.participants.lock()?.entry(client.group_id(key)).or_insert(Participant { txn, ..participant });
+        result
+    }
+
+    /// Executes a query in the participant of the group containing a key, given the
+    /// participant's transaction ID.
+    fn query(&self, key: &[u8], query: impl FnOnce(u64) -> Query) -> Result<Vec<u8>> {
+        let participant = self.participant(key)?;
+        futures::executor::block_on(self.client.lock()?.query_key_with(
+            key,
+            RaftSqlEngine::serialize(&query(participant.txn.txn_id))?,
+            participant.consistency,
+        ))
     }
 
-    /// Resumes an active transaction.
-    pub fn resume(client: raft::Client, id: u64) -> Result<Self> {
-        let client = Arc::new(Mutex::new(client));
-        let (id, mode) = RaftSqlEngine::deserialize(&futures::executor::block_on(
-            client.lock()?.query(RaftSqlEngine::serialize(&Query::Resume(id))?)
-        )?)?;
-        Ok(Self { client, id, mode })
+    /// Returns the current ranges, in key order.
+    fn ranges(&self) -> Result<Vec<RangeDescriptor>> {
+        futures::executor::block_on(self.client.lock()?.ranges())
     }
 
-    /// Executes an mutation.
-    fn mutate(&mut self, mutation: Mutation) -> Result<Vec<u8>> {
-        futures::executor::block_on(self.client.lock()?.mutate(RaftSqlEngine::serialize(&mutation)?))
+    /// Returns the key of a table row.
+    fn row_key(table: &str, id: &Value) -> Vec<u8> {
+        SqlKey::Row(table.into(), Some(id.into())).encode()
     }
 
-    /// Executes an query.
-    fn query(&self, query: Query) -> Result<Vec<u8>> {
-        futures::executor::block_on(self.client.lock()?.query(RaftSqlEngine::serialize(&query)?))
+    /// Returns the ranges holding rows of a table, each with a key in the range to route to.
+    fn table_ranges(&self, table: &str) -> Result<Vec<(RangeDescriptor, Vec<u8>)>> {
+        let prefix = SqlKey::Row(table.into(), None).encode();
+        Ok(self.ranges()?
+            .into_iter()
+            .filter_map(|range| {
+                let key = range.start.clone().max(prefix.clone());
+                (range.contains(&key) && key.starts_with(&prefix)).then_some((range, key))
+            })
+            .collect())
+    }
+
+    /// Commits or rolls back all participants directly, returning the first error.
+    fn finish(&self, commit: bool) -> Result<()> {
+        let participants: Vec<_> = self.participants.lock()?.values().cloned().collect();
+        let mut client = self.client.lock()?;
+        let time = now_millis();
+        let mut result = Ok(());
+        for Participant { txn, .. } in participants {
+            let mutation = match commit {
+                true => Mutation::Commit { txn_id: txn.txn_id, time },
+                false => Mutation::Rollback(txn.txn_id),
+            };
+            let finished = futures::executor::block_on(
+                client.mutate_key(&txn.key, RaftSqlEngine::serialize(&mutation)?)
+            ).and_then(|reply| RaftSqlEngine::deserialize::<()>(&reply));
+            result = result.and(finished);
+        }
+        result
+    }
+
+    /// Commits the participants atomically with a two-phase commit. Once the primary has decided,
+    /// the secondaries are resolved in the background if a Tokio runtime is available; those that
+    /// fail to resolve are left to the resolver.
+    fn commit_distributed(&self) -> Result<()> {
+        let primary = TxnRef { key: vec![], txn_id: self.id };
+        let participants: Vec<_> = self.participants.lock()?.values().map(|p| p.txn.clone()).collect();
+        let secondaries: Vec<_> = participants.iter().filter(|txn| *txn != &primary).cloned().collect();
+        let mut client = self.client.lock()?.fork();
+
+        let prepares = participants.iter().map(|txn| {
+            let mut client = client.fork();
+            let prepare = Mutation::Prepare {
+                txn_id: txn.txn_id,
+                primary: primary.clone(),
+                secondaries: secondaries.clone(),
+            };
+            async move {
+                let reply = client.mutate_key(&txn.key, RaftSqlEngine::serialize(&prepare)?).await?;
+                RaftSqlEngine::deserialize::<u64>(&reply)
+            }
+        });
+        let prepared = futures::executor::block_on(futures::future::join_all(prepares))
+            .into_iter()
+            .collect::<Result<Vec<_>>>();
+
+        // All participants commit at the same time, which none of them may commit before.
+        let time = prepared.iter().flatten().copied().fold(now_millis(), u64::max);
+        let decide = Mutation::Decide {
+            txn_id: primary.txn_id,
+            commit: prepared.is_ok(),
+            time,
+            secondaries: secondaries.clone(),
+        };
+        let status: TxnStatus = RaftSqlEngine::deserialize(&futures::executor::block_on(
+            client.mutate_key(&primary.key, RaftSqlEngine::serialize(&decide)?)
+        )?)?;
+        let commit = status != TxnStatus::Aborted;
+        let resolve = async move {
+            for txn in secondaries {
+                let resolve = Mutation::Resolve { txn_id: txn.txn_id, status };
+                client.mutate_key(&txn.key, RaftSqlEngine::serialize(&resolve)?).await?;
+            }
+            Ok::<_, Error>(())
+        };
+
+        // The participants left unresolved are rolled back by the resolver, from the primary's
+        // record, so the caller gets the error that aborted the transaction.
+        if !commit {
+            if let Err(err) = futures::executor::block_on(resolve) {
+                println!("Failed to roll back transaction {}: {}", primary.txn_id, err);
+            }
+            return Err(prepared.err().unwrap_or(Error::Serialization));
+        }
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => drop(handle.spawn(resolve)),
+            Err(_) => futures::executor::block_on(resolve)?,
+        }
+        Ok(())
     }
 }
 
@@ -177,18 +431,22 @@ impl SqlTxn for RaftSqlTxn {
         self.mode
     }
 
-    fn commit(mut self) -> Result<()> {
-        RaftSqlEngine::deserialize(&self.mutate(Mutation::Commit(self.id))?)
+    fn commit(self) -> Result<()> {
+        match self.mode.allows_write() && self.participants.lock()?.len() > 1 {
+            true => self.commit_distributed(),
+            false => self.finish(true),
+        }
     }
 
-    fn rollback(mut self) -> Result<()> {
-        RaftSqlEngine::deserialize(&self.mutate(Mutation::Rollback(self.id))?)
+    fn rollback(self) -> Result<()> {
+        self.finish(false)
     }
 
     fn create(&mut self, table: &str, row: Row) -> Result<()> {
-        RaftSqlEngine::deserialize(&self.mutate(
+        let key = Self::row_key(table, &self.assert_read_table(table)?.get_row_key(&row)?);
+        RaftSqlEngine::deserialize(&self.mutate(&key, |txn_id|
             Mutation::Create {
-                txn_id: self.id,
+                txn_id,
                 table: table.to_string(),
                 row,
             }
@@ -196,9 +454,9 @@ impl SqlTxn for RaftSqlTxn {
     }
 
     fn read(&self, table: &str, id: &Value) -> Result<Option<Row>> {
-        RaftSqlEngine::deserialize(&self.query(
+        RaftSqlEngine::deserialize(&self.query(&Self::row_key(table, id), |txn_id|
             Query::Read {
-                txn_id: self.id,
+                txn_id,
                 table: table.to_string(),
                 id: id.clone(),
             }
@@ -206,9 +464,14 @@ impl SqlTxn for RaftSqlTxn {
     }
 
     fn update(&mut self, table: &str, id: &Value, row: Row) -> Result<()> {
-        RaftSqlEngine::deserialize(&self.mutate(
+        // If the primary key changes, the new row may belong to another group.
+        if id != &self.assert_read_table(table)?.get_row_key(&row)? {
+            self.delete(table, id)?;
+            return self.create(table, row);
+        }
+        RaftSqlEngine::deserialize(&self.mutate(&Self::row_key(table, id), |txn_id|
             Mutation::Update {
-                txn_id: self.id,
+                txn_id,
                 table: table.to_string(),
                 id: id.clone(),
                 row,
@@ -217,9 +480,9 @@ impl SqlTxn for RaftSqlTxn {
     }
 
     fn delete(&mut self, table: &str, id: &Value) -> Result<()> {
-        RaftSqlEngine::deserialize(&self.mutate(
+        RaftSqlEngine::deserialize(&self.mutate(&Self::row_key(table, id), |txn_id|
             Mutation::Delete {
-                txn_id: self.id,
+                txn_id,
                 table: table.to_string(),
                 id: id.clone(),
             }
@@ -227,78 +490,100 @@ impl SqlTxn for RaftSqlTxn {
     }
 
     fn read_index(&self, table: &str, column: &str, value: &Value) -> Result<HashSet<Value>> {
-        RaftSqlEngine::deserialize(&self.query(
-            Query::ReadIndex {
-                txn_id: self.id,
-                table: table.to_string(),
-                column: column.to_string(),
-                value: value.clone(),
-            }
-        )?)
+        // Each group indexes its own rows, but may hold stale entries of rows split off of it.
+        let mut ids = HashSet::new();
+        for range in self.ranges()? {
+            let entries: HashSet<Value> = RaftSqlEngine::deserialize(&self.query(&range.start, |txn_id|
+                Query::ReadIndex {
+                    txn_id,
+                    table: table.to_string(),
+                    column: column.to_string(),
+                    value: value.clone(),
+                }
+            )?)?;
+            ids.extend(entries.into_iter().filter(|id| range.contains(&Self::row_key(table, id))));
+        }
+        Ok(ids)
     }
 
     fn scan(&self, table: &str, filter: Option<Expression>) -> Result<RowScan> {
-        Ok(Box::new(
-            RaftSqlEngine::deserialize::<Vec<_>>(&self.query(
+        let mut rows = vec![];
+        for (_, key) in self.table_ranges(table)? {
+            rows.extend(RaftSqlEngine::deserialize::<Vec<_>>(&self.query(&key, |txn_id|
                 Query::Scan {
-                    txn_id: self.id,
+                    txn_id,
                     table: table.to_string(),
-                    filter,
+                    filter: filter.clone(),
                 }
-            )?)?
-            .into_iter()
-            .map(Ok)
-        ))
+            )?)?);
+        }
+        Ok(Box::new(rows.into_iter().map(Ok)))
     }
 
     fn scan_index(&self, table: &str, column: &str) -> Result<IndexScan> {
-        Ok(Box::new(
-            RaftSqlEngine::deserialize::<Vec<_>>(&self.query(
+        // Merges the entries of all groups, like read_index().
+        let mut entries: Vec<(Value, HashSet<Value>)> = vec![];
+        let mut positions: HashMap<Value, usize> = HashMap::new();
+        for range in self.ranges()? {
+            let scan: Vec<(Value, HashSet<Value>)> = RaftSqlEngine::deserialize(&self.query(&range.start, |txn_id|
                 Query::ScanIndex {
-                    txn_id: self.id,
+                    txn_id,
                     table: table.to_string(),
                     column: column.to_string(),
                 }
-            )?)?
-            .into_iter()
-            .map(Ok)
-        ))
+            )?)?;
+            for (value, ids) in scan {
+                let ids = ids.into_iter().filter(|id| range.contains(&Self::row_key(table, id)));
+                let position = *positions.entry(value.clone()).or_insert_with(|| {
+                    entries.push((value, HashSet::new()));
+                    entries.len() - 1
+                });
+                entries[position].1.extend(ids);
+            }
+        }
+        Ok(Box::new(entries.into_iter().filter(|(_, ids)| !ids.is_empty()).map(Ok)))
     }
 }
 
 impl Catalog for RaftSqlTxn {
     fn create_table(&mut self, table: Table) -> Result<()> {
-        RaftSqlEngine::deserialize(&self.mutate(
-            Mutation::CreateTable {
-                txn_id: self.id,
-                schema: table,
-            }
-        )?)
+        for range in self.ranges()? {
+            RaftSqlEngine::deserialize::<()>(&self.mutate(&range.start, |txn_id|
+                Mutation::CreateTable {
+                    txn_id,
+                    schema: table.clone(),
+                }
+            )?)?;
+        }
+        Ok(())
     }
 
     fn read_table(&self, table: &str) -> Result<Option<Table>> {
-        RaftSqlEngine::deserialize(&self.query(
+        RaftSqlEngine::deserialize(&self.query(&[], |txn_id|
             Query::ReadTable {
-                txn_id: self.id,
+                txn_id,
                 table: table.to_string(),
             }
         )?)
     }
 
     fn delete_table(&mut self, table: &str) -> Result<()> {
-        RaftSqlEngine::deserialize(&self.mutate(
-            Mutation::DeleteTable {
-                txn_id: self.id,
-                table: table.to_string(),
-            }
-        )?)
+        for range in self.ranges()? {
+            RaftSqlEngine::deserialize::<()>(&self.mutate(&range.start, |txn_id|
+                Mutation::DeleteTable {
+                    txn_id,
+                    table: table.to_string(),
+                }
+            )?)?;
+        }
+        Ok(())
     }
 
     fn scan_tables(&self) -> Result<Tables> {
         Ok(Box::new(
-            RaftSqlEngine::deserialize::<Vec<_>>(&self.query(
+            RaftSqlEngine::deserialize::<Vec<_>>(&self.query(&[], |txn_id|
                 Query::ScanTables {
-                    txn_id: self.id,
+                    txn_id,
                 }
             )?)?
             .into_iter()
@@ -306,6 +591,137 @@ impl Catalog for RaftSqlTxn {
     }
 }
 
+/// Resolves the prepared participants of distributed transactions whose coordinator died. A
+/// participant that is still prepared one round after it was found is aborted at its primary,
+/// unless the primary has already decided, and is then resolved according to the recorded
+/// outcome. Several resolvers may run at once, since the primary only decides once.
+///
+/// The resolver also forgets the transaction records that are no longer needed. A record is
+/// deleted once no participant is prepared a round after it was found, by when all participants
+/// have been prepared, since they are prepared before the decision. Its secondaries are resolved
+/// first, which rolls back those left active when an aborting coordinator failed to.
+pub struct Resolver {
+    client: raft::Client,
+    /// The participants found prepared in the last round.
+    prepared: HashSet<(Vec<u8>, u64)>,
+    /// The transaction records found in the last round, by group ID and transaction ID.
+    decided: HashSet<(u64, u64)>,
+}
+
+impl Resolver {
+    /// Creates a new resolver using the given client.
+    pub fn new(client: raft::Client) -> Self {
+        Self { client, prepared: HashSet::new(), decided: HashSet::new() }
+    }
+
+    /// Runs a round every `RESOLVE_INTERVAL`. Failed rounds are logged and retried.
+    pub async fn run(mut self) -> Result<()> {
+        let mut interval = tokio::time::interval(RESOLVE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.resolve().await {
+                println!("Failed to resolve prepared transactions: {}", e);
+            }
+        }
+    }
+
+    /// Runs a round, resolving the participants that were already prepared in the last round,
+    /// and forgetting the transaction records that are no longer needed.
+    pub async fn resolve(&mut self) -> Result<()> {
+        let ranges = self.client.ranges().await?;
+        let mut prepared = HashSet::new();
+        // The records still needed by a prepared participant, by group ID and transaction ID.
+        let mut needed = HashSet::new();
+        for range in &ranges {
+            let query = RaftSqlEngine::serialize(&Query::Prepared)?;
+            let txns: Vec<(u64, TxnRef, Vec<TxnRef>)> =
+                RaftSqlEngine::deserialize(&self.client.query_key(&range.start, query).await?)?;
+            for (txn_id, primary, secondaries) in txns {
+                needed.insert((self.client.group_id(&primary.key), primary.txn_id));
+                let participant = (range.start.clone(), txn_id);
+                if !self.prepared.contains(&participant) {
+                    prepared.insert(participant);
+                    continue;
+                }
+                let decide = Mutation::Decide {
+                    txn_id: primary.txn_id,
+                    commit: false,
+                    time: now_millis(),
+                    secondaries,
+                };
+                let status: TxnStatus = RaftSqlEngine::deserialize(
+                    &self.client.mutate_key(&primary.key, RaftSqlEngine::serialize(&decide)?).await?
+                )?;
+                println!("Resolving prepared transaction {} in group {}: {:?}", txn_id, range.group_id, status);
+                let resolve = Mutation::Resolve { txn_id, status };
+                self.client.mutate_key(&range.start, RaftSqlEngine::serialize(&resolve)?).await?;
+            }
+        }
+        self.prepared = prepared;
+
+        let mut decided = HashSet::new();
+        for range in &ranges {
+            let query = RaftSqlEngine::serialize(&Query::Decided)?;
+            let records: Vec<(u64, TxnStatus, Vec<TxnRef>)> =
+                RaftSqlEngine::deserialize(&self.client.query_key(&range.start, query).await?)?;
+            for (txn_id, status, secondaries) in records {
+                let record = (range.group_id, txn_id);
+                if needed.contains(&record) || !self.decided.contains(&record) {
+                    decided.insert(record);
+                    continue;
+                }
+                for txn in secondaries {
+                    let resolve = Mutation::Resolve { txn_id: txn.txn_id, status };
+                    self.client.mutate_key(&txn.key, RaftSqlEngine::serialize(&resolve)?).await?;
+                }
+                let forget = Mutation::Forget(txn_id);
+                self.client.mutate_key(&range.start, RaftSqlEngine::serialize(&forget)?).await?;
+            }
+        }
+        self.decided = decided;
+        Ok(())
+    }
+}
+
+/// Garbage collects the MVCC stores of the ranges through their Raft logs, proposing a batch to
+/// each range every interval. The retention period is counted back from the latest commit time
+/// in each range's log, never from a local clock, so the replicas collect the same keys and agree
+/// on which `AS OF` times can still be read. Several collectors may run at once, since the
+/// watermark never moves back.
+pub struct Collector {
+    client: raft::Client,
+    opts: GcOptions,
+}
+
+impl Collector {
+    /// Creates a new collector using the given client.
+    pub fn new(client: raft::Client, opts: GcOptions) -> Self {
+        Self { client, opts }
+    }
+
+    /// Runs a round every `GcOptions::interval`. Failed rounds are logged and retried.
+    pub async fn run(mut self) -> Result<()> {
+        let mut interval = tokio::time::interval(self.opts.interval);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.collect().await {
+                println!("Failed to garbage collect: {}", e);
+            }
+        }
+    }
+
+    /// Runs a round, collecting a batch in each range. Returns the ranges' batch stats.
+    pub async fn collect(&mut self) -> Result<Vec<GcStats>> {
+        let gc = Mutation::Gc { retention: self.opts.retention, batch_size: self.opts.batch_size };
+        let gc = RaftSqlEngine::serialize(&gc)?;
+        let mut stats = vec![];
+        for range in self.client.ranges().await? {
+            stats.push(RaftSqlEngine::deserialize(&self.client.mutate_key(&range.start, gc.clone()).await?)?);
+        }
+        Ok(stats)
+    }
+}
+
 /// The Raft state machine for the Raft-based SQL engine, using a KV SQL engine
 pub struct StateMachine {
     /// The underlying KV SQL engine
@@ -328,9 +744,30 @@ impl StateMachine {
     fn apply(&mut self, mutation: Mutation) -> Result<Vec<u8>> {
         match mutation {
             Mutation::Begin(mode) => RaftSqlEngine::serialize(&self.engine.begin(mode)?.id()),
-            Mutation::Commit(txn_id) => RaftSqlEngine::serialize(&self.engine.resume(txn_id)?.commit()?),
+            Mutation::Commit { txn_id, time } => {
+                RaftSqlEngine::serialize(&self.engine.resume(txn_id)?.commit_at(time)?)
+            }
             Mutation::Rollback(txn_id) => RaftSqlEngine::serialize(&self.engine.resume(txn_id)?.rollback()?),
 
+            Mutation::Prepare { txn_id, primary, secondaries } => RaftSqlEngine::serialize(
+                &self.engine.resume(txn_id)?.prepare(RaftSqlEngine::serialize(&(primary, secondaries))?)?,
+            ),
+            Mutation::Decide { txn_id, commit, time, secondaries } => {
+                RaftSqlEngine::serialize(&self.decide(txn_id, commit, time, secondaries)?)
+            }
+            Mutation::Resolve { txn_id, status } => match self.engine.resume(txn_id) {
+                Ok(txn) => match status {
+                    TxnStatus::Committed { time } => RaftSqlEngine::serialize(&txn.commit_at(time)?),
+                    TxnStatus::Aborted => RaftSqlEngine::serialize(&txn.rollback()?),
+                },
+                Err(Error::Value(_)) => RaftSqlEngine::serialize(&()),
+                Err(err) => Err(err),
+            },
+            Mutation::Forget(txn_id) => RaftSqlEngine::serialize(&self.engine.kv.delete_txn_record(txn_id)?),
+            Mutation::Gc { retention, batch_size } => {
+                RaftSqlEngine::serialize(&self.engine.kv.collect_garbage(retention, batch_size)?)
+            }
+
             Mutation::Create { txn_id, table, row } => {
                 RaftSqlEngine::serialize(&self.engine.resume(txn_id)?.create(&table, row)?)
             }
@@ -349,6 +786,36 @@ impl StateMachine {
             }
         }
     }
+
+    /// Decides a distributed transaction at its primary, unless its transaction record shows it
+    /// was already decided. A primary that is no longer active without a record was rolled back,
+    /// or its record was forgotten once none of its participants was prepared. The record keeps
+    /// every secondary any decider knew of, for the resolver to resolve.
+    fn decide(&mut self, txn_id: u64, commit: bool, time: u64, secondaries: Vec<TxnRef>) -> Result<TxnStatus> {
+        if let Some(record) = self.engine.kv.get_txn_record(txn_id)? {
+            let (status, mut known): (TxnStatus, Vec<TxnRef>) = RaftSqlEngine::deserialize(&record)?;
+            let unknown: Vec<_> = secondaries.into_iter().filter(|txn| !known.contains(txn)).collect();
+            if !unknown.is_empty() {
+                known.extend(unknown);
+                self.engine.kv.set_txn_record(txn_id, RaftSqlEngine::serialize(&(status, known))?)?;
+            }
+            return Ok(status);
+        }
+        let status = match self.engine.resume(txn_id) {
+            Ok(txn) if commit => {
+                txn.commit_at(time)?;
+                TxnStatus::Committed { time }
+            },
+            Ok(txn) => {
+                txn.rollback()?;
+                TxnStatus::Aborted
+            },
+            Err(Error::Value(_)) => TxnStatus::Aborted,
+            Err(err) => return Err(err),
+        };
+        self.engine.kv.set_txn_record(txn_id, RaftSqlEngine::serialize(&(status, secondaries))?)?;
+        Ok(status)
+    }
 }
 
 impl raft::State for StateMachine {
@@ -369,6 +836,45 @@ impl raft::State for StateMachine {
         }
     }
 
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        self.engine.kv.export()
+    }
+
+    fn restore(&mut self, snapshot: Vec<u8>) -> Result<()> {
+        self.engine.kv.import(&snapshot)?;
+        self.applied_index = self
+            .engine
+            .get_metadata(b"applied_index")?
+            .map(|bytes| RaftSqlEngine::deserialize(&bytes))
+            .unwrap_or(Ok(0))?;
+        Ok(())
+    }
+
+    fn sizer(&self) -> Option<raft::Sizer> {
+        let kv = self.engine.kv.clone();
+        Some(Arc::new(move || kv.size(SIZE_BATCH)))
+    }
+
+    fn split_key(&self) -> Result<Option<Vec<u8>>> {
+        self.engine.split_key()
+    }
+
+    fn split(&mut self, key: &[u8]) -> Result<Vec<u8>> {
+        self.engine.split_off(key)
+    }
+
+    fn freeze(&self) -> Result<Vec<u8>> {
+        self.engine.kv.export_merge()
+    }
+
+    fn merge(&mut self, snapshot: Vec<u8>) -> Result<()> {
+        self.engine.kv.merge(&snapshot)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.kv.flush()
+    }
+
     fn query(&self, query: Vec<u8>) -> Result<Vec<u8>> {
         match RaftSqlEngine::deserialize(&query)? {
             Query::Resume(id) => {
@@ -400,6 +906,34 @@ impl raft::State for StateMachine {
             Query::ScanTables { txn_id } => {
                 RaftSqlEngine::serialize(&self.engine.resume(txn_id)?.scan_tables()?.collect::<Vec<_>>())
             },
+
+            Query::Prepared => RaftSqlEngine::serialize(
+                &self
+                    .engine
+                    .kv
+                    .prepared()?
+                    .into_iter()
+                    .map(|(txn_id, refs)| {
+                        let (primary, secondaries): (TxnRef, Vec<TxnRef>) = RaftSqlEngine::deserialize(&refs)?;
+                        Ok((txn_id, primary, secondaries))
+                    })
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+            Query::Decided => RaftSqlEngine::serialize(
+                &self
+                    .engine
+                    .kv
+                    .txn_records()?
+                    .into_iter()
+                    .map(|txn_id| {
+                        let record = self.engine.kv.get_txn_record(txn_id)?.ok_or_else(|| {
+                            Error::Internal(format!("Transaction record {} not found", txn_id))
+                        })?;
+                        let (status, secondaries): (TxnStatus, Vec<TxnRef>) = RaftSqlEngine::deserialize(&record)?;
+                        Ok((txn_id, status, secondaries))
+                    })
+                    .collect::<Result<Vec<_>>>()?,
+            ),
         }
     }
 }
\ No newline at end of file
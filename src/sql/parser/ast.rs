@@ -6,7 +6,7 @@ use crate::sql::types::DataType;
 pub enum Statement {
     Begin {
         read_only: bool,
-        version: Option<u64>,
+        as_of: Option<AsOf>,
     },
     Commit,
     Rollback,
@@ -31,6 +31,7 @@ pub enum Statement {
         order: Vec<(Expression, Order)>,
         offset: Option<Expression>,
         limit: Option<Expression>,
+        as_of: Option<AsOf>,
     },
     Update {
         table: String,
@@ -43,6 +44,15 @@ pub enum Statement {
     },
 }
 
+/// The point in time of an AS OF SYSTEM TIME clause
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AsOf {
+    /// A version, i.e. a transaction ID
+    Version(u64),
+    /// A commit time, in milliseconds since the Unix epoch
+    Time(u64),
+}
+
 /// A FROM item
 #[derive(Clone, Debug, PartialEq)]
 pub enum FromItem {
@@ -313,6 +313,7 @@ impl std::fmt::Display for Symbol {
 }
 
 /// A lexer that tokenizes an input string as an iterator.
+#[derive(Clone)]
 pub struct Lexer<'a> {
     iter: Peekable<Chars<'a>>,
 }
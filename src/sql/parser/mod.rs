@@ -111,6 +111,13 @@ impl<'a> Parser<'a> {
         self.lexer.peek().cloned().transpose()
     }
 
+    /// Peeks the lexer token after the next one, if any.
+    fn peek_second(&self) -> Result<Option<Token>> {
+        let mut lexer = self.lexer.clone();
+        lexer.next();
+        lexer.next().transpose()
+    }
+
     /// Parses an SQL statement.
     fn parse_statement(&mut self) -> Result<ast::Statement> {
         match self.peek()? {
@@ -138,7 +145,6 @@ impl<'a> Parser<'a> {
         match self.next()? {
             Token::Keyword(Keyword::Begin) => {
                 let mut read_only = false;
-                let mut version = None;
                 self.next_if_token(Keyword::Transaction.into());
                 if self.next_if_token(Keyword::Read.into()).is_some() {
                     match self.next()? {
@@ -147,16 +153,8 @@ impl<'a> Parser<'a> {
                         token => return Err(Error::Parse(format!("Unexpected token {}", token))),
                     }
                 }
-                if self.next_if_token(Keyword::As.into()).is_some() {
-                    self.next_expect(Some(Keyword::Of.into()))?;
-                    self.next_expect(Some(Keyword::System.into()))?;
-                    self.next_expect(Some(Keyword::Time.into()))?;
-                    match self.next()? {
-                        Token::Number(n) => version = Some(n.parse::<u64>()?),
-                        token => return Err(Error::Parse(format!("Expected number, got {}", token))),
-                    }
-                }
-                Ok(ast::Statement::Begin { read_only, version })
+                let as_of = self.parse_clause_as_of()?;
+                Ok(ast::Statement::Begin { read_only, as_of })
             }
 
             Token::Keyword(Keyword::Commit) => Ok(ast::Statement::Commit),
@@ -308,6 +306,7 @@ impl<'a> Parser<'a> {
         Ok(ast::Statement::Select {
             select: self.parse_clause_select()?,
             from: self.parse_clause_from()?,
+            as_of: self.parse_clause_as_of()?,
             r#where: self.parse_clause_where()?,
             group_by: self.parse_clause_group_by()?,
             having: self.parse_clause_having()?,
@@ -425,7 +424,11 @@ impl<'a> Parser<'a> {
     // Parses a from clause table
     fn parse_clause_from_table(&mut self) -> Result<ast::FromItem> {
         let name = self.next_identifier()?;
-        let alias = if self.next_if_token(Keyword::As.into()).is_some() {
+        // AS OF starts an AS OF SYSTEM TIME clause rather than an alias.
+        let alias = if self.peek()? == Some(Keyword::As.into())
+            && self.peek_second()? != Some(Keyword::Of.into())
+        {
+            self.next()?;
             Some(self.next_identifier()?)
         } else if let Some(Token::Identifier(_)) = self.peek()? {
             Some(self.next_identifier()?)
@@ -458,6 +461,21 @@ impl<'a> Parser<'a> {
         }
     }
 
+    /// Parses an AS OF SYSTEM TIME clause, given either a version or a UTC timestamp string
+    fn parse_clause_as_of(&mut self) -> Result<Option<ast::AsOf>> {
+        if self.next_if_token(Keyword::As.into()).is_none() {
+            return Ok(None);
+        }
+        self.next_expect(Some(Keyword::Of.into()))?;
+        self.next_expect(Some(Keyword::System.into()))?;
+        self.next_expect(Some(Keyword::Time.into()))?;
+        match self.next()? {
+            Token::Number(n) => Ok(Some(ast::AsOf::Version(n.parse::<u64>()?))),
+            Token::String(s) => Ok(Some(ast::AsOf::Time(parse_timestamp(&s)?))),
+            token => Err(Error::Parse(format!("Expected version or timestamp, got {}", token))),
+        }
+    }
+
     /// Parses a group by clause
     fn parse_clause_group_by(&mut self) -> Result<Vec<ast::Expression>> {
         let mut exprs = Vec::new();
@@ -799,3 +817,55 @@ pub(super) fn format_ident(ident: &str) -> String {
         format!("\"{}\"", ident.replace("\"", "\"\""))
     }
 }
+
+
+/// Parses a UTC timestamp of the form `YYYY-MM-DD[ HH:MM:SS[.fff]]`, optionally with a `T`
+/// separator and `Z` suffix, into milliseconds since the Unix epoch.
+pub fn parse_timestamp(timestamp: &str) -> Result<u64> {
+    lazy_static! {
+        static ref RE_TIMESTAMP: Regex = Regex::new(
+            r#"^(\d{4})-(\d{2})-(\d{2})(?:[ T](\d{2}):(\d{2}):(\d{2})(?:\.(\d{1,9}))?)?Z?$"#
+        ).unwrap();
+    }
+
+    let invalid = || Error::Parse(format!("Invalid timestamp {}", timestamp));
+    let captures = RE_TIMESTAMP.captures(timestamp).ok_or_else(invalid)?;
+    let field = |i: usize| captures.get(i).map_or(Ok(0), |m| m.as_str().parse::<u64>());
+    let (year, month, day) = (field(1)?, field(2)?, field(3)?);
+    let (hour, minute, second) = (field(4)?, field(5)?, field(6)?);
+    let millis = captures.get(7).map_or(Ok(0), |m| format!("{:0<3}", m.as_str())[..3].parse::<u64>())?;
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = [31, if leap { 29 } else { 28 }, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+    if year < 1970 || !(1..=12).contains(&month) || day < 1 || day > month_days[month as usize - 1]
+        || hour > 23 || minute > 59 || second > 59
+    {
+        return Err(invalid());
+    }
+
+    // Counts the days since the epoch in 400-year eras starting in March, so that leap days fall
+    // at the end of a year.
+    let (year, month) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let (era, year_of_era) = (year / 400, year % 400);
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    Ok(((days * 24 + hour) * 60 + minute) * 60_000 + second * 1000 + millis)
+}
+
+/// Formats milliseconds since the Unix epoch as a UTC timestamp that `parse_timestamp()` parses.
+pub fn format_timestamp(time: u64) -> String {
+    let (days, millis) = (time / 86_400_000 + 719468, time % 86_400_000);
+    let (era, day_of_era) = (days / 146097, days % 146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let (year, month) = match month < 10 {
+        true => (era * 400 + year_of_era, month + 3),
+        false => (era * 400 + year_of_era + 1, month - 9),
+    };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}",
+        year, month, day, millis / 3_600_000, millis / 60_000 % 60, millis / 1000 % 60, millis % 1000,
+    )
+}
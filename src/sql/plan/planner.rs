@@ -90,6 +90,8 @@ impl<'a, C: Catalog> Planner<'a, C> {
                 mut order,
                 offset,
                 limit,
+                // Handled by the session, which runs the query in a transaction as of then.
+                as_of: _,
             } => {
                 let environment = &mut Environment::new();
 
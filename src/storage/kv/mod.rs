@@ -24,6 +24,27 @@ pub trait KvStore: Display + Send + Sync {
 
     /// Flushes any buffered data to the underlying storage medium.
     fn flush(&self) -> Result<()>;
+
+    /// Returns statistics about an ordered range of key/value pairs. The default implementation
+    /// scans the range.
+    fn stats(&self, range: Range) -> Result<Stats> {
+        let mut stats = Stats::default();
+        for item in self.scan(range)? {
+            let (key, value) = item?;
+            stats.keys += 1;
+            stats.size += (key.len() + value.len()) as u64;
+        }
+        Ok(stats)
+    }
+}
+
+/// Statistics about a range of key/value pairs.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats {
+    /// The number of keys.
+    pub keys: u64,
+    /// The total size of the keys and values, in bytes.
+    pub size: u64,
 }
 
 #[derive(Clone)]
@@ -81,6 +102,7 @@ trait TestSuite<S: KvStore> {
         Self::test_get()?;
         Self::test_scan()?;
         Self::test_set()?;
+        Self::test_stats()?;
         Self::test_random()?;
         Ok(())
     }
@@ -209,4 +231,17 @@ trait TestSuite<S: KvStore> {
         assert_eq!(Some(vec![0x02]), s.get(b"a")?);
         Ok(())
     }
+
+    fn test_stats() -> Result<()> {
+        let s = Self::setup()?;
+        assert_eq!(Stats::default(), s.stats(Range::from(..))?);
+        s.set(b"a", vec![0x01])?;
+        s.set(b"b", vec![0x02, 0x02])?;
+        s.set(b"bb", vec![0x02, 0x02, 0x02])?;
+        assert_eq!(Stats { keys: 3, size: 10 }, s.stats(Range::from(..))?);
+        assert_eq!(Stats { keys: 2, size: 8 }, s.stats(Range::from(b"b".to_vec()..))?);
+        s.delete(b"b")?;
+        assert_eq!(Stats { keys: 1, size: 5 }, s.stats(Range::from(b"b".to_vec()..))?);
+        Ok(())
+    }
 }
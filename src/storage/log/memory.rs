@@ -31,6 +31,10 @@ impl LogStore for LogDemo {
         self.store.write()?.append(entry)
     }
 
+    fn flush(&mut self) -> Result<()> {
+        self.store.write()?.flush()
+    }
+
     fn commit(&mut self, index: u64) -> Result<()> {
         self.store.write()?.commit(index)
     }
@@ -47,6 +51,14 @@ impl LogStore for LogDemo {
         self.store.read().unwrap().len()
     }
 
+    fn compact(&mut self, index: u64) -> Result<u64> {
+        self.store.write()?.compact(index)
+    }
+
+    fn compact_index(&self) -> u64 {
+        self.store.read().unwrap().compact_index()
+    }
+
     fn scan(&self, range: Range) -> LogScan {
         // Since the mutex guard is scoped to this method, we simply buffer the result.
         Box::new(self.store.read().unwrap().scan(range).collect::<Vec<Result<_>>>().into_iter())
@@ -73,6 +85,8 @@ impl LogStore for LogDemo {
 // An in-memory log store.
 pub struct Memory {
     log: Vec<Vec<u8>>,
+    /// The number of entries removed from the front of the log by compaction.
+    offset: u64,
     commit_index: u64,
     metadata: HashMap<Vec<u8>, Vec<u8>>,
 }
@@ -80,7 +94,7 @@ pub struct Memory {
 impl Memory {
     /// Creates a new in-memory log.
     pub fn new() -> Self {
-        Self { log: Vec::new(), commit_index: 0, metadata: HashMap::new() }
+        Self { log: Vec::new(), offset: 0, commit_index: 0, metadata: HashMap::new() }
     }
 }
 
@@ -93,7 +107,11 @@ impl Display for Memory {
 impl LogStore for Memory {
     fn append(&mut self, entry: Vec<u8>) -> Result<u64> {
         self.log.push(entry);
-        Ok(self.log.len() as u64)
+        Ok(self.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
     }
 
     fn commit(&mut self, index: u64) -> Result<()> {
@@ -116,29 +134,49 @@ impl LogStore for Memory {
 
     fn get(&self, index: u64) -> Result<Option<Vec<u8>>> {
         match index {
-            0 => Ok(None),
-            i => Ok(self.log.get(i as usize - 1).cloned()),
+            i if i <= self.offset => Ok(None),
+            i => Ok(self.log.get((i - self.offset) as usize - 1).cloned()),
         }
     }
 
     fn len(&self) -> u64 {
-        self.log.len() as u64
+        self.offset + self.log.len() as u64
+    }
+
+    fn compact(&mut self, index: u64) -> Result<u64> {
+        if index <= self.offset {
+            return Ok(self.offset);
+        }
+        if index >= self.len() {
+            self.log.clear();
+        } else {
+            self.log.drain(..(index - self.offset) as usize);
+        }
+        self.offset = index;
+        self.commit_index = self.commit_index.max(index);
+        Ok(index)
+    }
+
+    fn compact_index(&self) -> u64 {
+        self.offset
     }
 
     fn scan(&self, range: Range) -> LogScan {
+        // Converts the 1-based log indexes into positions in the retained entries.
+        let position = |index: u64| index.saturating_sub(self.offset) as usize;
         Box::new(
             self.log
                 .iter()
                 .take(match range.end {
-                    Bound::Included(n) => n as usize,
+                    Bound::Included(n) => position(n),
                     Bound::Excluded(0) => 0,
-                    Bound::Excluded(n) => n as usize - 1,
-                    Bound::Unbounded => std::usize::MAX,
+                    Bound::Excluded(n) => position(n - 1),
+                    Bound::Unbounded => usize::MAX,
                 })
                 .skip(match range.start {
                     Bound::Included(0) => 0,
-                    Bound::Included(n) => n as usize - 1,
-                    Bound::Excluded(n) => n as usize,
+                    Bound::Included(n) => position(n - 1),
+                    Bound::Excluded(n) => position(n),
                     Bound::Unbounded => 0,
                 })
                 .cloned()
@@ -157,8 +195,14 @@ impl LogStore for Memory {
                 self.commit_index
             )));
         }
-        self.log.truncate(index as usize);
-        Ok(self.log.len() as u64)
+        if index < self.offset {
+            return Err(Error::Internal(format!(
+                "Cannot truncate below compacted index {}",
+                self.offset
+            )));
+        }
+        self.log.truncate((index - self.offset) as usize);
+        Ok(self.len())
     }
 
     fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
@@ -11,6 +11,10 @@ pub trait LogStore: Display + Sync + Send {
     /// Appends a log entry, returning its index.
     fn append(&mut self, entry: Vec<u8>) -> Result<u64>;
 
+    /// Flushes appended entries to durable storage. Appends may be buffered until flushed, so
+    /// that a batch of appends only pays for a single flush.
+    fn flush(&mut self) -> Result<()>;
+
     /// Commits log entries up to and including the given index, making them immutable.
     fn commit(&mut self, index: u64) -> Result<()>;
 
@@ -20,9 +24,17 @@ pub trait LogStore: Display + Sync + Send {
     /// Fetches a log entry, if it exists.
     fn get(&self, index: u64) -> Result<Option<Vec<u8>>>;
 
-    /// Returns the number of entries in the log.
+    /// Returns the index of the last entry in the log, including any compacted entries.
     fn len(&self) -> u64;
 
+    /// Compacts the log by removing all entries up to and including the given index, which are
+    /// considered committed afterwards. If the index is past the last entry, the log is emptied
+    /// and subsequent appends continue from `index + 1`. Returns the last compacted index.
+    fn compact(&mut self, index: u64) -> Result<u64>;
+
+    /// Returns the index of the last compacted entry, or 0 if the log has not been compacted.
+    fn compact_index(&self) -> u64;
+
     /// Scans the log between the given indexes.
     fn scan(&self, range: Range) -> LogScan;
 
@@ -41,7 +53,7 @@ pub trait LogStore: Display + Sync + Send {
 
     /// Returns true if the log has no entries.
     fn is_empty(&self) -> bool {
-        self.len() == 0
+        self.len() == self.compact_index()
     }
 }
 
@@ -0,0 +1,162 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use featherdb::concurrency::{now_millis, MVCC};
+use featherdb::encoding::{encode_string, encode_value};
+use featherdb::error::{Error, Result};
+use featherdb::proto::featherkv::FeatherKvServer;
+use featherdb::raft::{Client, FeatherKV, Options, RangeTable, SplitOptions, State};
+use featherdb::sql::engine::{RaftSqlEngine, Resolver, SqlEngine, SqlSession, StateMachine};
+use featherdb::sql::execution::ResultSet;
+use featherdb::sql::parser::format_timestamp;
+use featherdb::sql::types::Value;
+use featherdb::storage;
+use tonic::transport::Server;
+
+use super::allocate_peers;
+
+/// Starts a single FeatherKV server whose SQL rows are split into two ranges at row 5 of table
+/// `t`, and returns an SQL session on it along with its address and the groups' stores.
+async fn setup() -> Result<(Arc<SqlSession<RaftSqlEngine>>, String, Arc<Mutex<Vec<MVCC>>>)> {
+    let split_key = [&[0x03][..], &encode_string("t"), &encode_value(&Value::Integer(5))].concat();
+    let stores = Arc::new(Mutex::new(vec![]));
+    let groups_stores = stores.clone();
+    let server = FeatherKV::new(
+        0,
+        allocate_peers(1),
+        false,
+        Options::default(),
+        RangeTable::new(vec![split_key])?,
+        SplitOptions::default(),
+        move |_| {
+            let kv = MVCC::new(Box::new(storage::kv::StdBPlusTree::new()), true);
+            groups_stores.lock()?.push(kv.clone());
+            let state: Box<dyn State> = Box::new(StateMachine::new(kv)?);
+            let log_store: Box<dyn storage::log::LogStore> = Box::new(storage::log::Memory::new());
+            Ok((state, log_store))
+        },
+    ).await?;
+    let addr = allocate_peers(1)[&0].clone();
+    tokio::spawn(Server::builder().add_service(FeatherKvServer::new(server)).serve(addr.parse()?));
+    let engine = RaftSqlEngine::new([(0, addr.clone())].into()).await?;
+    Ok((Arc::new(engine.session()?), addr, stores))
+}
+
+/// Executes a query in a session, off the async runtime since sessions block.
+async fn execute(session: &Arc<SqlSession<RaftSqlEngine>>, query: &str) -> Result<ResultSet> {
+    let (session, query) = (session.clone(), query.to_string());
+    tokio::task::spawn_blocking(move || session.execute(&query)).await.unwrap()
+}
+
+/// Returns the rows of a query's result.
+async fn rows(session: &Arc<SqlSession<RaftSqlEngine>>, query: &str) -> Result<Vec<Vec<Value>>> {
+    match execute(session, query).await? {
+        ResultSet::Query { buffered_rows, .. } => buffered_rows,
+        result => panic!("Unexpected result {:?}", result),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_distributed_commit() -> Result<()> {
+    let (session, _, _) = setup().await?;
+    execute(&session, "CREATE TABLE t (id INTEGER PRIMARY KEY, v INTEGER INDEX)").await?;
+
+    // A transaction writing rows on both sides of the split commits atomically.
+    execute(&session, "BEGIN").await?;
+    execute(&session, "INSERT INTO t VALUES (1, 1), (2, 2), (7, 1), (8, 2)").await?;
+    execute(&session, "COMMIT").await?;
+    let ids = |rows: Vec<Vec<Value>>| rows.into_iter().map(|row| row[0].clone()).collect::<Vec<_>>();
+    assert_eq!(
+        vec![Value::Integer(1), Value::Integer(2), Value::Integer(7), Value::Integer(8)],
+        ids(rows(&session, "SELECT id FROM t").await?),
+    );
+    let mut indexed = ids(rows(&session, "SELECT id FROM t WHERE v = 1").await?);
+    indexed.sort_by_key(|id| id.to_string());
+    assert_eq!(vec![Value::Integer(1), Value::Integer(7)], indexed);
+
+    // An update moving rows across the split, then rolled back, leaves no trace on either side.
+    execute(&session, "BEGIN").await?;
+    execute(&session, "UPDATE t SET id = id + 10, v = 3").await?;
+    assert_eq!(4, rows(&session, "SELECT id FROM t WHERE v = 3").await?.len());
+    execute(&session, "ROLLBACK").await?;
+    assert_eq!(2, rows(&session, "SELECT id FROM t WHERE v = 2").await?.len());
+    assert!(rows(&session, "SELECT id FROM t WHERE id > 10").await?.is_empty());
+
+    // A statement failing on one side is not applied on the other.
+    assert!(execute(&session, "INSERT INTO t VALUES (9, 9), (1, 9)").await.is_err());
+    assert!(rows(&session, "SELECT id FROM t WHERE v = 9").await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_distributed_conflict() -> Result<()> {
+    let (session, addr, stores) = setup().await?;
+    let other = Arc::new(RaftSqlEngine::new([(0, addr.clone())].into()).await?.session()?);
+    execute(&session, "CREATE TABLE t (id INTEGER PRIMARY KEY, v INTEGER)").await?;
+    execute(&session, "INSERT INTO t VALUES (1, 0), (8, 0)").await?;
+
+    // Two transactions writing the same row on one side conflict, and the loser aborts on both.
+    execute(&session, "BEGIN").await?;
+    execute(&other, "BEGIN").await?;
+    execute(&session, "UPDATE t SET v = 1").await?;
+    assert_eq!(Err(Error::Serialization), execute(&other, "UPDATE t SET v = 2 WHERE id = 8").await.map(|_| ()));
+    execute(&other, "ROLLBACK").await?;
+    execute(&session, "COMMIT").await?;
+    assert_eq!(
+        vec![vec![Value::Integer(1), Value::Integer(1)], vec![Value::Integer(8), Value::Integer(1)]],
+        rows(&session, "SELECT * FROM t").await?,
+    );
+
+    // Nothing is left prepared for the resolver, which forgets the transaction records once none
+    // of their participants is prepared.
+    let records = || -> Result<usize> {
+        stores.lock()?.iter().map(|kv| Ok(kv.txn_records()?.len())).sum()
+    };
+    assert!(records()? > 0);
+    let mut resolver = Resolver::new(Client::new([(0, addr)].into()).await?);
+    for _ in 0..3 {
+        resolver.resolve().await?;
+    }
+    assert_eq!(0, records()?);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_distributed_as_of() -> Result<()> {
+    let (session, _, _) = setup().await?;
+    execute(&session, "CREATE TABLE t (id INTEGER PRIMARY KEY, v INTEGER)").await?;
+    execute(&session, "INSERT INTO t VALUES (1, 1), (8, 1)").await?;
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let before = format_timestamp(now_millis());
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    execute(&session, "UPDATE t SET v = 2").await?;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let new = vec![vec![Value::Integer(1), Value::Integer(2)], vec![Value::Integer(8), Value::Integer(2)]];
+    assert_eq!(new, rows(&session, "SELECT * FROM t ORDER BY id").await?);
+
+    // A query as of a time sees the rows committed by then on both sides of the split.
+    let old = vec![vec![Value::Integer(1), Value::Integer(1)], vec![Value::Integer(8), Value::Integer(1)]];
+    let query = format!("SELECT * FROM t AS OF SYSTEM TIME '{}' ORDER BY id", before);
+    assert_eq!(old, rows(&session, &query).await?);
+
+    // So does a read-only transaction, but its queries cannot pick another time.
+    execute(&session, &format!("BEGIN READ ONLY AS OF SYSTEM TIME '{}'", before)).await?;
+    assert_eq!(old, rows(&session, "SELECT * FROM t ORDER BY id").await?);
+    assert!(execute(&session, &query).await.is_err());
+    execute(&session, "COMMIT").await?;
+
+    // A snapshot as of a version cannot read across the split, since versions differ by group.
+    assert!(matches!(
+        execute(&session, "SELECT * FROM t AS OF SYSTEM TIME 2").await,
+        Err(Error::Value(message)) if message.contains("across ranges")
+    ));
+
+    // Times in the future and invalid timestamps are rejected.
+    let future = format_timestamp(now_millis() + 60_000);
+    assert!(execute(&session, &format!("SELECT * FROM t AS OF SYSTEM TIME '{}'", future)).await.is_err());
+    assert!(matches!(
+        execute(&session, "SELECT * FROM t AS OF SYSTEM TIME '2024-02-30 00:00:00'").await,
+        Err(Error::Parse(_))
+    ));
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use featherdb::concurrency::{now_millis, GcOptions, MVCC};
+use featherdb::error::{Error, Result};
+use featherdb::proto::featherkv::FeatherKvServer;
+use featherdb::raft::{Client, FeatherKV, Options, RangeTable, SplitOptions, State};
+use featherdb::sql::engine::{Collector, RaftSqlEngine, SqlEngine, SqlSession, StateMachine};
+use featherdb::sql::execution::ResultSet;
+use featherdb::sql::parser::format_timestamp;
+use featherdb::sql::types::Value;
+use featherdb::storage;
+use tonic::transport::Server;
+
+use super::allocate_peers;
+
+/// Starts a cluster of FeatherKV servers with a replica of a single SQL range on each, and
+/// returns their addresses along with the replicas' stores by server ID.
+async fn setup(replicas: u64) -> Result<(BTreeMap<u64, String>, Arc<Mutex<BTreeMap<u64, MVCC>>>)> {
+    let peers = allocate_peers(replicas);
+    let addrs = allocate_peers(replicas);
+    let stores = Arc::new(Mutex::new(BTreeMap::new()));
+    for id in 0..replicas {
+        let group_stores = stores.clone();
+        let server = FeatherKV::new(
+            id,
+            peers.clone(),
+            false,
+            Options::default(),
+            RangeTable::default(),
+            SplitOptions::default(),
+            move |_| {
+                let kv = MVCC::new(Box::new(storage::kv::StdBPlusTree::new()), true);
+                group_stores.lock()?.insert(id, kv.clone());
+                let state: Box<dyn State> = Box::new(StateMachine::new(kv)?);
+                let log_store: Box<dyn storage::log::LogStore> = Box::new(storage::log::Memory::new());
+                Ok((state, log_store))
+            },
+        ).await?;
+        tokio::spawn(Server::builder().add_service(FeatherKvServer::new(server)).serve(addrs[&id].parse()?));
+    }
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    Ok((addrs, stores))
+}
+
+/// Executes a query in a session, off the async runtime since sessions block.
+async fn execute(session: &Arc<SqlSession<RaftSqlEngine>>, query: &str) -> Result<ResultSet> {
+    let (session, query) = (session.clone(), query.to_string());
+    tokio::task::spawn_blocking(move || session.execute(&query)).await.unwrap()
+}
+
+/// Waits for all replicas to apply the same entries, and returns their exported stores.
+async fn exports(stores: &Arc<Mutex<BTreeMap<u64, MVCC>>>) -> Result<Vec<Vec<u8>>> {
+    let mut exports = vec![];
+    for _ in 0..50 {
+        exports = stores.lock()?.values().map(|kv| kv.export()).collect::<Result<Vec<_>>>()?;
+        if exports.windows(2).all(|pair| pair[0] == pair[1]) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    Ok(exports)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_replicated_gc() -> Result<()> {
+    let (addrs, stores) = setup(3).await?;
+    let session = Arc::new(RaftSqlEngine::new(addrs.clone()).await?.session()?);
+    let opts = GcOptions { batch_size: 2, retention: Duration::ZERO, ..GcOptions::default() };
+    let mut collector = Collector::new(Client::new(addrs).await?, opts);
+    execute(&session, "CREATE TABLE t (id INTEGER PRIMARY KEY, v INTEGER)").await?;
+    execute(&session, "INSERT INTO t VALUES (1, 0), (2, 0)").await?;
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let before = format_timestamp(now_millis());
+    tokio::time::sleep(Duration::from_millis(20)).await;
+
+    // Batches are collected through the log while transactions supersede the rows' versions.
+    let mut versions = 0;
+    for _ in 0..3 {
+        execute(&session, "UPDATE t SET v = v + 1").await?;
+        loop {
+            let stats = collector.collect().await?;
+            versions += stats.iter().map(|stats| stats.versions).sum::<u64>();
+            if stats.iter().all(|stats| stats.complete) {
+                break;
+            }
+        }
+    }
+    assert!(versions > 0);
+    match execute(&session, "SELECT * FROM t").await? {
+        ResultSet::Query { buffered_rows, .. } => {
+            let rows = vec![vec![Value::Integer(1), Value::Integer(3)], vec![Value::Integer(2), Value::Integer(3)]];
+            assert_eq!(rows, buffered_rows?)
+        },
+        result => panic!("Unexpected result {:?}", result),
+    }
+
+    // Without retention, the time before the updates is no longer readable once the latest
+    // commit in the log has been collected.
+    assert!(matches!(
+        execute(&session, &format!("SELECT * FROM t AS OF SYSTEM TIME '{}'", before)).await,
+        Err(Error::Value(message)) if message.contains("garbage collected")
+    ));
+
+    // All replicas collected the same keys and recorded the same watermark.
+    let exports = exports(&stores).await?;
+    assert_eq!(3, exports.len());
+    assert!(exports.windows(2).all(|pair| pair[0] == pair[1]));
+    Ok(())
+}
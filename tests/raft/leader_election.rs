@@ -1,5 +1,13 @@
+use std::time::Duration;
+
 use featherdb::error::Result;
-use super::setup;
+use featherdb::proto::raft::raft_service_server::RaftService;
+use featherdb::proto::raft::RequestVoteArgs;
+use featherdb::raft::{Clock, ManualClock, Node, Options, SimNetwork};
+use featherdb::storage;
+use tokio::sync::mpsc;
+use tonic::Request;
+use super::{allocate_peers, setup, setup_with, start_node, Cluster, APPLY_CHANNEL_CAPACITY};
 
 #[tokio::test]
 async fn test_initial_election() -> Result<()> {
@@ -7,4 +15,203 @@ async fn test_initial_election() -> Result<()> {
     cluster.check_one_leader().await?;
     // TODO: check that the leader remains unchanged without failures
     Ok(())
-}
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn test_start_with_peers_down() -> Result<()> {
+    // A node starts while its peers are down, but cannot elect itself alone.
+    let peers = allocate_peers(3);
+    let mut cluster = Cluster { nodes: vec![start_node(0, peers.clone(), Options::default())?] };
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert!(!cluster.nodes[0].node.is_leader()?);
+
+    // Once a quorum is up, it connects to its peers and elects a leader.
+    cluster.nodes.push(start_node(1, peers.clone(), Options::default())?);
+    cluster.check_one_leader().await?;
+    Ok(())
+}
+#[tokio::test]
+async fn test_leader_transfer() -> Result<()> {
+    let cluster = setup(3).await?;
+    let leader = cluster.check_one_leader().await?;
+    let term = cluster.nodes[leader as usize].node.term()?;
+
+    // Transferring to the leader itself or to a non-member is rejected.
+    assert!(cluster.nodes[leader as usize].node.transfer_leader(leader).is_err());
+    assert!(cluster.nodes[leader as usize].node.transfer_leader(7).is_err());
+
+    let target = (leader + 1) % 3;
+    cluster.nodes[leader as usize].node.transfer_leader(target)?;
+    assert_eq!(target, cluster.check_one_leader().await?);
+    assert!(cluster.nodes[target as usize].node.term()? > term);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_prevote_partitioned_follower() -> Result<()> {
+    let cluster = setup(3).await?;
+    let leader = cluster.check_one_leader().await?;
+    let term = cluster.nodes[leader as usize].node.term()?;
+
+    // A partitioned follower cannot win a pre-vote, so it does not increment its term.
+    let follower = (leader + 1) % 3;
+    cluster.disconnect(follower)?;
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    assert_eq!(term, cluster.nodes[follower as usize].node.term()?);
+
+    // When it rejoins, it does not disrupt the leader.
+    cluster.reconnect(follower)?;
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(leader, cluster.check_one_leader().await?);
+    assert_eq!(term, cluster.nodes[leader as usize].node.term()?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_prevote_partitioned_candidate() -> Result<()> {
+    let opts = Options { election_timeout_min: 4, election_timeout_max: 6, ..Options::default() };
+    let network = SimNetwork::new(0);
+    let clock = ManualClock::new();
+    let advance = |ticks: u64| {
+        let clock = clock.clone();
+        async move {
+            for _ in 0..ticks {
+                clock.advance(opts.tick_interval);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    };
+    let mut nodes = vec![];
+    for id in 0..3 {
+        let (apply_tx, apply_rx) = mpsc::channel(APPLY_CHANNEL_CAPACITY);
+        let node = Node::simulated(id, 3, opts, apply_tx, Box::new(storage::log::Memory::new()), &network)?;
+        node.set_clock(Clock::Manual(clock.clone()))?;
+        tokio::spawn(node.clone().serve());
+        nodes.push((node, apply_rx));
+    }
+    advance(3 * opts.election_timeout_max).await;
+    let leader = (0..3).find(|&id| nodes[id as usize].0.is_leader().unwrap()).unwrap();
+    let (candidate, follower) = ((leader + 1) % 3, (leader + 2) % 3);
+    let term = nodes[leader as usize].0.term()?;
+
+    // A node starts an election, as it would after winning a pre-vote, but is partitioned before
+    // its vote requests arrive.
+    network.cut(candidate, leader)?;
+    network.cut(candidate, follower)?;
+    nodes[leader as usize].0.transfer_leader(candidate)?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!("candidate", nodes[candidate as usize].0.status()?.role);
+    network.partition(&[vec![leader, follower], vec![candidate]])?;
+
+    // The majority elects a leader in the candidate's term. The candidate's election times out, but
+    // it cannot win a pre-vote, so it does not increment its term again.
+    advance(opts.election_timeout_max).await;
+    nodes[leader as usize].0.transfer_leader(follower)?;
+    advance(3 * opts.election_timeout_max).await;
+    assert!(nodes[follower as usize].0.is_leader()?);
+    assert_eq!(term + 1, nodes[follower as usize].0.term()?);
+    assert_eq!(term + 1, nodes[candidate as usize].0.term()?);
+
+    // When it rejoins, it does not disrupt the leader.
+    network.heal()?;
+    advance(3 * opts.election_timeout_max).await;
+    assert!(nodes[follower as usize].0.is_leader()?);
+    assert_eq!(term + 1, nodes[follower as usize].0.term()?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_check_quorum() -> Result<()> {
+    let cluster = setup(3).await?;
+    let leader = cluster.check_one_leader().await?;
+
+    // A partitioned leader steps down, and the majority elects a new leader.
+    cluster.disconnect(leader)?;
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    assert!(!cluster.nodes[leader as usize].node.is_leader()?);
+    let new_leader = cluster.check_one_leader().await?;
+    assert_ne!(leader, new_leader);
+
+    // The old leader rejoins as a follower.
+    cluster.reconnect(leader)?;
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(new_leader, cluster.check_one_leader().await?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_partition_without_prevote() -> Result<()> {
+    let cluster = setup_with(3, Options { pre_vote: false, ..Options::default() }).await?;
+    let leader = cluster.check_one_leader().await?;
+    let term = cluster.nodes[leader as usize].node.term()?;
+
+    // Without pre-vote, a partitioned follower keeps incrementing its term.
+    let follower = (leader + 1) % 3;
+    cluster.disconnect(follower)?;
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    assert!(cluster.nodes[follower as usize].node.term()? > term);
+    cluster.reconnect(follower)?;
+    cluster.check_one_leader().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_manual_clock() -> Result<()> {
+    let opts = Options { election_timeout_min: 4, election_timeout_max: 6, ..Options::default() };
+    assert!(Options { heartbeat_interval: 4, ..opts }.validate().is_err());
+    assert!(Options { election_timeout_max: 4, ..opts }.validate().is_err());
+
+    // Nodes driven by a manual clock do not tick on their own.
+    let network = SimNetwork::new(0);
+    let clock = ManualClock::new();
+    let mut nodes = vec![];
+    for id in 0..3 {
+        let (apply_tx, apply_rx) = mpsc::channel(APPLY_CHANNEL_CAPACITY);
+        let node = Node::simulated(id, 3, opts, apply_tx, Box::new(storage::log::Memory::new()), &network)?;
+        node.set_clock(Clock::Manual(clock.clone()))?;
+        tokio::spawn(node.clone().serve());
+        nodes.push((node, apply_rx));
+    }
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    for (node, _) in &nodes {
+        assert_eq!(0, node.term()?);
+    }
+
+    // Advancing the clock past the election timeout elects a leader.
+    for _ in 0..3 * opts.election_timeout_max {
+        clock.advance(opts.tick_interval);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let mut leaders = 0;
+    for (node, _) in &nodes {
+        leaders += node.is_leader()? as u64;
+    }
+    assert_eq!(1, leaders);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_restart_keeps_term_and_vote() -> Result<()> {
+    let network = SimNetwork::new(0);
+    let store = storage::log::LogDemo::new();
+    let vote = |term, candidate_id| Request::new(RequestVoteArgs {
+        term,
+        candidate_id,
+        last_log_index: 0,
+        last_log_term: 0,
+        pre_vote: false,
+    });
+
+    let (apply_tx, _apply_rx) = mpsc::channel(APPLY_CHANNEL_CAPACITY);
+    let node = Node::simulated(0, 3, Options::default(), apply_tx, Box::new(store.clone()), &network)?;
+    assert!(node.request_vote(vote(5, 1)).await?.into_inner().vote_granted);
+    node.shutdown()?;
+
+    // The restarted node recovers its term, and does not vote for another candidate in it.
+    let (apply_tx, _apply_rx) = mpsc::channel(APPLY_CHANNEL_CAPACITY);
+    let node = Node::simulated(0, 3, Options::default(), apply_tx, Box::new(store), &network)?;
+    assert_eq!(5, node.term()?);
+    assert!(!node.request_vote(vote(5, 2)).await?.into_inner().vote_granted);
+    assert!(node.request_vote(vote(5, 1)).await?.into_inner().vote_granted);
+    Ok(())
+}
@@ -0,0 +1,139 @@
+//! A linearizability checker for histories of operations on a key-value store, using the search
+//! of Wing & Gong with the memoization of Lowe. Linearizability is compositional, so each key's
+//! sub-history is checked on its own.
+
+use std::collections::{HashMap, HashSet};
+
+/// An operation on a key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    /// Writes a value.
+    Put(u64),
+    /// Reads a value, or None if the key had not been written.
+    Get(Option<u64>),
+}
+
+/// An operation in a history. Times are logical, and must be unique across the history.
+#[derive(Clone, Debug)]
+pub struct Operation {
+    pub key: u64,
+    pub kind: Kind,
+    /// When the operation was invoked.
+    pub call: u64,
+    /// When the operation returned, or None if its outcome is unknown (e.g. it timed out), in
+    /// which case it may have taken effect at any time after its invocation, or not at all.
+    pub ret: Option<u64>,
+}
+
+/// Checks whether a history is linearizable.
+pub fn check(history: &[Operation]) -> bool {
+    let mut keys = HashMap::<u64, Vec<&Operation>>::new();
+    for op in history {
+        // A read with an unknown outcome constrains nothing.
+        if let (Kind::Get(_), None) = (op.kind, op.ret) {
+            continue;
+        }
+        keys.entry(op.key).or_default().push(op);
+    }
+    keys.values().all(|ops| search(ops, &mut vec![false; ops.len()], None, &mut HashSet::new()))
+}
+
+/// Searches for a linearization of the remaining operations, given the ones already linearized
+/// and the resulting value.
+fn search(
+    ops: &[&Operation],
+    done: &mut Vec<bool>,
+    value: Option<u64>,
+    seen: &mut HashSet<(Vec<bool>, Option<u64>)>,
+) -> bool {
+    // Operations with unknown outcomes need not take effect, so only completed ones must be
+    // linearized. The next operation must have been invoked before all of those returned.
+    let min_ret = match ops.iter().zip(done.iter()).filter(|(_, done)| !**done).filter_map(|(op, _)| op.ret).min() {
+        Some(min_ret) => min_ret,
+        None => return true,
+    };
+    for i in 0..ops.len() {
+        if done[i] || ops[i].call > min_ret {
+            continue;
+        }
+        let next = match ops[i].kind {
+            Kind::Put(v) => Some(v),
+            Kind::Get(v) if v == value => value,
+            Kind::Get(_) => continue,
+        };
+        done[i] = true;
+        if seen.insert((done.clone(), next)) && search(ops, done, next, seen) {
+            return true;
+        }
+        done[i] = false;
+    }
+    false
+}
+
+fn op(key: u64, kind: Kind, call: u64, ret: Option<u64>) -> Operation {
+    Operation { key, kind, call, ret }
+}
+
+#[test]
+fn test_sequential() {
+    let history = vec![
+        op(0, Kind::Get(None), 0, Some(1)),
+        op(0, Kind::Put(1), 2, Some(3)),
+        op(0, Kind::Get(Some(1)), 4, Some(5)),
+        op(1, Kind::Get(None), 6, Some(7)),
+    ];
+    assert!(check(&history));
+}
+
+#[test]
+fn test_concurrent() {
+    // A read concurrent with a write may see either value.
+    for read in [None, Some(1)] {
+        let history = vec![
+            op(0, Kind::Put(1), 0, Some(3)),
+            op(0, Kind::Get(read), 1, Some(2)),
+        ];
+        assert!(check(&history));
+    }
+
+    // But once a read has seen the new value, later reads must not see the old one.
+    let history = vec![
+        op(0, Kind::Put(1), 0, Some(10)),
+        op(0, Kind::Get(Some(1)), 1, Some(2)),
+        op(0, Kind::Get(None), 3, Some(4)),
+    ];
+    assert!(!check(&history));
+}
+
+#[test]
+fn test_stale_read() {
+    let history = vec![
+        op(0, Kind::Put(1), 0, Some(1)),
+        op(0, Kind::Put(2), 2, Some(3)),
+        op(0, Kind::Get(Some(1)), 4, Some(5)),
+    ];
+    assert!(!check(&history));
+
+    // A value that was never written cannot be read.
+    let history = vec![op(0, Kind::Get(Some(7)), 0, Some(1))];
+    assert!(!check(&history));
+}
+
+#[test]
+fn test_unknown_outcome() {
+    // A write with an unknown outcome may or may not take effect, at any time after its call.
+    for read in [None, Some(1)] {
+        let history = vec![
+            op(0, Kind::Put(1), 0, None),
+            op(0, Kind::Get(read), 5, Some(6)),
+        ];
+        assert!(check(&history));
+    }
+
+    // But it cannot take effect before its call.
+    let history = vec![
+        op(0, Kind::Get(Some(1)), 0, Some(1)),
+        op(0, Kind::Put(1), 2, None),
+    ];
+    assert!(!check(&history));
+}
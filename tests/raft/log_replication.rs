@@ -1,4 +1,8 @@
-use featherdb::{error::Result, raft::Command};
+use std::time::Duration;
+
+use featherdb::{error::Result, raft::{ApplyMsg, Command, Node, Options, SimNetwork}};
+use featherdb::storage;
+use tokio::sync::mpsc;
 use super::setup;
 
 #[tokio::test]
@@ -6,15 +10,164 @@ async fn test_basic_replication() -> Result<()> {
     let mut cluster = setup(3).await?;
     let leader = cluster.check_one_leader().await?;
     println!("leader: {}", leader);
-    cluster.nodes[leader as usize].node.start(Command::Mutation { session_id: 0, sequence_number: 0, mutation: b"123".to_vec() })?;
-    cluster.nodes[leader as usize].node.start(Command::Mutation { session_id: 0, sequence_number: 1, mutation: b"456".to_vec() })?;
-    cluster.nodes[leader as usize].node.start(Command::Mutation { session_id: 0, sequence_number: 2, mutation: b"789".to_vec() })?;
-    for _ in 0..3 {
-        for i in 0..3 {
-            let raft_node = &mut cluster.nodes[i];
+    cluster.nodes[leader as usize].node.propose(Command::Mutation { session_id: 0, sequence_number: 0, lowest_unacked: 0, key: vec![], mutation: b"123".to_vec() }).await?;
+    cluster.nodes[leader as usize].node.propose(Command::Mutation { session_id: 0, sequence_number: 1, lowest_unacked: 0, key: vec![], mutation: b"456".to_vec() }).await?;
+    cluster.nodes[leader as usize].node.propose(Command::Mutation { session_id: 0, sequence_number: 2, lowest_unacked: 0, key: vec![], mutation: b"789".to_vec() }).await?;
+    for i in 0..3 {
+        let raft_node = &mut cluster.nodes[i];
+        let mut applied = 0;
+        while applied < 3 {
             let apply_msg = raft_node.apply_rx.recv().await.unwrap();
             println!("apply_msg: {:?}", apply_msg);
+            // Skips the noop committed by the new leader.
+            if let ApplyMsg::Command { command: Command::Noop, .. } = apply_msg {
+                continue;
+            }
+            applied += 1;
         }
     }
     Ok(())
-}
\ No newline at end of file
+}
+#[tokio::test]
+async fn test_batched_replication() -> Result<()> {
+    let mut cluster = setup(3).await?;
+    let leader = cluster.check_one_leader().await?;
+    for i in 0..200 {
+        let mutation = vec![0; 1024];
+        cluster.nodes[leader as usize].node.propose(Command::Mutation { session_id: 0, sequence_number: i, lowest_unacked: 0, key: vec![], mutation }).await?;
+    }
+    for raft_node in cluster.nodes.iter_mut() {
+        let mut expected = 0;
+        while expected < 200 {
+            match raft_node.apply_rx.recv().await.unwrap() {
+                ApplyMsg::Command { command: Command::Mutation { sequence_number, .. }, .. } => {
+                    assert_eq!(expected, sequence_number);
+                    expected += 1;
+                }
+                _ => continue,
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_diverged_follower() -> Result<()> {
+    let mut cluster = setup(3).await?;
+    let old_leader = cluster.check_one_leader().await?;
+
+    // A partitioned leader appends entries that are never committed.
+    cluster.disconnect(old_leader)?;
+    for i in 0..50 {
+        let command = Command::Mutation { session_id: 1, sequence_number: i, lowest_unacked: 0, key: vec![], mutation: vec![] };
+        cluster.nodes[old_leader as usize].node.propose(command).await?;
+    }
+
+    // The rest of the cluster elects a new leader, which commits conflicting entries.
+    let new_leader = loop {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let leader = (0..3).filter(|id| *id != old_leader)
+            .find(|id| cluster.nodes[*id as usize].node.is_leader().unwrap_or(false));
+        if let Some(leader) = leader {
+            break leader;
+        }
+    };
+    for i in 0..30 {
+        let command = Command::Mutation { session_id: 2, sequence_number: i, lowest_unacked: 0, key: vec![], mutation: vec![] };
+        cluster.nodes[new_leader as usize].node.propose(command).await?;
+    }
+
+    // Once reconnected, the old leader replaces its entries with the new leader's.
+    cluster.reconnect(old_leader)?;
+    let raft_node = &mut cluster.nodes[old_leader as usize];
+    let mut expected = 0;
+    while expected < 30 {
+        let apply_msg = tokio::time::timeout(Duration::from_secs(10), raft_node.apply_rx.recv())
+            .await
+            .expect("Timed out waiting for replication")
+            .unwrap();
+        match apply_msg {
+            ApplyMsg::Command { command: Command::Mutation { session_id, sequence_number, .. }, .. } => {
+                assert_eq!((2, expected), (session_id, sequence_number));
+                expected += 1;
+            }
+            _ => continue,
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_group_commit() -> Result<()> {
+    let mut cluster = setup(3).await?;
+    let leader = cluster.check_one_leader().await?;
+
+    // Concurrent proposals are appended in a batch, each at its own index in the same term.
+    let node = &cluster.nodes[leader as usize].node;
+    let proposals = (0..100).map(|i| {
+        node.propose(Command::Mutation { session_id: 0, sequence_number: i, lowest_unacked: 0, key: vec![], mutation: vec![] })
+    });
+    let entries = futures::future::join_all(proposals).await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+    let term = entries[0].1;
+    for window in entries.windows(2) {
+        assert_eq!(window[0].0 + 1, window[1].0);
+        assert_eq!(term, window[1].1);
+    }
+
+    for raft_node in cluster.nodes.iter_mut() {
+        let mut applied = 0;
+        while applied < 100 {
+            if let ApplyMsg::Command { command: Command::Mutation { .. }, .. } = raft_node.apply_rx.recv().await.unwrap() {
+                applied += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_apply_backpressure() -> Result<()> {
+    let network = SimNetwork::new(0);
+    let (apply_tx, mut apply_rx) = mpsc::channel(4);
+    let node = Node::simulated(
+        0, 1, Options::default(), apply_tx, Box::new(storage::log::Memory::new()), &network,
+    )?;
+    tokio::spawn(node.clone().serve());
+    while !node.is_leader()? {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let command = |sequence_number| Command::Mutation {
+        session_id: 0, sequence_number, lowest_unacked: 0, key: vec![], mutation: vec![],
+    };
+
+    // Committed entries beyond the channel's capacity wait in the log, and count towards the lag.
+    let (noop_index, _) = node.propose(command(0)).await?;
+    for i in 1..10 {
+        node.propose(command(i)).await?;
+    }
+    assert_eq!(noop_index + 9, node.apply_lag()?);
+
+    // Once the backlog is large enough, proposals are held back until the state machine catches up.
+    let mut i = 10;
+    while tokio::time::timeout(Duration::from_secs(1), node.propose(command(i))).await.is_ok() {
+        i += 1;
+    }
+    assert!(i > 1000);
+    let lag = node.apply_lag()?;
+
+    // Draining the channel applies all entries in order, and releases the held back proposal.
+    for index in 1..=lag {
+        match apply_rx.recv().await.unwrap() {
+            ApplyMsg::Command { log_index, .. } => assert_eq!(index, log_index),
+            msg => panic!("Unexpected apply message {:?}", msg),
+        }
+    }
+    match apply_rx.recv().await.unwrap() {
+        ApplyMsg::Command { log_index, .. } => assert_eq!(lag + 1, log_index),
+        msg => panic!("Unexpected apply message {:?}", msg),
+    }
+    assert_eq!(0, node.apply_lag()?);
+    Ok(())
+}
@@ -0,0 +1,228 @@
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use featherdb::error::{Error, Result};
+use featherdb::raft::{Clock, Command, Log, ManualClock, Membership, Node, Options, SimNetwork, Snapshot};
+use featherdb::storage;
+use featherdb::storage::log::{LogScan, LogStore, Range};
+use tokio::sync::mpsc;
+
+use super::APPLY_CHANNEL_CAPACITY;
+
+/// An in-memory log store that fails to append entries while `failing` is set.
+struct FlakyLog {
+    store: storage::log::Memory,
+    failing: Arc<AtomicBool>,
+}
+
+impl Display for FlakyLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "flaky memory")
+    }
+}
+
+impl LogStore for FlakyLog {
+    fn append(&mut self, entry: Vec<u8>) -> Result<u64> {
+        if self.failing.load(Ordering::SeqCst) {
+            return Err(Error::Internal("Log store is failing".into()));
+        }
+        self.store.append(entry)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.store.flush()
+    }
+
+    fn commit(&mut self, index: u64) -> Result<()> {
+        self.store.commit(index)
+    }
+
+    fn commit_index(&self) -> u64 {
+        self.store.commit_index()
+    }
+
+    fn get(&self, index: u64) -> Result<Option<Vec<u8>>> {
+        self.store.get(index)
+    }
+
+    fn len(&self) -> u64 {
+        self.store.len()
+    }
+
+    fn compact(&mut self, index: u64) -> Result<u64> {
+        self.store.compact(index)
+    }
+
+    fn compact_index(&self) -> u64 {
+        self.store.compact_index()
+    }
+
+    fn scan(&self, range: Range) -> LogScan<'_> {
+        self.store.scan(range)
+    }
+
+    fn size(&self) -> u64 {
+        self.store.size()
+    }
+
+    fn truncate(&mut self, index: u64) -> Result<u64> {
+        self.store.truncate(index)
+    }
+
+    fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.store.get_metadata(key)
+    }
+
+    fn set_metadata(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.store.set_metadata(key, value)
+    }
+}
+
+fn bootstrap(count: u64) -> Membership {
+    let peers = (0..count).map(|i| (i, format!("127.0.0.1:{}", 50100 + i))).collect();
+    Membership::bootstrap(&peers)
+}
+
+#[test]
+fn test_bootstrap() {
+    let membership = bootstrap(3);
+    assert_eq!(vec![0, 1, 2], membership.members().collect::<Vec<_>>());
+    assert!(membership.is_voter(2));
+    assert!(!membership.contains(3));
+    assert_eq!(2, membership.quorum());
+}
+
+#[test]
+fn test_bootstrap_ids() {
+    // IDs come from the peer map, not from the order of the peers.
+    let peers = [(7, "127.0.0.1:50107".to_string()), (3, "127.0.0.1:50103".to_string())];
+    let membership = Membership::bootstrap(&peers.into_iter().collect());
+    assert_eq!(vec![3, 7], membership.members().collect::<Vec<_>>());
+    assert_eq!(Some(&"127.0.0.1:50107".to_string()), membership.addrs.get(&7));
+}
+
+#[test]
+fn test_check_id() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let data_dir = dir.path().join("data");
+
+    // The ID is persisted on first boot, and must match on later boots.
+    Node::check_id(&data_dir, 3)?;
+    Node::check_id(&data_dir, 3)?;
+    assert!(Node::check_id(&data_dir, 4).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_add_promote_remove() -> Result<()> {
+    let membership = bootstrap(3);
+
+    // Learners do not count towards the quorum until promoted.
+    let membership = membership.add_learner(3, "127.0.0.1:50103".into())?;
+    assert!(membership.contains(3));
+    assert!(!membership.is_voter(3));
+    assert_eq!(2, membership.quorum());
+    assert!(membership.add_learner(3, "127.0.0.1:50103".into()).is_err());
+
+    let membership = membership.promote(3)?;
+    assert!(membership.is_voter(3));
+    assert_eq!(3, membership.quorum());
+    assert!(membership.promote(3).is_err());
+
+    let membership = membership.remove(0)?;
+    assert!(!membership.contains(0));
+    assert_eq!(None, membership.addrs.get(&0));
+    assert_eq!(2, membership.quorum());
+    assert!(membership.remove(0).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_remove_last_voter() -> Result<()> {
+    let membership = bootstrap(1).add_learner(1, "127.0.0.1:50101".into())?;
+    assert!(membership.remove(0).is_err());
+    assert!(membership.remove(1).is_ok());
+    Ok(())
+}
+
+#[test]
+fn test_witness() -> Result<()> {
+    // A witness joins as a learner, and keeps its flag once promoted. It counts towards the
+    // quorum, but may not lead.
+    let membership = bootstrap(2).add_witness(2, "127.0.0.1:50102".into())?;
+    assert!(membership.is_witness(2) && !membership.is_voter(2));
+    let membership = membership.promote(2)?;
+    assert!(membership.is_witness(2) && membership.is_voter(2));
+    assert_eq!(2, membership.quorum());
+    assert!(membership.can_lead(0));
+    assert!(!membership.can_lead(2));
+
+    let membership = membership.remove(2)?;
+    assert!(!membership.is_witness(2));
+    Ok(())
+}
+
+#[test]
+fn test_snapshot_membership() -> Result<()> {
+    // The configuration survives log compaction as part of the snapshot.
+    let mut log = Log::new(Box::new(storage::log::Memory::new()))?;
+    let membership = bootstrap(3).add_learner(3, "127.0.0.1:50103".into())?;
+    log.append(1, Command::Membership(membership.clone()))?;
+    log.append(1, Command::Registration { session_id: 1 })?;
+    log.commit(2)?;
+    log.compact(Snapshot { index: 2, term: 1, data: vec![], membership: Some(membership.clone()) })?;
+
+    assert_eq!(None, log.get(1)?);
+    assert_eq!(Some(membership), log.snapshot()?.and_then(|s| s.membership));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_change_waits_for_term_commit() -> Result<()> {
+    // Only node 0's clock advances, so it is the only node to campaign. The followers vote for it,
+    // but fail to append its term-start noop.
+    let opts = Options { election_timeout_min: 4, election_timeout_max: 6, ..Options::default() };
+    let network = SimNetwork::new(0);
+    let failing = Arc::new(AtomicBool::new(true));
+    let clock = ManualClock::new();
+    let mut nodes = vec![];
+    for id in 0..3 {
+        let (apply_tx, apply_rx) = mpsc::channel(APPLY_CHANNEL_CAPACITY);
+        let (failing, clock) = match id {
+            0 => (Arc::new(AtomicBool::new(false)), clock.clone()),
+            _ => (failing.clone(), ManualClock::new()),
+        };
+        let store = FlakyLog { store: storage::log::Memory::new(), failing };
+        let node = Node::simulated(id, 3, opts, apply_tx, Box::new(store), &network)?;
+        node.set_clock(Clock::Manual(clock))?;
+        tokio::spawn(node.clone().serve());
+        nodes.push((node, apply_rx));
+    }
+    let advance = |ticks: u64| {
+        let clock = clock.clone();
+        async move {
+            for _ in 0..ticks {
+                clock.advance(opts.tick_interval);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    };
+    advance(2 * opts.election_timeout_max).await;
+    let leader = &nodes[0].0;
+    assert!(leader.is_leader()?);
+    assert_eq!(0, leader.status()?.commit_index);
+
+    // Until the noop is committed, the leader rejects membership changes.
+    assert!(matches!(leader.add_node(3, "sim-3".into()), Err(Error::Value(_))));
+    assert!(matches!(leader.remove_node(2), Err(Error::Value(_))));
+
+    // Once the followers recover and the noop is committed, the change is accepted.
+    failing.store(false, Ordering::SeqCst);
+    advance(opts.election_timeout_min).await;
+    assert_eq!(1, leader.status()?.commit_index);
+    leader.add_node(3, "sim-3".into())?;
+    assert!(leader.membership()?.learners.contains(&3));
+    Ok(())
+}
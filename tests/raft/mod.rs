@@ -1,56 +1,79 @@
+mod distributed_txn;
+mod gc;
 mod leader_election;
+mod linearizability;
 mod log_replication;
+mod membership;
+mod multi_raft;
+mod placement;
+mod read_index;
+mod session;
+mod shutdown;
+mod simulation;
+mod snapshot;
+mod status;
+mod witness;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU16, Ordering};
 use std::time::Duration;
 
 use featherdb::error::{Error, Result};
-use featherdb::raft::{Node, ApplyMsg};
+use featherdb::raft::{Node, ApplyMsg, Options};
 use featherdb::storage;
 use tokio::sync::mpsc;
 
+/// The capacity of a node's apply channel. Tests that do not read the channel can commit this many
+/// entries before the node stops applying them.
+const APPLY_CHANNEL_CAPACITY: usize = 1024;
+
+/// The next free port for test clusters, so that concurrent tests do not collide.
+static NEXT_PORT: AtomicU16 = AtomicU16::new(50057);
+
 /// Set up a cluster of `cluster_size` nodes.
 async fn setup(cluster_size: u64) -> Result<Cluster> {
-    let (node_tx, node_rx) = std::sync::mpsc::channel();
-    let (apply_ch_tx, apply_ch_rx) = std::sync::mpsc::channel();
-    for i in 0..cluster_size {
-        let node_tx = node_tx.clone();
-        let apply_ch_tx = apply_ch_tx.clone();
-        std::thread::spawn(move || {
-            let rt = tokio::runtime::Runtime::new().unwrap();
-            rt.block_on(async move {
-                let (apply_tx, apply_rx) = mpsc::unbounded_channel();
-                let node = Node::new(
-                    i,
-                    // TODO: make this configurable
-                    vec![
-                        "127.0.0.1:50057".to_string(),
-                        "127.0.0.1:50058".to_string(),
-                        "127.0.0.1:50059".to_string(),
-                    ],
-                    apply_tx,
-                    Box::new(storage::log::Memory::new()),
-                ).await.unwrap();
-                node_tx.send(node.clone()).unwrap();
-                drop(node_tx);
-                apply_ch_tx.send(apply_rx).unwrap();
-                drop(apply_ch_tx);
-                node.serve().await.unwrap();
-            })
-        });
-    }
+    setup_with(cluster_size, Options::default()).await
+}
 
+/// Set up a cluster of `cluster_size` nodes with the given Raft options.
+async fn setup_with(cluster_size: u64, opts: Options) -> Result<Cluster> {
+    let peers = allocate_peers(cluster_size);
     let mut nodes = vec![];
-    for _ in 0..cluster_size {
-        let node = node_rx.recv().unwrap();
-        let apply_rx = apply_ch_rx.recv().unwrap();
-        nodes.push(RaftNode { node, apply_rx });
+    for i in 0..cluster_size {
+        nodes.push(start_node(i, peers.clone(), opts)?);
     }
-    nodes.sort_by(|a, b| a.node.id().unwrap().cmp(&b.node.id().unwrap()));
-    
     Ok(Cluster { nodes })
 }
 
+/// Allocates addresses for a cluster of `cluster_size` nodes, with IDs from 0.
+fn allocate_peers(cluster_size: u64) -> BTreeMap<u64, String> {
+    let base_port = NEXT_PORT.fetch_add(cluster_size as u16, Ordering::SeqCst);
+    (0..cluster_size)
+        .map(|i| (i, format!("127.0.0.1:{}", base_port + i as u16)))
+        .collect()
+}
+
+/// Starts a node of a cluster on its own thread and runtime.
+fn start_node(id: u64, peers: BTreeMap<u64, String>, opts: Options) -> Result<RaftNode> {
+    let (node_tx, node_rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let (apply_tx, apply_rx) = mpsc::channel(APPLY_CHANNEL_CAPACITY);
+            let node = Node::new(
+                id,
+                peers,
+                opts,
+                apply_tx,
+                Box::new(storage::log::Memory::new()),
+            ).await.unwrap();
+            node_tx.send(RaftNode { node: node.clone(), apply_rx }).unwrap();
+            node.serve().await.unwrap();
+        })
+    });
+    node_rx.recv().map_err(|e| Error::Internal(e.to_string()))
+}
+
 /// A cluster of raft nodes.
 pub struct Cluster {
     pub nodes: Vec<RaftNode>,
@@ -59,10 +82,20 @@ pub struct Cluster {
 /// A raft node with a simulated state machine channel.
 pub struct RaftNode {
     pub node: Node,
-    pub apply_rx: mpsc::UnboundedReceiver<ApplyMsg>,
+    pub apply_rx: mpsc::Receiver<ApplyMsg>,
 }
 
 impl Cluster {
+    /// Partitions a node from the rest of the cluster.
+    fn disconnect(&self, id: u64) -> Result<()> {
+        self.nodes[id as usize].node.disconnect()
+    }
+
+    /// Heals the partition of a node.
+    fn reconnect(&self, id: u64) -> Result<()> {
+        self.nodes[id as usize].node.reconnect()
+    }
+
     /// Check that there is exactly one leader in the cluster.
     /// Returns the ID of the leader.
     async fn check_one_leader(&self) -> Result<u64> {
@@ -0,0 +1,429 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use featherdb::error::Result;
+use featherdb::proto::featherkv::{ExecutionRequest, FeatherKvClient, FeatherKvServer, RangesRequest, RegistrationRequest};
+use featherdb::raft::{ApplyMsg, Client, Command, Entry, FeatherKV, Node, Options, RaftRouter, RangeDescriptor, RangeTable, RpcStatus};
+use featherdb::raft::{Sizer, SplitOptions, State};
+use featherdb::storage;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tonic::transport::Server;
+
+use super::allocate_peers;
+
+/// A state machine that replies to every operation with its group ID and mutation count.
+struct GroupState {
+    group_id: u64,
+    mutations: u64,
+}
+
+impl State for GroupState {
+    fn applied_index(&self) -> u64 {
+        0
+    }
+
+    fn mutate(&mut self, _index: u64, _mutation: Vec<u8>) -> Result<Vec<u8>> {
+        self.mutations += 1;
+        self.query(vec![])
+    }
+
+    fn query(&self, _query: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&(self.group_id, self.mutations))?)
+    }
+
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        Ok(self.mutations.to_be_bytes().to_vec())
+    }
+
+    fn restore(&mut self, snapshot: Vec<u8>) -> Result<()> {
+        self.mutations = u64::from_be_bytes(snapshot[..].try_into().unwrap());
+        Ok(())
+    }
+}
+
+/// A key/value state machine, where a mutation sets a key and a query reads one. Supports splits
+/// and merges.
+#[derive(Default)]
+struct KvState {
+    data: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl State for KvState {
+    fn applied_index(&self) -> u64 {
+        0
+    }
+
+    fn mutate(&mut self, _index: u64, mutation: Vec<u8>) -> Result<Vec<u8>> {
+        let (key, value): (Vec<u8>, Vec<u8>) = bincode::deserialize(&mutation)?;
+        self.data.insert(key, value.clone());
+        Ok(value)
+    }
+
+    fn query(&self, query: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&self.data.get(&query))?)
+    }
+
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&self.data)?)
+    }
+
+    fn restore(&mut self, snapshot: Vec<u8>) -> Result<()> {
+        self.data = bincode::deserialize(&snapshot)?;
+        Ok(())
+    }
+
+    fn sizer(&self) -> Option<Sizer> {
+        let size = self.data.iter().map(|(key, value)| (key.len() + value.len()) as u64).sum();
+        Some(Arc::new(move || Ok(size)))
+    }
+
+    fn split_key(&self) -> Result<Option<Vec<u8>>> {
+        Ok(self.data.keys().nth(self.data.len() / 2).filter(|_| self.data.len() > 1).cloned())
+    }
+
+    fn split(&mut self, key: &[u8]) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&self.data.split_off(key))?)
+    }
+
+    fn merge(&mut self, snapshot: Vec<u8>) -> Result<()> {
+        self.data.extend(bincode::deserialize::<BTreeMap<_, _>>(&snapshot)?);
+        Ok(())
+    }
+}
+
+/// Starts a single-node FeatherKV of key/value state machines, returning its address.
+async fn serve_kv(ranges: RangeTable, split: SplitOptions) -> Result<String> {
+    serve_kv_with(ranges, split, BTreeMap::new()).await
+}
+
+/// Like `serve_kv()`, but the state machines of the groups in `initial` start out with its data.
+async fn serve_kv_with(
+    ranges: RangeTable,
+    split: SplitOptions,
+    initial: BTreeMap<u64, BTreeMap<Vec<u8>, Vec<u8>>>,
+) -> Result<String> {
+    let peers = allocate_peers(1);
+    let serve_addr = allocate_peers(1)[&0].clone();
+    let server = FeatherKV::new(0, peers, false, Options::default(), ranges, split, move |group_id| {
+        let data = initial.get(&group_id).cloned().unwrap_or_default();
+        let state: Box<dyn State> = Box::new(KvState { data });
+        let log_store: Box<dyn storage::log::LogStore> = Box::new(storage::log::Memory::new());
+        Ok((state, log_store))
+    }).await?;
+    tokio::spawn(Server::builder().add_service(FeatherKvServer::new(server)).serve(serve_addr.parse()?));
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    Ok(serve_addr)
+}
+
+/// Fetches a server's range descriptor table.
+async fn get_ranges(addr: &str) -> Result<RangeTable> {
+    let mut server = FeatherKvClient::connect(format!("http://{}", addr)).await.unwrap();
+    let reply = server.get_ranges(RangesRequest { }).await?.into_inner();
+    Ok(bincode::deserialize(&reply.ranges)?)
+}
+
+#[test]
+fn test_range_table() -> Result<()> {
+    let ranges = RangeTable::new(vec![b"g".to_vec(), b"p".to_vec()])?;
+    assert_eq!(0, ranges.lookup(b"").group_id);
+    assert_eq!(0, ranges.lookup(b"a").group_id);
+    assert_eq!(1, ranges.lookup(b"g").group_id);
+    assert_eq!(1, ranges.lookup(b"orange").group_id);
+    assert_eq!(2, ranges.lookup(b"p").group_id);
+    assert_eq!(2, ranges.lookup(b"zzz").group_id);
+    assert!(ranges.get(1).unwrap().contains(b"h"));
+    assert!(!ranges.get(1).unwrap().contains(b"p"));
+    assert!(ranges.get(3).is_none());
+
+    assert_eq!(1, RangeTable::default().ranges().count());
+    assert!(RangeTable::new(vec![b"p".to_vec(), b"g".to_vec()]).is_err());
+    assert!(RangeTable::new(vec![vec![]]).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_range_table_apply() -> Result<()> {
+    let range = |group_id, start: &[u8], end: Option<&[u8]>, generation| RangeDescriptor {
+        group_id, start: start.to_vec(), end: end.map(|end| end.to_vec()), generation,
+    };
+    let mut ranges = RangeTable::default();
+
+    // The right-hand side of a split cuts the old range short, even if it is learned first.
+    assert!(ranges.apply(range(7, b"m", None, 1)).is_empty());
+    assert_eq!(&range(0, b"", Some(b"m"), 0), ranges.lookup(b"a"));
+    assert!(ranges.apply(range(0, b"", Some(b"m"), 1)).is_empty());
+    assert_eq!(7, ranges.lookup(b"x").group_id);
+
+    // A stale descriptor is ignored.
+    assert!(ranges.apply(range(0, b"", None, 0)).is_empty());
+    assert_eq!(7, ranges.lookup(b"x").group_id);
+
+    // A merge removes the ranges it absorbed.
+    assert_eq!(vec![7], ranges.apply(range(0, b"", None, 2)));
+    assert_eq!(0, ranges.lookup(b"x").group_id);
+    assert_eq!(1, ranges.ranges().count());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_groups_share_address() -> Result<()> {
+    // Three nodes run two Raft groups each, multiplexed over one address per node.
+    let peers = allocate_peers(3);
+    let mut apply_rxs = vec![];
+    let mut nodes = vec![];
+    for (&id, addr) in &peers {
+        let router = RaftRouter::new();
+        for group_id in 0..2 {
+            let (apply_tx, apply_rx) = mpsc::channel(1024);
+            let log_store = Box::new(storage::log::Memory::new());
+            let node = Node::grouped(id, group_id, &peers, false, Options::default(), apply_tx, log_store, &router)?;
+            tokio::spawn(node.clone().serve());
+            nodes.push((group_id, node));
+            apply_rxs.push(apply_rx);
+        }
+        router.serve(addr, CancellationToken::new())?;
+    }
+
+    // Each group elects its own leader.
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    for group_id in 0..2 {
+        let leaders = nodes.iter()
+            .filter(|(group, node)| *group == group_id && node.is_leader().unwrap())
+            .count();
+        assert_eq!(1, leaders);
+    }
+
+    // A mutation in group 1 is only applied by group 1's replicas.
+    let (_, leader) = nodes.iter().find(|(group, node)| *group == 1 && node.is_leader().unwrap()).unwrap();
+    let mutation = Command::Mutation { session_id: 0, sequence_number: 1, lowest_unacked: 0, key: vec![], mutation: vec![] };
+    leader.propose(mutation.clone()).await?;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    for ((group_id, _), apply_rx) in nodes.iter().zip(apply_rxs.iter_mut()) {
+        let mut mutations = 0;
+        while let Ok(ApplyMsg::Command { command, .. }) = apply_rx.try_recv() {
+            if command == mutation {
+                mutations += 1;
+            }
+        }
+        assert_eq!(*group_id, mutations);
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_client_routes_by_key() -> Result<()> {
+    let peers = allocate_peers(1);
+    let serve_addr = allocate_peers(1)[&0].clone();
+    let ranges = RangeTable::new(vec![b"m".to_vec()])?;
+    let server = FeatherKV::new(0, peers, false, Options::default(), ranges, SplitOptions::default(), |group_id| {
+        let state: Box<dyn State> = Box::new(GroupState { group_id, mutations: 0 });
+        let log_store: Box<dyn storage::log::LogStore> = Box::new(storage::log::Memory::new());
+        Ok((state, log_store))
+    }).await?;
+    let addr = serve_addr.parse()?;
+    tokio::spawn(Server::builder().add_service(FeatherKvServer::new(server)).serve(addr));
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    // The client starts out routing every key to group 0, and refreshes its routing table once
+    // group 0 rejects a key outside its range.
+    let mut client = Client::new(BTreeMap::from([(0, serve_addr)])).await?;
+    let reply = |reply: Vec<u8>| bincode::deserialize::<(u64, u64)>(&reply).unwrap();
+    assert_eq!((1, 1), reply(client.mutate_key(b"zebra", vec![]).await?));
+    assert_eq!((1, 2), reply(client.mutate_key(b"m", vec![]).await?));
+    assert_eq!((0, 1), reply(client.mutate_key(b"apple", vec![]).await?));
+    assert_eq!((0, 2), reply(client.mutate(vec![]).await?));
+    assert_eq!((1, 2), reply(client.query_key(b"x", vec![]).await?));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_split_on_size() -> Result<()> {
+    let split = SplitOptions { max_size: 200, ..SplitOptions::default() };
+    let addr = serve_kv(RangeTable::default(), split).await?;
+    let mut client = Client::new(BTreeMap::from([(0, addr.clone())])).await?;
+    let keys = (0..20).map(|i| format!("key{:02}", i).into_bytes()).collect::<Vec<_>>();
+    for key in &keys {
+        client.mutate_key(key, bincode::serialize(&(key, b"0123456789".to_vec()))?).await?;
+    }
+
+    // The range is split at the leader's next clock ticks, until every range is below the limit.
+    tokio::time::sleep(Duration::from_secs(4)).await;
+    let ranges = get_ranges(&addr).await?;
+    assert!(ranges.ranges().count() > 1);
+    assert!(ranges.ranges().all(|range| range.start.is_empty() || keys.contains(&range.start)));
+
+    // The client follows the split, and every key is still readable and writable.
+    for key in &keys {
+        let value: Option<Vec<u8>> = bincode::deserialize(&client.query_key(key, key.clone()).await?)?;
+        assert_eq!(Some(b"0123456789".to_vec()), value);
+    }
+    client.mutate_key(b"key19", bincode::serialize(&(b"key19".to_vec(), b"new".to_vec()))?).await?;
+    let value: Option<Vec<u8>> = bincode::deserialize(&client.query_key(b"key19", b"key19".to_vec()).await?)?;
+    assert_eq!(Some(b"new".to_vec()), value);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_session_dedup_across_split() -> Result<()> {
+    let split = SplitOptions { max_size: 200, ..SplitOptions::default() };
+    let addr = serve_kv(RangeTable::default(), split).await?;
+    let mut server = FeatherKvClient::connect(format!("http://{}", addr)).await.unwrap();
+    let session_id = server.register(RegistrationRequest { group_id: 0 }).await?.into_inner().session_id;
+
+    // A mutation is applied in group 0, but the client does not see the reply.
+    let mut request = ExecutionRequest {
+        session_id,
+        sequence_number: 1,
+        operation: bincode::serialize(&(b"key19".to_vec(), b"old".to_vec()))?,
+        consistency: vec![],
+        lowest_unacked: 1,
+        group_id: 0,
+        key: b"key19".to_vec(),
+    };
+    let first = server.mutate(request.clone()).await?.into_inner();
+    assert!(matches!(bincode::deserialize(&first.status)?, RpcStatus::Ok));
+
+    // The range is split, and the key is overwritten in its new group.
+    let mut client = Client::new(BTreeMap::from([(0, addr.clone())])).await?;
+    for i in 0..20 {
+        let key = format!("key{:02}", i).into_bytes();
+        client.mutate_key(&key, bincode::serialize(&(&key, b"0123456789".to_vec()))?).await?;
+    }
+    tokio::time::sleep(Duration::from_secs(4)).await;
+    let group_id = get_ranges(&addr).await?.lookup(b"key19").group_id;
+    assert_ne!(0, group_id);
+
+    // The retry in the new group returns the original result, instead of being applied again.
+    request.group_id = group_id;
+    let mut retry = None;
+    for _ in 0..50 {
+        let reply = server.mutate(request.clone()).await?.into_inner();
+        if matches!(bincode::deserialize(&reply.status)?, RpcStatus::Ok) {
+            retry = Some(reply);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let retry = retry.expect("The session was not found in the new group");
+    assert_eq!(first.response, retry.response);
+    let value: Option<Vec<u8>> = bincode::deserialize(&client.query_key(b"key19", b"key19".to_vec()).await?)?;
+    assert_eq!(Some(b"0123456789".to_vec()), value);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_merge_small_ranges() -> Result<()> {
+    let split = SplitOptions { merge_size: 1000, ..SplitOptions::default() };
+    let addr = serve_kv(RangeTable::new(vec![b"g".to_vec(), b"p".to_vec()])?, split).await?;
+    let mut client = Client::new(BTreeMap::from([(0, addr.clone())])).await?;
+    for key in [b"apple", b"mango", b"zebra"] {
+        client.mutate_key(key, bincode::serialize(&(key.to_vec(), key.to_vec()))?).await?;
+    }
+
+    // The small ranges are merged one pair at a time, keeping their data.
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    assert_eq!(1, get_ranges(&addr).await?.ranges().count());
+    for key in [b"apple", b"mango", b"zebra"] {
+        let value: Option<Vec<u8>> = bincode::deserialize(&client.query_key(key, key.to_vec()).await?)?;
+        assert_eq!(Some(key.to_vec()), value);
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_session_dedup_across_merge() -> Result<()> {
+    // Group 1 is too large to merge until its padding is removed.
+    let split = SplitOptions { merge_size: 1000, ..SplitOptions::default() };
+    let padding = BTreeMap::from([(b"zebra".to_vec(), vec![0; 1000])]);
+    let addr = serve_kv_with(RangeTable::new(vec![b"g".to_vec()])?, split, BTreeMap::from([(1, padding)])).await?;
+    let mut server = FeatherKvClient::connect(format!("http://{}", addr)).await.unwrap();
+    let session_id = server.register(RegistrationRequest { group_id: 1 }).await?.into_inner().session_id;
+
+    // A mutation is applied in group 1, but the client does not see the reply.
+    let mut request = ExecutionRequest {
+        session_id,
+        sequence_number: 1,
+        operation: bincode::serialize(&(b"mango".to_vec(), b"old".to_vec()))?,
+        consistency: vec![],
+        lowest_unacked: 1,
+        group_id: 1,
+        key: b"mango".to_vec(),
+    };
+    let first = server.mutate(request.clone()).await?.into_inner();
+    assert!(matches!(bincode::deserialize(&first.status)?, RpcStatus::Ok));
+
+    // The range is merged into group 0, and the key is overwritten there.
+    let mut client = Client::new(BTreeMap::from([(0, addr.clone())])).await?;
+    client.mutate_key(b"zebra", bincode::serialize(&(b"zebra".to_vec(), Vec::<u8>::new()))?).await?;
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    assert_eq!(0, get_ranges(&addr).await?.lookup(b"mango").group_id);
+    client.mutate_key(b"mango", bincode::serialize(&(b"mango".to_vec(), b"new".to_vec()))?).await?;
+
+    // The retry in group 0 returns the original result, instead of being applied again.
+    request.group_id = 0;
+    let retry = server.mutate(request).await?.into_inner();
+    assert!(matches!(bincode::deserialize(&retry.status)?, RpcStatus::Ok));
+    assert_eq!(first.response, retry.response);
+    let value: Option<Vec<u8>> = bincode::deserialize(&client.query_key(b"mango", b"mango".to_vec()).await?)?;
+    assert_eq!(Some(b"new".to_vec()), value);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_session_expiry_across_merge() -> Result<()> {
+    // Group 1's log replays 1000 clock ticks, so its replicated clock is far ahead of group 0's.
+    // Its padding keeps it from merging until removed.
+    let split = SplitOptions { merge_size: 1000, ..SplitOptions::default() };
+    let padding = BTreeMap::from([(b"zebra".to_vec(), vec![0; 1000])]);
+    let peers = allocate_peers(1);
+    let addr = allocate_peers(1)[&0].clone();
+    let ranges = RangeTable::new(vec![b"g".to_vec()])?;
+    let server = FeatherKV::new(0, peers, false, Options::default(), ranges, split, move |group_id| {
+        let mut log_store: Box<dyn storage::log::LogStore> = Box::new(storage::log::Memory::new());
+        let mut data = BTreeMap::new();
+        if group_id == 1 {
+            for index in 1..=1000 {
+                log_store.append(bincode::serialize(&Entry { index, term: 0, command: Command::Clock })?)?;
+            }
+            data = padding.clone();
+        }
+        let state: Box<dyn State> = Box::new(KvState { data });
+        Ok((state, log_store))
+    }).await?;
+    tokio::spawn(Server::builder().add_service(FeatherKvServer::new(server)).serve(addr.parse()?));
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    // A mutation is applied in group 1, but the client does not see the reply.
+    let mut server = FeatherKvClient::connect(format!("http://{}", addr)).await.unwrap();
+    let session_id = server.register(RegistrationRequest { group_id: 1 }).await?.into_inner().session_id;
+    let mut request = ExecutionRequest {
+        session_id,
+        sequence_number: 1,
+        operation: bincode::serialize(&(b"mango".to_vec(), b"old".to_vec()))?,
+        consistency: vec![],
+        lowest_unacked: 1,
+        group_id: 1,
+        key: b"mango".to_vec(),
+    };
+    let first = server.mutate(request.clone()).await?.into_inner();
+    assert!(matches!(bincode::deserialize(&first.status)?, RpcStatus::Ok));
+
+    // The range is merged into group 0, whose clock keeps ticking behind the session's last
+    // activity in group 1.
+    let mut client = Client::new(BTreeMap::from([(0, addr.clone())])).await?;
+    client.mutate_key(b"zebra", bincode::serialize(&(b"zebra".to_vec(), Vec::<u8>::new()))?).await?;
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    assert_eq!(0, get_ranges(&addr).await?.lookup(b"mango").group_id);
+
+    // The session is still live in group 0, and the retry returns the original result.
+    request.group_id = 0;
+    let retry = server.mutate(request).await?.into_inner();
+    assert!(matches!(bincode::deserialize(&retry.status)?, RpcStatus::Ok));
+    assert_eq!(first.response, retry.response);
+    client.mutate_key(b"mango", bincode::serialize(&(b"mango".to_vec(), b"new".to_vec()))?).await?;
+    let value: Option<Vec<u8>> = bincode::deserialize(&client.query_key(b"mango", b"mango".to_vec()).await?)?;
+    assert_eq!(Some(b"new".to_vec()), value);
+    Ok(())
+}
@@ -0,0 +1,357 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use featherdb::error::{Error, Result};
+use featherdb::placement::{self, GroupPlacement, Heartbeat, Operator, PlacementState, ReplicaReport, Routes, Scheduler, Store};
+use featherdb::proto::featherkv::FeatherKvServer;
+use featherdb::raft::{Client, FeatherKV, Membership, Options, RangeTable, SplitOptions, State};
+use featherdb::storage;
+use tokio_util::sync::CancellationToken;
+use tonic::transport::Server;
+
+use super::allocate_peers;
+
+/// A state machine that counts its mutations.
+#[derive(Default)]
+struct CountState {
+    mutations: u64,
+}
+
+impl State for CountState {
+    fn applied_index(&self) -> u64 {
+        0
+    }
+
+    fn mutate(&mut self, _index: u64, _mutation: Vec<u8>) -> Result<Vec<u8>> {
+        self.mutations += 1;
+        self.query(vec![])
+    }
+
+    fn query(&self, _query: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&self.mutations)?)
+    }
+
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&self.mutations)?)
+    }
+
+    fn restore(&mut self, snapshot: Vec<u8>) -> Result<()> {
+        self.mutations = bincode::deserialize(&snapshot)?;
+        Ok(())
+    }
+}
+
+/// Returns `count` live stores with unlimited capacity.
+fn stores(count: u64) -> BTreeMap<u64, Store> {
+    (0..count)
+        .map(|id| (id, Store {
+            id,
+            kv_addr: format!("kv{}", id),
+            raft_addr: format!("raft{}", id),
+            capacity: 0,
+            last_heartbeat: 0,
+            dead: false,
+        }))
+        .collect()
+}
+
+/// Returns the placement of a group with the given voters and leader.
+fn group(voters: &[u64], leader: u64) -> GroupPlacement {
+    let peers = voters.iter().map(|&id| (id, format!("raft{}", id))).collect();
+    GroupPlacement { leader, membership: Membership::bootstrap(&peers), membership_index: 1 }
+}
+
+/// Applies a mutation to a placement state machine.
+fn mutate(state: &mut PlacementState, mutation: placement::Mutation) -> Result<Vec<u8>> {
+    state.mutate(0, bincode::serialize(&mutation)?)
+}
+
+/// Sends a heartbeat to a placement state machine, returning the groups the store should drop.
+fn heartbeat(state: &mut PlacementState, store: u64, replicas: Vec<ReplicaReport>) -> Result<Vec<u64>> {
+    let heartbeat = Heartbeat {
+        store,
+        kv_addr: format!("kv{}", store),
+        raft_addr: format!("raft{}", store),
+        capacity: 0,
+        replicas,
+    };
+    Ok(bincode::deserialize(&mutate(state, placement::Mutation::Heartbeat(heartbeat))?)?)
+}
+
+#[test]
+fn test_schedule_replica_count() -> Result<()> {
+    let scheduler = Scheduler::new(3);
+    let stores = stores(4);
+
+    // An over-replicated group loses its most loaded follower, an under-replicated one gains a
+    // replica on the least loaded store.
+    let groups = BTreeMap::from([(0, group(&[0, 1, 2, 3], 0)), (1, group(&[3], 3))]);
+    assert_eq!(
+        vec![
+            Operator::RemoveReplica { group_id: 0, store: 3 },
+            Operator::AddReplica { group_id: 1, store: 0, raft_addr: "raft0".into() },
+        ],
+        scheduler.schedule(&stores, &groups),
+    );
+
+    // A group with a learner is left alone until it is promoted.
+    let mut learning = group(&[0], 0);
+    learning.membership = learning.membership.add_learner(1, "raft1".into())?;
+    assert!(scheduler.schedule(&stores, &BTreeMap::from([(0, learning)])).is_empty());
+
+    // Stores without room are skipped.
+    let mut full = stores.clone();
+    full.values_mut().for_each(|store| store.capacity = 1);
+    let groups = BTreeMap::from([(0, group(&[0, 1, 2], 0)), (1, group(&[3], 3))]);
+    assert!(scheduler.schedule(&full, &groups).is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_schedule_dead_store() -> Result<()> {
+    let scheduler = Scheduler::new(3);
+    let mut stores = stores(4);
+    stores.get_mut(&1).unwrap().dead = true;
+
+    // A replica on a dead store is replaced: first a replica is added on a live store, then the
+    // dead one is removed.
+    let groups = BTreeMap::from([(0, group(&[0, 1, 2], 0))]);
+    assert_eq!(
+        vec![Operator::AddReplica { group_id: 0, store: 3, raft_addr: "raft3".into() }],
+        scheduler.schedule(&stores, &groups),
+    );
+    let groups = BTreeMap::from([(0, group(&[0, 1, 2, 3], 0))]);
+    assert_eq!(vec![Operator::RemoveReplica { group_id: 0, store: 1 }], scheduler.schedule(&stores, &groups));
+
+    // Without a live store to move to, the dead replica is removed right away.
+    stores.remove(&3);
+    let groups = BTreeMap::from([(0, group(&[0, 1, 2], 0))]);
+    assert_eq!(vec![Operator::RemoveReplica { group_id: 0, store: 1 }], scheduler.schedule(&stores, &groups));
+
+    // A store that has not sent a heartbeat yet is not considered dead.
+    let groups = BTreeMap::from([(0, group(&[0, 2, 5], 0))]);
+    assert!(scheduler.schedule(&stores, &groups).is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_schedule_balance() -> Result<()> {
+    let scheduler = Scheduler::new(3);
+    let stores = stores(4);
+
+    // Replicas are moved to the empty store until the counts differ by at most one, then leaders
+    // are spread across the voters.
+    let groups = (0..3).map(|group_id| (group_id, group(&[0, 1, 2], 0))).collect();
+    assert_eq!(
+        vec![
+            Operator::AddReplica { group_id: 0, store: 3, raft_addr: "raft3".into() },
+            Operator::AddReplica { group_id: 1, store: 3, raft_addr: "raft3".into() },
+            Operator::TransferLeader { group_id: 2, store: 1 },
+        ],
+        scheduler.schedule(&stores, &groups),
+    );
+
+    // A balanced placement needs no operators.
+    let groups = BTreeMap::from([(0, group(&[0, 1, 2], 0)), (1, group(&[1, 2, 3], 3))]);
+    assert!(scheduler.schedule(&stores, &groups).is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_placement_state() -> Result<()> {
+    let mut state = PlacementState::new();
+    let report = |leader, voters: &[u64], membership_index| {
+        let peers = voters.iter().map(|&id| (id, format!("raft{}", id))).collect();
+        ReplicaReport {
+            range: RangeTable::default().lookup(b"").clone(),
+            leader,
+            membership: Membership::bootstrap(&peers),
+            membership_index,
+        }
+    };
+
+    // The leader's report is authoritative. A replica behind a configuration that excludes it was
+    // removed and should be dropped, while a new replica that has not caught up is kept.
+    assert!(heartbeat(&mut state, 0, vec![report(true, &[0, 1, 2], 5)])?.is_empty());
+    assert!(heartbeat(&mut state, 1, vec![report(false, &[0], 0)])?.is_empty());
+    assert_eq!(vec![0], heartbeat(&mut state, 3, vec![report(false, &[0, 3], 3)])?);
+
+    // Stores are declared dead once they miss heartbeats, and routed around.
+    for _ in 0..placement::DEAD_TICKS {
+        mutate(&mut state, placement::Mutation::Tick)?;
+    }
+    heartbeat(&mut state, 0, vec![])?;
+    mutate(&mut state, placement::Mutation::Tick)?;
+    let stores: BTreeMap<u64, Store> = bincode::deserialize(&state.query(bincode::serialize(&placement::Query::Stores)?)?)?;
+    assert_eq!(vec![1, 3], stores.values().filter(|store| store.dead).map(|store| store.id).collect::<Vec<_>>());
+    let routes: Routes = bincode::deserialize(&state.query(bincode::serialize(&placement::Query::Routes)?)?)?;
+    assert_eq!(BTreeMap::from([(0, "kv0".to_string())]), routes.stores);
+    assert_eq!(BTreeMap::from([(0, 0)]), routes.leaders);
+
+    // A heartbeat revives a dead store, and the state survives a snapshot.
+    heartbeat(&mut state, 1, vec![])?;
+    let mut restored = PlacementState::new();
+    restored.restore(state.snapshot()?)?;
+    let routes: Routes = bincode::deserialize(&restored.query(bincode::serialize(&placement::Query::Routes)?)?)?;
+    assert_eq!(vec![0, 1], routes.stores.keys().copied().collect::<Vec<_>>());
+    Ok(())
+}
+
+/// A placement state machine that rejects heartbeats while `failing` is set.
+struct FlakyPlacement {
+    state: PlacementState,
+    failing: Arc<AtomicBool>,
+}
+
+impl State for FlakyPlacement {
+    fn applied_index(&self) -> u64 {
+        self.state.applied_index()
+    }
+
+    fn mutate(&mut self, index: u64, mutation: Vec<u8>) -> Result<Vec<u8>> {
+        let heartbeat = matches!(bincode::deserialize(&mutation)?, placement::Mutation::Heartbeat(_));
+        if heartbeat && self.failing.load(Ordering::SeqCst) {
+            return Err(Error::Value("Placement driver unavailable".into()));
+        }
+        self.state.mutate(index, mutation)
+    }
+
+    fn query(&self, query: Vec<u8>) -> Result<Vec<u8>> {
+        self.state.query(query)
+    }
+
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        self.state.snapshot()
+    }
+
+    fn restore(&mut self, snapshot: Vec<u8>) -> Result<()> {
+        self.state.restore(snapshot)
+    }
+}
+
+/// Starts a single-node placement driver keeping `replicas` replicas of each group, served on
+/// `addr` until `shutdown` is cancelled. Heartbeats are rejected while `failing` is set.
+async fn start_placement_driver(
+    addr: &str,
+    replicas: usize,
+    shutdown: CancellationToken,
+    failing: Arc<AtomicBool>,
+) -> Result<Arc<FeatherKV>> {
+    let pd = Arc::new(FeatherKV::new(
+        0,
+        allocate_peers(1),
+        false,
+        Options::default(),
+        RangeTable::default(),
+        SplitOptions::default(),
+        move |_| {
+            let state: Box<dyn State> = Box::new(FlakyPlacement { state: PlacementState::new(), failing: failing.clone() });
+            let log_store: Box<dyn storage::log::LogStore> = Box::new(storage::log::Memory::new());
+            Ok((state, log_store))
+        },
+    ).await?);
+    let placement = BTreeMap::from([(0, addr.to_string())]);
+    tokio::spawn(Scheduler::new(replicas).run(pd.clone(), placement));
+    tokio::spawn(
+        Server::builder()
+            .add_service(FeatherKvServer::from_arc(pd.clone()))
+            .serve_with_shutdown(addr.parse()?, shutdown.cancelled_owned()),
+    );
+    Ok(pd)
+}
+
+/// Starts `count` stores bootstrapping a group with a replica on each, reporting to the placement
+/// driver.
+async fn start_stores(count: u64, placement: &BTreeMap<u64, String>) -> Result<()> {
+    let peers = allocate_peers(count);
+    let kv_addrs = allocate_peers(count);
+    for id in 0..count {
+        let store = FeatherKV::new(id, peers.clone(), false, Options::default(), RangeTable::default(), SplitOptions::default(), |_| {
+            let state: Box<dyn State> = Box::new(CountState::default());
+            let log_store: Box<dyn storage::log::LogStore> = Box::new(storage::log::Memory::new());
+            Ok((state, log_store))
+        }).await?;
+        store.report_to(placement.clone(), kv_addrs[&id].clone(), 0);
+        tokio::spawn(Server::builder().add_service(FeatherKvServer::new(store)).serve(kv_addrs[&id].parse()?));
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_placement_driver() -> Result<()> {
+    // A single-node placement driver keeping 3 replicas of each group, and four stores with a
+    // replica of a group each.
+    let pd_addr = allocate_peers(1)[&0].clone();
+    start_placement_driver(&pd_addr, 3, CancellationToken::new(), Arc::default()).await?;
+    let placement = BTreeMap::from([(0, pd_addr.clone())]);
+    start_stores(4, &placement).await?;
+
+    // The placement driver removes the extra replica.
+    let mut pd_client = Client::new(placement.clone()).await?;
+    let mut voters = BTreeSet::new();
+    for _ in 0..30 {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let groups: BTreeMap<u64, GroupPlacement> =
+            bincode::deserialize(&pd_client.query(bincode::serialize(&placement::Query::Groups)?).await?)?;
+        voters = groups.get(&0).map(|group| group.membership.voters.clone()).unwrap_or_default();
+        if voters.len() == 3 {
+            break;
+        }
+    }
+    assert_eq!(3, voters.len());
+
+    // A client routed via the placement driver reaches the group.
+    let mut client = Client::placed(placement).await?;
+    client.mutate(vec![]).await?;
+    let mutations: u64 = bincode::deserialize(&client.query(vec![]).await?)?;
+    assert_eq!(1, mutations);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_placement_driver_restart() -> Result<()> {
+    let pd_addr = allocate_peers(1)[&0].clone();
+    let shutdown = CancellationToken::new();
+    let pd = start_placement_driver(&pd_addr, 2, shutdown.clone(), Arc::default()).await?;
+    let placement = BTreeMap::from([(0, pd_addr.clone())]);
+    start_stores(2, &placement).await?;
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    // The placement driver restarts, losing its state, and heartbeats go unanswered meanwhile.
+    pd.shutdown().await?;
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    shutdown.cancel();
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    start_placement_driver(&pd_addr, 2, CancellationToken::new(), Arc::default()).await?;
+
+    // The stores keep sending heartbeats to the new placement driver, and stay live.
+    tokio::time::sleep(Duration::from_secs(placement::DEAD_TICKS + 5)).await;
+    let mut pd_client = Client::new(placement).await?;
+    let stores: BTreeMap<u64, Store> =
+        bincode::deserialize(&pd_client.query(bincode::serialize(&placement::Query::Stores)?).await?)?;
+    assert_eq!(vec![(0, false), (1, false)], stores.values().map(|store| (store.id, store.dead)).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_placement_driver_failing_heartbeats() -> Result<()> {
+    let pd_addr = allocate_peers(1)[&0].clone();
+    let failing = Arc::new(AtomicBool::new(false));
+    start_placement_driver(&pd_addr, 2, CancellationToken::new(), failing.clone()).await?;
+    let placement = BTreeMap::from([(0, pd_addr.clone())]);
+    start_stores(2, &placement).await?;
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    // The placement driver rejects heartbeats for a while, and the stores retry them.
+    failing.store(true, Ordering::SeqCst);
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    failing.store(false, Ordering::SeqCst);
+    tokio::time::sleep(Duration::from_secs(placement::DEAD_TICKS + 5)).await;
+    let mut pd_client = Client::new(placement).await?;
+    let stores: BTreeMap<u64, Store> =
+        bincode::deserialize(&pd_client.query(bincode::serialize(&placement::Query::Stores)?).await?)?;
+    assert_eq!(vec![(0, false), (1, false)], stores.values().map(|store| (store.id, store.dead)).collect::<Vec<_>>());
+    Ok(())
+}
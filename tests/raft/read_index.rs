@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+use featherdb::error::{Error, Result};
+use featherdb::raft::{ApplyMsg, Command, Options};
+use super::{setup, setup_with};
+
+#[tokio::test]
+async fn test_read_index() -> Result<()> {
+    let mut cluster = setup(3).await?;
+    let leader = cluster.check_one_leader().await?;
+
+    // The new leader commits a noop in its term, which is the earliest possible read index.
+    let apply_msg = cluster.nodes[leader as usize].apply_rx.recv().await.unwrap();
+    let noop_index = match apply_msg {
+        ApplyMsg::Command { log_index, command: Command::Noop } => log_index,
+        msg => panic!("Expected noop, got {:?}", msg),
+    };
+    assert_eq!(noop_index, cluster.nodes[leader as usize].node.read_index().await?);
+
+    // Followers cannot serve reads.
+    let follower = (leader + 1) % 3;
+    assert_eq!(Err(Error::NotLeader), cluster.nodes[follower as usize].node.read_index().await);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_read_index_partitioned_leader() -> Result<()> {
+    let cluster = setup(3).await?;
+    let leader = cluster.check_one_leader().await?;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // A partitioned leader cannot confirm its leadership, since a newer leader may exist.
+    cluster.disconnect(leader)?;
+    assert_eq!(Err(Error::NotLeader), cluster.nodes[leader as usize].node.read_index().await);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lease_read() -> Result<()> {
+    let opts = Options { lease_reads: true, ..Options::default() };
+    let mut cluster = setup_with(3, opts).await?;
+    let leader = cluster.check_one_leader().await?;
+    let apply_msg = cluster.nodes[leader as usize].apply_rx.recv().await.unwrap();
+    assert!(matches!(apply_msg, ApplyMsg::Command { command: Command::Noop, .. }));
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    // While the lease holds, the leader serves reads without contacting its peers.
+    cluster.disconnect(leader)?;
+    assert!(cluster.nodes[leader as usize].node.read_index().await.is_ok());
+
+    // Once the lease expires, it falls back to ReadIndex, which fails without a quorum.
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(Err(Error::NotLeader), cluster.nodes[leader as usize].node.read_index().await);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stale_read() -> Result<()> {
+    let mut cluster = setup(3).await?;
+    let leader = cluster.check_one_leader().await?;
+    let apply_msg = cluster.nodes[leader as usize].apply_rx.recv().await.unwrap();
+    let noop_index = match apply_msg {
+        ApplyMsg::Command { log_index, command: Command::Noop } => log_index,
+        msg => panic!("Expected noop, got {:?}", msg),
+    };
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    // A follower in contact with the leader serves reads at the leader's commit index.
+    let follower = (leader + 1) % 3;
+    let max_staleness = Duration::from_secs(1);
+    assert_eq!(noop_index, cluster.nodes[follower as usize].node.stale_read_index(max_staleness)?);
+    assert_eq!(noop_index, cluster.nodes[leader as usize].node.stale_read_index(max_staleness)?);
+
+    // A partitioned follower rejects reads once it has been out of contact for too long.
+    cluster.disconnect(follower)?;
+    tokio::time::sleep(Duration::from_millis(1200)).await;
+    assert_eq!(
+        Err(Error::NotLeader),
+        cluster.nodes[follower as usize].node.stale_read_index(max_staleness),
+    );
+    Ok(())
+}
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use featherdb::error::{Error, Result};
+use featherdb::raft::{ApplyResult, Command, Driver, Node, Options, SimNetwork, State, Waiters};
+use featherdb::storage;
+use tokio::sync::{mpsc, oneshot};
+
+/// A state machine that counts the mutations applied to it.
+struct Counter {
+    count: Arc<Mutex<u64>>,
+}
+
+impl State for Counter {
+    fn applied_index(&self) -> u64 {
+        0
+    }
+
+    fn mutate(&mut self, _index: u64, _mutation: Vec<u8>) -> Result<Vec<u8>> {
+        let mut count = self.count.lock()?;
+        *count += 1;
+        Ok(count.to_be_bytes().to_vec())
+    }
+
+    fn query(&self, _query: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(self.count.lock()?.to_be_bytes().to_vec())
+    }
+
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        Ok(self.count.lock()?.to_be_bytes().to_vec())
+    }
+
+    fn restore(&mut self, snapshot: Vec<u8>) -> Result<()> {
+        *self.count.lock()? = u64::from_be_bytes(snapshot[..].try_into().unwrap());
+        Ok(())
+    }
+}
+
+/// A simulated node driving a counter.
+struct CounterNode {
+    node: Node,
+    waiters: Waiters,
+    count: Arc<Mutex<u64>>,
+}
+
+impl CounterNode {
+    /// Proposes a command and waits for the driver to apply it.
+    async fn execute(&self, command: Command, key: (u64, u64)) -> Result<ApplyResult> {
+        let (result_tx, result_rx) = oneshot::channel();
+        self.waiters.lock()?.insert(key, result_tx);
+        self.node.propose(command).await?;
+        Ok(result_rx.await?)
+    }
+
+    /// Applies a mutation for a session.
+    async fn mutate(&self, session_id: u64, sequence_number: u64) -> Result<ApplyResult> {
+        self.mutate_acked(session_id, sequence_number, 0).await
+    }
+
+    /// Applies a mutation for a session, acknowledging the results below `lowest_unacked`.
+    async fn mutate_acked(&self, session_id: u64, sequence_number: u64, lowest_unacked: u64) -> Result<ApplyResult> {
+        let command = Command::Mutation { session_id, sequence_number, lowest_unacked, key: vec![], mutation: vec![] };
+        self.execute(command, (session_id, sequence_number)).await
+    }
+}
+
+/// Starts a cluster of counters on a simulated network.
+fn setup_counters(cluster_size: u64, network: &SimNetwork) -> Result<Vec<CounterNode>> {
+    let mut nodes = vec![];
+    for id in 0..cluster_size {
+        let (apply_tx, apply_rx) = mpsc::channel(16);
+        let (_read_tx, read_rx) = mpsc::unbounded_channel();
+        let node = Node::simulated(
+            id, cluster_size, Options::default(), apply_tx, Box::new(storage::log::Memory::new()), network,
+        )?;
+        let waiters: Waiters = Arc::new(Mutex::new(HashMap::new()));
+        let count = Arc::new(Mutex::new(0));
+        let state = Box::new(Counter { count: count.clone() });
+        tokio::spawn(Driver::new(node.clone(), state, apply_rx, read_rx, waiters.clone()).drive());
+        tokio::spawn(node.clone().serve());
+        nodes.push(CounterNode { node, waiters, count });
+    }
+    Ok(nodes)
+}
+
+/// Waits for a node to become the leader.
+async fn wait_leader(node: &Node) -> Result<()> {
+    for _ in 0..100 {
+        if node.is_leader()? {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    Err(Error::Internal("No leader elected".into()))
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_session_dedup_and_expiry() -> Result<()> {
+    let network = SimNetwork::new(0);
+    let nodes = setup_counters(1, &network)?;
+    let node = &nodes[0];
+    wait_leader(&node.node).await?;
+
+    let registration = node.execute(Command::Registration { session_id: 7 }, (7, 0)).await?;
+    assert!(matches!(registration.result, Some(Ok(_))));
+
+    // A retried mutation returns the original result and index, without being applied again.
+    let first = node.mutate(7, 1).await?;
+    let retry = node.mutate(7, 1).await?;
+    assert_eq!(first.index, retry.index);
+    assert_eq!(1u64.to_be_bytes().to_vec(), retry.result.unwrap()?);
+    assert_eq!(1, *node.count.lock()?);
+
+    // Once acknowledged, a retry is rejected without halting the driver.
+    node.mutate_acked(7, 2, 2).await?;
+    assert!(node.mutate(7, 1).await?.result.unwrap().is_err());
+    assert_eq!(2, *node.count.lock()?);
+
+    // Unknown sessions are reported as expired.
+    assert!(node.mutate(9, 1).await?.result.is_none());
+
+    // A session idle for long enough in replicated clock ticks expires.
+    for _ in 0..600 {
+        node.node.propose(Command::Clock).await?;
+    }
+    assert!(node.mutate(7, 3).await?.result.is_none());
+    assert_eq!(2, *node.count.lock()?);
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_session_pipelining() -> Result<()> {
+    let network = SimNetwork::new(0);
+    let nodes = setup_counters(1, &network)?;
+    let node = &nodes[0];
+    wait_leader(&node.node).await?;
+    node.execute(Command::Registration { session_id: 7 }, (7, 0)).await?;
+
+    // Many mutations of a session can be in flight at once, and are each applied once.
+    let results = futures::future::join_all((1..=10).rev().map(|i| node.mutate(7, i))).await;
+    let mut indexes = HashMap::new();
+    for (result, sequence_number) in results.into_iter().zip((1..=10).rev()) {
+        indexes.insert(sequence_number, result?.index);
+    }
+    assert_eq!(10, *node.count.lock()?);
+
+    // Unacknowledged results are all cached, in any order.
+    for sequence_number in [3, 10, 1] {
+        assert_eq!(indexes[&sequence_number], node.mutate(7, sequence_number).await?.index);
+    }
+    assert_eq!(10, *node.count.lock()?);
+
+    // Acknowledging discards the earlier results, but keeps the later ones.
+    node.mutate_acked(7, 11, 6).await?;
+    assert!(node.mutate(7, 5).await?.result.unwrap().is_err());
+    assert_eq!(indexes[&6], node.mutate(7, 6).await?.index);
+    assert_eq!(11, *node.count.lock()?);
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_sessions_survive_snapshot() -> Result<()> {
+    let network = SimNetwork::new(0);
+    let nodes = setup_counters(3, &network)?;
+    let leader = loop {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        if let Some(leader) = nodes.iter().position(|n| n.node.is_leader().unwrap()) {
+            break leader;
+        }
+    };
+    let lagging = (leader + 1) % 3;
+
+    // The lagging node misses the session's mutation and the log compaction that follows it.
+    nodes[lagging].node.disconnect()?;
+    nodes[leader].execute(Command::Registration { session_id: 7 }, (7, 0)).await?;
+    let first = nodes[leader].mutate(7, 1).await?;
+    for _ in 0..1000 {
+        nodes[leader].node.propose(Command::Noop).await?;
+    }
+
+    // Once it catches up via a snapshot and takes over, a retry still returns the original result.
+    nodes[lagging].node.reconnect()?;
+    while *nodes[lagging].count.lock()? == 0 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    nodes[leader].node.transfer_leader(lagging as u64)?;
+    wait_leader(&nodes[lagging].node).await?;
+    let retry = nodes[lagging].mutate(7, 1).await?;
+    assert_eq!(first.index, retry.index);
+    assert_eq!(1u64.to_be_bytes().to_vec(), retry.result.unwrap()?);
+    assert_eq!(1, *nodes[lagging].count.lock()?);
+    Ok(())
+}
@@ -0,0 +1,121 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use featherdb::error::{Error, Result};
+use featherdb::proto::featherkv::FeatherKvServer;
+use featherdb::raft::{ApplyMsg, Client, Clock, Command, FeatherKV, ManualClock, Node, Options, RangeTable, SimNetwork};
+use featherdb::raft::{SplitOptions, State};
+use featherdb::storage;
+use tokio::sync::mpsc;
+use tonic::transport::Server;
+
+use super::{allocate_peers, APPLY_CHANNEL_CAPACITY};
+
+/// A state machine that counts its mutations, and records whether it was flushed.
+struct FlushState {
+    mutations: Arc<AtomicU64>,
+    flushed: Arc<AtomicBool>,
+}
+
+impl State for FlushState {
+    fn applied_index(&self) -> u64 {
+        0
+    }
+
+    fn mutate(&mut self, _index: u64, _mutation: Vec<u8>) -> Result<Vec<u8>> {
+        self.mutations.fetch_add(1, Ordering::SeqCst);
+        Ok(vec![])
+    }
+
+    fn query(&self, _query: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(vec![])
+    }
+
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        Ok(vec![])
+    }
+
+    fn restore(&mut self, _snapshot: Vec<u8>) -> Result<()> {
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.flushed.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_node_shutdown() -> Result<()> {
+    let opts = Options { election_timeout_min: 4, election_timeout_max: 6, ..Options::default() };
+    let network = SimNetwork::new(0);
+    let clock = ManualClock::new();
+    let mut nodes = vec![];
+    for id in 0..3 {
+        let (apply_tx, apply_rx) = mpsc::channel(APPLY_CHANNEL_CAPACITY);
+        let node = Node::simulated(id, 3, opts, apply_tx, Box::new(storage::log::Memory::new()), &network)?;
+        node.set_clock(Clock::Manual(clock.clone()))?;
+        let serve = tokio::spawn(node.clone().serve());
+        nodes.push((node, apply_rx, serve));
+    }
+    let advance = || async {
+        for _ in 0..3 * opts.election_timeout_max {
+            clock.advance(opts.tick_interval);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    advance().await;
+    let leader = nodes.iter().position(|(node, _, _)| node.is_leader().unwrap()).unwrap();
+    let (index, _) = nodes[leader].0.propose(Command::Noop).await?;
+    advance().await;
+
+    // Once shut down, the leader steps down, stops serving, and has sent its committed entries.
+    let (node, mut apply_rx, serve) = nodes.remove(leader);
+    node.shutdown()?;
+    tokio::time::timeout(Duration::from_secs(1), node.stopped()).await.expect("Node did not stop");
+    serve.await.map_err(Error::from)??;
+    assert!(!node.is_leader()?);
+    assert!(matches!(node.propose(Command::Noop).await, Err(Error::NotLeader)));
+    let mut applied = 0;
+    while let Ok(msg) = apply_rx.try_recv() {
+        if let ApplyMsg::Command { log_index, .. } = msg {
+            applied = log_index;
+        }
+    }
+    assert_eq!(index, applied);
+
+    // The remaining nodes elect a new leader without it.
+    advance().await;
+    assert!(nodes.iter().any(|(node, _, _)| node.is_leader().unwrap()));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_featherkv_shutdown() -> Result<()> {
+    let peers = allocate_peers(1);
+    let serve_addr = allocate_peers(1)[&0].clone();
+    let mutations = Arc::new(AtomicU64::new(0));
+    let flushed = Arc::new(AtomicBool::new(false));
+    let state = (mutations.clone(), flushed.clone());
+    let server = Arc::new(FeatherKV::new(0, peers, false, Options::default(), RangeTable::default(), SplitOptions::default(), move |_| {
+        let state: Box<dyn State> = Box::new(FlushState { mutations: state.0.clone(), flushed: state.1.clone() });
+        let log_store: Box<dyn storage::log::LogStore> = Box::new(storage::log::Memory::new());
+        Ok((state, log_store))
+    }).await?);
+    tokio::spawn(Server::builder().add_service(FeatherKvServer::from_arc(server.clone())).serve(serve_addr.parse()?));
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    let mut client = Client::new(BTreeMap::from([(0, serve_addr)])).await?;
+    for _ in 0..3 {
+        client.mutate(vec![]).await?;
+    }
+
+    // Shutting down waits for the state machine to apply the committed mutations and flush.
+    tokio::time::timeout(Duration::from_secs(5), server.shutdown()).await.expect("Server did not shut down")?;
+    assert_eq!(3, mutations.load(Ordering::SeqCst));
+    assert!(flushed.load(Ordering::SeqCst));
+    assert!(!server.is_leader(0)?);
+    Ok(())
+}
@@ -0,0 +1,266 @@
+//! Jepsen-style tests of a key-value store on Raft, running on a simulated network with faults.
+//! Clients write and read keys concurrently while the network drops, delays, reorders and
+//! duplicates messages and a nemesis partitions the cluster; the resulting history must be
+//! linearizable. Set `SIM_SEED` to rerun a single seed.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use featherdb::error::{Error, Result};
+use featherdb::raft::{ApplyMsg, Command, Faults, Node, Options, SimNetwork};
+use featherdb::storage;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use tokio::sync::{mpsc, watch};
+
+use super::linearizability::{check, Kind, Operation};
+
+/// How long a client waits for an operation before considering its outcome unknown.
+const OPERATION_TIMEOUT: Duration = Duration::from_secs(2);
+/// The capacity of a node's apply channel.
+const APPLY_CHANNEL_CAPACITY: usize = 16;
+/// How long clients run for, in virtual time.
+const WORKLOAD_DURATION: Duration = Duration::from_secs(60);
+
+/// A node running a key-value state machine on a simulated network.
+#[derive(Clone)]
+struct SimNode {
+    node: Node,
+    state: Arc<Mutex<KvState>>,
+    applied_rx: watch::Receiver<u64>,
+}
+
+/// A key-value state machine.
+#[derive(Default)]
+struct KvState {
+    data: HashMap<u64, u64>,
+    /// The (client, sequence number) of the write applied at each log index.
+    writes: HashMap<u64, (u64, u64)>,
+}
+
+/// Starts a cluster on a simulated network.
+fn setup_sim(cluster_size: u64, network: &SimNetwork) -> Result<Vec<SimNode>> {
+    let mut nodes = vec![];
+    for id in 0..cluster_size {
+        let (apply_tx, apply_rx) = mpsc::channel(APPLY_CHANNEL_CAPACITY);
+        let node = Node::simulated(
+            id,
+            cluster_size,
+            Options::default(),
+            apply_tx,
+            Box::new(storage::log::Memory::new()),
+            network,
+        )?;
+        let state = Arc::new(Mutex::new(KvState::default()));
+        let (applied_tx, applied_rx) = watch::channel(0);
+        tokio::spawn(apply(apply_rx, state.clone(), applied_tx));
+        tokio::spawn(node.clone().serve());
+        nodes.push(SimNode { node, state, applied_rx });
+    }
+    Ok(nodes)
+}
+
+/// Applies committed writes to the state machine.
+async fn apply(
+    mut apply_rx: mpsc::Receiver<ApplyMsg>,
+    state: Arc<Mutex<KvState>>,
+    applied_tx: watch::Sender<u64>,
+) {
+    while let Some(apply_msg) = apply_rx.recv().await {
+        if let ApplyMsg::Command { log_index, command } = apply_msg {
+            if let Command::Mutation { session_id, sequence_number, mutation, .. } = command {
+                let mut state = state.lock().unwrap();
+                let key = u64::from_be_bytes(mutation[..8].try_into().unwrap());
+                let value = u64::from_be_bytes(mutation[8..].try_into().unwrap());
+                state.data.insert(key, value);
+                state.writes.insert(log_index, (session_id, sequence_number));
+            }
+            let _ = applied_tx.send(log_index);
+        }
+    }
+}
+
+impl SimNode {
+    /// Waits for the state machine to apply an index.
+    async fn wait_applied(&mut self, index: u64) -> Result<()> {
+        while *self.applied_rx.borrow() < index {
+            self.applied_rx.changed().await.map_err(|e| Error::Internal(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Writes a value. Fails if the write definitely did not take effect.
+    async fn put(&mut self, client: u64, sequence_number: u64, key: u64, value: u64) -> Result<()> {
+        let mutation = [key.to_be_bytes(), value.to_be_bytes()].concat();
+        let (index, _) = self.node
+            .propose(Command::Mutation { session_id: client, sequence_number, lowest_unacked: 0, key: vec![], mutation })
+            .await?;
+        self.wait_applied(index).await?;
+        match self.state.lock()?.writes.get(&index) {
+            Some(write) if *write == (client, sequence_number) => Ok(()),
+            // Another entry was committed at the index, replacing the write.
+            _ => Err(Error::NotLeader),
+        }
+    }
+
+    /// Reads a value via the ReadIndex protocol.
+    async fn get(&mut self, key: u64) -> Result<Option<u64>> {
+        let index = self.node.read_index().await?;
+        self.wait_applied(index).await?;
+        Ok(self.state.lock()?.data.get(&key).copied())
+    }
+}
+
+/// Runs a client, which performs random operations against whichever node accepts them, and
+/// records them in the history. Stops once `operations` have completed, or the workload times out.
+async fn run_client(
+    client: u64,
+    mut nodes: Vec<SimNode>,
+    seed: u64,
+    operations: u64,
+    clock: Arc<AtomicU64>,
+    history: Arc<Mutex<Vec<Operation>>>,
+) -> Result<()> {
+    let mut rng = SmallRng::seed_from_u64(seed ^ client);
+    let mut target = client as usize % nodes.len();
+    let deadline = tokio::time::Instant::now() + WORKLOAD_DURATION;
+    let mut completed = 0;
+    let mut sequence_number = 0;
+    while completed < operations && tokio::time::Instant::now() < deadline {
+        sequence_number += 1;
+        let key = rng.gen_range(0..3);
+        let node = &mut nodes[target];
+        let call = clock.fetch_add(1, Ordering::SeqCst);
+        let op = match rng.gen_bool(0.5) {
+            true => {
+                let value = client * 1000 + sequence_number;
+                let result = tokio::time::timeout(
+                    OPERATION_TIMEOUT, node.put(client, sequence_number, key, value),
+                ).await;
+                let ret = clock.fetch_add(1, Ordering::SeqCst);
+                match result {
+                    Ok(Ok(())) => Some(Operation { key, kind: Kind::Put(value), call, ret: Some(ret) }),
+                    Ok(Err(_)) => None,
+                    Err(_) => Some(Operation { key, kind: Kind::Put(value), call, ret: None }),
+                }
+            }
+            false => {
+                let result = tokio::time::timeout(OPERATION_TIMEOUT, node.get(key)).await;
+                let ret = clock.fetch_add(1, Ordering::SeqCst);
+                match result {
+                    Ok(Ok(value)) => Some(Operation { key, kind: Kind::Get(value), call, ret: Some(ret) }),
+                    _ => None,
+                }
+            }
+        };
+        match op {
+            Some(op) if op.ret.is_some() => {
+                history.lock()?.push(op);
+                completed += 1;
+            }
+            op => {
+                // Tries another node after a failure, backing off while there is no leader.
+                history.lock()?.extend(op);
+                target = (target + 1) % nodes.len();
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs a workload against a cluster under faults, and checks that its history is linearizable.
+fn run_simulation(seed: u64) -> Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build()?;
+    runtime.block_on(async move {
+        let network = SimNetwork::new(seed);
+        network.set_faults(Faults {
+            drop: 0.05,
+            drop_reply: 0.05,
+            duplicate: 0.05,
+            max_delay: Duration::from_millis(20),
+        })?;
+        let nodes = setup_sim(5, &network)?;
+
+        // The nemesis partitions the cluster at random, and heals it in between.
+        let nemesis_network = network.clone();
+        let nemesis = tokio::spawn(async move {
+            let mut rng = SmallRng::seed_from_u64(seed);
+            loop {
+                tokio::time::sleep(Duration::from_millis(rng.gen_range(1000..3000))).await;
+                let mut ids = (0..5).collect::<Vec<u64>>();
+                for i in (1..ids.len()).rev() {
+                    ids.swap(i, rng.gen_range(0..=i));
+                }
+                let split = rng.gen_range(1..=2);
+                nemesis_network.partition(&[ids[..split].to_vec(), ids[split..].to_vec()])?;
+                tokio::time::sleep(Duration::from_millis(rng.gen_range(1000..3000))).await;
+                nemesis_network.heal()?;
+            }
+            #[allow(unreachable_code)]
+            Ok::<_, Error>(())
+        });
+
+        let clock = Arc::new(AtomicU64::new(0));
+        let history = Arc::new(Mutex::new(Vec::new()));
+        let clients = (0..4).map(|client| {
+            tokio::spawn(run_client(client, nodes.clone(), seed, 25, clock.clone(), history.clone()))
+        }).collect::<Vec<_>>();
+        for client in clients {
+            client.await.map_err(|e| Error::Internal(e.to_string()))??;
+        }
+        nemesis.abort();
+
+        let history = history.lock()?.clone();
+        let completed = history.iter().filter(|op| op.ret.is_some()).count();
+        println!("Seed {}: {} operations, {} completed", seed, history.len(), completed);
+        assert!(completed > 0, "Seed {}: no operations completed", seed);
+        assert!(check(&history), "Seed {}: history is not linearizable: {:#?}", seed, history);
+        Ok(())
+    })
+}
+
+#[test]
+fn test_linearizable_kv_under_faults() -> Result<()> {
+    let seeds = match std::env::var("SIM_SEED") {
+        Ok(seed) => vec![seed.parse().map_err(|_| Error::Value(format!("Invalid seed {}", seed)))?],
+        Err(_) => (0..3).collect(),
+    };
+    for seed in seeds {
+        run_simulation(seed)?;
+    }
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_sim_partition() -> Result<()> {
+    let network = SimNetwork::new(0);
+    let nodes = setup_sim(3, &network)?;
+    let leader = find_leader(&nodes).await;
+
+    // An isolated leader is replaced by a new one in the majority.
+    let others = (0..3).filter(|id| *id != leader).collect::<Vec<_>>();
+    network.partition(&[vec![leader], others.clone()])?;
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let new_leader = find_leader(&nodes).await;
+    assert!(others.contains(&new_leader));
+    assert!(!nodes[leader as usize].node.is_leader()?);
+    Ok(())
+}
+
+/// Waits for a node to become the leader, and returns its ID.
+async fn find_leader(nodes: &[SimNode]) -> u64 {
+    loop {
+        for (id, node) in nodes.iter().enumerate() {
+            if node.node.is_leader().unwrap() {
+                return id as u64;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
@@ -0,0 +1,94 @@
+use featherdb::error::Result;
+use featherdb::raft::{Command, Log, Snapshot};
+use featherdb::storage;
+
+/// Creates a log with entries 1 to `count`, all in term 1 and committed.
+fn setup(count: u64) -> Result<Log> {
+    let mut log = Log::new(Box::new(storage::log::Memory::new()))?;
+    for i in 1..=count {
+        log.append(1, Command::Registration { session_id: i })?;
+    }
+    log.commit(count)?;
+    Ok(log)
+}
+
+#[test]
+fn test_compact() -> Result<()> {
+    let mut log = setup(5)?;
+    log.compact(Snapshot { index: 3, term: 1, data: b"state".to_vec(), membership: None })?;
+
+    assert_eq!(None, log.get(3)?);
+    assert_eq!(Some(1), log.term(3)?);
+    assert_eq!(None, log.term(2)?);
+    assert_eq!(
+        vec![4, 5],
+        log.scan(..).map(|e| e.map(|e| e.index)).collect::<Result<Vec<_>>>()?,
+    );
+    assert_eq!(Some(b"state".to_vec()), log.snapshot()?.map(|s| s.data));
+
+    // Appends continue after the retained entries.
+    assert_eq!(6, log.append(2, Command::Registration { session_id: 6 })?.index);
+    assert_eq!(Some(2), log.term(6)?);
+    Ok(())
+}
+
+#[test]
+fn test_compact_uncommitted() -> Result<()> {
+    let mut log = setup(3)?;
+    log.append(1, Command::Registration { session_id: 4 })?;
+    assert!(log.compact(Snapshot { index: 4, term: 1, data: vec![], membership: None }).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_install_matching() -> Result<()> {
+    let mut log = setup(2)?;
+    for i in 3..=5 {
+        log.append(1, Command::Registration { session_id: i })?;
+    }
+
+    // The entries following a matching snapshot are retained.
+    log.install(Snapshot { index: 3, term: 1, data: vec![], membership: None })?;
+    assert_eq!(
+        vec![4, 5],
+        log.scan(..).map(|e| e.map(|e| e.index)).collect::<Result<Vec<_>>>()?,
+    );
+    Ok(())
+}
+
+#[test]
+fn test_install_conflicting() -> Result<()> {
+    let mut log = setup(2)?;
+    for i in 3..=5 {
+        log.append(1, Command::Registration { session_id: i })?;
+    }
+
+    // A snapshot from a later term discards the whole log.
+    log.install(Snapshot { index: 4, term: 2, data: vec![], membership: None })?;
+    assert!(log.scan(..).next().is_none());
+    assert_eq!(Some(2), log.term(4)?);
+    assert_eq!(5, log.append(2, Command::Registration { session_id: 5 })?.index);
+
+    // A snapshot past the end of the log also discards it.
+    log.install(Snapshot { index: 10, term: 3, data: vec![], membership: None })?;
+    assert!(log.scan(..).next().is_none());
+    assert_eq!(11, log.append(3, Command::Registration { session_id: 11 })?.index);
+    Ok(())
+}
+
+#[test]
+fn test_reopen() -> Result<()> {
+    let store = storage::log::LogDemo::new();
+    let mut log = Log::new(Box::new(store.clone()))?;
+    for i in 1..=4 {
+        log.append(1, Command::Registration { session_id: i })?;
+    }
+    log.commit(4)?;
+    log.compact(Snapshot { index: 4, term: 1, data: vec![], membership: None })?;
+
+    // A fully compacted log recovers its last index from the snapshot.
+    let mut log = Log::new(Box::new(store))?;
+    assert_eq!(Some(1), log.term(4)?);
+    assert_eq!(5, log.append(1, Command::Registration { session_id: 5 })?.index);
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use featherdb::error::Result;
+use featherdb::proto::featherkv::FeatherKvServer;
+use featherdb::raft::{Client, Clock, Command, FeatherKV, ManualClock, Node, Options, RangeTable, SimNetwork};
+use featherdb::raft::{SplitOptions, State};
+use featherdb::storage;
+use tokio::sync::mpsc;
+use tonic::transport::Server;
+
+use super::{allocate_peers, APPLY_CHANNEL_CAPACITY};
+
+/// A state machine that ignores its mutations.
+struct NoopState;
+
+impl State for NoopState {
+    fn applied_index(&self) -> u64 {
+        0
+    }
+
+    fn mutate(&mut self, _index: u64, _mutation: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(vec![])
+    }
+
+    fn query(&self, _query: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(vec![])
+    }
+
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        Ok(vec![])
+    }
+
+    fn restore(&mut self, _snapshot: Vec<u8>) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_node_status() -> Result<()> {
+    let opts = Options { election_timeout_min: 4, election_timeout_max: 6, ..Options::default() };
+    let network = SimNetwork::new(0);
+    let clock = ManualClock::new();
+    let mut nodes = vec![];
+    for id in 0..3 {
+        let (apply_tx, apply_rx) = mpsc::channel(APPLY_CHANNEL_CAPACITY);
+        let node = Node::simulated(id, 3, opts, apply_tx, Box::new(storage::log::Memory::new()), &network)?;
+        node.set_clock(Clock::Manual(clock.clone()))?;
+        tokio::spawn(node.clone().serve());
+        nodes.push((node, apply_rx));
+    }
+    let advance = || async {
+        for _ in 0..3 * opts.election_timeout_max {
+            clock.advance(opts.tick_interval);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    advance().await;
+
+    let leader = nodes.iter().find(|(node, _)| node.is_leader().unwrap()).unwrap().0.clone();
+    leader.propose(Command::Noop).await?;
+    advance().await;
+
+    // The leader reports each peer's progress, caught up with its log.
+    let status = leader.status()?;
+    assert_eq!("leader", status.role);
+    assert_eq!(leader.id()?, status.leader);
+    assert_eq!(status.last_index, status.commit_index);
+    assert_eq!(vec![0, 1, 2].into_iter().filter(|&id| id != status.id).collect::<Vec<_>>(),
+        status.progress.keys().copied().collect::<Vec<_>>());
+    for progress in status.progress.values() {
+        assert_eq!(status.last_index, progress.match_index);
+        assert_eq!(status.last_index + 1, progress.next_index);
+        assert!(progress.last_contact.is_some_and(|contact| contact <= opts.tick_interval * 2));
+    }
+
+    // Followers know the leader and term, but report no progress.
+    for (node, _) in nodes.iter().filter(|(node, _)| !node.is_leader().unwrap()) {
+        let follower = node.status()?;
+        assert_eq!("follower", follower.role);
+        assert_eq!((status.term, status.leader), (follower.term, follower.leader));
+        assert_eq!(status.commit_index, follower.commit_index);
+        assert!(follower.progress.is_empty());
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_status_rpc() -> Result<()> {
+    let peers = allocate_peers(1);
+    let serve_addr = allocate_peers(1)[&0].clone();
+    let ranges = RangeTable::new(vec![b"m".to_vec()])?;
+    let server = FeatherKV::new(0, peers, false, Options::default(), ranges, SplitOptions::default(), |_| {
+        let state: Box<dyn State> = Box::new(NoopState);
+        let log_store: Box<dyn storage::log::LogStore> = Box::new(storage::log::Memory::new());
+        Ok((state, log_store))
+    }).await?;
+    tokio::spawn(Server::builder().add_service(FeatherKvServer::new(server)).serve(serve_addr.parse()?));
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    // The server reports the status of both groups' replicas.
+    let mut client = Client::new(BTreeMap::from([(0, serve_addr)])).await?;
+    client.mutate(vec![]).await?;
+    let status = client.status().await?;
+    assert_eq!(vec![0], status.keys().copied().collect::<Vec<_>>());
+    assert_eq!(vec![0, 1], status[&0].keys().copied().collect::<Vec<_>>());
+    let group = &status[&0][&0];
+    assert_eq!(("leader", 0), (group.role.as_str(), group.leader));
+    assert!(group.commit_index >= 2);
+    assert!(group.log_size > 0);
+    Ok(())
+}
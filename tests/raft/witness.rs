@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+use featherdb::error::Result;
+use featherdb::raft::{ApplyMsg, Clock, Command, ManualClock, Node, Options, SimNetwork};
+use featherdb::storage;
+use tokio::sync::mpsc;
+
+use super::APPLY_CHANNEL_CAPACITY;
+
+/// Returns a mutation command with the given sequence number.
+fn mutation(sequence_number: u64) -> Command {
+    Command::Mutation { session_id: 1, sequence_number, lowest_unacked: 1, key: vec![], mutation: vec![7; 100] }
+}
+
+#[tokio::test]
+async fn test_witness() -> Result<()> {
+    let opts = Options { election_timeout_min: 4, election_timeout_max: 6, ..Options::default() };
+    let network = SimNetwork::new(0);
+    let clock = ManualClock::new();
+    let advance = |ticks: u64| {
+        let clock = clock.clone();
+        async move {
+            for _ in 0..ticks {
+                clock.advance(opts.tick_interval);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    };
+
+    // Nodes 0 and 1 bootstrap the cluster, node 2 waits to be added as a witness.
+    let mut nodes = vec![];
+    for id in 0..3 {
+        let (apply_tx, apply_rx) = mpsc::channel(APPLY_CHANNEL_CAPACITY);
+        let node = Node::simulated(id, 2, opts, apply_tx, Box::new(storage::log::Memory::new()), &network)?;
+        node.set_clock(Clock::Manual(clock.clone()))?;
+        tokio::spawn(node.clone().serve());
+        nodes.push((node, apply_rx));
+    }
+    advance(3 * opts.election_timeout_max).await;
+    let leader = (0..2).find(|&id| nodes[id].0.is_leader().unwrap()).unwrap();
+    let follower = 1 - leader;
+    nodes[leader].0.add_witness(2, "sim-2".into())?;
+    advance(opts.election_timeout_max).await;
+    let membership = nodes[leader].0.membership()?;
+    assert!(membership.is_voter(2) && membership.is_witness(2));
+    assert!(nodes[2].0.is_witness()?);
+    assert!(nodes[leader].0.transfer_leader(2).is_err());
+
+    // With the follower cut off, the witness makes up the quorum, but does not apply the entries.
+    network.partition(&[vec![leader as u64, 2], vec![follower as u64]])?;
+    let (index, _) = nodes[leader].0.propose(mutation(1)).await?;
+    advance(opts.election_timeout_min).await;
+    assert_eq!(index, nodes[leader].0.status()?.commit_index);
+    let witness = nodes[2].0.status()?;
+    assert_eq!(("witness", index), (witness.role.as_str(), witness.commit_index));
+    assert!(nodes[2].1.try_recv().is_err());
+    let mut applied = vec![];
+    while let Ok(msg) = nodes[leader].1.try_recv() {
+        applied.push(msg);
+    }
+    assert!(applied.iter().any(|msg| matches!(msg, ApplyMsg::Command { command, .. } if *command == mutation(1))));
+
+    // Once the follower has caught up, it stores the whole mutation, but the witness only its header.
+    network.heal()?;
+    advance(opts.election_timeout_min).await;
+    assert!(nodes[2].0.status()?.log_size < nodes[follower].0.status()?.log_size);
+
+    // With the leader cut off, the follower is elected with the witness's vote. The witness never
+    // campaigns itself.
+    network.partition(&[vec![leader as u64], vec![follower as u64, 2]])?;
+    advance(3 * opts.election_timeout_max).await;
+    assert!(nodes[follower].0.is_leader()?);
+    assert!(!nodes[2].0.is_leader()?);
+    assert_eq!(nodes[follower].0.term()?, nodes[2].0.term()?);
+    Ok(())
+}